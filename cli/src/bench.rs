@@ -0,0 +1,250 @@
+//! Micro-benchmarks for `upstool bench`, so users reporting slowness can attach comparable
+//! numbers.
+//!
+//! Each operation runs a few times on the same data and the best and mean times are reported,
+//! along with what affects them: the upstool version, target and whether it's a debug build. These
+//! aren't rigorous measurements, use the criterion benchmarks in `lib/benches` for that.
+use std::fmt::{self, Display, Formatter};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use ups::{ByteSize, Patch};
+
+/// Size of the synthetic source file used by `upstool bench` by default.
+pub const DEFAULT_SIZE: usize = 16 * 1024 * 1024;
+
+/// Timings for one operation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    /// Operation name, e.g. `apply`.
+    pub op: &'static str,
+    /// Bytes the operation reads per run.
+    pub bytes: usize,
+    /// Number of runs.
+    pub iterations: u32,
+    /// Fastest run.
+    pub best: Duration,
+    /// Mean of all runs.
+    pub mean: Duration,
+}
+
+/// Results of [`run`] with the environment they were measured in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    /// Size of the source file.
+    pub source_size: usize,
+    /// Size of the destination file.
+    pub dest_size: usize,
+    /// Size of the patch between them.
+    pub patch_size: usize,
+    /// Whether the files were generated by [`synthetic`].
+    pub synthetic: bool,
+    /// Timings of each operation, in order.
+    pub results: Vec<BenchResult>,
+}
+
+impl BenchResult {
+    /// Bytes read per second in the fastest run.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.best.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Time diffing, serializing, parsing, applying and reverting a patch between `src` and `dst`,
+/// `iterations` times each.
+///
+/// # Panics
+///
+/// Panics if `iterations` is 0.
+pub fn run(src: &[u8], dst: &[u8], iterations: u32, synthetic: bool) -> BenchReport {
+    assert!(iterations > 0, "iterations must be positive");
+    let patch = Patch::diff(src, dst);
+    let raw_patch = patch.serialize();
+    let results = vec![
+        time("diff", src.len() + dst.len(), iterations, || {
+            Patch::diff(src, dst)
+        }),
+        time("serialize", raw_patch.len(), iterations, || {
+            patch.serialize()
+        }),
+        time("parse", raw_patch.len(), iterations, || {
+            Patch::parse(&raw_patch)
+        }),
+        time("apply", src.len(), iterations, || patch.apply(src)),
+        time("revert", dst.len(), iterations, || patch.revert(dst)),
+    ];
+    BenchReport {
+        source_size: src.len(),
+        dest_size: dst.len(),
+        patch_size: raw_patch.len(),
+        synthetic,
+        results,
+    }
+}
+
+/// Pseudo-random source file of `size` bytes and a destination with a mix of small edits, large
+/// rewritten regions and appended data. The same `size` always gives the same files.
+pub fn synthetic(size: usize) -> (Vec<u8>, Vec<u8>) {
+    const REGION: usize = 256 * 1024;
+    let src = pseudo_random(size, 0x2545_f491_4f6c_dd1d);
+    let mut dst = src.clone();
+    for (i, chunk) in dst.chunks_mut(REGION).enumerate() {
+        if i % 4 == 3 {
+            // Rewritten region, one large block.
+            for b in chunk.iter_mut() {
+                *b = !*b;
+            }
+        } else {
+            // Short edits every 64 bytes, like a translation.
+            for (j, edit) in chunk.chunks_mut(64).enumerate() {
+                let len = std::cmp::min(1 + j % 8, edit.len());
+                for b in &mut edit[..len] {
+                    *b = b.wrapping_add(1);
+                }
+            }
+        }
+    }
+    dst.extend(pseudo_random(size / 16, 0x9e37_79b9_7f4a_7c15));
+    (src, dst)
+}
+
+fn pseudo_random(len: usize, mut x: u64) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+fn time<T, F: FnMut() -> T>(
+    op: &'static str,
+    bytes: usize,
+    iterations: u32,
+    mut f: F,
+) -> BenchResult {
+    let mut best = Duration::MAX;
+    let mut total = Duration::ZERO;
+    for _ in 0..iterations {
+        let start = Instant::now();
+        black_box(f());
+        let elapsed = start.elapsed();
+        best = std::cmp::min(best, elapsed);
+        total += elapsed;
+    }
+    BenchResult {
+        op,
+        bytes,
+        iterations,
+        best,
+        mean: total / iterations,
+    }
+}
+
+impl BenchReport {
+    /// Render this report as a JSON object under a `bench` key.
+    pub fn to_json(&self) -> String {
+        serde_json::json!({ "bench": self }).to_string()
+    }
+}
+
+impl Serialize for BenchResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("BenchResult", 6)?;
+        s.serialize_field("op", self.op)?;
+        s.serialize_field("bytes", &self.bytes)?;
+        s.serialize_field("iterations", &self.iterations)?;
+        s.serialize_field("best_secs", &self.best.as_secs_f64())?;
+        s.serialize_field("mean_secs", &self.mean.as_secs_f64())?;
+        s.serialize_field("throughput_bytes_per_sec", &self.throughput())?;
+        s.end()
+    }
+}
+
+impl Serialize for BenchReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("BenchReport", 10)?;
+        s.serialize_field("version", env!("CARGO_PKG_VERSION"))?;
+        s.serialize_field("os", std::env::consts::OS)?;
+        s.serialize_field("arch", std::env::consts::ARCH)?;
+        s.serialize_field("debug", &cfg!(debug_assertions))?;
+        s.serialize_field("source_size", &self.source_size)?;
+        s.serialize_field("dest_size", &self.dest_size)?;
+        s.serialize_field("patch_size", &self.patch_size)?;
+        s.serialize_field("synthetic", &self.synthetic)?;
+        s.serialize_field("results", &self.results)?;
+        s.end()
+    }
+}
+
+// Table with one line per operation.
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "upstool {} ({} {}{})",
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            if cfg!(debug_assertions) {
+                ", debug build"
+            } else {
+                ""
+            },
+        )?;
+        writeln!(
+            f,
+            "{} files: source {}, destination {}, patch {}",
+            if self.synthetic { "Synthetic" } else { "Input" },
+            ByteSize(self.source_size),
+            ByteSize(self.dest_size),
+            ByteSize(self.patch_size),
+        )?;
+        for result in &self.results {
+            writeln!(
+                f,
+                "{:<10} {:>10.2} ms best {:>10.2} ms mean {:>12}/s",
+                result.op,
+                result.best.as_secs_f64() * 1000.0,
+                result.mean.as_secs_f64() * 1000.0,
+                ByteSize(result.throughput() as usize).to_string(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_synthetic() {
+        let (src, dst) = synthetic(1024 * 1024);
+        assert_eq!(src.len(), 1024 * 1024);
+        assert_eq!(dst.len(), 1024 * 1024 + 64 * 1024);
+        assert_eq!(synthetic(1024 * 1024), (src.clone(), dst.clone()));
+        let patch = Patch::diff(&src, &dst);
+        assert!(patch.blocks().len() > 1000);
+        assert_eq!(patch.apply(&src).unwrap(), dst);
+    }
+
+    #[test]
+    fn test_run() {
+        let (src, dst) = synthetic(64 * 1024);
+        let report = run(&src, &dst, 2, true);
+        let ops: Vec<_> = report.results.iter().map(|r| r.op).collect();
+        assert_eq!(ops, ["diff", "serialize", "parse", "apply", "revert"]);
+        assert_eq!(report.results[3].bytes, src.len());
+        assert_eq!(report.results[3].iterations, 2);
+        assert!(report.results.iter().all(|r| r.best <= r.mean));
+        assert_eq!(report.to_string().lines().count(), 7);
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["bench"]["patch_size"], report.patch_size);
+        assert_eq!(json["bench"]["results"][4]["op"], "revert");
+    }
+}
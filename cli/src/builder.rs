@@ -0,0 +1,730 @@
+// Constructors and builder methods for the argument structs, so programs embedding upstool don't
+// need `StructOpt` or command-line strings. The structs are `#[non_exhaustive]`: new options are
+// added with their default values here instead of breaking struct literals.
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use ups::ChecksumOrder;
+
+use crate::{
+    Args, BenchArgs, ByteEdit, ChecksumArg, Command, ConvertArgs, CorpusAddArgs, DedupeArgs,
+    DoctorArgs, EditArgs, ExplainArgs, FixArgs, GenerateArgs, InfoArgs, InspectArgs, MetaGetArgs,
+    MetaSetArgs, NamePattern, OutputNamer, PatchArgs, PatchDirection, PatchOptions, RouteArgs,
+    ScrubArgs, SplitArgs, StoreAddArgs, StoreGetArgs, WhatisArgs,
+};
+
+#[cfg(feature = "map")]
+use crate::MapArgs;
+
+// Builder method setting `$field`, or `$inner.$field`, to `$ty`, or `Some` of it for `opt`.
+macro_rules! setter {
+    ($(#[$doc:meta])* $inner:ident.$field:ident: $ty:ty) => {
+        $(#[$doc])*
+        pub fn $field(mut self, $field: $ty) -> Self {
+            self.$inner.$field = $field;
+            self
+        }
+    };
+    ($(#[$doc:meta])* opt $inner:ident.$field:ident: $ty:ty) => {
+        $(#[$doc])*
+        pub fn $field<T: Into<$ty>>(mut self, $field: T) -> Self {
+            self.$inner.$field = Some($field.into());
+            self
+        }
+    };
+    ($(#[$doc:meta])* $field:ident: $ty:ty) => {
+        $(#[$doc])*
+        pub fn $field(mut self, $field: $ty) -> Self {
+            self.$field = $field;
+            self
+        }
+    };
+    ($(#[$doc:meta])* opt $field:ident: $ty:ty) => {
+        $(#[$doc])*
+        pub fn $field<T: Into<$ty>>(mut self, $field: T) -> Self {
+            self.$field = Some($field.into());
+            self
+        }
+    };
+}
+
+impl Args {
+    /// Run `command` with plain text errors.
+    pub fn new(command: Command) -> Self {
+        Args {
+            json: false,
+            stats_file: None,
+            checksum_order: ChecksumOrder::LittleEndian,
+            command,
+        }
+    }
+
+    setter!(
+        /// Print errors as JSON objects.
+        json: bool
+    );
+    setter!(
+        /// Append statistics about the run to this file.
+        opt stats_file: PathBuf
+    );
+    setter!(
+        /// Byte order to print checksums in.
+        checksum_order: ChecksumOrder
+    );
+}
+
+impl PatchArgs {
+    /// Apply `patch` to stdin, writing to stdout.
+    pub fn new<P: Into<PathBuf>>(patch: P) -> Self {
+        PatchArgs {
+            options: PatchOptions {
+                patch: patch.into(),
+                input: None,
+                output: None,
+                auto: false,
+                output_template: None,
+                output_dir: None,
+                in_place: false,
+                force_stdout: false,
+                verify_output: false,
+                quiet: false,
+                yes: false,
+                patch_inline: false,
+                upload: None,
+                tmp_dir: None,
+                refuse_unmodified_output: false,
+            },
+            direction: PatchDirection::Apply,
+        }
+    }
+
+    setter!(
+        /// Input file, "-" for stdin.
+        opt options.input: PathBuf
+    );
+    setter!(
+        /// Output file, "-" for stdout.
+        opt options.output: PathBuf
+    );
+    setter!(
+        /// Apply or revert the patch.
+        direction: PatchDirection
+    );
+    setter!(
+        /// Also apply numbered patches following the patch.
+        options.auto: bool
+    );
+    setter!(
+        /// Name the output from a template.
+        opt options.output_template: OutputNamer
+    );
+    setter!(
+        /// Write the output to this directory.
+        opt options.output_dir: PathBuf
+    );
+    setter!(
+        /// Overwrite the input with the output.
+        options.in_place: bool
+    );
+    setter!(
+        /// Write binary output to stdout even if it's a terminal.
+        options.force_stdout: bool
+    );
+    setter!(
+        /// Read the output file back and check its checksum.
+        options.verify_output: bool
+    );
+    setter!(
+        /// Don't print a summary.
+        options.quiet: bool
+    );
+    setter!(
+        /// Don't ask for confirmation before overwriting files.
+        options.yes: bool
+    );
+    setter!(
+        /// Read the patch path as hex or base64 patch contents.
+        options.patch_inline: bool
+    );
+    setter!(
+        /// Upload the output to this destination, see [`sink::open_sink`](crate::sink::open_sink).
+        opt options.upload: String
+    );
+    setter!(
+        /// Directory for temporary files, by default next to the output file.
+        opt options.tmp_dir: PathBuf
+    );
+    setter!(
+        /// Refuse to write an output identical to the input or to the unmodified base file.
+        options.refuse_unmodified_output: bool
+    );
+}
+
+impl GenerateArgs {
+    /// Generate a patch from `source` to `dest`, writing it to stdout.
+    pub fn new<S: Into<PathBuf>, D: Into<PathBuf>>(source: S, dest: D) -> Self {
+        GenerateArgs {
+            source: source.into(),
+            dest: dest.into(),
+            patch: None,
+            latest: false,
+            window_size: None,
+            prefetch: false,
+            report: false,
+            force_stdout: false,
+            record_generator: false,
+            yes: false,
+        }
+    }
+
+    setter!(
+        /// Patch file, "-" for stdout.
+        opt patch: PathBuf
+    );
+    setter!(
+        /// Use the newest file in the destination directory.
+        latest: bool
+    );
+    setter!(
+        /// Compare files in windows of this many bytes.
+        opt window_size: usize
+    );
+    setter!(
+        /// Read the files ahead on background threads.
+        prefetch: bool
+    );
+    setter!(
+        /// Print metrics about the generated patch.
+        report: bool
+    );
+    setter!(
+        /// Write the patch to stdout even if it's a terminal.
+        force_stdout: bool
+    );
+    setter!(
+        /// Record the upstool version in a sidecar file.
+        record_generator: bool
+    );
+    setter!(
+        /// Don't ask for confirmation before overwriting files.
+        yes: bool
+    );
+}
+
+impl DedupeArgs {
+    /// Report duplicate `*.ups` patches in `dir`.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        DedupeArgs {
+            dir: dir.into(),
+            pattern: NamePattern::new(OsStr::new("*.ups")),
+            hardlink: false,
+            delete: false,
+            yes: false,
+        }
+    }
+
+    setter!(
+        /// Only compare files with names matching this pattern.
+        pattern: NamePattern
+    );
+    setter!(
+        /// Replace byte-identical duplicates with hard links.
+        hardlink: bool
+    );
+    setter!(
+        /// Delete duplicates.
+        delete: bool
+    );
+    setter!(
+        /// Don't ask for confirmation.
+        yes: bool
+    );
+}
+
+impl EditArgs {
+    /// Edit `base`, writing the edited file to `output` and the patch to `patch`.
+    pub fn new<B, O, P>(base: B, output: O, patch: P) -> Self
+    where
+        B: Into<PathBuf>,
+        O: Into<PathBuf>,
+        P: Into<PathBuf>,
+    {
+        EditArgs {
+            base: base.into(),
+            output: output.into(),
+            patch: patch.into(),
+            edits: Vec::new(),
+            edits_file: None,
+            yes: false,
+        }
+    }
+
+    setter!(
+        /// Bytes to overwrite.
+        edits: Vec<ByteEdit>
+    );
+    setter!(
+        /// Read more edits from this file.
+        opt edits_file: PathBuf
+    );
+    setter!(
+        /// Don't ask for confirmation before overwriting files.
+        yes: bool
+    );
+}
+
+impl DoctorArgs {
+    /// Diagnose `patch` against `rom`.
+    pub fn new<P: Into<PathBuf>, R: Into<PathBuf>>(patch: P, rom: R) -> Self {
+        DoctorArgs {
+            patch: patch.into(),
+            rom: rom.into(),
+        }
+    }
+}
+
+impl InfoArgs {
+    /// Show information about `patch`.
+    pub fn new<P: Into<PathBuf>>(patch: P) -> Self {
+        InfoArgs {
+            patch: patch.into(),
+            deep: false,
+        }
+    }
+
+    setter!(
+        /// Also run slower analyses of UPS patches.
+        deep: bool
+    );
+}
+
+impl FixArgs {
+    /// Repair `patch`, writing it next to the original.
+    pub fn new<P: Into<PathBuf>>(patch: P) -> Self {
+        FixArgs {
+            patch: patch.into(),
+            output: None,
+            normalize: false,
+            base: None,
+            target: None,
+            yes: false,
+        }
+    }
+
+    setter!(
+        /// Path to write the repaired patch to.
+        opt output: PathBuf
+    );
+    setter!(
+        /// Rewrite every block in canonical form.
+        normalize: bool
+    );
+    setter!(
+        /// Source file to recompute the patch metadata from.
+        opt base: PathBuf
+    );
+    setter!(
+        /// Patched file to recompute the patch metadata from.
+        opt target: PathBuf
+    );
+    setter!(
+        /// Don't ask for confirmation before overwriting files.
+        yes: bool
+    );
+}
+
+impl InspectArgs {
+    /// Show block `block` of `patch`.
+    pub fn new<P: Into<PathBuf>>(patch: P, block: usize) -> Self {
+        InspectArgs {
+            patch: patch.into(),
+            block,
+            base: None,
+        }
+    }
+
+    setter!(
+        /// File the patch applies to.
+        opt base: PathBuf
+    );
+}
+
+impl ExplainArgs {
+    /// Explain `code`, or list every code for `None`.
+    pub fn new(code: Option<String>) -> Self {
+        ExplainArgs { code }
+    }
+}
+
+impl MetaSetArgs {
+    /// Set no fields of the sidecar of `patch`.
+    pub fn new<P: Into<PathBuf>>(patch: P) -> Self {
+        MetaSetArgs {
+            patch: patch.into(),
+            title: None,
+            author: None,
+            version: None,
+        }
+    }
+
+    setter!(
+        /// Name of the hack or translation, empty to remove it.
+        opt title: String
+    );
+    setter!(
+        /// Author of the patch, empty to remove it.
+        opt author: String
+    );
+    setter!(
+        /// Release version of the patch, empty to remove it.
+        opt version: String
+    );
+}
+
+impl MetaGetArgs {
+    /// Print every field of the sidecar of `patch`.
+    pub fn new<P: Into<PathBuf>>(patch: P) -> Self {
+        MetaGetArgs {
+            patch: patch.into(),
+            field: None,
+        }
+    }
+
+    setter!(
+        /// Print only this field.
+        opt field: String
+    );
+}
+
+impl StoreAddArgs {
+    /// Add `patches` to the store in `store`.
+    pub fn new<S: Into<PathBuf>>(store: S, patches: Vec<PathBuf>) -> Self {
+        StoreAddArgs {
+            store: store.into(),
+            patches,
+        }
+    }
+}
+
+impl StoreGetArgs {
+    /// List the patches in `store` applying to `rom`.
+    pub fn new<S: Into<PathBuf>, R: Into<PathBuf>>(store: S, rom: R) -> Self {
+        StoreGetArgs {
+            store: store.into(),
+            rom: rom.into(),
+        }
+    }
+}
+
+impl CorpusAddArgs {
+    /// Add a case named `name` applying `patch` to `input` to the corpus in `corpus`.
+    pub fn new<C, P, I>(corpus: C, patch: P, input: I, name: &str, description: &str) -> Self
+    where
+        C: Into<PathBuf>,
+        P: Into<PathBuf>,
+        I: Into<PathBuf>,
+    {
+        CorpusAddArgs {
+            corpus: corpus.into(),
+            patch: patch.into(),
+            input: input.into(),
+            name: name.to_string(),
+            description: description.to_string(),
+            direction: PatchDirection::Apply,
+        }
+    }
+
+    setter!(
+        /// Apply or revert the patch.
+        direction: PatchDirection
+    );
+}
+
+#[cfg(feature = "map")]
+impl MapArgs {
+    /// Render the map of `patch` to `output`, 16 pixels tall and at most 1024 wide.
+    pub fn new<P: Into<PathBuf>, O: Into<PathBuf>>(patch: P, output: O) -> Self {
+        MapArgs {
+            patch: patch.into(),
+            output: output.into(),
+            kib_per_pixel: None,
+            height: 16,
+        }
+    }
+
+    setter!(
+        /// KiB of the patched file per pixel.
+        opt kib_per_pixel: usize
+    );
+    setter!(
+        /// Height of the map in pixels.
+        height: u32
+    );
+}
+
+impl RouteArgs {
+    /// Route from `from` to `to` through the patches in a directory or index file.
+    pub fn new<P: Into<PathBuf>>(patches: P, from: ChecksumArg, to: ChecksumArg) -> Self {
+        RouteArgs {
+            patches: patches.into(),
+            from,
+            to,
+        }
+    }
+}
+
+impl WhatisArgs {
+    /// Identify `file` with the patches in a directory or index file.
+    pub fn new<F: Into<PathBuf>, I: Into<PathBuf>>(file: F, index: I) -> Self {
+        WhatisArgs {
+            file: file.into(),
+            index: index.into(),
+        }
+    }
+}
+
+impl SplitArgs {
+    /// Split `patch` in patches of at most `max_size` bytes.
+    pub fn new<P: Into<PathBuf>>(patch: P, max_size: usize) -> Self {
+        SplitArgs {
+            patch: patch.into(),
+            max_size,
+            output: None,
+            yes: false,
+        }
+    }
+
+    setter!(
+        /// Path to the first patch.
+        opt output: PathBuf
+    );
+    setter!(
+        /// Don't ask for confirmation before overwriting files.
+        yes: bool
+    );
+}
+
+impl ScrubArgs {
+    /// Scrub `patch`, writing the result to `output`.
+    pub fn new<P: Into<PathBuf>, O: Into<PathBuf>>(patch: P, output: O) -> Self {
+        ScrubArgs {
+            patch: patch.into(),
+            output: output.into(),
+            yes: false,
+        }
+    }
+
+    setter!(
+        /// Don't ask for confirmation before overwriting files.
+        yes: bool
+    );
+}
+
+impl ConvertArgs {
+    /// Convert `patch` to the format of `output`, without a source file.
+    pub fn new<P: Into<PathBuf>, O: Into<PathBuf>>(patch: P, output: O) -> Self {
+        ConvertArgs {
+            patch: patch.into(),
+            output: output.into(),
+            source: None,
+            allow_lossy: false,
+            yes: false,
+        }
+    }
+
+    setter!(
+        /// Source file the patch applies to.
+        opt source: PathBuf
+    );
+    setter!(
+        /// Drop metadata the output format can't hold instead of failing.
+        allow_lossy: bool
+    );
+    setter!(
+        /// Don't ask for confirmation before overwriting files.
+        yes: bool
+    );
+}
+
+impl BenchArgs {
+    /// Benchmark on 16 MiB of synthetic data, running each operation 5 times.
+    pub fn new() -> Self {
+        BenchArgs {
+            source: None,
+            dest: None,
+            size: None,
+            iterations: 5,
+        }
+    }
+
+    /// Benchmark on `source` and `dest` instead of synthetic data.
+    pub fn files<S: Into<PathBuf>, D: Into<PathBuf>>(mut self, source: S, dest: D) -> Self {
+        self.source = Some(source.into());
+        self.dest = Some(dest.into());
+        self
+    }
+
+    setter!(
+        /// Size of the synthetic source file.
+        opt size: usize
+    );
+    setter!(
+        /// Number of times to run each operation.
+        iterations: u32
+    );
+}
+
+impl Default for BenchArgs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use structopt::StructOpt;
+
+    use crate::RevertArgs;
+
+    // Builders without options set match the command line defaults.
+    #[test]
+    fn test_defaults_match_command_line() {
+        let args =
+            |argv: &[&str]| Args::from_iter(std::iter::once("upstool").chain(argv.iter().copied()));
+        let debug = |args: Args| format!("{:?}", args);
+
+        assert_eq!(
+            debug(args(&["patch", "hack.ups"])),
+            debug(Args::new(Command::Patch(PatchArgs::new("hack.ups")))),
+        );
+        assert_eq!(
+            debug(args(&["revert", "hack.ups", "rom.gba", "-q"])),
+            debug(Args::new(Command::Revert(RevertArgs::from(
+                PatchArgs::new("hack.ups").input("rom.gba").quiet(true)
+            )))),
+        );
+        assert!(Args::from_iter_safe(&["upstool", "revert", "hack.ups", "-d", "apply"]).is_err());
+        assert_eq!(
+            debug(args(&["generate", "a.bin", "b.bin"])),
+            debug(Args::new(Command::Generate(GenerateArgs::new(
+                "a.bin", "b.bin"
+            )))),
+        );
+        assert_eq!(
+            debug(args(&["bench"])),
+            debug(Args::new(Command::Bench(BenchArgs::new()))),
+        );
+        assert_eq!(
+            debug(args(&[
+                "route",
+                "patches",
+                "--from",
+                "0x3C2B0F8A",
+                "--to",
+                "1"
+            ])),
+            debug(Args::new(Command::Route(RouteArgs::new(
+                "patches",
+                ChecksumArg(0x3c2b_0f8a),
+                ChecksumArg(1)
+            )))),
+        );
+        assert_eq!(
+            debug(args(&["whatis", "rom.gba", "--index", "index.json"])),
+            debug(Args::new(Command::Whatis(WhatisArgs::new(
+                "rom.gba",
+                "index.json"
+            )))),
+        );
+        assert_eq!(
+            debug(args(&["split", "hack.ups", "--max-size", "8MiB"])),
+            debug(Args::new(Command::Split(SplitArgs::new(
+                "hack.ups",
+                8 * 1024 * 1024
+            )))),
+        );
+        assert_eq!(
+            debug(args(&["scrub", "hack.ups", "out.ups", "-y"])),
+            debug(Args::new(Command::Scrub(
+                ScrubArgs::new("hack.ups", "out.ups").yes(true)
+            ))),
+        );
+        assert_eq!(
+            debug(args(&[
+                "convert",
+                "hack.ips",
+                "hack.ups",
+                "-s",
+                "rom.sfc",
+                "--allow-lossy",
+            ])),
+            debug(Args::new(Command::Convert(
+                ConvertArgs::new("hack.ips", "hack.ups")
+                    .source("rom.sfc")
+                    .allow_lossy(true)
+            ))),
+        );
+        assert_eq!(
+            debug(args(&["fix", "hack.ups"])),
+            debug(Args::new(Command::Fix(FixArgs::new("hack.ups")))),
+        );
+        assert_eq!(
+            debug(args(&["fix", "hack.ups", "--base", "rom.gba"])),
+            debug(Args::new(Command::Fix(
+                FixArgs::new("hack.ups").base("rom.gba")
+            ))),
+        );
+        assert_eq!(
+            debug(args(&["inspect", "hack.ups", "--block", "1234"])),
+            debug(Args::new(Command::Inspect(InspectArgs::new(
+                "hack.ups", 1234
+            )))),
+        );
+        assert_eq!(
+            debug(args(&["dedupe", "patches"])),
+            debug(Args::new(Command::Dedupe(DedupeArgs::new("patches")))),
+        );
+        assert_eq!(
+            debug(args(&[
+                "--json", "edit", "a.bin", "-o", "b.bin", "-p", "c.ups"
+            ])),
+            debug(Args::new(Command::Edit(EditArgs::new("a.bin", "b.bin", "c.ups"))).json(true)),
+        );
+        assert_eq!(
+            debug(args(&["patch", "hack.ups", "--stats-file", "stats.jsonl"])),
+            debug(Args::new(Command::Patch(PatchArgs::new("hack.ups"))).stats_file("stats.jsonl")),
+        );
+        assert_eq!(
+            debug(args(&["info", "hack.ups", "--checksum-order", "be"])),
+            debug(
+                Args::new(Command::Info(InfoArgs::new("hack.ups")))
+                    .checksum_order(ChecksumOrder::BigEndian)
+            ),
+        );
+        assert_eq!(
+            debug(args(&["info", "hack.ups", "--deep"])),
+            debug(Args::new(Command::Info(
+                InfoArgs::new("hack.ups").deep(true)
+            ))),
+        );
+        assert_eq!(
+            debug(args(&[
+                "patch",
+                "hack.ups",
+                "rom.gba",
+                "-d",
+                "revert",
+                "--output-dir",
+                "out"
+            ])),
+            debug(Args::new(Command::Patch(
+                PatchArgs::new("hack.ups")
+                    .input("rom.gba")
+                    .direction(PatchDirection::Revert)
+                    .output_dir("out")
+            ))),
+        );
+    }
+}
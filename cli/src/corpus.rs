@@ -0,0 +1,167 @@
+//! Regression corpus of edge case patches, e.g. interesting fuzzing findings.
+//!
+//! The corpus is a JSON file of cases run by the library tests, see
+//! `lib/tests/regressions/README.md` for its format. Each case records the errors it produced
+//! when it was added, so changes in how malformed patches are handled show up as test failures.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use ups::{Patch, PatchDirection};
+
+/// Cases are only parsed when the patch declares files larger than this, they're not applied.
+pub const SIZE_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Contents of a corpus file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Corpus {
+    pub version: u32,
+    pub cases: Vec<CorpusCase>,
+}
+
+/// A patch and input, with the errors they produce.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorpusCase {
+    /// Unique snake case name.
+    pub name: String,
+    /// What the case exercises.
+    pub description: String,
+    /// Hex encoded patch file.
+    pub patch: String,
+    /// `apply` or `revert`.
+    pub direction: String,
+    /// Hex encoded input file.
+    pub input: String,
+    /// Error kinds, sorted, empty when the patch applies cleanly.
+    pub expected_error: Vec<String>,
+}
+
+impl Default for Corpus {
+    fn default() -> Self {
+        Corpus {
+            version: 1,
+            cases: Vec::new(),
+        }
+    }
+}
+
+impl CorpusCase {
+    /// Case for `patch` and `input`, recording the errors they produce now.
+    pub fn new(
+        name: &str,
+        description: &str,
+        patch: &[u8],
+        direction: PatchDirection,
+        input: &[u8],
+    ) -> Self {
+        CorpusCase {
+            name: name.to_string(),
+            description: description.to_string(),
+            patch: hex(patch),
+            direction: match direction {
+                PatchDirection::Apply => "apply",
+                PatchDirection::Revert => "revert",
+            }
+            .to_string(),
+            input: hex(input),
+            expected_error: errors(patch, direction, input),
+        }
+    }
+}
+
+/// Add `case` to the corpus at `path`, creating it if needed. Names must be unique.
+pub fn add(path: &Path, case: CorpusCase) -> io::Result<Corpus> {
+    let mut corpus = match fs::read(path) {
+        Ok(raw) => serde_json::from_slice(&raw)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Corpus::default(),
+        Err(e) => return Err(e),
+    };
+    if corpus.cases.iter().any(|c| c.name == case.name) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("the corpus already has a case named \"{}\"", case.name),
+        ));
+    }
+    corpus.cases.push(case);
+    let mut raw = serde_json::to_vec_pretty(&corpus)?;
+    raw.push(b'\n');
+    fs::write(path, raw)?;
+    Ok(corpus)
+}
+
+// Same rules as `lib/tests/test_regressions.rs`.
+fn errors(patch: &[u8], direction: PatchDirection, input: &[u8]) -> Vec<String> {
+    let patch = match Patch::parse(patch) {
+        Ok(patch) => patch,
+        Err(e) => return vec![e.kind().to_string()],
+    };
+    if patch.src_size > SIZE_LIMIT || patch.dst_size > SIZE_LIMIT {
+        return vec!["size_limit".to_string()];
+    }
+    let mut errors: Vec<_> = match patch.patch(direction, input) {
+        Ok(_) => Vec::new(),
+        Err(errs) => errs.iter().map(|e| e.kind().to_string()).collect(),
+    };
+    errors.sort();
+    errors
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_add() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cases.json");
+        let patch = Patch::diff(b"abc", b"abd").serialize();
+
+        let ok = CorpusCase::new("ok", "Applies.", &patch, PatchDirection::Apply, b"abc");
+        assert_eq!(ok.expected_error, Vec::<String>::new());
+        add(&path, ok.clone()).unwrap();
+        let wrong_input = CorpusCase::new(
+            "wrong_input",
+            "Wrong input.",
+            &patch,
+            PatchDirection::Apply,
+            b"xyzw",
+        );
+        assert_eq!(
+            wrong_input.expected_error,
+            vec![
+                "dest_checksum_mismatch",
+                "source_checksum_mismatch",
+                "source_size_mismatch",
+            ],
+        );
+        let corpus = add(&path, wrong_input).unwrap();
+        assert_eq!(corpus.cases.len(), 2);
+
+        let raw = fs::read(&path).unwrap();
+        assert_eq!(serde_json::from_slice::<Corpus>(&raw).unwrap(), corpus);
+        let err = add(&path, ok).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let mut patch = Patch::diff(b"abc", b"abd");
+        patch.dst_size = SIZE_LIMIT + 1;
+        let case = CorpusCase::new(
+            "huge",
+            "Huge.",
+            &patch.serialize(),
+            PatchDirection::Apply,
+            b"abc",
+        );
+        assert_eq!(case.expected_error, vec!["size_limit"]);
+        let case = CorpusCase::new("bad", "Bad.", b"UPS", PatchDirection::Apply, b"");
+        assert_eq!(case.expected_error, vec!["format_mismatch"]);
+    }
+}
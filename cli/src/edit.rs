@@ -0,0 +1,141 @@
+//! Byte edits for the edit subcommand.
+//!
+//! Edits are written as `OFFSET=HEXBYTES`, e.g. `0x1f4=DEADBEEF`. Offsets are decimal or
+//! hexadecimal with a `0x` prefix. Edit files have one edit per line, blank lines and lines
+//! starting with `#` are ignored.
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// Overwrite `bytes` at `offset`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteEdit {
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+}
+
+/// Invalid [`ByteEdit`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum EditError {
+    #[error("expected OFFSET=HEXBYTES, found \"{}\"", .0)]
+    Syntax(String),
+    #[error("invalid offset \"{}\"", .0)]
+    Offset(String),
+    #[error("invalid hex bytes \"{}\"", .0)]
+    Bytes(String),
+    #[error("line {}: {}", .0, .1)]
+    Line(usize, Box<EditError>),
+}
+
+impl FromStr for ByteEdit {
+    type Err = EditError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (offset, bytes) = match s.find('=') {
+            Some(i) => (s[..i].trim(), s[i + 1..].trim()),
+            None => return Err(EditError::Syntax(s.into())),
+        };
+        let parsed_offset = match offset
+            .strip_prefix("0x")
+            .or_else(|| offset.strip_prefix("0X"))
+        {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => offset.parse(),
+        };
+        let offset = parsed_offset.map_err(|_| EditError::Offset(offset.into()))?;
+        let bytes = parse_hex(bytes).ok_or_else(|| EditError::Bytes(bytes.into()))?;
+        Ok(ByteEdit { offset, bytes })
+    }
+}
+
+impl Display for ByteEdit {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:#x}=", self.offset)?;
+        for b in &self.bytes {
+            write!(f, "{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the contents of an edits file, see the [module docs](self).
+pub fn parse_edits(contents: &str) -> Result<Vec<ByteEdit>, EditError> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            line.parse()
+                .map_err(|e| EditError::Line(i + 1, Box::new(e)))
+        })
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.is_empty() {
+        return None;
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => Some((hex_digit(*hi)? << 4) | hex_digit(*lo)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_edit() {
+        assert_eq!(
+            "0x1f4=DEADbeef".parse(),
+            Ok(ByteEdit {
+                offset: 0x1f4,
+                bytes: vec![0xde, 0xad, 0xbe, 0xef],
+            }),
+        );
+        assert_eq!(
+            "16 = 00".parse(),
+            Ok(ByteEdit {
+                offset: 16,
+                bytes: vec![0],
+            }),
+        );
+        assert_eq!(
+            "0x10".parse::<ByteEdit>(),
+            Err(EditError::Syntax("0x10".into()))
+        );
+        assert_eq!(
+            "x=00".parse::<ByteEdit>(),
+            Err(EditError::Offset("x".into()))
+        );
+        assert_eq!(
+            "0=ABC".parse::<ByteEdit>(),
+            Err(EditError::Bytes("ABC".into()))
+        );
+        assert_eq!("0=".parse::<ByteEdit>(), Err(EditError::Bytes("".into())));
+    }
+
+    #[test]
+    fn test_parse_edits_file() {
+        let edits = parse_edits("# title\n0x0=41\n\n  4=4243\n").unwrap();
+        assert_eq!(
+            edits.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+            vec!["0x0=41", "0x4=4243"],
+        );
+        assert_eq!(
+            parse_edits("0=00\nbad"),
+            Err(EditError::Line(
+                2,
+                Box::new(EditError::Syntax("bad".into()))
+            )),
+        );
+    }
+}
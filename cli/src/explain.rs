@@ -0,0 +1,461 @@
+//! Extended explanations for error codes, shown by `upstool explain`.
+//!
+//! Every [`RunError`](crate::RunError) has a code, see [`RunError::code`](crate::RunError::code).
+//! Codes are stable: never reuse or renumber them, add new ones at the end.
+use std::fmt::{self, Display, Formatter};
+
+/// Explanation and remediation steps for an error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Explanation {
+    /// Error code, e.g. `E0003`.
+    pub code: &'static str,
+    /// Matching `kind` in JSON errors.
+    pub kind: &'static str,
+    /// One-line description.
+    pub summary: &'static str,
+    /// Causes and what to try, one paragraph each.
+    pub details: &'static [&'static str],
+}
+
+/// Every known error code, in order.
+pub const EXPLANATIONS: &[Explanation] = &[
+    Explanation {
+        code: "E0001",
+        kind: "format_mismatch",
+        summary: "The patch file isn't a valid UPS patch.",
+        details: &[
+            "The file doesn't start with the \"UPS1\" preamble or ends before its metadata. It may \
+             be a patch in another format: IPS, EBP, BPS, PPF, APS, NINJA and xdelta patches are \
+             only applied from files ending in `.ips`, `.ebp`, `.bps`, `.ppf`, `.aps`, `.rup` and \
+             `.xdelta`.",
+            "Check the file extension and the patch distribution notes. A truncated download \
+             also causes this, download the patch again. `upstool doctor PATCH ROM` identifies \
+             other common patch formats.",
+        ],
+    },
+    Explanation {
+        code: "E0002",
+        kind: "patch_checksum_mismatch",
+        summary: "The patch file is corrupted.",
+        details: &[
+            "UPS and BPS patches end with a checksum of their own contents, which doesn't match. \
+             The patch was truncated or modified after it was created, usually by an interrupted \
+             download or a text-mode transfer.",
+            "Download the patch again, from the original source if possible. Compare its size \
+             and hash with the ones published by the author.",
+        ],
+    },
+    Explanation {
+        code: "E0003",
+        kind: "source_checksum_mismatch",
+        summary: "The input ROM isn't the one the patch was made for.",
+        details: &[
+            "The input has different contents than the file the patch was made from. The most \
+             common causes are a different revision or region of the game (e.g. v1.0 vs v1.1, \
+             USA vs Europe), a headered ROM, or a bad or modified dump.",
+            "Check the CRC32 the patch expects with `upstool info PATCH` and compare it with \
+             your ROM, the patch notes usually name the right revision. Add \
+             `--checksum-order be` to compare with No-Intro or emulator CRC32s. `upstool doctor \
+             PATCH ROM` detects copier headers, N64 byte orders and overdumps.",
+        ],
+    },
+    Explanation {
+        code: "E0004",
+        kind: "source_size_mismatch",
+        summary: "The input ROM doesn't have the size the patch expects.",
+        details: &[
+            "A size 512 bytes larger than expected is a copier header, common on SNES ROMs. \
+             Other differences are usually overdumps, padded or trimmed ROMs, or another \
+             revision of the game.",
+            "Run `upstool doctor PATCH ROM` to find out whether removing a header or trailing \
+             data makes the patch apply.",
+        ],
+    },
+    Explanation {
+        code: "E0005",
+        kind: "dest_checksum_mismatch",
+        summary: "The patched file doesn't match the checksum from the patch.",
+        details: &[
+            "When applying, the output doesn't match what the patch author got. This follows \
+             a source mismatch, or happens with a corrupted patch or an input which changed \
+             while patching.",
+            "When reverting, the input isn't the patched file. Make sure to revert the file \
+             produced by this exact patch.",
+        ],
+    },
+    Explanation {
+        code: "E0006",
+        kind: "dest_size_mismatch",
+        summary: "The patched file doesn't have the size the patch expects.",
+        details: &[
+            "When reverting, the input must be the patched file, which has a different size. \
+             When applying, the output size comes from the patch so this only happens with \
+             corrupted patches.",
+            "Check you're reverting the right file, or download the patch again.",
+        ],
+    },
+    Explanation {
+        code: "E0007",
+        kind: "already_patched",
+        summary: "The input already is the output of this patch.",
+        details: &[
+            "The input matches the other side of the patch: it was already patched when \
+             applying, or it's the original file when reverting.",
+            "Nothing needs to be done. To undo a patch use `upstool revert`, to patch the \
+             original file use `upstool patch`.",
+        ],
+    },
+    Explanation {
+        code: "E0008",
+        kind: "io",
+        summary: "A file couldn't be read or written.",
+        details: &[
+            "The message names the file and the operating system error: a missing file, \
+             missing permissions or a full disk are the usual causes.",
+            "Check the path and its permissions. With `--json` the `io_kind` field holds the \
+             error category.",
+        ],
+    },
+    Explanation {
+        code: "E0009",
+        kind: "usage",
+        summary: "The command-line arguments are invalid.",
+        details: &[
+            "Some options can't be combined or need other arguments, e.g. `--in-place` needs an \
+             input file.",
+            "Run the subcommand with `--help` for its usage.",
+        ],
+    },
+    Explanation {
+        code: "E0010",
+        kind: "verify_failed",
+        summary: "The output file read back doesn't match what was written.",
+        details: &[
+            "`--verify-output` found different data on disk than what upstool wrote. The disk, \
+             its filesystem or another program modified the file.",
+            "Write the output again, to another disk if this keeps happening, and check the \
+             storage device for errors.",
+        ],
+    },
+    Explanation {
+        code: "E0011",
+        kind: "cancelled",
+        summary: "A confirmation prompt was declined.",
+        details: &[
+            "Nothing was written. Use `--yes` to skip confirmation prompts in scripts.",
+        ],
+    },
+    Explanation {
+        code: "E0012",
+        kind: "input_modified",
+        summary: "The input changed while it was being patched.",
+        details: &[
+            "Regions left unchanged by the patch don't match the input anymore. Another program \
+             wrote to the input file or memory during patching.",
+            "Make sure nothing else uses the input, e.g. an emulator with the ROM loaded, and \
+             patch again.",
+        ],
+    },
+    Explanation {
+        code: "E0013",
+        kind: "invalid_text",
+        summary: "An inline patch isn't valid hex or base64.",
+        details: &[
+            "`--patch-inline` expects the whole patch file encoded as hex digits or base64. The \
+             text may be truncated or have extra characters.",
+            "Copy the patch text again, whitespace and line breaks are ignored.",
+        ],
+    },
+    Explanation {
+        code: "E0014",
+        kind: "no_route",
+        summary: "No sequence of patches leads from one file to the other.",
+        details: &[
+            "`upstool route` only applies patches, it never reverts them, and needs every \
+             intermediate version. A patch for a missing step may not be indexed, or the \
+             checksums are for the wrong files.",
+            "Check the checksums with `upstool info` on the patches, and add the patches for the \
+             missing versions to the directory.",
+        ],
+    },
+    Explanation {
+        code: "E0015",
+        kind: "ips_format_mismatch",
+        summary: "The `.ips` patch file isn't a valid IPS patch.",
+        details: &[
+            "Files ending in `.ips` are applied as IPS patches. The file doesn't start with the \
+             \"PATCH\" preamble, or ends before the \"EOF\" marker.",
+            "A truncated download causes this, download the patch again. If it's a UPS patch \
+             with the wrong extension, rename it to end in `.ups`.",
+        ],
+    },
+    Explanation {
+        code: "E0016",
+        kind: "bps_format_mismatch",
+        summary: "The `.bps` patch file isn't a valid BPS patch.",
+        details: &[
+            "Files ending in `.bps` are applied as BPS patches. The file doesn't start with the \
+             \"BPS1\" preamble, ends early, or copies data from outside the files.",
+            "A truncated download causes this, download the patch again. If it's a UPS patch \
+             with the wrong extension, rename it to end in `.ups`.",
+        ],
+    },
+    Explanation {
+        code: "E0017",
+        kind: "vcdiff_format_mismatch",
+        summary: "The `.xdelta` or `.vcdiff` patch file isn't a valid VCDIFF patch.",
+        details: &[
+            "Files ending in `.xdelta` and `.vcdiff` are applied as VCDIFF patches, the format \
+             written by xdelta3. The file doesn't start with the VCDIFF magic bytes, ends early, \
+             or copies data from outside its windows. Patches from the original xdelta 1 tool use \
+             another format.",
+            "A truncated download causes this, download the patch again. If it's a UPS patch \
+             with the wrong extension, rename it to end in `.ups`.",
+        ],
+    },
+    Explanation {
+        code: "E0018",
+        kind: "vcdiff_unsupported",
+        summary: "The VCDIFF patch uses a feature upstool doesn't implement.",
+        details: &[
+            "xdelta3 can compress patches further with secondary compressors like LZMA or DJW, \
+             or use a custom instruction table. upstool only applies patches without them.",
+            "Apply the patch with xdelta3 itself, or ask the author for a patch created with \
+             `xdelta3 -S none`.",
+        ],
+    },
+    Explanation {
+        code: "E0019",
+        kind: "ppf_format_mismatch",
+        summary: "The `.ppf` patch file isn't a valid PPF 3.0 patch.",
+        details: &[
+            "Files ending in `.ppf` are applied as PPF 3.0 patches. The file doesn't start with \
+             the \"PPF30\" preamble, or ends in the middle of a record. Older PPF 1.0 and 2.0 \
+             patches aren't supported.",
+            "A truncated download causes this, download the patch again. Apply older PPF \
+             versions with PPF-O-Matic or ApplyPPF.",
+        ],
+    },
+    Explanation {
+        code: "E0020",
+        kind: "aps_format_mismatch",
+        summary: "The `.aps` patch file isn't a valid APS patch.",
+        details: &[
+            "Files ending in `.aps` are applied as APS patches, in their GBA or N64 variant. The \
+             file doesn't start with the \"APS1\" preamble, or ends in the middle of a record.",
+            "A truncated download causes this, download the patch again. If it's a UPS patch \
+             with the wrong extension, rename it to end in `.ups`.",
+        ],
+    },
+    Explanation {
+        code: "E0021",
+        kind: "unmodified_output",
+        summary: "The output would be an unmodified file, and `--refuse-unmodified-output` is set.",
+        details: &[
+            "With `--refuse-unmodified-output`, upstool doesn't write outputs with the checksum of \
+             the input or of the patch's base file, so automation can't redistribute clean ROMs \
+             by mistake. Reverting a patch always gives the base file back, and some patches \
+             don't change anything.",
+            "Check the patch and the direction. Drop the flag if writing the base file is \
+             intended, e.g. to restore a local backup.",
+        ],
+    },
+    Explanation {
+        code: "E0022",
+        kind: "ninja_format_mismatch",
+        summary: "The `.rup` patch file isn't a valid NINJA 2.0 patch.",
+        details: &[
+            "Files ending in `.rup` are applied as NINJA 2.0 patches. The file doesn't start with \
+             the \"NINJA2\" preamble, ends before its 2 KiB header, or ends in the middle of a \
+             command.",
+            "A truncated download causes this, download the patch again. If it's a UPS patch \
+             with the wrong extension, rename it to end in `.ups`.",
+        ],
+    },
+    Explanation {
+        code: "E0023",
+        kind: "ninja_unsupported",
+        summary: "The NINJA patch uses a feature upstool doesn't implement.",
+        details: &[
+            "NINJA patches can change several files at once, e.g. the tracks of a CD image or the \
+             files of an archive. upstool only applies patches for a single file.",
+            "Apply the patch with the NINJA patcher itself.",
+        ],
+    },
+    Explanation {
+        code: "E0024",
+        kind: "ebp_format_mismatch",
+        summary: "The `.ebp` patch file isn't a valid EBP patch.",
+        details: &[
+            "Files ending in `.ebp` are applied as EBP patches, IPS patches followed by JSON \
+             metadata. The file doesn't start with the \"PATCH\" preamble, ends before the \
+             \"EOF\" marker, or the metadata after it isn't a JSON object of strings.",
+            "A truncated download causes this, download the patch again. If only the metadata is \
+             broken, rename the patch to end in `.ips` to apply it without its metadata.",
+        ],
+    },
+    Explanation {
+        code: "E0025",
+        kind: "broken_chain",
+        summary: "A patch in a numbered chain doesn't apply to the output of the previous one.",
+        details: &[
+            "Numbered patches like `hack.ups`, `hack.ups1` and `hack.ups2` are applied in order, \
+             each one to the output of the previous one. The size or checksum one patch outputs \
+             doesn't match what the next one applies to, so the chain is checked and rejected \
+             before patching anything.",
+            "A patch from another version of the hack is usually mixed in. Check the release \
+             notes for the order, and remove or renumber the patches that don't belong.",
+        ],
+    },
+    Explanation {
+        code: "E0026",
+        kind: "convert_unsupported",
+        summary: "upstool can't write patches in the format to convert to.",
+        details: &[
+            "`upstool convert` reads patches in every supported format, but only writes UPS and \
+             BPS patches. The format is picked from the extension of the output file.",
+            "Give the output file a `.ups` or `.bps` extension.",
+        ],
+    },
+    Explanation {
+        code: "E0027",
+        kind: "convert_needs_source",
+        summary: "Converting the patch needs the file it applies to.",
+        details: &[
+            "Patch formats store different data, e.g. IPS patches overwrite bytes while UPS \
+             patches store how they changed, so the changes are recomputed from the source file. \
+             Only patches already in the output format convert without it.",
+            "Pass the original, unpatched file with `--source`. The converted patch only applies \
+             to that file.",
+        ],
+    },
+    Explanation {
+        code: "E0028",
+        kind: "convert_lossy",
+        summary: "Converting the patch would lose data the output format can't hold.",
+        details: &[
+            "The patch carries data besides the changes, like the title and author of an EBP \
+             patch or the description of a PPF patch, and the output format has no place for it.",
+            "Check the data with `upstool info` and keep it in the release notes, then convert \
+             with `--allow-lossy` to drop it.",
+        ],
+    },
+    Explanation {
+        code: "E0029",
+        kind: "unknown",
+        summary: "The error has no specific code.",
+        details: &[
+            "This version of upstool has no explanation for the error, the error message \
+             describes what went wrong.",
+            "Please report the message so the error gets its own code and remediation steps.",
+        ],
+    },
+    Explanation {
+        code: "E0030",
+        kind: "stale_metadata",
+        summary: "The patch was edited without updating its checksums.",
+        details: &[
+            "A program edited the blocks of the patch through the `ups` library and used it \
+             without recomputing its sizes and checksums, so they don't describe the blocks.",
+            "This is a bug in the program that edited the patch. Report it to its authors.",
+        ],
+    },
+];
+
+/// Code for errors without an explanation of their own.
+pub const FALLBACK_CODE: &str = "E0029";
+
+/// Find the explanation for an error code, case-insensitive. JSON error kinds are accepted too.
+pub fn find(code: &str) -> Option<&'static Explanation> {
+    EXPLANATIONS
+        .iter()
+        .find(|e| e.code.eq_ignore_ascii_case(code) || e.kind == code)
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} ({}): {}", self.code, self.kind, self.summary)?;
+        for paragraph in self.details {
+            write!(f, "\n\n{}", paragraph)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_codes_are_unique_and_ordered() {
+        for (i, explanation) in EXPLANATIONS.iter().enumerate() {
+            assert_eq!(explanation.code, format!("E{:04}", i + 1));
+            assert_eq!(find(explanation.kind), Some(explanation));
+        }
+        assert_eq!(
+            find("e0003").map(|e| e.kind),
+            Some("source_checksum_mismatch")
+        );
+        assert_eq!(find("E9999"), None);
+        assert_eq!(find(FALLBACK_CODE).map(|e| e.kind), Some("unknown"));
+    }
+
+    #[test]
+    fn test_run_error_codes() {
+        use crate::RunError;
+        use ups::{Patch, PatchDirection};
+
+        assert_eq!(RunError::Usage(String::new()).code(), "E0009");
+        assert_eq!(
+            RunError::AlreadyPatched(PatchDirection::Apply).code(),
+            "E0007"
+        );
+        let parse_err = Patch::parse(b"PATCH").unwrap_err();
+        assert_eq!(RunError::Parse(parse_err).code(), "E0001");
+        let ips_err = ups::ips::IpsPatch::parse(b"UPS1").unwrap_err();
+        assert_eq!(RunError::IpsParse(ips_err).code(), "E0015");
+        let bps_err = ups::bps::BpsPatch::parse(b"UPS1").unwrap_err();
+        assert_eq!(RunError::BpsParse(bps_err).code(), "E0016");
+        let ppf_err = ups::ppf::PpfPatch::parse(b"PPF20").unwrap_err();
+        assert_eq!(RunError::PpfParse(ppf_err).code(), "E0019");
+        let aps_err = ups::aps::ApsPatch::parse(b"UPS1").unwrap_err();
+        assert_eq!(RunError::ApsParse(aps_err).code(), "E0020");
+        assert_eq!(RunError::UnmodifiedOutput { base: true }.code(), "E0021");
+        let ninja_err = ups::ninja::NinjaPatch::parse(b"UPS1").unwrap_err();
+        assert_eq!(RunError::NinjaParse(ninja_err).code(), "E0022");
+        let unsupported = ups::ninja::NinjaParseError::Unsupported("multiple files".into());
+        assert_eq!(RunError::NinjaParse(unsupported).code(), "E0023");
+        let ebp_err = ups::ebp::EbpPatch::parse(b"UPS1").unwrap_err();
+        assert_eq!(RunError::EbpParse(ebp_err).code(), "E0024");
+        let v1 = Patch::diff(b"v0", b"v1");
+        let compose_err = ups::softpatch::ChainError::Compose {
+            index: 1,
+            path: "hack.ups1".into(),
+            source: v1.can_compose(&v1).unwrap_err(),
+        };
+        assert_eq!(RunError::Chain(compose_err).code(), "E0025");
+        let unsupported = ups::convert::ConvertError::Unsupported(ups::PatchFormat::Ips);
+        assert_eq!(RunError::Convert(unsupported).code(), "E0026");
+        let converter = ups::convert::Converter::new();
+        let ips = ups::parse_any(b"PATCHEOF").unwrap();
+        let needs_source = converter.convert(&ips, ups::PatchFormat::Ups).unwrap_err();
+        assert_eq!(RunError::Convert(needs_source).code(), "E0027");
+        let ebp = ups::parse_any(b"PATCHEOF{\"title\": \"Hi\"}").unwrap();
+        let lossy = converter.convert(&ebp, ups::PatchFormat::Ups).unwrap_err();
+        assert_eq!(RunError::Convert(lossy).code(), "E0028");
+        #[cfg(feature = "vcdiff")]
+        {
+            use ups::vcdiff::VcdiffParseError;
+
+            let vcdiff_err = ups::vcdiff::VcdiffPatch::parse(b"UPS1").unwrap_err();
+            assert_eq!(RunError::VcdiffParse(vcdiff_err).code(), "E0017");
+            let unsupported = VcdiffParseError::Unsupported("custom code table".into());
+            assert_eq!(RunError::VcdiffParse(unsupported).code(), "E0018");
+        }
+        let patch = Patch::diff(b"abc", b"abd");
+        let patch_errs = patch.apply(b"xyz").unwrap_err();
+        assert_eq!(RunError::Patch(patch_errs).code(), "E0003");
+        let patch_errs = patch.apply(b"abcd").unwrap_err();
+        assert!(["E0003", "E0004", "E0005", "E0006"].contains(&RunError::Patch(patch_errs).code()));
+    }
+}
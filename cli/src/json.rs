@@ -0,0 +1,51 @@
+// Helpers for the JSON output of the CLI.
+use std::path::Path;
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+// Serializes a path losslessly. Valid UTF-8 paths are plain strings, others are objects with a
+// `lossy` string for display and the raw OS string: `bytes` on Unix and UTF-16 `wide` units on
+// Windows, e.g. `{"lossy": "\u{fffd}.gba", "bytes": [130, 46, 103, 98, 97]}`.
+pub(crate) struct SerializePath<'a>(pub &'a Path);
+
+impl<'a> Serialize for SerializePath<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if let Some(path) = self.0.to_str() {
+            return serializer.serialize_str(path);
+        }
+        let mut s = serializer.serialize_struct("Path", 2)?;
+        s.serialize_field("lossy", &self.0.to_string_lossy())?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            s.serialize_field("bytes", self.0.as_os_str().as_bytes())?;
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::ffi::OsStrExt;
+            let wide: Vec<u16> = self.0.as_os_str().encode_wide().collect();
+            s.serialize_field("wide", &wide)?;
+        }
+        s.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_serialize_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let json = |path: &Path| serde_json::to_string(&SerializePath(path)).unwrap();
+        assert_eq!(json(Path::new("roms/ゲーム.gba")), r#""roms/ゲーム.gba""#);
+        let path = Path::new(OsStr::from_bytes(b"\x82.gba"));
+        assert_eq!(
+            json(path),
+            "{\"lossy\":\"\u{fffd}.gba\",\"bytes\":[130,46,103,98,97]}",
+        );
+    }
+}
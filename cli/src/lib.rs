@@ -6,44 +6,156 @@
 //! ```no_run
 //! use ups_cli::{PatchArgs, PatchDirection};
 //!
-//! let args = PatchArgs {
-//!     patch: "some_patch.ups".into(),
-//!     input: Some("some_rom.bin".into()),
-//!     output: Some("patched_rom.bin".into()),
-//!     direction: PatchDirection::Apply,
-//! };
-//! ups_cli::patch(&args).unwrap()
+//! let args = PatchArgs::new("some_patch.ups")
+//!     .input("some_rom.bin")
+//!     .output("patched_rom.bin")
+//!     .direction(PatchDirection::Apply);
+//! let metrics = ups_cli::patch(&args).unwrap();
+//! println!("{}", metrics);
 //! ```
+#![forbid(unsafe_code)]
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
 use std::fs::{self, File};
-use std::io::{self, Read, Write};
-use std::path::PathBuf;
+use std::io::{self, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 use structopt::StructOpt;
 
-use ups::{Patch, UpsParseError, UpsPatchErrors};
+use ups::aps::{ApsParseError, ApsPatch};
+use ups::bps::{BpsParseError, BpsPatch};
+use ups::codec::PatchCodec;
+use ups::convert::{ConvertError, Converter};
+use ups::diff;
+use ups::ebp::{EbpParseError, EbpPatch};
+use ups::index::{MatchKind, PatchIndex};
+use ups::ips::{IpsParseError, IpsPatch};
+use ups::ninja::{NinjaParseError, NinjaPatch};
+use ups::ppf::{PpfParseError, PpfPatch};
+use ups::softpatch::ChainError;
+use ups::store::PatchStore;
+#[cfg(feature = "vcdiff")]
+use ups::vcdiff::{VcdiffParseError, VcdiffPatch};
+use ups::{
+    detect_format, parse_any, AnyParseError, ByteSize, Checksum, ChecksumOrder, Patch,
+    PatchBuilder, PatchFormat, SerializeChecksumHex, UpsParseError, UpsPatchErrors,
+};
+
+use crate::json::SerializePath;
 
+pub use edit::ByteEdit;
+pub use naming::OutputNamer;
+pub use plugin::Plugin;
+pub use select::NamePattern;
 pub use structopt;
+pub use transaction::{apply_transaction, TransactionEvent};
 pub use ups::{self, PatchDirection};
 
+pub mod bench;
+mod builder;
+pub mod corpus;
+pub mod edit;
+pub mod explain;
+mod json;
+#[cfg(feature = "map")]
+pub mod map;
+pub mod naming;
+pub mod plugin;
+pub mod prefetch;
+pub mod select;
+pub mod sidecar;
+pub mod sink;
+pub mod stats;
+pub mod transaction;
+
 /// Command-line arguments for upstool.
 #[derive(Debug, StructOpt)]
 #[structopt(name = "upstool", about = "Simple UPS patcher")]
-pub enum Args {
-    /// Apply or revert UPS patches.
+#[non_exhaustive]
+pub struct Args {
+    /// Print errors as JSON objects instead of plain text.
+    #[structopt(long, global = true)]
+    pub json: bool,
+    /// Append anonymous statistics about patch, revert and generate runs to this file, one JSON
+    /// object per line. See `ups_cli::stats`.
+    #[structopt(long, global = true)]
+    pub stats_file: Option<PathBuf>,
+    /// Byte order to print checksums in: "le" as stored in UPS patches, or "be" for the CRC32
+    /// value shown by No-Intro, emulators and most hashing tools. JSON output has both.
+    #[structopt(
+        long,
+        global = true,
+        default_value = "le",
+        possible_values(&["le", "be"]),
+        parse(try_from_str = parse_checksum_order),
+    )]
+    pub checksum_order: ChecksumOrder,
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+/// upstool subcommands.
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Apply or revert UPS and PPF patches, or apply IPS, EBP, BPS, APS, NINJA and VCDIFF
+    /// (xdelta) patches.
     Patch(PatchArgs),
+    /// Get the original file back from a patched one, same as `patch --direction revert`.
+    Revert(RevertArgs),
     /// Generate UPS patch from input files.
     Generate(GenerateArgs),
+    /// Find duplicate patches in a directory.
+    Dedupe(DedupeArgs),
+    /// Overwrite bytes in a file, writing the edited file and a patch with the edits.
+    Edit(EditArgs),
+    /// Explain why a patch doesn't apply to a ROM and what to try.
+    Doctor(DoctorArgs),
+    /// Show patch metadata and what it changes.
+    Info(InfoArgs),
+    /// Repair blocks with terminator anomalies from third-party generators, or wrong checksums.
+    Fix(FixArgs),
+    /// Show a single block of a patch, e.g. to debug a patch generator at a specific location.
+    Inspect(InspectArgs),
+    /// Explain an error code, e.g. E0003, or list every code.
+    Explain(ExplainArgs),
+    /// Read or write patch metadata in the sidecar file, e.g. `hack.ups.json`.
+    Meta(MetaArgs),
+    /// Store patches by fingerprint and find them by ROM.
+    Store(StoreArgs),
+    /// Manage the regression corpus of edge case patches.
+    Corpus(CorpusArgs),
+    /// Render a PNG strip of the regions a patch changes.
+    #[cfg(feature = "map")]
+    Map(MapArgs),
+    /// Find a sequence of patches turning a file into another, e.g. to upgrade a hack.
+    Route(RouteArgs),
+    /// Identify which indexed patches produced a file.
+    Whatis(WhatisArgs),
+    /// Split a patch in a sequence of smaller patches, e.g. for attachment size limits.
+    Split(SplitArgs),
+    /// Replace the data of a patch with pseudo-random bytes, keeping its structure, to share
+    /// patches for commercial ROMs in bug reports.
+    Scrub(ScrubArgs),
+    /// Re-express a patch in another format, e.g. an IPS patch as UPS. The output format is
+    /// picked from the output file extension.
+    Convert(ConvertArgs),
+    /// Time parsing, applying and diffing patches, to compare performance between machines.
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    Bench(BenchArgs),
+    /// Subcommand upstool doesn't know, with its arguments, for [`plugin`]s.
+    #[structopt(external_subcommand)]
+    External(Vec<String>),
 }
 
 /// Arguments for patch subcommand.
-#[derive(Debug, StructOpt)]
+#[derive(Debug, Clone, StructOpt)]
+#[non_exhaustive]
 pub struct PatchArgs {
-    /// Path to UPS patch file.
-    pub patch: PathBuf,
-    /// Path to input file or - for stdin.
-    pub input: Option<PathBuf>,
-    /// Path to output file or - for stdout.
-    pub output: Option<PathBuf>,
+    #[structopt(flatten)]
+    pub options: PatchOptions,
     /// Whether to patch a source file or get it back from the patched one.
     #[structopt(
         short, long,
@@ -54,6 +166,89 @@ pub struct PatchArgs {
     pub direction: PatchDirection,
 }
 
+/// Arguments for revert subcommand, [`PatchArgs`] without the direction.
+#[derive(Debug, Clone, StructOpt)]
+#[non_exhaustive]
+pub struct RevertArgs {
+    #[structopt(flatten)]
+    pub options: PatchOptions,
+}
+
+/// Options shared by the patch and revert subcommands.
+#[derive(Debug, Clone, StructOpt)]
+#[non_exhaustive]
+pub struct PatchOptions {
+    /// Path to UPS patch file, or IPS, EBP, BPS, PPF, APS, NINJA or VCDIFF patch file ending in
+    /// .ips, .ebp, .bps, .ppf, .aps, .rup or .xdelta. Only UPS and PPF patches can be reverted.
+    pub patch: PathBuf,
+    /// Path to input file or - for stdin, the patched file when reverting.
+    pub input: Option<PathBuf>,
+    /// Path to output file or - for stdout.
+    pub output: Option<PathBuf>,
+    /// Also apply or revert numbered patches following PATCH (e.g. hack.ups1, hack.ups2...) in
+    /// sequence.
+    #[structopt(long)]
+    pub auto: bool,
+    /// Name the output file from a template when OUTPUT isn't given, e.g.
+    /// "{stem} ({patchname}).{ext}". Variables: {stem}, {ext}, {patchname}. {ext} is inferred
+    /// from the ROM header when the input's doesn't match it, e.g. for .bin dumps.
+    #[structopt(long)]
+    pub output_template: Option<OutputNamer>,
+    /// Write the output to this directory when OUTPUT isn't given, named from --output-template or
+    /// "{stem} ({patchname}).{ext}" by default.
+    #[structopt(long, conflicts_with = "output")]
+    pub output_dir: Option<PathBuf>,
+    /// Overwrite INPUT with the output instead of writing a new file.
+    #[structopt(long, conflicts_with_all = &["output", "output-template", "output-dir"])]
+    pub in_place: bool,
+    /// Write binary output to stdout even if it's a terminal.
+    #[structopt(long)]
+    pub force_stdout: bool,
+    /// Read the output file back after writing it and check its checksum.
+    #[structopt(long)]
+    pub verify_output: bool,
+    /// Don't print a summary to stderr after patching.
+    #[structopt(short, long)]
+    pub quiet: bool,
+    /// Don't ask for confirmation before overwriting files.
+    #[structopt(short, long)]
+    pub yes: bool,
+    /// Read PATCH as the patch contents encoded as hex or base64 instead of a file path.
+    #[structopt(long, conflicts_with = "auto")]
+    pub patch_inline: bool,
+    /// Upload the output to this destination instead of writing a local file, named like with
+    /// --output-dir or after OUTPUT: a directory, https://HOST[:PORT][/PREFIX] (or http://) or
+    /// s3://BUCKET[/PREFIX] (AWS_* environment variables). See `ups_cli::sink`.
+    #[structopt(long, conflicts_with_all = &["output-dir", "in-place", "verify-output"])]
+    pub upload: Option<String>,
+    /// Directory for temporary files, by default next to the output file. For destinations where
+    /// only the output file is writable, but not its directory.
+    #[structopt(long, conflicts_with = "upload")]
+    pub tmp_dir: Option<PathBuf>,
+    /// Refuse to write an output identical to the input or to the unmodified base file, going by
+    /// their checksums. Guards automation from redistributing clean ROMs, e.g. after reverting.
+    #[structopt(long)]
+    pub refuse_unmodified_output: bool,
+}
+
+impl From<RevertArgs> for PatchArgs {
+    fn from(args: RevertArgs) -> Self {
+        PatchArgs {
+            options: args.options,
+            direction: PatchDirection::Revert,
+        }
+    }
+}
+
+/// Revert options from `args`, ignoring its direction.
+impl From<PatchArgs> for RevertArgs {
+    fn from(args: PatchArgs) -> Self {
+        RevertArgs {
+            options: args.options,
+        }
+    }
+}
+
 fn parse_direction(s: &str) -> Result<PatchDirection, String> {
     match s {
         "apply" => Ok(PatchDirection::Apply),
@@ -62,15 +257,432 @@ fn parse_direction(s: &str) -> Result<PatchDirection, String> {
     }
 }
 
+fn parse_checksum_order(s: &str) -> Result<ChecksumOrder, String> {
+    match s {
+        "le" => Ok(ChecksumOrder::LittleEndian),
+        "be" => Ok(ChecksumOrder::BigEndian),
+        _ => Err(format!("Invalid checksum order value \"{}\"", s)),
+    }
+}
+
 /// Arguments for generate subcommand.
 #[derive(Debug, StructOpt)]
+#[non_exhaustive]
 pub struct GenerateArgs {
-    /// Path to source file.
+    /// Path to source file or - for stdin.
     pub source: PathBuf,
-    /// Path to destination file.
+    /// Path to destination file or - for stdin, or a directory with `--latest`.
     pub dest: PathBuf,
-    /// Path to output patch file or - for stdout.
+    /// Path to output patch file or - for stdout. Files ending in .bps are BPS patches, which
+    /// encode moved and inserted data much more compactly.
     pub patch: Option<PathBuf>,
+    /// Use the most recently modified file in the destination directory with the source file
+    /// extension, e.g. the last build of a hack with timestamps or version numbers in its file
+    /// names. The source and output patch are skipped.
+    #[structopt(long)]
+    pub latest: bool,
+    /// Compare files in windows of this size (e.g. 64MiB) instead of loading them in memory.
+    #[structopt(long, parse(try_from_str = parse_size))]
+    pub window_size: Option<usize>,
+    /// Read the files ahead on background threads while comparing windows, faster on spinning
+    /// disks and network mounts.
+    #[structopt(long, requires = "window-size")]
+    pub prefetch: bool,
+    /// Print metrics about the generated patch to stderr.
+    #[structopt(long, conflicts_with = "window-size")]
+    pub report: bool,
+    /// Write the patch to stdout even if it's a terminal.
+    #[structopt(long)]
+    pub force_stdout: bool,
+    /// Record the upstool version in a sidecar file next to the patch, e.g. `hack.ups.json`.
+    /// Off by default so patches don't depend on the upstool version.
+    #[structopt(long)]
+    pub record_generator: bool,
+    /// Don't ask for confirmation before overwriting files.
+    #[structopt(short, long)]
+    pub yes: bool,
+}
+
+/// CRC32 as written on the command line, whose byte order depends on `--checksum-order`. See
+/// [`parse_checksum`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumArg(pub u32);
+
+impl ChecksumArg {
+    /// The checksum, reading the digits in `order` like [`Checksum::display`] prints them.
+    pub fn checksum(self, order: ChecksumOrder) -> Checksum {
+        match order {
+            ChecksumOrder::LittleEndian => Checksum(self.0.swap_bytes()),
+            ChecksumOrder::BigEndian => Checksum(self.0),
+        }
+    }
+}
+
+/// Parse a CRC32 written as up to 8 hex digits, e.g. `3c2b0f8a` or `0x3C2B0F8A`. The digits are
+/// in the `--checksum-order` byte order, so checksums printed by `info` can be pasted as is.
+pub fn parse_checksum(s: &str) -> Result<ChecksumArg, String> {
+    let s = s.trim();
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    if digits.is_empty() || digits.len() > 8 {
+        return Err(format!(
+            "Invalid CRC32 \"{}\", expected up to 8 hex digits",
+            s
+        ));
+    }
+    u32::from_str_radix(digits, 16)
+        .map(ChecksumArg)
+        .map_err(|_| format!("Invalid CRC32 \"{}\", expected up to 8 hex digits", s))
+}
+
+/// Parse a size in bytes with an optional binary unit suffix, e.g. `4096`, `64KiB` or `8M`.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let multiplier: usize = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        _ => return Err(format!("Invalid size unit \"{}\"", unit)),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("Invalid size \"{}\"", s))
+}
+
+/// Arguments for dedupe subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct DedupeArgs {
+    /// Directory with UPS patches.
+    pub dir: PathBuf,
+    /// Only compare files with names matching this pattern, `*` matches any characters and `?` a
+    /// single one.
+    #[structopt(long, default_value = "*.ups", parse(from_os_str = NamePattern::new))]
+    pub pattern: NamePattern,
+    /// Replace byte-identical duplicates with hard links to the first copy.
+    #[structopt(long, conflicts_with = "delete")]
+    pub hardlink: bool,
+    /// Delete duplicates, keeping the first copy in file name order.
+    #[structopt(long)]
+    pub delete: bool,
+    /// Don't ask for confirmation before deleting or replacing duplicates.
+    #[structopt(short, long)]
+    pub yes: bool,
+}
+
+/// Arguments for edit subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct EditArgs {
+    /// Path to the file to edit.
+    pub base: PathBuf,
+    /// Path to write the edited file to.
+    #[structopt(short, long)]
+    pub output: PathBuf,
+    /// Path to write the patch with the edits to.
+    #[structopt(short, long)]
+    pub patch: PathBuf,
+    /// Bytes to overwrite as OFFSET=HEXBYTES, e.g. 0x1f4=DEADBEEF. Can be repeated.
+    #[structopt(long = "set", number_of_values = 1, value_name = "OFFSET=HEXBYTES")]
+    pub edits: Vec<ByteEdit>,
+    /// Read more edits from a file, one OFFSET=HEXBYTES per line. Lines starting with # are
+    /// ignored.
+    #[structopt(long)]
+    pub edits_file: Option<PathBuf>,
+    /// Don't ask for confirmation before overwriting files.
+    #[structopt(short, long)]
+    pub yes: bool,
+}
+
+/// Arguments for doctor subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct DoctorArgs {
+    /// Path to UPS patch file.
+    pub patch: PathBuf,
+    /// Path to the ROM the patch should apply to.
+    pub rom: PathBuf,
+}
+
+/// Arguments for info subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct InfoArgs {
+    /// Path to UPS, NINJA or EBP patch file, or - for stdin.
+    pub patch: PathBuf,
+    /// Also run slower analyses of UPS patches, like estimating how well they compress.
+    #[structopt(long)]
+    pub deep: bool,
+}
+
+/// Arguments for fix subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct FixArgs {
+    /// Path to UPS patch file or - for stdin.
+    pub patch: PathBuf,
+    /// Path to write the repaired patch to, "<patch stem>-fixed.ups" next to the patch by default.
+    #[structopt(short, long)]
+    pub output: Option<PathBuf>,
+    /// Rewrite every block in canonical form instead of only repairing the anomalies, which also
+    /// drops blocks past the end of the files.
+    #[structopt(long)]
+    pub normalize: bool,
+    /// Source file to take the patch source size and checksum from, e.g. after hand-editing the
+    /// patch. The destination checksum is recomputed from it unless --target is given.
+    #[structopt(long)]
+    pub base: Option<PathBuf>,
+    /// Patched file to take the patch destination size and checksum from.
+    #[structopt(long)]
+    pub target: Option<PathBuf>,
+    /// Don't ask for confirmation before overwriting files.
+    #[structopt(short, long)]
+    pub yes: bool,
+}
+
+/// Arguments for inspect subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct InspectArgs {
+    /// Path to UPS patch file or - for stdin.
+    pub patch: PathBuf,
+    /// Index of the block to show, counting from 0.
+    #[structopt(long)]
+    pub block: usize,
+    /// File the patch applies to, to show the bytes before and after the block.
+    #[structopt(long)]
+    pub base: Option<PathBuf>,
+}
+
+/// Arguments for explain subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct ExplainArgs {
+    /// Error code, e.g. E0003, or JSON error kind. Lists every code if omitted.
+    pub code: Option<String>,
+}
+
+/// Subcommands of meta.
+#[derive(Debug, StructOpt)]
+pub enum MetaArgs {
+    /// Set metadata fields, creating the sidecar file if needed. Empty values remove a field.
+    Set(MetaSetArgs),
+    /// Print metadata fields.
+    Get(MetaGetArgs),
+}
+
+/// Arguments for meta set subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct MetaSetArgs {
+    /// Path to UPS patch file.
+    pub patch: PathBuf,
+    /// Name of the hack or translation.
+    #[structopt(long)]
+    pub title: Option<String>,
+    /// Author of the patch.
+    #[structopt(long)]
+    pub author: Option<String>,
+    /// Release version of the patch, e.g. 1.2.
+    #[structopt(long)]
+    pub version: Option<String>,
+}
+
+/// Arguments for meta get subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct MetaGetArgs {
+    /// Path to UPS patch file.
+    pub patch: PathBuf,
+    /// Print only this field's value, empty if it's not set.
+    #[structopt(possible_values(&["title", "author", "version", "generator"]))]
+    pub field: Option<String>,
+}
+
+/// Subcommands of store.
+#[derive(Debug, StructOpt)]
+pub enum StoreArgs {
+    /// Add patches to a store, printing their fingerprints.
+    Add(StoreAddArgs),
+    /// List stored patches applying to a ROM.
+    Get(StoreGetArgs),
+}
+
+/// Arguments for store add subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct StoreAddArgs {
+    /// Store directory, created if needed.
+    pub store: PathBuf,
+    /// Paths to UPS patch files.
+    #[structopt(required = true)]
+    pub patches: Vec<PathBuf>,
+}
+
+/// Arguments for store get subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct StoreGetArgs {
+    /// Store directory.
+    pub store: PathBuf,
+    /// Path to ROM file or - for stdin.
+    pub rom: PathBuf,
+}
+
+/// Subcommands of corpus.
+#[derive(Debug, StructOpt)]
+pub enum CorpusArgs {
+    /// Add a patch and input to a corpus, recording the errors they produce.
+    Add(CorpusAddArgs),
+}
+
+/// Arguments for corpus add subcommand.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct CorpusAddArgs {
+    /// Corpus file, e.g. lib/tests/regressions/cases.json. Created if needed.
+    pub corpus: PathBuf,
+    /// Path to the patch file, it doesn't need to be valid.
+    pub patch: PathBuf,
+    /// Path to the input file or - for stdin.
+    pub input: PathBuf,
+    /// Unique snake case name for the case.
+    #[structopt(long)]
+    pub name: String,
+    /// What the case exercises.
+    #[structopt(long)]
+    pub description: String,
+    /// Whether to apply or revert the patch.
+    #[structopt(
+        short, long,
+        default_value = "apply",
+        possible_values(&["apply", "revert"]),
+        parse(try_from_str = parse_direction),
+    )]
+    pub direction: PatchDirection,
+}
+
+/// Arguments for map subcommand.
+#[cfg(feature = "map")]
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct MapArgs {
+    /// Path to UPS patch file.
+    pub patch: PathBuf,
+    /// Path to output PNG file.
+    #[structopt(short, long)]
+    pub output: PathBuf,
+    /// KiB of the patched file per pixel, by default the smallest power of two fitting the map in
+    /// 1024 pixels.
+    #[structopt(long)]
+    pub kib_per_pixel: Option<usize>,
+    /// Height of the map in pixels.
+    #[structopt(long, default_value = "16")]
+    pub height: u32,
+}
+
+/// Arguments for route subcommand.
+#[non_exhaustive]
+#[derive(Debug, StructOpt)]
+pub struct RouteArgs {
+    /// Directory of UPS patches or patch index JSON file.
+    pub patches: PathBuf,
+    /// CRC32 of the file to start from, as 8 hex digits in the --checksum-order byte order, e.g.
+    /// 0x3C2B0F8A as printed by `info`.
+    #[structopt(long, parse(try_from_str = parse_checksum))]
+    pub from: ChecksumArg,
+    /// CRC32 of the file to end up with.
+    #[structopt(long, parse(try_from_str = parse_checksum))]
+    pub to: ChecksumArg,
+}
+
+/// Arguments for whatis subcommand.
+#[non_exhaustive]
+#[derive(Debug, StructOpt)]
+pub struct WhatisArgs {
+    /// Path to the file to identify or - for stdin.
+    pub file: PathBuf,
+    /// Directory of UPS patches or patch index JSON file.
+    #[structopt(long)]
+    pub index: PathBuf,
+}
+
+/// Arguments for split subcommand.
+#[non_exhaustive]
+#[derive(Debug, StructOpt)]
+pub struct SplitArgs {
+    /// Path to UPS patch file.
+    pub patch: PathBuf,
+    /// Maximum size of each patch, e.g. 8MiB.
+    #[structopt(long, parse(try_from_str = parse_size))]
+    pub max_size: usize,
+    /// Path to the first patch, the next ones are numbered after it (e.g. hack-split.ups1,
+    /// hack-split.ups2...) so `patch --auto` applies them all. "<patch stem>-split.ups" next to
+    /// the patch by default.
+    #[structopt(short, long)]
+    pub output: Option<PathBuf>,
+    /// Don't ask for confirmation before overwriting files.
+    #[structopt(short, long)]
+    pub yes: bool,
+}
+
+/// Arguments for scrub subcommand.
+#[non_exhaustive]
+#[derive(Debug, StructOpt)]
+pub struct ScrubArgs {
+    /// Path to UPS patch file or - for stdin. It doesn't need to be valid, scrubbing keeps parse
+    /// errors after the header.
+    pub patch: PathBuf,
+    /// Path to write the scrubbed patch to or - for stdout.
+    pub output: PathBuf,
+    /// Don't ask for confirmation before overwriting files.
+    #[structopt(short, long)]
+    pub yes: bool,
+}
+
+/// Arguments for convert subcommand.
+#[non_exhaustive]
+#[derive(Debug, StructOpt)]
+pub struct ConvertArgs {
+    /// Path to the patch file to convert, in any supported format, or - for stdin.
+    pub patch: PathBuf,
+    /// Path to write the converted patch to, ending in `.ups` or `.bps`.
+    pub output: PathBuf,
+    /// Source file the patch applies to, needed unless the patch is already in the output
+    /// format. The converted patch only applies to this file.
+    #[structopt(short, long)]
+    pub source: Option<PathBuf>,
+    /// Drop metadata the output format can't hold, like EBP titles, instead of failing.
+    #[structopt(long)]
+    pub allow_lossy: bool,
+    /// Don't ask for confirmation before overwriting files.
+    #[structopt(short, long)]
+    pub yes: bool,
+}
+
+/// Arguments for bench subcommand.
+#[non_exhaustive]
+#[derive(Debug, StructOpt)]
+pub struct BenchArgs {
+    /// Path to source file, synthetic files are generated if omitted.
+    #[structopt(requires = "dest")]
+    pub source: Option<PathBuf>,
+    /// Path to destination file.
+    pub dest: Option<PathBuf>,
+    /// Size of the synthetic source file, e.g. 64MiB. 16MiB by default.
+    #[structopt(long, parse(try_from_str = parse_size), conflicts_with = "source")]
+    pub size: Option<usize>,
+    /// Number of times to run each operation.
+    #[structopt(long, default_value = "5")]
+    pub iterations: u32,
 }
 
 /// Possible errors for any CLI command.
@@ -82,6 +694,289 @@ pub enum RunError {
     Parse(#[from] UpsParseError),
     #[error(transparent)]
     Patch(#[from] UpsPatchErrors),
+    #[error(transparent)]
+    Chain(#[from] ChainError),
+    #[error(transparent)]
+    IpsParse(#[from] IpsParseError),
+    #[error(transparent)]
+    BpsParse(#[from] BpsParseError),
+    #[error(transparent)]
+    PpfParse(#[from] PpfParseError),
+    #[error(transparent)]
+    ApsParse(#[from] ApsParseError),
+    #[error(transparent)]
+    NinjaParse(#[from] NinjaParseError),
+    #[error(transparent)]
+    EbpParse(#[from] EbpParseError),
+    #[error(transparent)]
+    Convert(#[from] ConvertError),
+    #[cfg(feature = "vcdiff")]
+    #[error(transparent)]
+    VcdiffParse(#[from] VcdiffParseError),
+    #[error("{}", .0)]
+    Usage(String),
+    /// The input matches the output side of the patch, it was already patched or reverted.
+    #[error(
+        "this file already appears to be {}, run `upstool {}` with the same arguments to {}",
+        if *.0 == PatchDirection::Apply {
+            "patched with this patch, so `upstool patch` would apply it twice"
+        } else {
+            "unpatched, so there's nothing for `upstool revert` to undo"
+        },
+        if *.0 == PatchDirection::Apply { "revert" } else { "patch" },
+        if *.0 == PatchDirection::Apply { "restore the original" } else { "patch it" },
+    )]
+    AlreadyPatched(PatchDirection),
+    /// With `--refuse-unmodified-output`, the output would be the unmodified base file, or
+    /// identical to the input otherwise.
+    #[error(
+        "refusing to write {}, because of --refuse-unmodified-output",
+        if *.base { "the unmodified base file" } else { "an output identical to the input" },
+    )]
+    UnmodifiedOutput { base: bool },
+    /// The user declined a confirmation prompt.
+    #[error("Cancelled")]
+    Cancelled,
+    /// The output file read back with `--verify-output` doesn't match what was written.
+    #[error(
+        "Output file \"{}\" is corrupted: expected CRC32 {}, read back {}",
+        .path.display(),
+        .expected,
+        .actual
+    )]
+    VerifyFailed {
+        path: PathBuf,
+        expected: Checksum,
+        actual: Checksum,
+    },
+    /// No sequence of indexed patches turns a file with checksum `from` into one with `to`.
+    #[error(
+        "No route from CRC32 {} to {} in {} patches",
+        .from.display(*.order),
+        .to.display(*.order),
+        .patches
+    )]
+    NoRoute {
+        from: Checksum,
+        to: Checksum,
+        patches: usize,
+        /// Byte order to print the checksums in, from `--checksum-order`.
+        order: ChecksumOrder,
+    },
+}
+
+impl From<AnyParseError> for RunError {
+    fn from(e: AnyParseError) -> Self {
+        match e {
+            AnyParseError::Disabled(format) => RunError::Usage(format!(
+                "this upstool was built without {} support, rebuild it with `--features vcdiff`",
+                format.name()
+            )),
+            AnyParseError::Ups(e) => e.into(),
+            AnyParseError::Ips(e) => e.into(),
+            AnyParseError::Ebp(e) => e.into(),
+            AnyParseError::Bps(e) => e.into(),
+            AnyParseError::Ppf(e) => e.into(),
+            AnyParseError::Aps(e) => e.into(),
+            AnyParseError::Ninja(e) => e.into(),
+            #[cfg(feature = "vcdiff")]
+            AnyParseError::Vcdiff(e) => e.into(),
+            // Formats `ups` was built with but upstool wasn't, e.g. VCDIFF through another crate
+            // enabling `ups/vcdiff`.
+            e => RunError::Usage(e.to_string()),
+        }
+    }
+}
+
+// Same shape as the library errors: `{"kind": ..., ...fields}`.
+impl Serialize for RunError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            RunError::Io(context, e) => {
+                let mut s = serializer.serialize_struct("RunError", 4)?;
+                s.serialize_field("kind", "io")?;
+                s.serialize_field("context", context)?;
+                s.serialize_field("io_kind", &format!("{:?}", e.kind()))?;
+                s.serialize_field("reason", &e.to_string())?;
+                s.end()
+            }
+            RunError::Parse(e) => e.serialize(serializer),
+            RunError::Patch(e) => e.serialize(serializer),
+            RunError::Chain(e) => e.serialize(serializer),
+            RunError::IpsParse(IpsParseError::FormatMismatch(reason)) => {
+                let mut s = serializer.serialize_struct("RunError", 2)?;
+                s.serialize_field("kind", "ips_format_mismatch")?;
+                s.serialize_field("reason", reason)?;
+                s.end()
+            }
+            RunError::BpsParse(BpsParseError::FormatMismatch(reason)) => {
+                let mut s = serializer.serialize_struct("RunError", 2)?;
+                s.serialize_field("kind", "bps_format_mismatch")?;
+                s.serialize_field("reason", reason)?;
+                s.end()
+            }
+            RunError::BpsParse(BpsParseError::PatchChecksumMismatch {
+                expected, actual, ..
+            }) => {
+                let mut s = serializer.serialize_struct("RunError", 5)?;
+                s.serialize_field("kind", "patch_checksum_mismatch")?;
+                s.serialize_field("expected", expected)?;
+                s.serialize_field("actual", actual)?;
+                s.serialize_field("expected_hex", &SerializeChecksumHex(*expected))?;
+                s.serialize_field("actual_hex", &SerializeChecksumHex(*actual))?;
+                s.end()
+            }
+            RunError::PpfParse(PpfParseError::FormatMismatch(reason)) => {
+                let mut s = serializer.serialize_struct("RunError", 2)?;
+                s.serialize_field("kind", "ppf_format_mismatch")?;
+                s.serialize_field("reason", reason)?;
+                s.end()
+            }
+            RunError::ApsParse(ApsParseError::FormatMismatch(reason)) => {
+                let mut s = serializer.serialize_struct("RunError", 2)?;
+                s.serialize_field("kind", "aps_format_mismatch")?;
+                s.serialize_field("reason", reason)?;
+                s.end()
+            }
+            RunError::NinjaParse(e) => {
+                let (kind, reason) = match e {
+                    NinjaParseError::FormatMismatch(reason) => ("ninja_format_mismatch", reason),
+                    NinjaParseError::Unsupported(reason) => ("ninja_unsupported", reason),
+                };
+                let mut s = serializer.serialize_struct("RunError", 2)?;
+                s.serialize_field("kind", kind)?;
+                s.serialize_field("reason", reason)?;
+                s.end()
+            }
+            RunError::EbpParse(EbpParseError::FormatMismatch(reason)) => {
+                let mut s = serializer.serialize_struct("RunError", 2)?;
+                s.serialize_field("kind", "ebp_format_mismatch")?;
+                s.serialize_field("reason", reason)?;
+                s.end()
+            }
+            #[cfg(feature = "vcdiff")]
+            RunError::VcdiffParse(e) => {
+                let (kind, reason) = match e {
+                    VcdiffParseError::FormatMismatch(reason) => ("vcdiff_format_mismatch", reason),
+                    VcdiffParseError::Unsupported(reason) => ("vcdiff_unsupported", reason),
+                };
+                let mut s = serializer.serialize_struct("RunError", 2)?;
+                s.serialize_field("kind", kind)?;
+                s.serialize_field("reason", reason)?;
+                s.end()
+            }
+            RunError::Convert(ConvertError::Patch(e)) => e.serialize(serializer),
+            RunError::Convert(e) => {
+                let mut s = serializer.serialize_struct("RunError", 2)?;
+                s.serialize_field("kind", self.kind())?;
+                s.serialize_field("reason", &e.to_string())?;
+                s.end()
+            }
+            RunError::AlreadyPatched(direction) => {
+                let mut s = serializer.serialize_struct("RunError", 2)?;
+                s.serialize_field("kind", "already_patched")?;
+                s.serialize_field("direction", direction)?;
+                s.end()
+            }
+            RunError::UnmodifiedOutput { base } => {
+                let mut s = serializer.serialize_struct("RunError", 2)?;
+                s.serialize_field("kind", "unmodified_output")?;
+                s.serialize_field("base", base)?;
+                s.end()
+            }
+            RunError::VerifyFailed {
+                path,
+                expected,
+                actual,
+            } => {
+                let mut s = serializer.serialize_struct("RunError", 6)?;
+                s.serialize_field("kind", "verify_failed")?;
+                s.serialize_field("path", &SerializePath(path))?;
+                s.serialize_field("expected", expected)?;
+                s.serialize_field("actual", actual)?;
+                s.serialize_field("expected_hex", &SerializeChecksumHex(*expected))?;
+                s.serialize_field("actual_hex", &SerializeChecksumHex(*actual))?;
+                s.end()
+            }
+            RunError::NoRoute {
+                from, to, patches, ..
+            } => {
+                let mut s = serializer.serialize_struct("RunError", 6)?;
+                s.serialize_field("kind", "no_route")?;
+                s.serialize_field("from", from)?;
+                s.serialize_field("to", to)?;
+                s.serialize_field("from_hex", &SerializeChecksumHex(*from))?;
+                s.serialize_field("to_hex", &SerializeChecksumHex(*to))?;
+                s.serialize_field("patches", patches)?;
+                s.end()
+            }
+            RunError::Cancelled => {
+                let mut s = serializer.serialize_struct("RunError", 1)?;
+                s.serialize_field("kind", "cancelled")?;
+                s.end()
+            }
+            RunError::Usage(reason) => {
+                let mut s = serializer.serialize_struct("RunError", 2)?;
+                s.serialize_field("kind", "usage")?;
+                s.serialize_field("reason", reason)?;
+                s.end()
+            }
+        }
+    }
+}
+
+impl RunError {
+    /// Render this error as a JSON object with its `code` and an additional human-readable
+    /// `message`.
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "error": self,
+            "code": self.code(),
+            "message": self.to_string(),
+        })
+        .to_string()
+    }
+
+    /// Stable error code, see [`explain`] for what each one means. Errors without an explanation
+    /// of their own get [`explain::FALLBACK_CODE`].
+    pub fn code(&self) -> &'static str {
+        explain::find(self.kind()).map_or(explain::FALLBACK_CODE, |e| e.code)
+    }
+
+    // Same as the `kind` field in JSON errors. For multiple patch errors, the first source error's.
+    fn kind(&self) -> &'static str {
+        match self {
+            RunError::Io(..) | RunError::Chain(ChainError::Io { .. }) => "io",
+            RunError::Parse(e) | RunError::Chain(ChainError::Parse { source: e, .. }) => e.kind(),
+            RunError::Patch(e)
+            | RunError::Chain(ChainError::Patch { source: e, .. })
+            | RunError::Convert(ConvertError::Patch(e)) => e.kind(),
+            RunError::Chain(ChainError::Compose { .. }) => "broken_chain",
+            RunError::IpsParse(_) => "ips_format_mismatch",
+            RunError::BpsParse(BpsParseError::FormatMismatch(_)) => "bps_format_mismatch",
+            RunError::BpsParse(BpsParseError::PatchChecksumMismatch { .. }) => {
+                "patch_checksum_mismatch"
+            }
+            RunError::PpfParse(_) => "ppf_format_mismatch",
+            RunError::ApsParse(_) => "aps_format_mismatch",
+            RunError::NinjaParse(NinjaParseError::FormatMismatch(_)) => "ninja_format_mismatch",
+            RunError::NinjaParse(NinjaParseError::Unsupported(_)) => "ninja_unsupported",
+            RunError::EbpParse(_) => "ebp_format_mismatch",
+            RunError::Convert(ConvertError::Unsupported(_)) => "convert_unsupported",
+            RunError::Convert(ConvertError::SourceNeeded { .. }) => "convert_needs_source",
+            RunError::Convert(ConvertError::Lossy { .. }) => "convert_lossy",
+            #[cfg(feature = "vcdiff")]
+            RunError::VcdiffParse(VcdiffParseError::FormatMismatch(_)) => "vcdiff_format_mismatch",
+            #[cfg(feature = "vcdiff")]
+            RunError::VcdiffParse(VcdiffParseError::Unsupported(_)) => "vcdiff_unsupported",
+            RunError::Usage(_) => "usage",
+            RunError::AlreadyPatched(_) => "already_patched",
+            RunError::UnmodifiedOutput { .. } => "unmodified_output",
+            RunError::Cancelled => "cancelled",
+            RunError::VerifyFailed { .. } => "verify_failed",
+            RunError::NoRoute { .. } => "no_route",
+        }
+    }
 }
 
 impl Args {
@@ -95,64 +990,1575 @@ impl Args {
 
     /// Run the CLI application using these arguments.
     pub fn run(&self) -> Result<(), RunError> {
-        match self {
-            Args::Patch(args) => patch(args),
-            Args::Generate(args) => generate(args),
+        self.run_with(&[])
+    }
+
+    /// Like [`run`](Args::run), running unknown subcommands with `plugins`, see [`plugin`].
+    pub fn run_with(&self, plugins: &[&dyn Plugin]) -> Result<(), RunError> {
+        match &self.command {
+            Command::Patch(args) => self.report(patch(args)?, args.options.quiet),
+            Command::Revert(args) => self.report(patch(&args.clone().into())?, args.options.quiet),
+            // Generate has no summary of its own, only JSON metrics.
+            Command::Generate(args) => self.report(generate(args)?, !self.json),
+            Command::Dedupe(args) => dedupe(args),
+            Command::Edit(args) => edit(args),
+            Command::Doctor(args) => doctor(args),
+            Command::Info(args) => info(args, self.checksum_order),
+            Command::Fix(args) => fix(args, self.checksum_order),
+            Command::Inspect(args) => inspect(args),
+            Command::Explain(args) => explain(args),
+            Command::Meta(args) => meta(args),
+            Command::Store(args) => store(args),
+            Command::Corpus(args) => corpus(args),
+            #[cfg(feature = "map")]
+            Command::Map(args) => map(args),
+            Command::Route(args) => route(args, self.checksum_order),
+            Command::Whatis(args) => whatis(args, self.checksum_order),
+            Command::Split(args) => split(args, self.checksum_order),
+            Command::Scrub(args) => scrub(args),
+            Command::Convert(args) => convert(args),
+            Command::Bench(args) => {
+                let report = bench(args)?;
+                if self.json {
+                    println!("{}", report.to_json());
+                } else {
+                    print!("{}", report);
+                }
+                Ok(())
+            }
+            Command::External(argv) => plugin::dispatch(plugins, self, argv),
+        }
+    }
+
+    /// Print `err` to stderr the way upstool does: as JSON with `--json`, otherwise with a hint
+    /// to explain its code.
+    pub fn print_error(&self, err: &RunError) {
+        if self.json {
+            eprintln!("{}", err.to_json());
+        } else {
+            eprintln!("{}", err);
+            eprintln!("(run `upstool explain {}` for help)", err.code());
         }
     }
 }
 
-/// Implementation for the patch subcommand.
-pub fn patch(args: &PatchArgs) -> Result<(), RunError> {
-    let raw_patch = fs::read(&args.patch).map_err(|e| {
+/// Metrics from a patch or generate run, see [`Args::run`].
+///
+/// With `--json` these are printed as a JSON object so automation can watch performance and spot
+/// anomalous patches, otherwise patch and revert print them as a one-line summary.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    /// Subcommand name.
+    pub command: &'static str,
+    /// Patching direction, `None` when generating patches.
+    pub direction: Option<PatchDirection>,
+    /// Patch file read or written, "inline" for `--patch-inline`.
+    pub patch: PathBuf,
+    /// Number of patches applied, more than one with `--auto`.
+    pub patches: usize,
+    /// Number of blocks in the patch, or actions for BPS patches, when known.
+    pub blocks: Option<usize>,
+    /// Number of bytes changed by the patch, when known.
+    pub bytes_changed: Option<usize>,
+    /// Total size of the input files.
+    pub input_size: u64,
+    /// Size of the output, when known.
+    pub output_size: Option<usize>,
+    /// Checksum of the output, when known.
+    pub output_crc32: Option<Checksum>,
+    /// Output file, `None` for stdout.
+    pub output: Option<PathBuf>,
+    /// Time spent reading, processing and writing.
+    pub duration: Duration,
+}
+
+impl Metrics {
+    /// Input bytes processed per second.
+    pub fn throughput(&self) -> f64 {
+        self.input_size as f64 / self.duration.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// One-line summary printed after patching, with the output checksum in `order`. Same as
+    /// `Display`, which uses the default order.
+    pub fn summary(&self, order: ChecksumOrder) -> impl Display + '_ {
+        Summary(self, order)
+    }
+
+    /// Render these metrics as a JSON object under a `metrics` key.
+    pub fn to_json(&self) -> String {
+        serde_json::json!({ "metrics": self }).to_string()
+    }
+}
+
+impl Serialize for Metrics {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Metrics", 13)?;
+        s.serialize_field("command", self.command)?;
+        s.serialize_field("direction", &self.direction)?;
+        s.serialize_field("patch", &SerializePath(&self.patch))?;
+        s.serialize_field("patches", &self.patches)?;
+        s.serialize_field("blocks", &self.blocks)?;
+        s.serialize_field("bytes_changed", &self.bytes_changed)?;
+        s.serialize_field("input_size", &self.input_size)?;
+        s.serialize_field("output_size", &self.output_size)?;
+        s.serialize_field("output_crc32", &self.output_crc32)?;
+        s.serialize_field(
+            "output_crc32_hex",
+            &self.output_crc32.map(SerializeChecksumHex),
+        )?;
+        match &self.output {
+            Some(p) => s.serialize_field("output", &SerializePath(p))?,
+            None => s.serialize_field("output", "<stdout>")?,
+        }
+        s.serialize_field("duration_secs", &self.duration.as_secs_f64())?;
+        s.serialize_field("throughput_bytes_per_sec", &self.throughput())?;
+        s.end()
+    }
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Display::fmt(&self.summary(ChecksumOrder::default()), f)
+    }
+}
+
+// See `Metrics::summary`.
+struct Summary<'a>(&'a Metrics, ChecksumOrder);
+
+impl Display for Summary<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let Summary(metrics, order) = self;
+        let verb = match metrics.direction {
+            Some(direction) => direction_verb(direction),
+            None => "Generated",
+        };
+        match (metrics.blocks, metrics.bytes_changed) {
+            (Some(blocks), Some(changed)) => write!(
+                f,
+                "{} {}: {} blocks, {} changed",
+                verb,
+                metrics.patch.display(),
+                blocks,
+                ByteSize(changed),
+            )?,
+            _ => write!(
+                f,
+                "{} {} patches from {}",
+                verb,
+                metrics.patches,
+                metrics.patch.display(),
+            )?,
+        }
+        if let Some(checksum) = metrics.output_crc32 {
+            write!(f, ", output CRC32 {}", checksum.display(*order))?;
+        }
+        write!(f, ", wrote {}", output_name(&metrics.output))
+    }
+}
+
+impl Args {
+    // Print `metrics` as JSON or as a summary, unless `quiet`, and record them in the stats file.
+    fn report(&self, metrics: Metrics, quiet: bool) -> Result<(), RunError> {
+        if self.json {
+            eprintln!("{}", metrics.to_json());
+        } else if !quiet {
+            eprintln!("{}", metrics.summary(self.checksum_order));
+        }
+        // The run itself succeeded, failing to record it isn't an error.
+        if let Some(path) = &self.stats_file {
+            if let Err(e) = stats::append(path, &metrics) {
+                eprintln!(
+                    "warning: failed to append statistics to \"{}\": {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Implementation for the patch subcommand, the summary is printed by [`Args::run`]. Files are
+/// written with [`apply_transaction`].
+pub fn patch(args: &PatchArgs) -> Result<Metrics, RunError> {
+    apply_transaction(args, |_| ())
+}
+
+// Read and parse the patch from the patch argument, or the argument itself for `--patch-inline`.
+fn parse_patch_arg(args: &PatchArgs) -> Result<Patch, RunError> {
+    if args.options.patch_inline {
+        return parse_inline_patch(&args.options.patch.to_string_lossy());
+    }
+    let raw_patch = fs::read(&args.options.patch).map_err(|e| {
+        RunError::Io(
+            format!(
+                "Failed to read patch file \"{}\"",
+                args.options.patch.display()
+            ),
+            e,
+        )
+    })?;
+    Ok(Patch::parse(&raw_patch)?)
+}
+
+// Format of the patch argument if it's an IPS, EBP, BPS, PPF, APS, NINJA or VCDIFF file, going by
+// its extension.
+fn foreign_patch_format(args: &PatchArgs) -> Option<PatchFormat> {
+    if args.options.patch_inline {
+        return None;
+    }
+    let ext = args.options.patch.extension()?.to_str()?;
+    PatchFormat::ALL
+        .iter()
+        .copied()
+        .filter(|format| *format != PatchFormat::Ups)
+        .find(|format| {
+            format
+                .extensions()
+                .iter()
+                .any(|format_ext| ext.eq_ignore_ascii_case(format_ext))
+        })
+}
+
+// Parse an IPS, EBP, BPS, PPF, APS, NINJA or VCDIFF patch argument as the equivalent UPS patch
+// for `input`. Only PPF patches with undo data can be reverted.
+fn parse_foreign_patch_arg(
+    args: &PatchArgs,
+    format: PatchFormat,
+    input: &[u8],
+) -> Result<Patch, RunError> {
+    if args.direction == PatchDirection::Revert && format != PatchFormat::Ppf {
+        return Err(RunError::Usage(format!(
+            "{} patches can't be reverted, keep a copy of the original file instead",
+            format.name()
+        )));
+    }
+    let raw_patch = fs::read(&args.options.patch).map_err(|e| {
+        RunError::Io(
+            format!(
+                "Failed to read patch file \"{}\"",
+                args.options.patch.display()
+            ),
+            e,
+        )
+    })?;
+    match format {
+        PatchFormat::Ups => Ok(Patch::parse(&raw_patch)?),
+        PatchFormat::Ips => Ok(IpsPatch::parse(&raw_patch)?.to_ups(input)),
+        PatchFormat::Ebp => Ok(EbpPatch::parse(&raw_patch)?.to_ups(input)),
+        PatchFormat::Bps => Ok(BpsPatch::parse(&raw_patch)?.to_ups(input)?),
+        PatchFormat::Aps => Ok(ApsPatch::parse(&raw_patch)?.to_ups(input)?),
+        PatchFormat::Ninja => Ok(NinjaPatch::parse(&raw_patch)?.to_ups(input)?),
+        PatchFormat::Ppf => {
+            let patch = PpfPatch::parse(&raw_patch)?;
+            match args.direction {
+                PatchDirection::Apply => Ok(patch.to_ups(input)?),
+                PatchDirection::Revert => {
+                    let src = patch.revert(input).ok_or_else(|| {
+                        RunError::Usage(
+                            "this PPF patch has no undo data, so it can't be reverted, keep a \
+                             copy of the original file instead"
+                                .into(),
+                        )
+                    })??;
+                    Ok(Patch::diff(&src, input))
+                }
+            }
+        }
+        #[cfg(feature = "vcdiff")]
+        PatchFormat::Vcdiff => Ok(VcdiffPatch::parse(&raw_patch)?.to_ups(input)?),
+        #[cfg(not(feature = "vcdiff"))]
+        PatchFormat::Vcdiff => Err(RunError::Usage(
+            "this upstool was built without VCDIFF support, rebuild it with `--features vcdiff`"
+                .into(),
+        )),
+    }
+}
+
+fn direction_verb(direction: PatchDirection) -> &'static str {
+    match direction {
+        PatchDirection::Apply => "Applied",
+        PatchDirection::Revert => "Reverted",
+    }
+}
+
+fn output_name(output: &Option<PathBuf>) -> String {
+    match output {
+        Some(p) => p.display().to_string(),
+        None => "<stdout>".into(),
+    }
+}
+
+// Read back `path` to check it was written correctly.
+fn verify_output(path: &Path, expected: Checksum) -> Result<(), RunError> {
+    let actual = File::open(path)
+        .and_then(Checksum::from_reader)
+        .map_err(|e| {
+            RunError::Io(
+                format!("Failed to read back output file \"{}\"", path.display()),
+                e,
+            )
+        })?;
+    if actual != expected {
+        return Err(RunError::VerifyFailed {
+            path: path.to_path_buf(),
+            expected,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+// Explicit output path, the input for `--in-place` or one named from `--output-template` and
+// `--output-dir`. `None` means stdout.
+fn output_path(args: &PatchArgs) -> Result<Option<PathBuf>, RunError> {
+    if args.options.in_place {
+        return match file_path(&args.options.input) {
+            Some(input) => Ok(Some(input.to_path_buf())),
+            None => Err(RunError::Usage("--in-place requires an input file".into())),
+        };
+    }
+    if args.options.output.is_some() {
+        return Ok(file_path(&args.options.output).map(Path::to_path_buf));
+    }
+    if args.options.output_template.is_none()
+        && args.options.output_dir.is_none()
+        && args.options.upload.is_none()
+    {
+        return Ok(None);
+    }
+    let input = file_path(&args.options.input).ok_or_else(|| {
+        RunError::Usage("--output-template, --output-dir and --upload require an input file".into())
+    })?;
+    let namer = args.options.output_template.clone().unwrap_or_default();
+    let name = namer
+        .name_for_rom(input, patch_name(args), &read_rom_header(input)?)
+        .map_err(|e| RunError::Usage(e.to_string()))?;
+    Ok(Some(match &args.options.output_dir {
+        Some(dir) => dir.join(name.file_name().unwrap_or_default()),
+        None => name,
+    }))
+}
+
+// Start of `input` to detect its console when naming outputs.
+fn read_rom_header(input: &Path) -> Result<Vec<u8>, RunError> {
+    let mut header = Vec::new();
+    File::open(input)
+        .and_then(|f| {
+            f.take(naming::ROM_HEADER_LEN as u64)
+                .read_to_end(&mut header)
+        })
+        .map_err(|e| {
+            RunError::Io(
+                format!("Failed to read input file \"{}\"", input.display()),
+                e,
+            )
+        })?;
+    Ok(header)
+}
+
+// Patch path for messages and output names, "inline" for `--patch-inline`.
+fn patch_name(args: &PatchArgs) -> &Path {
+    if args.options.patch_inline {
+        Path::new("inline")
+    } else {
+        &args.options.patch
+    }
+}
+
+// Parse a patch from hex or base64 text. UPS patches start with "UPS1", which is "VVBT" in base64
+// and "55505331" in hex, so the encoding is never ambiguous.
+fn parse_inline_patch(text: &str) -> Result<Patch, RunError> {
+    let is_hex = text
+        .chars()
+        .all(|c| c.is_ascii_hexdigit() || c.is_ascii_whitespace());
+    Ok(if is_hex {
+        Patch::parse_hex(text)?
+    } else {
+        Patch::parse_base64(text)?
+    })
+}
+
+// Path to a file, `None` for "-" which means stdin or stdout.
+fn file_path(path: &Option<PathBuf>) -> Option<&Path> {
+    path.as_deref().filter(|p| !is_stdio(p))
+}
+
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+// Read `path`, or stdin for "-". `name` describes the file in errors.
+fn read_file(path: &Path, name: &str) -> Result<Vec<u8>, RunError> {
+    let mut data = Vec::new();
+    let (filename, result) = if is_stdio(path) {
+        ("<stdin>".to_string(), io::stdin().read_to_end(&mut data))
+    } else {
+        (
+            format!("\"{}\"", path.display()),
+            File::open(path).and_then(|mut f| f.read_to_end(&mut data)),
+        )
+    };
+    result.map_err(|e| RunError::Io(format!("Failed to read {} file {}", name, filename), e))?;
+    Ok(data)
+}
+
+// Refuse to write `output` if it's the same file as `input`, following symlinks. `hint` is appended
+// to the error message.
+fn check_clobber(output: &Path, input: &Path, name: &str, hint: &str) -> Result<(), RunError> {
+    let same_file = match (fs::canonicalize(output), fs::canonicalize(input)) {
+        (Ok(output), Ok(input)) => output == input,
+        // The output doesn't exist yet
+        _ => false,
+    };
+    if same_file {
+        return Err(RunError::Usage(format!(
+            "Refusing to overwrite the {} file \"{}\" with the output{}",
+            name,
+            input.display(),
+            hint,
+        )));
+    }
+    Ok(())
+}
+
+// Ask to confirm `question` on a terminal. Confirms without asking with `--yes` or when stdin isn't
+// a terminal, so scripts keep working.
+fn confirm(question: &str, yes: bool) -> Result<bool, RunError> {
+    if yes || !io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|e| RunError::Io("Failed to read answer".into(), e))?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn confirm_overwrite(path: &Path, yes: bool) -> Result<(), RunError> {
+    if path.exists() && !confirm(&format!("Overwrite \"{}\"?", path.display()), yes)? {
+        return Err(RunError::Cancelled);
+    }
+    Ok(())
+}
+
+// Refuse to dump binary data on a terminal.
+fn check_stdout(force: bool) -> Result<(), RunError> {
+    if !force && io::stdout().is_terminal() {
+        return Err(RunError::Usage(
+            "Refusing to write binary data to a terminal, give an output file, redirect stdout or \
+             use --force-stdout"
+                .into(),
+        ));
+    }
+    Ok(())
+}
+
+fn read_input(path: &Option<PathBuf>) -> Result<Vec<u8>, RunError> {
+    read_file(path.as_deref().unwrap_or_else(|| Path::new("-")), "input")
+}
+
+/// Implementation for the generate subcommand.
+pub fn generate(args: &GenerateArgs) -> Result<Metrics, RunError> {
+    let start = Instant::now();
+    let dest = if args.latest {
+        let latest = latest_dest(args)?;
+        eprintln!("Using latest destination \"{}\"", latest.display());
+        latest
+    } else {
+        args.dest.clone()
+    };
+    if file_path(&args.patch).is_none() {
+        check_stdout(args.force_stdout)?;
+    }
+    if is_stdio(&args.source) && is_stdio(&dest) {
+        return Err(RunError::Usage(
+            "Only one of source and destination can be read from stdin".into(),
+        ));
+    }
+    if args.record_generator && file_path(&args.patch).is_none() {
+        return Err(RunError::Usage(
+            "--record-generator needs a patch file to write the sidecar next to".into(),
+        ));
+    }
+    let bps = file_path(&args.patch)
+        .and_then(Path::extension)
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("bps"));
+    if bps && (args.window_size.is_some() || args.report) {
+        return Err(RunError::Usage(
+            "--window-size and --report only apply to UPS patches".into(),
+        ));
+    }
+    if let Some(p) = file_path(&args.patch) {
+        check_clobber(p, &args.source, "source", "")?;
+        check_clobber(p, &dest, "destination", "")?;
+        confirm_overwrite(p, args.yes)?;
+    }
+    if let Some(window_size) = args.window_size {
+        let input_size = generate_windowed(args, &dest, window_size)?;
+        write_generator_sidecar(args)?;
+        return Ok(Metrics {
+            command: "generate",
+            direction: None,
+            patch: args.patch.clone().unwrap_or_else(|| "-".into()),
+            patches: 1,
+            blocks: None,
+            bytes_changed: None,
+            input_size,
+            output_size: None,
+            output_crc32: None,
+            output: file_path(&args.patch).map(Path::to_path_buf),
+            duration: start.elapsed(),
+        });
+    }
+
+    let src = read_file(&args.source, "source")?;
+    let dst = read_file(&dest, "destination")?;
+    if bps {
+        let patch = BpsPatch::diff(&src, &dst);
+        let serialized = patch.serialize();
+        write_output(&args.patch, &serialized)?;
+        write_generator_sidecar(args)?;
+        return Ok(Metrics {
+            command: "generate",
+            direction: None,
+            patch: args.patch.clone().unwrap_or_else(|| "-".into()),
+            patches: 1,
+            blocks: Some(patch.actions.len()),
+            bytes_changed: None,
+            input_size: (src.len() + dst.len()) as u64,
+            output_size: Some(serialized.len()),
+            output_crc32: Some(Checksum::from_bytes(&serialized)),
+            output: file_path(&args.patch).map(Path::to_path_buf),
+            duration: start.elapsed(),
+        });
+    }
+    let patch = Patch::diff(&src, &dst);
+    if args.report {
+        eprintln!("{}", diff::DiffReport::new(&src, &dst, &patch));
+    } else if let Some(r) = diff::find_realignment(&src, &dst) {
+        eprintln!(
+            "warning: destination data at offset {} is shifted by {:+} bytes from the source, \
+             UPS can't encode moved data so the patch stores the shifted region in full",
+            r.dst_offset,
+            r.shift(),
+        );
+        eprintln!(
+            "note: name the patch file *.bps to generate a BPS patch, or pass --report to \
+             estimate how much smaller it would be"
+        );
+    }
+    if patch.is_noop() {
+        eprintln!("warning: patch makes no changes, the source and destination are identical");
+    }
+    let serialized = patch.serialize();
+    write_output(&args.patch, &serialized)?;
+    write_generator_sidecar(args)?;
+    Ok(Metrics {
+        command: "generate",
+        direction: None,
+        patch: args.patch.clone().unwrap_or_else(|| "-".into()),
+        patches: 1,
+        blocks: Some(patch.blocks().len()),
+        bytes_changed: Some(
+            patch
+                .block_offsets()
+                .changed_ranges()
+                .map(|r| r.len())
+                .sum(),
+        ),
+        input_size: (src.len() + dst.len()) as u64,
+        output_size: Some(serialized.len()),
+        output_crc32: Some(Checksum::from_bytes(&serialized)),
+        output: file_path(&args.patch).map(Path::to_path_buf),
+        duration: start.elapsed(),
+    })
+}
+
+// Latest file in the destination directory for `--latest`, with the source extension. The source
+// and the patch with its sidecar are skipped, they may be in the same directory.
+fn latest_dest(args: &GenerateArgs) -> Result<PathBuf, RunError> {
+    let ext = args.source.extension();
+    let mut exclude = vec![args.source.clone()];
+    if let Some(patch) = file_path(&args.patch) {
+        exclude.push(patch.to_path_buf());
+        exclude.push(sidecar::path_for(patch));
+    }
+    let exclude: Vec<_> = exclude.iter().map(PathBuf::as_path).collect();
+    let latest = select::latest_file(&args.dest, ext, &exclude).map_err(|e| {
         RunError::Io(
-            format!("Failed to read patch file \"{}\"", args.patch.display()),
+            format!(
+                "Failed to read destination directory \"{}\"",
+                args.dest.display()
+            ),
             e,
         )
     })?;
-    let patch = Patch::parse(&raw_patch)?;
+    latest.ok_or_else(|| {
+        let kind = match ext {
+            Some(ext) => format!(".{} files", ext.to_string_lossy()),
+            None => "files".into(),
+        };
+        RunError::Usage(format!(
+            "No {} in destination directory \"{}\"",
+            kind,
+            args.dest.display()
+        ))
+    })
+}
+
+fn write_generator_sidecar(args: &GenerateArgs) -> Result<(), RunError> {
+    let path = match file_path(&args.patch) {
+        Some(p) if args.record_generator => p,
+        _ => return Ok(()),
+    };
+    sidecar::update(path, |s| s.generator = Some(sidecar::Generator::current()))
+        .map_err(|e| sidecar_error(path, e))?;
+    Ok(())
+}
 
-    let mut input_data = Vec::new();
-    let (input_filename, input_stream_res) = match &args.input {
+fn sidecar_error(patch: &Path, e: io::Error) -> RunError {
+    RunError::Io(
+        format!(
+            "Failed to access sidecar file \"{}\"",
+            sidecar::path_for(patch).display()
+        ),
+        e,
+    )
+}
+
+// Files are read in windows, but stdin is buffered in memory since its size must be known upfront.
+// Returns the total size of both files.
+fn generate_windowed(
+    args: &GenerateArgs,
+    dest: &Path,
+    window_size: usize,
+) -> Result<u64, RunError> {
+    let open = |path: &Path, name: &str| -> Result<(u64, Box<dyn Read>), RunError> {
+        if is_stdio(path) {
+            let data = read_file(path, name)?;
+            return Ok((data.len() as u64, Box::new(io::Cursor::new(data))));
+        }
+        File::open(path)
+            .and_then(|f| Ok((f.metadata()?.len(), f)))
+            .map(|(len, f)| {
+                let reader: Box<dyn Read> = if args.prefetch {
+                    Box::new(prefetch::PrefetchReader::new(f))
+                } else {
+                    Box::new(BufReader::new(f))
+                };
+                (len, reader)
+            })
+            .map_err(|e| {
+                RunError::Io(
+                    format!("Failed to read {} file \"{}\"", name, path.display()),
+                    e,
+                )
+            })
+    };
+    let (src_size, src) = open(&args.source, "source")?;
+    let (dst_size, dst) = open(dest, "destination")?;
+
+    let (output_filename, output): (_, Box<dyn Write>) = match file_path(&args.patch) {
         Some(p) => (
             format!("\"{}\"", p.display()),
-            File::open(p).and_then(|mut f| f.read_to_end(&mut input_data)),
-        ),
-        None => (
-            "<stdin>".to_string(),
-            io::stdin().read_to_end(&mut input_data),
+            Box::new(File::create(p).map_err(|e| {
+                RunError::Io(
+                    format!("Failed to write to output file \"{}\"", p.display()),
+                    e,
+                )
+            })?),
         ),
+        None => ("<stdout>".to_string(), Box::new(io::stdout())),
+    };
+    let mut output = BufWriter::new(output);
+    diff::diff_to_writer(src, src_size, dst, dst_size, window_size, &mut output)
+        .and_then(|_| output.flush())
+        .map_err(|e| RunError::Io(format!("Failed to generate patch {}", output_filename), e))?;
+    Ok(src_size + dst_size)
+}
+
+/// Implementation for the dedupe subcommand.
+///
+/// Byte-identical patches are found by comparing file contents, equivalent ones by comparing their
+/// [normalized](Patch::normalize) forms, which ignores differences in block encoding.
+pub fn dedupe(args: &DedupeArgs) -> Result<(), RunError> {
+    let read_err = |e| {
+        RunError::Io(
+            format!("Failed to read directory \"{}\"", args.dir.display()),
+            e,
+        )
     };
-    input_stream_res
-        .map_err(|e| RunError::Io(format!("Failed to read input file {}", input_filename), e))?;
+    let paths = select::select_files(&args.dir, &args.pattern).map_err(read_err)?;
+
+    if args.hardlink || args.delete {
+        let action = if args.delete { "Delete" } else { "Hard link" };
+        let question = format!(
+            "{} duplicate patches in \"{}\"?",
+            action,
+            args.dir.display()
+        );
+        if !confirm(&question, args.yes)? {
+            return Err(RunError::Cancelled);
+        }
+    }
 
-    let output_data = patch.patch(args.direction, &input_data)?;
-    write_output(&args.output, &output_data)
+    // Originals keyed by contents and by normalized patch.
+    let mut by_contents: HashMap<Vec<u8>, PathBuf> = HashMap::new();
+    let mut by_normalized: HashMap<Patch, PathBuf> = HashMap::new();
+    for path in paths {
+        let raw = fs::read(&path).map_err(|e| {
+            RunError::Io(
+                format!("Failed to read patch file \"{}\"", path.display()),
+                e,
+            )
+        })?;
+
+        if let Some(original) = by_contents.get(&raw) {
+            println!(
+                "identical: {} (same as {})",
+                path.display(),
+                original.display()
+            );
+            if args.hardlink {
+                let link_err =
+                    |e| RunError::Io(format!("Failed to hard link \"{}\"", path.display()), e);
+                fs::remove_file(&path).map_err(link_err)?;
+                fs::hard_link(original, &path).map_err(link_err)?;
+            } else if args.delete {
+                remove_duplicate(&path)?;
+            }
+            continue;
+        }
+
+        let normalized = Patch::parse(&raw).ok().map(|p| p.normalize());
+        by_contents.insert(raw, path.clone());
+        if let Some(normalized) = normalized {
+            match by_normalized.get(&normalized) {
+                Some(original) => {
+                    println!(
+                        "equivalent: {} (same changes as {})",
+                        path.display(),
+                        original.display()
+                    );
+                    if args.delete {
+                        remove_duplicate(&path)?;
+                    }
+                }
+                None => {
+                    by_normalized.insert(normalized, path);
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
-/// Implementation for the generate subcommand.
-pub fn generate(args: &GenerateArgs) -> Result<(), RunError> {
-    let src = fs::read(&args.source).map_err(|e| {
+fn remove_duplicate(path: &Path) -> Result<(), RunError> {
+    fs::remove_file(path)
+        .map_err(|e| RunError::Io(format!("Failed to delete \"{}\"", path.display()), e))
+}
+
+/// Implementation for the doctor subcommand, prints a ranked [`ups::doctor::Diagnosis`].
+pub fn doctor(args: &DoctorArgs) -> Result<(), RunError> {
+    let raw_patch = read_file(&args.patch, "patch")?;
+    let rom = read_file(&args.rom, "ROM")?;
+    println!("{}", ups::doctor::diagnose(&raw_patch, &rom));
+    Ok(())
+}
+
+/// Implementation for the explain subcommand.
+pub fn explain(args: &ExplainArgs) -> Result<(), RunError> {
+    match &args.code {
+        Some(code) => {
+            let explanation = explain::find(code).ok_or_else(|| {
+                RunError::Usage(format!(
+                    "Unknown error code \"{}\", run `upstool explain` to list them",
+                    code
+                ))
+            })?;
+            println!("{}", explanation);
+        }
+        None => {
+            for explanation in explain::EXPLANATIONS {
+                println!("{}  {}", explanation.code, explanation.summary);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Implementation for the store subcommand.
+pub fn store(args: &StoreArgs) -> Result<(), RunError> {
+    let store_err = |store: &Path, e| {
+        RunError::Io(
+            format!("Failed to access patch store \"{}\"", store.display()),
+            e,
+        )
+    };
+    match args {
+        StoreArgs::Add(args) => {
+            let patch_store = PatchStore::new(&args.store);
+            for path in &args.patches {
+                let patch = Patch::parse(&read_file(path, "patch")?)?;
+                let stored = patch_store
+                    .add(&patch)
+                    .map_err(|e| store_err(&args.store, e))?;
+                println!("{:016x}  {}", stored.fingerprint, path.display());
+            }
+        }
+        StoreArgs::Get(args) => {
+            let rom = read_file(&args.rom, "ROM")?;
+            let found = PatchStore::new(&args.store)
+                .find_for_source(Checksum::from_bytes(&rom))
+                .map_err(|e| store_err(&args.store, e))?;
+            for stored in found {
+                println!("{:016x}  {}", stored.fingerprint, stored.path.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Implementation for the map subcommand.
+#[cfg(feature = "map")]
+pub fn map(args: &MapArgs) -> Result<(), RunError> {
+    let patch = Patch::parse(&read_file(&args.patch, "patch")?)?;
+    let diff_map = match args.kib_per_pixel {
+        Some(0) => return Err(RunError::Usage("--kib-per-pixel must be positive".into())),
+        Some(kib) => {
+            let bucket_size = kib
+                .checked_mul(1024)
+                .ok_or_else(|| RunError::Usage("--kib-per-pixel is too large".into()))?;
+            map::DiffMap::new(&patch, bucket_size).map_err(|e| RunError::Usage(e.to_string()))?
+        }
+        None => map::DiffMap::with_max_width(&patch, map::DEFAULT_MAX_WIDTH),
+    };
+    if args.height == 0 {
+        return Err(RunError::Usage("--height must be positive".into()));
+    }
+    fs::write(&args.output, diff_map.to_png(args.height)).map_err(|e| {
+        RunError::Io(
+            format!("Failed to write map file \"{}\"", args.output.display()),
+            e,
+        )
+    })?;
+    println!(
+        "Wrote {}: {}x{}, {} per pixel",
+        args.output.display(),
+        diff_map.width(),
+        args.height,
+        ByteSize(diff_map.bucket_size),
+    );
+    Ok(())
+}
+
+/// Implementation for the route subcommand.
+pub fn route(args: &RouteArgs, checksum_order: ChecksumOrder) -> Result<(), RunError> {
+    let index = load_index(&args.patches)?;
+    let from = args.from.checksum(checksum_order);
+    let to = args.to.checksum(checksum_order);
+    let steps = index.route(from, to).ok_or(RunError::NoRoute {
+        from,
+        to,
+        patches: index.entries().len(),
+        order: checksum_order,
+    })?;
+    if steps.is_empty() {
+        println!("The files are the same, no patches needed");
+    }
+    for (i, entry) in steps.iter().enumerate() {
+        println!(
+            "{}. {} ({} -> {})",
+            i + 1,
+            entry.path.display(),
+            entry.src_checksum.display(checksum_order),
+            entry.dst_checksum.display(checksum_order),
+        );
+    }
+    Ok(())
+}
+
+/// Implementation for the whatis subcommand.
+pub fn whatis(args: &WhatisArgs, checksum_order: ChecksumOrder) -> Result<(), RunError> {
+    let index = load_index(&args.index)?;
+    let file = read_file(&args.file, "input")?;
+    let checksum = Checksum::from_bytes(&file);
+    // Checksums can collide, the size must match too.
+    let producers: Vec<_> = index
+        .find_for_source(checksum)
+        .into_iter()
+        .filter(|c| c.kind == MatchKind::AlreadyPatched && c.entry.dst_size == file.len())
+        .collect();
+    if producers.is_empty() {
+        println!(
+            "No indexed patch produces this file ({}, CRC32 {})",
+            ByteSize(file.len()),
+            checksum.display(checksum_order),
+        );
+        return Ok(());
+    }
+    println!(
+        "Produced by {} of {} indexed patches ({}, CRC32 {}):",
+        producers.len(),
+        index.entries().len(),
+        ByteSize(file.len()),
+        checksum.display(checksum_order),
+    );
+    for candidate in producers {
+        println!(
+            "  {} applied to a {} base, CRC32 {}",
+            candidate.entry.path.display(),
+            ByteSize(candidate.entry.src_size),
+            candidate.entry.src_checksum.display(checksum_order),
+        );
+    }
+    Ok(())
+}
+
+/// Implementation for the split subcommand.
+///
+/// Besides the patches, writes a manifest next to the first one (e.g.
+/// `hack-split.ups.manifest.json`) listing them in the order they apply.
+pub fn split(args: &SplitArgs, checksum_order: ChecksumOrder) -> Result<(), RunError> {
+    let patch = Patch::parse(&read_file(&args.patch, "patch")?)?;
+    let pieces = patch
+        .split(args.max_size)
+        .map_err(|e| RunError::Usage(format!("Invalid --max-size: {}", e)))?;
+
+    let first = match &args.output {
+        Some(output) => output.clone(),
+        None => {
+            let mut name = args.patch.file_stem().unwrap_or_default().to_os_string();
+            name.push("-split.ups");
+            args.patch.with_file_name(name)
+        }
+    };
+    let paths: Vec<_> = (0..=pieces.len())
+        .map(|i| {
+            let mut path = first.clone().into_os_string();
+            if i > 0 {
+                path.push(i.to_string());
+            }
+            PathBuf::from(path)
+        })
+        .collect();
+    let mut manifest_path = first.clone().into_os_string();
+    manifest_path.push(".manifest.json");
+    let manifest_path = PathBuf::from(manifest_path);
+    // `patch --auto` would apply a leftover patch after the new ones.
+    let (paths, next) = paths.split_at(pieces.len());
+    if next[0].exists() {
+        return Err(RunError::Usage(format!(
+            "\"{}\" would be applied after the split patches with --auto, remove it first",
+            next[0].display(),
+        )));
+    }
+    for path in paths.iter().chain(std::iter::once(&manifest_path)) {
+        check_clobber(path, &args.patch, "patch", "")?;
+        confirm_overwrite(path, args.yes)?;
+    }
+
+    let mut entries = Vec::with_capacity(pieces.len());
+    for (piece, path) in pieces.iter().zip(paths) {
+        let raw = piece.serialize();
+        write_output(&Some(path.clone()), &raw)?;
+        entries.push(serde_json::json!({
+            "path": path.file_name().map(|n| n.to_string_lossy()),
+            "size": raw.len(),
+            "src_size": piece.src_size,
+            "src_crc32": SerializeChecksumHex(piece.src_checksum),
+            "dst_size": piece.dst_size,
+            "dst_crc32": SerializeChecksumHex(piece.dst_checksum),
+        }));
+    }
+    let manifest = serde_json::json!({
+        "source": { "size": patch.src_size, "crc32": SerializeChecksumHex(patch.src_checksum) },
+        "destination": { "size": patch.dst_size, "crc32": SerializeChecksumHex(patch.dst_checksum) },
+        "patches": entries,
+    });
+    let mut raw_manifest = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| RunError::Io("Failed to serialize manifest".into(), e.into()))?;
+    raw_manifest.push(b'\n');
+    write_output(&Some(manifest_path.clone()), &raw_manifest)?;
+
+    println!(
+        "Split into {} patches of at most {}:",
+        pieces.len(),
+        ByteSize(args.max_size)
+    );
+    for (i, (piece, path)) in pieces.iter().zip(paths).enumerate() {
+        println!(
+            "{}. {} ({} -> {})",
+            i + 1,
+            path.display(),
+            piece.src_checksum.display(checksum_order),
+            piece.dst_checksum.display(checksum_order),
+        );
+    }
+    println!("Wrote manifest {}", manifest_path.display());
+    println!(
+        "Apply them in order with: upstool patch \"{}\" ROM --auto",
+        first.display()
+    );
+    Ok(())
+}
+
+// Index the patches in a directory, or read an index serialized as JSON.
+fn load_index(path: &Path) -> Result<PatchIndex, RunError> {
+    let err = |e| {
         RunError::Io(
-            format!("Failed to read source file \"{}\"", args.source.display()),
+            format!("Failed to read patch index \"{}\"", path.display()),
             e,
         )
+    };
+    if path.is_dir() {
+        return PatchIndex::from_dir(path).map_err(err);
+    }
+    let raw = fs::read(path).map_err(err)?;
+    serde_json::from_slice(&raw).map_err(|e| err(io::Error::new(io::ErrorKind::InvalidData, e)))
+}
+
+/// Implementation for the bench subcommand, the report is printed by [`Args::run`].
+pub fn bench(args: &BenchArgs) -> Result<bench::BenchReport, RunError> {
+    if args.iterations == 0 {
+        return Err(RunError::Usage("--iterations must be positive".into()));
+    }
+    if cfg!(debug_assertions) {
+        eprintln!("warning: this is a debug build, timings aren't representative");
+    }
+    let report = match (&args.source, &args.dest) {
+        (Some(source), Some(dest)) => bench::run(
+            &read_file(source, "source")?,
+            &read_file(dest, "destination")?,
+            args.iterations,
+            false,
+        ),
+        _ => {
+            let (src, dst) = bench::synthetic(args.size.unwrap_or(bench::DEFAULT_SIZE));
+            bench::run(&src, &dst, args.iterations, true)
+        }
+    };
+    Ok(report)
+}
+
+/// Implementation for the corpus subcommand.
+pub fn corpus(args: &CorpusArgs) -> Result<(), RunError> {
+    match args {
+        CorpusArgs::Add(args) => {
+            let case = corpus::CorpusCase::new(
+                &args.name,
+                &args.description,
+                &read_file(&args.patch, "patch")?,
+                args.direction,
+                &read_file(&args.input, "input")?,
+            );
+            let errors = match case.expected_error.as_slice() {
+                [] => "no errors".to_string(),
+                errors => errors.join(", "),
+            };
+            corpus::add(&args.corpus, case).map_err(|e| {
+                RunError::Io(
+                    format!("Failed to update corpus \"{}\"", args.corpus.display()),
+                    e,
+                )
+            })?;
+            println!("Added {}: {}", args.name, errors);
+        }
+    }
+    Ok(())
+}
+
+/// Implementation for the meta subcommand.
+pub fn meta(args: &MetaArgs) -> Result<(), RunError> {
+    match args {
+        MetaArgs::Set(args) => {
+            // Don't leave sidecars for mistyped patch names around.
+            fs::metadata(&args.patch).map_err(|e| {
+                RunError::Io(
+                    format!("Failed to read patch file \"{}\"", args.patch.display()),
+                    e,
+                )
+            })?;
+            // Empty values remove the field.
+            let set = |field: &mut Option<String>, value: &Option<String>| {
+                if let Some(v) = value {
+                    *field = Some(v.clone()).filter(|v| !v.is_empty());
+                }
+            };
+            sidecar::update(&args.patch, |s| {
+                set(&mut s.title, &args.title);
+                set(&mut s.author, &args.author);
+                set(&mut s.version, &args.version);
+            })
+            .map_err(|e| sidecar_error(&args.patch, e))?;
+        }
+        MetaArgs::Get(args) => {
+            let sidecar = sidecar::read(&args.patch)
+                .map_err(|e| sidecar_error(&args.patch, e))?
+                .unwrap_or_default();
+            let generator = sidecar
+                .generator
+                .as_ref()
+                .map(|g| format!("{} {}", g.name, g.version));
+            let value = match args.field.as_deref() {
+                None => {
+                    print_sidecar(&sidecar);
+                    return Ok(());
+                }
+                Some("title") => sidecar.title,
+                Some("author") => sidecar.author,
+                Some("version") => sidecar.version,
+                Some(_) => generator,
+            };
+            println!("{}", value.unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+// Print sidecar fields aligned with `info` output, skipping unset ones.
+fn print_sidecar(sidecar: &sidecar::Sidecar) {
+    let generator = sidecar
+        .generator
+        .as_ref()
+        .map(|g| format!("{} {}", g.name, g.version));
+    let fields = [
+        ("Title", &sidecar.title),
+        ("Author", &sidecar.author),
+        ("Version", &sidecar.version),
+        ("Generator", &generator),
+    ];
+    for (name, value) in fields {
+        if let Some(value) = value {
+            println!("{:<12} {}", format!("{}:", name), value);
+        }
+    }
+}
+
+/// Implementation for the info subcommand.
+pub fn info(args: &InfoArgs, checksum_order: ChecksumOrder) -> Result<(), RunError> {
+    let raw_patch = read_file(&args.patch, "patch")?;
+    match detect_format(&raw_patch) {
+        Some(PatchFormat::Ninja) => {
+            ninja_info(&NinjaPatch::parse(&raw_patch)?);
+            return Ok(());
+        }
+        Some(PatchFormat::Ips | PatchFormat::Ebp) => {
+            ebp_info(&EbpPatch::parse(&raw_patch)?);
+            return Ok(());
+        }
+        _ => {}
+    }
+    let (patch, warnings) = Patch::parse_with_warnings(&raw_patch)?;
+    let requirements = patch.requirements();
+    let changed: usize = patch
+        .block_offsets()
+        .changed_ranges()
+        .map(|r| r.len())
+        .sum();
+    if !is_stdio(&args.patch) {
+        let sidecar = sidecar::read(&args.patch).map_err(|e| sidecar_error(&args.patch, e))?;
+        print_sidecar(&sidecar.unwrap_or_default());
+    }
+    for (name, requirement) in [
+        ("Source:", requirements.src),
+        ("Destination:", requirements.dst),
+    ] {
+        println!(
+            "{:<12} {} ROM, CRC32 {}",
+            name,
+            ByteSize(requirement.size),
+            requirement.crc32.display(checksum_order),
+        );
+    }
+    println!(
+        "Blocks:      {}, {} changed",
+        patch.blocks().len(),
+        ByteSize(changed)
+    );
+    if patch.is_noop() {
+        println!("Patch makes no changes");
+    } else if let Some(appended) = patch.appended_data() {
+        println!(
+            "Extends ROM from {} to {}, {} appended",
+            ByteSize(patch.src_size),
+            ByteSize(patch.dst_size),
+            ByteSize(appended.len()),
+        );
+    } else if patch.dst_size < patch.src_size {
+        println!(
+            "Shrinks ROM from {} to {}",
+            ByteSize(patch.src_size),
+            ByteSize(patch.dst_size),
+        );
+    }
+    if args.deep {
+        print_compressibility(&patch, raw_patch.len());
+    }
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(())
+}
+
+// Compressed size of a patch, to decide whether to ship it as a zstd-compressed .upz file.
+fn print_compressibility(patch: &Patch, patch_size: usize) {
+    let compressibility = patch.compressibility();
+    let approx = if compressibility.measured { "" } else { "~" };
+    println!(
+        "Compression: XOR data {} -> {}{} ({:.0}%, {})",
+        ByteSize(compressibility.xor_bytes),
+        approx,
+        ByteSize(compressibility.compressed_bytes),
+        compressibility.ratio() * 100.0,
+        if compressibility.measured {
+            "zstd"
+        } else {
+            "estimate"
+        },
+    );
+    // Offsets, sizes and checksums don't compress, only the XOR data shrinks.
+    let compressed_size = patch_size - compressibility.saved_bytes();
+    if compressed_size * 4 < patch_size * 3 {
+        println!(
+            "Shipping the patch as .upz (zstd-compressed UPS) would save {}{}",
+            approx,
+            ByteSize(patch_size - compressed_size)
+        );
+    } else {
+        println!("Patch compresses poorly, ship it as plain UPS");
+    }
+}
+
+// Info for EBP patches, which hold their metadata instead of a sidecar file. Also works for IPS
+// patches, which are EBP patches without metadata.
+fn ebp_info(patch: &EbpPatch) {
+    let metadata = patch.metadata.clone().unwrap_or_default();
+    let fields = [
+        ("Title", &metadata.title),
+        ("Author", &metadata.author),
+        ("Patcher", &metadata.patcher),
+    ];
+    for (name, value) in fields.iter().copied().chain(
+        metadata
+            .other
+            .iter()
+            .map(|(name, value)| (name.as_str(), value)),
+    ) {
+        if !value.is_empty() {
+            println!("{:<12} {}", format!("{}:", name), value);
+        }
+    }
+    let written: usize = patch.ips.records.iter().map(|r| r.len()).sum();
+    println!(
+        "Records:     {}, {} written",
+        patch.ips.records.len(),
+        ByteSize(written)
+    );
+    if !metadata.description.is_empty() {
+        println!("\n{}", metadata.description);
+    }
+}
+
+// Info for NINJA patches, which hold their metadata instead of a sidecar file.
+fn ninja_info(patch: &NinjaPatch) {
+    let metadata = &patch.metadata;
+    let fields = [
+        ("Title", &metadata.title),
+        ("Author", &metadata.author),
+        ("Version", &metadata.version),
+        ("Genre", &metadata.genre),
+        ("Language", &metadata.language),
+        ("Date", &metadata.date),
+        ("Website", &metadata.website),
+        ("File", &patch.file_name),
+    ];
+    for (name, value) in fields {
+        if !value.is_empty() {
+            println!("{:<12} {}", format!("{}:", name), value);
+        }
+    }
+    for (name, size, md5) in [
+        ("Source:", patch.src_size, patch.src_md5),
+        ("Destination:", patch.dst_size, patch.dst_md5),
+    ] {
+        let md5: String = md5.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("{:<12} {} ROM, MD5 {}", name, ByteSize(size), md5);
+    }
+    println!("Records:     {}", patch.records.len());
+    if !metadata.info.is_empty() {
+        println!("\n{}", metadata.info);
+    }
+}
+
+/// Implementation for the fix subcommand.
+pub fn fix(args: &FixArgs, checksum_order: ChecksumOrder) -> Result<(), RunError> {
+    let (patch, warnings) = Patch::parse_with_warnings(&read_file(&args.patch, "patch")?)?;
+    for warning in &warnings {
+        println!("{}", warning);
+    }
+    let mut fixed = if args.normalize {
+        patch.normalize()
+    } else {
+        patch.repair_terminators()
+    };
+    if args.base.is_some() || args.target.is_some() {
+        let base = args
+            .base
+            .as_ref()
+            .map(|p| read_file(p, "base"))
+            .transpose()?;
+        let target = args
+            .target
+            .as_ref()
+            .map(|p| read_file(p, "target"))
+            .transpose()?;
+        fixed.recompute_metadata(base.as_deref(), target.as_deref());
+        for (side, before, after) in [
+            ("Source", patch.src_checksum, fixed.src_checksum),
+            ("Destination", patch.dst_checksum, fixed.dst_checksum),
+        ] {
+            if before != after {
+                println!(
+                    "{} checksum: {} -> {}",
+                    side,
+                    before.display(checksum_order),
+                    after.display(checksum_order)
+                );
+            }
+        }
+    }
+    if fixed == patch {
+        println!("Nothing to fix");
+        return Ok(());
+    }
+
+    let output = match &args.output {
+        Some(output) => output.clone(),
+        None if is_stdio(&args.patch) => {
+            return Err(RunError::Usage(
+                "--output is required when reading the patch from stdin".into(),
+            ))
+        }
+        None => {
+            let mut name = args.patch.file_stem().unwrap_or_default().to_os_string();
+            name.push("-fixed.ups");
+            args.patch.with_file_name(name)
+        }
+    };
+    check_clobber(&output, &args.patch, "patch", "")?;
+    confirm_overwrite(&output, args.yes)?;
+    write_output(&Some(output.clone()), &fixed.serialize())?;
+    println!(
+        "Wrote {}: {} blocks, {} before",
+        output.display(),
+        fixed.blocks().len(),
+        patch.blocks().len(),
+    );
+    Ok(())
+}
+
+/// Implementation for the scrub subcommand.
+pub fn scrub(args: &ScrubArgs) -> Result<(), RunError> {
+    let raw_patch = read_file(&args.patch, "patch")?;
+    let scrubbed = ups::scrub::scrub(&raw_patch)?;
+    let output = Some(args.output.clone()).filter(|p| !is_stdio(p));
+    if let Some(path) = &output {
+        check_clobber(path, &args.patch, "patch", "")?;
+        confirm_overwrite(path, args.yes)?;
+    }
+    write_output(&output, &scrubbed)?;
+    if let Some(path) = &output {
+        println!(
+            "Wrote {}: block data replaced, sizes, offsets and checksums kept",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Implementation for the convert subcommand.
+pub fn convert(args: &ConvertArgs) -> Result<(), RunError> {
+    let target = args
+        .output
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| {
+            PatchFormat::ALL.iter().copied().find(|format| {
+                format
+                    .extensions()
+                    .iter()
+                    .any(|format_ext| ext.eq_ignore_ascii_case(format_ext))
+            })
+        })
+        .ok_or_else(|| {
+            RunError::Usage(format!(
+                "Can't tell the format to convert to from \"{}\", use a `.ups` or `.bps` \
+                 extension",
+                args.output.display()
+            ))
+        })?;
+    let patch = parse_any(&read_file(&args.patch, "patch")?).map_err(|e| match e {
+        AnyParseError::UnknownFormat => RunError::Usage(format!(
+            "\"{}\" isn't a patch in a format upstool knows",
+            args.patch.display()
+        )),
+        e => e.into(),
+    })?;
+    let src = args
+        .source
+        .as_ref()
+        .map(|path| read_file(path, "source"))
+        .transpose()?;
+    let mut converter = Converter::new().allow_lossy(args.allow_lossy);
+    if let Some(src) = &src {
+        converter = converter.source(src);
+    }
+    let converted = converter.convert(&patch, target)?;
+    check_clobber(&args.output, &args.patch, "patch", "")?;
+    confirm_overwrite(&args.output, args.yes)?;
+    write_output(&Some(args.output.clone()), &converted)?;
+    println!(
+        "Converted {} patch to {}: {}",
+        patch.format().name(),
+        target.name(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// Implementation for the inspect subcommand.
+pub fn inspect(args: &InspectArgs) -> Result<(), RunError> {
+    let patch = Patch::parse(&read_file(&args.patch, "patch")?)?;
+    let block = patch.blocks().get(args.block).ok_or_else(|| {
+        RunError::Usage(format!(
+            "Block {} doesn't exist, the patch has {} blocks",
+            args.block,
+            patch.blocks().len(),
+        ))
     })?;
-    let dst = fs::read(&args.dest).map_err(|e| {
+    let start = patch.block_offsets().start(args.block).unwrap_or_default();
+    let data = block.xor_data();
+    println!("Block {} of {}", args.block, patch.blocks().len());
+    if args.block == 0 {
+        println!("Relative offset: {}", block.offset());
+    } else {
+        println!(
+            "Relative offset: {} from the end of block {}",
+            block.offset(),
+            args.block - 1,
+        );
+    }
+    println!("Absolute offset: {:#010x} ({})", start, start);
+    let terminated = data.last() == Some(&0);
+    println!(
+        "Length:          {} bytes{}",
+        data.len(),
+        if terminated {
+            ", including the 0 terminator"
+        } else {
+            ", without a terminator"
+        },
+    );
+    // The terminator of a block at the end of the files is usually past it.
+    let changed = data.len() - usize::from(terminated);
+    let output_size = std::cmp::max(patch.src_size, patch.dst_size);
+    if start.saturating_add(changed) > output_size {
+        println!(
+            "note: the block goes past the end of both files ({}), the bytes past it are ignored",
+            ByteSize(output_size),
+        );
+    }
+    println!("XOR bytes:");
+    print!("{}", hexdump(start, data));
+
+    if let Some(base) = &args.base {
+        let base = read_file(base, "base")?;
+        // Bytes past the end of the base count as zeroes.
+        let before: Vec<u8> = (start..start.saturating_add(data.len()))
+            .map(|i| base.get(i).copied().unwrap_or(0))
+            .collect();
+        let after: Vec<u8> = before.iter().zip(data).map(|(b, x)| b ^ x).collect();
+        println!("Before:");
+        print!("{}", hexdump(start, &before));
+        println!("After:");
+        print!("{}", hexdump(start, &after));
+    }
+    Ok(())
+}
+
+// Lines of 16 bytes in hex prefixed with their absolute position, up to `HEXDUMP_LINES` lines.
+fn hexdump(start: usize, data: &[u8]) -> String {
+    const HEXDUMP_LINES: usize = 16;
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).take(HEXDUMP_LINES).enumerate() {
+        let bytes: Vec<_> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        out.push_str(&format!(
+            "  {:08x}  {}\n",
+            start.saturating_add(16 * i),
+            bytes.join(" ")
+        ));
+    }
+    let shown = 16 * HEXDUMP_LINES;
+    if data.len() > shown {
+        out.push_str(&format!("  ... {} more bytes\n", data.len() - shown));
+    }
+    out
+}
+
+/// Implementation for the edit subcommand.
+///
+/// Edits from `--set` are applied after the ones from `--edits-file`, so they win where they
+/// overlap.
+pub fn edit(args: &EditArgs) -> Result<(), RunError> {
+    for output in [&args.output, &args.patch] {
+        check_clobber(output, &args.base, "base", "")?;
+        confirm_overwrite(output, args.yes)?;
+    }
+    let base = fs::read(&args.base).map_err(|e| {
         RunError::Io(
-            format!(
-                "Failed to read destination file \"{}\"",
-                args.dest.display()
-            ),
+            format!("Failed to read base file \"{}\"", args.base.display()),
             e,
         )
     })?;
-    let patch = Patch::diff(&src, &dst);
-    write_output(&args.patch, &patch.serialize())
+    let mut edits = match &args.edits_file {
+        Some(path) => {
+            let contents = fs::read_to_string(path).map_err(|e| {
+                RunError::Io(
+                    format!("Failed to read edits file \"{}\"", path.display()),
+                    e,
+                )
+            })?;
+            edit::parse_edits(&contents).map_err(|e| {
+                RunError::Usage(format!("Invalid edits file \"{}\": {}", path.display(), e))
+            })?
+        }
+        None => Vec::new(),
+    };
+    edits.extend(args.edits.iter().cloned());
+    if edits.is_empty() {
+        return Err(RunError::Usage(
+            "No edits given, use --set or --edits-file".into(),
+        ));
+    }
+
+    let mut builder = PatchBuilder::new(&base);
+    for e in &edits {
+        builder
+            .set(e.offset, &e.bytes)
+            .map_err(|e| RunError::Usage(format!("Invalid edit: {}", e)))?;
+    }
+    let patch = builder.build();
+    write_output(&Some(args.patch.clone()), &patch.serialize())?;
+    write_output(&Some(args.output.clone()), builder.output())
 }
 
+// Write `data` to `path`, or stdout if it's missing or "-". The file is written in place, unlike
+// patch outputs going through `apply_transaction`.
 fn write_output(path: &Option<PathBuf>, data: &[u8]) -> Result<(), RunError> {
-    let (output_filename, output_stream_res) = match path {
+    let (output_filename, output_stream_res) = match file_path(path) {
         Some(p) => (format!("\"{}\"", p.display()), fs::write(p, data)),
         None => ("<stdout>".to_string(), io::stdout().write_all(data)),
     };
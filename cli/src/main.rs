@@ -1,14 +1,13 @@
+#![forbid(unsafe_code)]
+
 use std::process::exit;
 
 use ups_cli::Args;
 
 fn main() {
     let args = Args::from_args();
-    match args.run() {
-        Ok(_) => (),
-        Err(e) => {
-            eprintln!("{}", e);
-            exit(1);
-        }
+    if let Err(e) = args.run() {
+        args.print_error(&e);
+        exit(1);
     }
 }
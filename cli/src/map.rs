@@ -0,0 +1,249 @@
+//! Thumbnails of the changes made by a patch ("diff maps"), for hack release pages and review
+//! tools.
+//!
+//! A [`DiffMap`] splits the patched file in buckets of a fixed size and counts the bytes changed
+//! in each one. [`DiffMap::to_png`] renders it as a strip with one column of pixels per bucket,
+//! dark for unchanged buckets and from yellow to red for buckets with a few to all bytes changed.
+use ups::{Checksum, Patch};
+
+/// Widest map picked by [`DiffMap::with_max_width`], in pixels.
+pub const DEFAULT_MAX_WIDTH: usize = 1024;
+
+/// Widest map [`DiffMap::new`] accepts, in pixels.
+pub const MAX_WIDTH: usize = 1 << 16;
+
+const UNCHANGED: [u8; 3] = [0x2b, 0x2b, 0x2b];
+const FEW_CHANGED: [u8; 3] = [0xf5, 0xd0, 0x42];
+const ALL_CHANGED: [u8; 3] = [0xe0, 0x28, 0x1e];
+
+/// Error from [`DiffMap::new`]: the map would be `width` pixels wide, over [`MAX_WIDTH`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error(
+    "the map would be {} pixels wide, the limit is {} pixels, use larger buckets",
+    .width,
+    MAX_WIDTH
+)]
+pub struct MapTooWide {
+    pub width: usize,
+}
+
+/// Bytes changed in each bucket of the patched file, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffMap {
+    /// Bytes per bucket.
+    pub bucket_size: usize,
+    /// Size of the patched file.
+    pub size: usize,
+    /// Bytes changed in each bucket, in order.
+    pub changed: Vec<usize>,
+}
+
+impl DiffMap {
+    /// Map the changes `patch` makes to its destination file in buckets of `bucket_size` bytes,
+    /// failing if there would be more than [`MAX_WIDTH`] buckets.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bucket_size` is 0.
+    pub fn new(patch: &Patch, bucket_size: usize) -> Result<Self, MapTooWide> {
+        assert!(bucket_size > 0, "bucket size must be positive");
+        let width = bucket_count(patch.dst_size, bucket_size);
+        if width > MAX_WIDTH {
+            return Err(MapTooWide { width });
+        }
+        Ok(DiffMap::with_buckets(patch, bucket_size, width))
+    }
+
+    // `new` for `width` buckets, already checked.
+    fn with_buckets(patch: &Patch, bucket_size: usize, width: usize) -> Self {
+        let size = patch.dst_size;
+        let mut changed = vec![0; width];
+        for range in patch.block_offsets().changed_ranges() {
+            let mut start = range.start;
+            let end = range.end.min(size);
+            while start < end {
+                let bucket = start / bucket_size;
+                let bucket_end = end.min((bucket * bucket_size).saturating_add(bucket_size));
+                changed[bucket] += bucket_end - start;
+                start = bucket_end;
+            }
+        }
+        DiffMap {
+            bucket_size,
+            size,
+            changed,
+        }
+    }
+
+    /// Map with the smallest power of two KiB per bucket fitting in `max_width` pixels, up to
+    /// [`MAX_WIDTH`].
+    pub fn with_max_width(patch: &Patch, max_width: usize) -> Self {
+        let max_width = max_width.clamp(1, MAX_WIDTH);
+        let mut bucket_size: usize = 1024;
+        // The largest bucket size fits any file in 2 pixels.
+        while bucket_count(patch.dst_size, bucket_size) > max_width {
+            match bucket_size.checked_mul(2) {
+                Some(doubled) => bucket_size = doubled,
+                None => break,
+            }
+        }
+        let width = bucket_count(patch.dst_size, bucket_size);
+        DiffMap::with_buckets(patch, bucket_size, width)
+    }
+
+    /// Width of the map in pixels, one per bucket and at least one.
+    pub fn width(&self) -> usize {
+        self.changed.len().max(1)
+    }
+
+    /// Render the map as an RGB PNG image `height` pixels tall.
+    pub fn to_png(&self, height: u32) -> Vec<u8> {
+        let mut row = Vec::with_capacity(1 + 3 * self.width());
+        // Filter type "None".
+        row.push(0);
+        for i in 0..self.width() {
+            row.extend_from_slice(&self.color(i));
+        }
+        let pixels = row.repeat(height as usize);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width() as u32).to_be_bytes());
+        header.extend_from_slice(&height.to_be_bytes());
+        // 8 bits per channel, RGB, default compression, filtering and no interlacing.
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(&mut png, b"IHDR", &header);
+        write_chunk(&mut png, b"IDAT", &zlib_stored(&pixels));
+        write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    // Color of bucket `i`.
+    fn color(&self, i: usize) -> [u8; 3] {
+        let changed = self.changed.get(i).copied().unwrap_or_default();
+        if changed == 0 {
+            return UNCHANGED;
+        }
+        let len = self.bucket_size.min(self.size - i * self.bucket_size);
+        let t = changed as f64 / len as f64;
+        let mut color = [0; 3];
+        for (c, (few, all)) in color.iter_mut().zip(FEW_CHANGED.iter().zip(&ALL_CHANGED)) {
+            *c = (*few as f64 + (*all as f64 - *few as f64) * t).round() as u8;
+        }
+        color
+    }
+}
+
+fn bucket_count(size: usize, bucket_size: usize) -> usize {
+    size.div_ceil(bucket_size)
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = Checksum::from_bytes(&png[start..]);
+    png.extend_from_slice(&crc.0.to_be_bytes());
+}
+
+// zlib stream with uncompressed deflate blocks. Maps are small and compress well anyway, but this
+// avoids pulling in a compression library.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xffff;
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        out.push(last as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff_map() {
+        let src = vec![0; 10 * 1024];
+        let mut dst = src.clone();
+        dst[100] = 1;
+        dst[3 * 1024..5 * 1024].fill(1);
+        dst.extend_from_slice(&[1; 512]);
+        let patch = Patch::diff(&src, &dst);
+
+        let map = DiffMap::new(&patch, 1024).unwrap();
+        assert_eq!(map.changed, vec![1, 0, 0, 1024, 1024, 0, 0, 0, 0, 0, 512]);
+        assert_eq!(map.color(0), [0xf5, 0xd0, 0x42]);
+        assert_eq!(map.color(1), UNCHANGED);
+        assert_eq!(map.color(3), ALL_CHANGED);
+        // The last bucket is only partially used.
+        assert_eq!(map.color(10), ALL_CHANGED);
+
+        let map = DiffMap::with_max_width(&patch, 4);
+        assert_eq!(map.bucket_size, 4096);
+        assert_eq!(map.changed, vec![1025, 1024, 512]);
+    }
+
+    #[test]
+    fn test_diff_map_huge_file() {
+        // Sizes come from the patch header, the file itself isn't needed.
+        let mut patch = Patch::diff(b"a", b"b");
+        patch.dst_size = usize::MAX;
+        assert_eq!(
+            DiffMap::new(&patch, 1024),
+            Err(MapTooWide {
+                width: usize::MAX / 1024 + 1
+            })
+        );
+        let map = DiffMap::new(&patch, usize::MAX).unwrap();
+        assert_eq!(map.changed, vec![1]);
+
+        let map = DiffMap::with_max_width(&patch, 1);
+        assert_eq!(map.changed, vec![1, 0]);
+        let map = DiffMap::with_max_width(&patch, usize::MAX);
+        assert_eq!(map.width(), MAX_WIDTH);
+    }
+
+    #[test]
+    fn test_to_png() {
+        let patch = Patch::diff(&[0; 2048], &[1; 2048]);
+        let png = DiffMap::new(&patch, 1024).unwrap().to_png(3);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 3]);
+        // IDAT holds 3 rows of a filter byte and 2 RGB pixels, in a single stored block.
+        let idat = &png[33..];
+        assert_eq!(&idat[4..8], b"IDAT");
+        assert_eq!(&idat[8..15], &[0x78, 0x01, 1, 21, 0, !21, 0xff]);
+        assert_eq!(&idat[15..22], &[0, 0xe0, 0x28, 0x1e, 0xe0, 0x28, 0x1e]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+    }
+
+    #[test]
+    fn test_zlib_stored() {
+        assert_eq!(
+            zlib_stored(b""),
+            vec![0x78, 0x01, 1, 0, 0, 0xff, 0xff, 0, 0, 0, 1]
+        );
+        // Adler-32 of "Wikipedia" is 0x11E60398.
+        assert_eq!(&zlib_stored(b"Wikipedia")[16..], &[0x11, 0xe6, 0x03, 0x98]);
+        let long = zlib_stored(&[0; 0x10000]);
+        assert_eq!(&long[2..7], &[0, 0xff, 0xff, 0, 0]);
+        assert_eq!(&long[0x10006..0x1000b], &[1, 1, 0, 0xfe, 0xff]);
+    }
+}
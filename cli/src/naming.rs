@@ -0,0 +1,385 @@
+//! Output file naming from templates.
+//!
+//! Templates are strings with `{variable}` placeholders, use `{{` and `}}` for literal braces.
+//! Available variables:
+//!
+//! - `{stem}`: input file name without extension.
+//! - `{ext}`: input file extension. A `.` right before an empty `{ext}` is dropped. With
+//!   [`name_for_rom`](OutputNamer::name_for_rom), the usual extension for the console detected
+//!   from the ROM header replaces extensions like `.bin`.
+//! - `{patchname}`: patch file name without extension.
+//!
+//! Expanded names must be plain file names: names with path separators, or which are `.` or
+//! `..`, are rejected so a template or file name can't write outside the output directory.
+use std::fmt::{self, Display, Formatter};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use ups::transform::{N64ByteOrder, N64Format};
+
+/// Default template for patched files, e.g. `game (hack).gba`.
+pub const DEFAULT_TEMPLATE: &str = "{stem} ({patchname}).{ext}";
+
+/// Computes output file names from a template, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputNamer {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Variable(Variable),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variable {
+    Stem,
+    Ext,
+    PatchName,
+}
+
+/// Invalid [`OutputNamer`] template.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    #[error("unknown template variable \"{{{}}}\"", .0)]
+    UnknownVariable(String),
+    #[error("unclosed \"{{\" in template")]
+    Unclosed,
+    #[error("unmatched \"}}\" in template, use \"}}}}\" for a literal brace")]
+    Unmatched,
+    #[error("output name \"{}\" isn't a plain file name", .0)]
+    InvalidName(String),
+}
+
+impl OutputNamer {
+    /// Parse a template, see the [module docs](self) for the syntax.
+    pub fn new(template: &str) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(TemplateError::Unclosed),
+                        }
+                    }
+                    let var = match name.as_str() {
+                        "stem" => Variable::Stem,
+                        "ext" => Variable::Ext,
+                        "patchname" => Variable::PatchName,
+                        _ => return Err(TemplateError::UnknownVariable(name)),
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Variable(var));
+                }
+                '}' => return Err(TemplateError::Unmatched),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(OutputNamer { parts })
+    }
+
+    /// Output file name for patching `input` with `patch`, in the same directory as `input`.
+    pub fn name(&self, input: &Path, patch: &Path) -> Result<PathBuf, TemplateError> {
+        let ext = input.extension().unwrap_or_default().to_string_lossy();
+        self.render(input, patch, &ext)
+    }
+
+    /// Same as [`name`](OutputNamer::name), but `{ext}` is inferred from the first
+    /// [`ROM_HEADER_LEN`] bytes of the ROM in `rom` unless the input already has one of the usual
+    /// extensions for its console, see [`rom_extensions`].
+    pub fn name_for_rom(
+        &self,
+        input: &Path,
+        patch: &Path,
+        rom: &[u8],
+    ) -> Result<PathBuf, TemplateError> {
+        let ext = input.extension().unwrap_or_default().to_string_lossy();
+        match rom_extensions(rom) {
+            Some(exts) if !exts.iter().any(|e| ext.eq_ignore_ascii_case(e)) => {
+                self.render(input, patch, exts[0])
+            }
+            _ => self.render(input, patch, &ext),
+        }
+    }
+
+    fn render(&self, input: &Path, patch: &Path, ext: &str) -> Result<PathBuf, TemplateError> {
+        let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+        let patch_name = patch.file_stem().unwrap_or_default().to_string_lossy();
+
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => name.push_str(s),
+                Part::Variable(Variable::Stem) => name.push_str(&stem),
+                Part::Variable(Variable::PatchName) => name.push_str(&patch_name),
+                Part::Variable(Variable::Ext) if ext.is_empty() => {
+                    if name.ends_with('.') {
+                        name.pop();
+                    }
+                }
+                Part::Variable(Variable::Ext) => name.push_str(ext),
+            }
+        }
+        let plain = !name.contains(&['/', '\\'][..])
+            && matches!(
+                Path::new(&name).components().collect::<Vec<_>>()[..],
+                [Component::Normal(_)]
+            );
+        if !plain {
+            return Err(TemplateError::InvalidName(name));
+        }
+        Ok(input.with_file_name(name))
+    }
+
+    /// Same as [`name`](OutputNamer::name), but in `dir` instead of next to `input`.
+    pub fn name_in(
+        &self,
+        dir: &Path,
+        input: &Path,
+        patch: &Path,
+    ) -> Result<PathBuf, TemplateError> {
+        let name = self.name(input, patch)?;
+        Ok(dir.join(name.file_name().unwrap_or_default()))
+    }
+}
+
+/// Bytes from the start of a ROM needed to detect its console, enough for SNES HiROM headers
+/// after a copier header.
+pub const ROM_HEADER_LEN: usize = 0x10200;
+
+/// Usual file extensions for the console of a ROM detected from its header, the preferred one
+/// first. `None` if the console isn't recognized.
+///
+/// Detects Game Boy Advance, Game Boy (Color), NES (iNES), SNES and N64 ROMs.
+pub fn rom_extensions(rom: &[u8]) -> Option<&'static [&'static str]> {
+    // Start of the Nintendo logo, checked by the boot ROM of both Game Boys.
+    const GBA_LOGO: [u8; 4] = [0x24, 0xff, 0xae, 0x51];
+    const GB_LOGO: [u8; 4] = [0xce, 0xed, 0x66, 0x66];
+
+    if rom.get(0x04..0x08) == Some(&GBA_LOGO) && rom.get(0xb2) == Some(&0x96) {
+        return Some(&["gba", "agb"]);
+    }
+    if rom.get(0x104..0x108) == Some(&GB_LOGO) {
+        // CGB flag, set for games with Game Boy Color support.
+        return Some(match rom.get(0x143) {
+            Some(0x80) | Some(0xc0) => &["gbc", "cgb", "gb"],
+            _ => &["gb", "gbc", "dmg"],
+        });
+    }
+    if rom.starts_with(b"NES\x1a") {
+        return Some(&["nes"]);
+    }
+    if let Some(format) = N64ByteOrder::detect(rom) {
+        return Some(match format {
+            N64Format::Z64 => &["z64", "n64", "v64"],
+            N64Format::V64 => &["v64", "z64", "n64"],
+            N64Format::N64 => &["n64", "z64", "v64"],
+        });
+    }
+    if is_snes(rom) {
+        return Some(&["sfc", "smc", "swc", "fig"]);
+    }
+    None
+}
+
+// SNES internal headers are at the end of the first LoROM or HiROM bank, possibly after a 512-byte
+// copier header. Their checksum and its complement add up to 0xffff.
+fn is_snes(rom: &[u8]) -> bool {
+    let copier_header = if rom.len() % 1024 == 512 { 512 } else { 0 };
+    [0x7fc0, 0xffc0].iter().any(|bank_header| {
+        let start = copier_header + bank_header;
+        let header = match rom.get(start..start + 0x20) {
+            Some(h) => h,
+            None => return false,
+        };
+        let map_mode = header[0x15];
+        let complement = u16::from_le_bytes([header[0x1c], header[0x1d]]);
+        let checksum = u16::from_le_bytes([header[0x1e], header[0x1f]]);
+        map_mode & 0xe0 == 0x20 && complement ^ checksum == 0xffff
+    })
+}
+
+impl Default for OutputNamer {
+    fn default() -> Self {
+        OutputNamer::new(DEFAULT_TEMPLATE).unwrap()
+    }
+}
+
+impl FromStr for OutputNamer {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OutputNamer::new(s)
+    }
+}
+
+impl Display for OutputNamer {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => write!(f, "{}", s.replace('{', "{{").replace('}', "}}"))?,
+                Part::Variable(Variable::Stem) => f.write_str("{stem}")?,
+                Part::Variable(Variable::Ext) => f.write_str("{ext}")?,
+                Part::Variable(Variable::PatchName) => f.write_str("{patchname}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_template() {
+        let namer = OutputNamer::default();
+        assert_eq!(
+            namer
+                .name(Path::new("roms/game.gba"), Path::new("patches/hack.ups"))
+                .unwrap(),
+            Path::new("roms/game (hack).gba"),
+        );
+        assert_eq!(
+            namer
+                .name(Path::new("game"), Path::new("hack.ups"))
+                .unwrap(),
+            Path::new("game (hack)"),
+        );
+        assert_eq!(
+            namer
+                .name_in(
+                    Path::new("patched"),
+                    Path::new("roms/game.gba"),
+                    Path::new("hack.ups")
+                )
+                .unwrap(),
+            Path::new("patched/game (hack).gba"),
+        );
+    }
+
+    #[test]
+    fn test_name_for_rom() {
+        let namer = OutputNamer::default();
+        let mut gba = vec![0; 0xc0];
+        gba[0x04..0x08].copy_from_slice(&[0x24, 0xff, 0xae, 0x51]);
+        gba[0xb2] = 0x96;
+        assert_eq!(
+            namer
+                .name_for_rom(Path::new("game.bin"), Path::new("hack.ups"), &gba)
+                .unwrap(),
+            Path::new("game (hack).gba"),
+        );
+        assert_eq!(
+            namer
+                .name_for_rom(Path::new("game"), Path::new("hack.ups"), &gba)
+                .unwrap(),
+            Path::new("game (hack).gba"),
+        );
+        // Usual extensions are kept.
+        assert_eq!(
+            namer
+                .name_for_rom(Path::new("game.AGB"), Path::new("hack.ups"), &gba)
+                .unwrap(),
+            Path::new("game (hack).AGB"),
+        );
+        assert_eq!(
+            namer
+                .name_for_rom(Path::new("game.bin"), Path::new("hack.ups"), &[0; 0xc0])
+                .unwrap(),
+            Path::new("game (hack).bin"),
+        );
+    }
+
+    #[test]
+    fn test_rom_extensions() {
+        let mut gb = vec![0; 0x150];
+        gb[0x104..0x108].copy_from_slice(&[0xce, 0xed, 0x66, 0x66]);
+        assert_eq!(rom_extensions(&gb).map(|e| e[0]), Some("gb"));
+        gb[0x143] = 0x80;
+        assert_eq!(rom_extensions(&gb).map(|e| e[0]), Some("gbc"));
+
+        assert_eq!(
+            rom_extensions(b"NES\x1a\x02\x01").map(|e| e[0]),
+            Some("nes")
+        );
+        assert_eq!(
+            rom_extensions(&[0x37, 0x80, 0x40, 0x12]).map(|e| e[0]),
+            Some("v64")
+        );
+
+        let mut snes = vec![0; 0x10000];
+        snes[0xffd5] = 0x21;
+        snes[0xffdc..0xffe0].copy_from_slice(&[0x34, 0x12, 0xcb, 0xed]);
+        assert_eq!(rom_extensions(&snes).map(|e| e[0]), Some("sfc"));
+        let headered: Vec<u8> = vec![0; 512].into_iter().chain(snes).collect();
+        assert_eq!(rom_extensions(&headered).map(|e| e[0]), Some("sfc"));
+
+        assert_eq!(rom_extensions(&[0; 0x10000]), None);
+        assert_eq!(rom_extensions(&[]), None);
+    }
+
+    #[test]
+    fn test_template_syntax() {
+        let namer = OutputNamer::new("{{{patchname}}}_{stem}.patched.{ext}").unwrap();
+        assert_eq!(
+            namer
+                .name(Path::new("game.sfc"), Path::new("hack.ups"))
+                .unwrap(),
+            Path::new("{hack}_game.patched.sfc"),
+        );
+        assert_eq!(namer.to_string(), "{{{patchname}}}_{stem}.patched.{ext}");
+
+        assert_eq!(
+            OutputNamer::new("{name}"),
+            Err(TemplateError::UnknownVariable("name".into())),
+        );
+        assert_eq!(OutputNamer::new("{stem"), Err(TemplateError::Unclosed));
+        assert_eq!(OutputNamer::new("stem}"), Err(TemplateError::Unmatched));
+    }
+
+    #[test]
+    fn test_reject_paths() {
+        let namer = OutputNamer::new("{stem}").unwrap();
+        assert_eq!(
+            namer.name(Path::new("../.."), Path::new("hack.ups")),
+            Err(TemplateError::InvalidName("".into())),
+        );
+        let namer = OutputNamer::new("{patchname}").unwrap();
+        assert_eq!(
+            namer.name(Path::new("game.gba"), Path::new("...ups")),
+            Err(TemplateError::InvalidName("..".into())),
+        );
+        assert_eq!(
+            namer.name(Path::new("game.gba"), Path::new("..\\hack.ups")),
+            Err(TemplateError::InvalidName("..\\hack".into())),
+        );
+        for template in ["../{stem}", "/tmp/{stem}", "out/{stem}", ".."] {
+            let namer = OutputNamer::new(template).unwrap();
+            assert!(matches!(
+                namer.name(Path::new("game.gba"), Path::new("hack.ups")),
+                Err(TemplateError::InvalidName(_))
+            ));
+        }
+    }
+}
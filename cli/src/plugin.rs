@@ -0,0 +1,112 @@
+//! Extra subcommands for binaries embedding upstool.
+//!
+//! Wrappers adding their own subcommands, e.g. for a distribution's ROM database, don't need to
+//! fork the CLI: implement [`Plugin`] and run the arguments with [`Args::run_with`]. Subcommands
+//! upstool doesn't know are collected in [`Command::External`](crate::Command::External) and
+//! passed to the plugin with the same name. Plugins parse them with their own
+//! [`StructOpt`](structopt::StructOpt) types, which can flatten upstool's argument types, and
+//! fail with [`RunError`]s, printed by [`Args::print_error`] like the built-in ones.
+//!
+//! Plugin subcommands aren't listed in `upstool --help`, and global options like `--json` are
+//! only parsed by upstool when they come before the subcommand.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use ups_cli::plugin::Plugin;
+//! use ups_cli::structopt::StructOpt;
+//! use ups_cli::{Args, PatchArgs, RunError};
+//!
+//! /// Apply a patch and register the output in the local ROM database.
+//! #[derive(StructOpt)]
+//! struct InstallArgs {
+//!     #[structopt(flatten)]
+//!     patch: PatchArgs,
+//! }
+//!
+//! struct Install;
+//!
+//! impl Plugin for Install {
+//!     fn name(&self) -> &str {
+//!         "install"
+//!     }
+//!
+//!     fn run(&self, _args: &Args, argv: &[String]) -> Result<(), RunError> {
+//!         let install = InstallArgs::from_iter(argv);
+//!         ups_cli::patch(&install.patch)?;
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let args = Args::from_args();
+//! if let Err(e) = args.run_with(&[&Install]) {
+//!     args.print_error(&e);
+//!     std::process::exit(1);
+//! }
+//! ```
+use crate::{Args, RunError};
+
+/// Subcommand added by a binary embedding upstool, see the [module docs](self).
+pub trait Plugin {
+    /// Name of the subcommand, as typed on the command line.
+    fn name(&self) -> &str;
+
+    /// Run the subcommand. `argv` holds its name and its arguments, ready for
+    /// [`StructOpt::from_iter`](structopt::StructOpt::from_iter), and `args` the global options.
+    fn run(&self, args: &Args, argv: &[String]) -> Result<(), RunError>;
+}
+
+// Run the plugin for the external subcommand `argv`.
+pub(crate) fn dispatch(
+    plugins: &[&dyn Plugin],
+    args: &Args,
+    argv: &[String],
+) -> Result<(), RunError> {
+    let name = argv.first().map_or("", String::as_str);
+    let plugin = plugins.iter().find(|p| p.name() == name).ok_or_else(|| {
+        RunError::Usage(format!(
+            "unknown subcommand `{}`, run `upstool help` for the list of subcommands",
+            name
+        ))
+    })?;
+    plugin.run(args, argv)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use structopt::StructOpt;
+
+    use super::*;
+    use crate::Command;
+
+    struct Echo(RefCell<Vec<String>>);
+
+    impl Plugin for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn run(&self, args: &Args, argv: &[String]) -> Result<(), RunError> {
+            if !args.json {
+                return Err(RunError::Usage("expected --json".into()));
+            }
+            *self.0.borrow_mut() = argv.to_vec();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_run_with() {
+        let echo = Echo(RefCell::new(Vec::new()));
+        let args = Args::from_iter(vec!["upstool", "--json", "echo", "a", "--flag"]);
+        assert!(matches!(&args.command, Command::External(_)));
+        args.run_with(&[&echo]).unwrap();
+        assert_eq!(*echo.0.borrow(), vec!["echo", "a", "--flag"]);
+
+        let args = Args::from_iter(vec!["upstool", "unknown"]);
+        assert!(matches!(args.run_with(&[&echo]), Err(RunError::Usage(_))));
+        assert!(matches!(args.run(), Err(RunError::Usage(_))));
+    }
+}
@@ -0,0 +1,159 @@
+//! Reading ahead on a background thread, for `generate --prefetch`.
+//!
+//! [`PrefetchReader`] fills one buffer on its thread while the other is being consumed, so the
+//! latency of slow storage like spinning disks or network mounts overlaps with diffing instead of
+//! adding up with it. It doesn't help with fast local storage, where reads are already quicker
+//! than the work done on the data.
+use std::io::{self, BufRead, Read};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+/// Size of the buffers [`PrefetchReader::new`] reads ahead into.
+pub const DEFAULT_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Double-buffered reader reading ahead of its consumer on a background thread.
+///
+/// The thread stops at the end of the input, after an error or once the reader is dropped.
+/// Dropping the reader doesn't wait for a read in progress.
+#[derive(Debug)]
+pub struct PrefetchReader {
+    filled: Receiver<io::Result<Vec<u8>>>,
+    // Buffers handed back to the thread to be filled again.
+    recycled: Sender<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl PrefetchReader {
+    /// Read `inner` ahead in buffers of [`DEFAULT_BUFFER_SIZE`].
+    pub fn new<R: Read + Send + 'static>(inner: R) -> Self {
+        Self::with_buffer_size(inner, DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Read `inner` ahead in buffers of `buffer_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buffer_size` is 0.
+    pub fn with_buffer_size<R: Read + Send + 'static>(mut inner: R, buffer_size: usize) -> Self {
+        assert!(buffer_size > 0, "buffer_size must be positive");
+        // One buffer waiting to be consumed while the thread fills the next one.
+        let (filled_tx, filled) = mpsc::sync_channel(1);
+        let (recycled, recycled_rx) = mpsc::channel::<Vec<u8>>();
+        thread::spawn(move || loop {
+            let mut buf = recycled_rx.try_recv().unwrap_or_default();
+            buf.resize(buffer_size, 0);
+            let (len, err) = fill(&mut inner, &mut buf);
+            buf.truncate(len);
+            // An empty buffer or an error ends the input, data read before an error comes first.
+            let last = len == 0 || err.is_some();
+            if len > 0 && filled_tx.send(Ok(buf)).is_err() {
+                return;
+            }
+            if last {
+                let _ = filled_tx.send(err.map_or_else(|| Ok(Vec::new()), Err));
+                return;
+            }
+        });
+        PrefetchReader {
+            filled,
+            recycled,
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+}
+
+// Read until `buf` is full, the input ends or a read fails, returning the number of bytes read
+// and the error.
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> (usize, Option<io::Error>) {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return (len, Some(e)),
+        }
+    }
+    (len, None)
+}
+
+impl BufRead for PrefetchReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() && !self.done {
+            let next = match self.filled.recv() {
+                Ok(next) => next,
+                Err(_) => Err(io::Error::other("prefetch thread stopped unexpectedly")),
+            };
+            match next {
+                Ok(buf) if !buf.is_empty() => {
+                    let old = std::mem::replace(&mut self.buf, buf);
+                    // The thread is gone if this fails, the buffer isn't needed anymore.
+                    let _ = self.recycled.send(old);
+                    self.pos = 0;
+                }
+                Ok(_) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = std::cmp::min(self.pos + amt, self.buf.len());
+    }
+}
+
+impl Read for PrefetchReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = std::cmp::min(available.len(), out.len());
+        out[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prefetch_reader() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let mut reader = PrefetchReader::with_buffer_size(io::Cursor::new(data.clone()), 64);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(reader.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_prefetch_reader_error() {
+        struct Failing(usize);
+        impl Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "disk on fire"));
+                }
+                let len = std::cmp::min(self.0, buf.len());
+                buf[..len].iter_mut().for_each(|b| *b = 1);
+                self.0 -= len;
+                Ok(len)
+            }
+        }
+
+        let mut reader = PrefetchReader::with_buffer_size(Failing(100), 64);
+        let mut out = [0; 64];
+        assert_eq!(reader.read(&mut out).unwrap(), 64);
+        assert_eq!(reader.read(&mut out).unwrap(), 36);
+        let err = reader.read(&mut out).unwrap_err();
+        assert_eq!(err.to_string(), "disk on fire");
+    }
+}
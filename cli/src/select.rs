@@ -0,0 +1,244 @@
+//! Selecting files by name for batch commands, or the latest file in a directory.
+//!
+//! Patterns and names are compared as OS strings, so names which aren't valid UTF-8, like Shift
+//! JIS encoded Japanese names on Unix, can be selected too. Patterns support `*` for any run of
+//! characters and `?` for a single one. Bytes or UTF-16 units which aren't part of a valid
+//! character count as one character each.
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// File name pattern, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamePattern {
+    units: Vec<Unit>,
+}
+
+// A character, or a raw byte or UTF-16 unit from an invalid sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Char(char),
+    Raw(u16),
+}
+
+impl NamePattern {
+    /// Parse a pattern.
+    pub fn new(pattern: &OsStr) -> Self {
+        NamePattern {
+            units: units(pattern),
+        }
+    }
+
+    /// Whether `name` matches the whole pattern.
+    pub fn matches(&self, name: &OsStr) -> bool {
+        let name = units(name);
+        // Classic backtracking to the last `*`, linear in practice.
+        let (mut p, mut n) = (0, 0);
+        let mut backtrack = None;
+        while n < name.len() {
+            match self.units.get(p) {
+                Some(Unit::Char('*')) => {
+                    backtrack = Some((p, n));
+                    p += 1;
+                    continue;
+                }
+                Some(Unit::Char('?')) => {
+                    p += 1;
+                    n += 1;
+                    continue;
+                }
+                Some(u) if *u == name[n] => {
+                    p += 1;
+                    n += 1;
+                    continue;
+                }
+                _ => (),
+            }
+            match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    n = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            }
+        }
+        self.units[p..].iter().all(|u| *u == Unit::Char('*'))
+    }
+}
+
+/// Files in `dir` with names matching `pattern`, non-recursively and sorted by path.
+pub fn select_files(dir: &Path, pattern: &NamePattern) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if pattern.matches(&entry.file_name()) && entry.path().is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Most recently modified regular file in `dir` with extension `ext`, ignoring case, or any
+/// extension for `None`. Hidden files and files in `exclude` are skipped, e.g. the output of the
+/// command itself. Ties go to the last file name so the choice doesn't depend on directory order.
+pub fn latest_file(
+    dir: &Path,
+    ext: Option<&OsStr>,
+    exclude: &[&Path],
+) -> io::Result<Option<PathBuf>> {
+    let exclude: Vec<_> = exclude
+        .iter()
+        .filter_map(|p| fs::canonicalize(p).ok())
+        .collect();
+    let mut latest = None;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if let Some(ext) = ext {
+            let matches = path.extension().is_some_and(|e| {
+                e.to_string_lossy()
+                    .eq_ignore_ascii_case(&ext.to_string_lossy())
+            });
+            if !matches {
+                continue;
+            }
+        }
+        // Follow symlinks, e.g. a `latest.gba` link to the last build.
+        let metadata = fs::metadata(&path)?;
+        if !metadata.is_file() || exclude.contains(&fs::canonicalize(&path)?) {
+            continue;
+        }
+        let key = (metadata.modified()?, path);
+        if latest.as_ref() < Some(&key) {
+            latest = Some(key);
+        }
+    }
+    Ok(latest.map(|(_, path)| path))
+}
+
+#[cfg(unix)]
+fn units(s: &OsStr) -> Vec<Unit> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let bytes = s.as_bytes();
+    let mut units = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        // UTF-8 sequences are at most 4 bytes long.
+        let decoded = (1..=std::cmp::min(4, bytes.len() - i)).find_map(|len| {
+            let c = std::str::from_utf8(&bytes[i..i + len])
+                .ok()?
+                .chars()
+                .next()?;
+            Some((c, len))
+        });
+        match decoded {
+            Some((c, len)) => {
+                units.push(Unit::Char(c));
+                i += len;
+            }
+            None => {
+                units.push(Unit::Raw(u16::from(bytes[i])));
+                i += 1;
+            }
+        }
+    }
+    units
+}
+
+#[cfg(windows)]
+fn units(s: &OsStr) -> Vec<Unit> {
+    use std::os::windows::ffi::OsStrExt;
+
+    std::char::decode_utf16(s.encode_wide())
+        .map(|c| c.map_or_else(|e| Unit::Raw(e.unpaired_surrogate()), Unit::Char))
+        .collect()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn units(s: &OsStr) -> Vec<Unit> {
+    s.to_string_lossy().chars().map(Unit::Char).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        NamePattern::new(OsStr::new(pattern)).matches(OsStr::new(name))
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("*.ups", "hack.ups"));
+        assert!(matches("*.ups", ".ups"));
+        assert!(!matches("*.ups", "hack.ups.json"));
+        assert!(matches("hack-v?.ups", "hack-v2.ups"));
+        assert!(!matches("hack-v?.ups", "hack-v10.ups"));
+        assert!(matches("*-*-*", "a-b-c-d"));
+        assert!(matches("ゲーム?.ups", "ゲーム2.ups"));
+        assert!(matches("*", ""));
+        assert!(!matches("?", ""));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_matches_non_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        // "ゲーム.ups" in Shift JIS.
+        let name = OsStr::from_bytes(b"\x83\x51\x81\x5b\x83\x80.ups");
+        assert!(NamePattern::new(OsStr::new("*.ups")).matches(name));
+        assert!(NamePattern::new(OsStr::from_bytes(b"\x83?\x81*.ups")).matches(name));
+        assert!(!NamePattern::new(OsStr::new("??.ups")).matches(name));
+    }
+
+    #[test]
+    fn test_latest_file() {
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        let touch = |name: &str, age: u64| {
+            let path = dir.path().join(name);
+            let file = fs::File::create(&path).unwrap();
+            file.set_modified(now - Duration::from_secs(age)).unwrap();
+            path
+        };
+        touch("clean.gba", 300);
+        let build = touch("hack-v2.GBA", 200);
+        touch("hack-v1.gba", 250);
+        touch("notes.txt", 100);
+        touch(".hack.gba.swp", 0);
+        let patch = touch("hack.ups", 10);
+        let sidecar = touch("hack.ups.json", 5);
+        fs::create_dir(dir.path().join("newer.gba")).unwrap();
+
+        let gba = Some(OsStr::new("gba"));
+        assert_eq!(
+            latest_file(dir.path(), gba, &[&patch, &sidecar]).unwrap(),
+            Some(build.clone())
+        );
+        // Without an extension filter, the output and its sidecar are still skipped.
+        assert_eq!(
+            latest_file(dir.path(), None, &[&patch, &sidecar]).unwrap(),
+            Some(dir.path().join("notes.txt"))
+        );
+        assert_eq!(latest_file(dir.path(), None, &[]).unwrap(), Some(sidecar));
+        assert_eq!(
+            latest_file(dir.path(), Some(OsStr::new("sfc")), &[]).unwrap(),
+            None
+        );
+        // Excluding a path which doesn't exist is fine.
+        assert_eq!(
+            latest_file(dir.path(), gba, &[&dir.path().join("missing.gba")]).unwrap(),
+            Some(build)
+        );
+    }
+}
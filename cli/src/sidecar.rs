@@ -0,0 +1,120 @@
+//! Sidecar metadata stored next to patch files, e.g. `hack.ups.json` for `hack.ups`.
+//!
+//! UPS has no room for metadata, so information about how a patch was made is kept in a separate
+//! JSON file. Patches are the same with or without a sidecar.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Contents of a sidecar file. Unknown fields are kept as they are when updating it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sidecar {
+    /// Name of the hack or translation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Author of the patch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Release version of the patch, e.g. `1.2`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Tool which generated the patch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generator: Option<Generator>,
+    /// Fields written by other tools.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// Name and version of a patch generator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Generator {
+    pub name: String,
+    pub version: String,
+}
+
+impl Generator {
+    /// This build of upstool.
+    pub fn current() -> Self {
+        Generator {
+            name: "upstool".into(),
+            version: env!("CARGO_PKG_VERSION").into(),
+        }
+    }
+}
+
+/// Path of the sidecar file for `patch`.
+pub fn path_for(patch: &Path) -> PathBuf {
+    let mut path = patch.as_os_str().to_owned();
+    path.push(".json");
+    path.into()
+}
+
+/// Read the sidecar for `patch`, returning `None` if there's none.
+pub fn read(patch: &Path) -> io::Result<Option<Sidecar>> {
+    let raw = match fs::read(path_for(patch)) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_slice(&raw)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Update the sidecar for `patch` with `f`, creating it if there's none, and return the new
+/// contents.
+pub fn update(patch: &Path, f: impl FnOnce(&mut Sidecar)) -> io::Result<Sidecar> {
+    let mut sidecar = read(patch)?.unwrap_or_default();
+    f(&mut sidecar);
+    write(patch, &sidecar)?;
+    Ok(sidecar)
+}
+
+/// Write the sidecar for `patch`, replacing any existing one.
+pub fn write(patch: &Path, sidecar: &Sidecar) -> io::Result<()> {
+    let mut raw = serde_json::to_vec_pretty(sidecar)?;
+    raw.push(b'\n');
+    fs::write(path_for(patch), raw)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_path_for() {
+        assert_eq!(
+            path_for(Path::new("dir/hack.ups")),
+            Path::new("dir/hack.ups.json")
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let sidecar = Sidecar {
+            title: Some("Hack".into()),
+            generator: Some(Generator {
+                name: "upstool".into(),
+                version: "1.2.3".into(),
+            }),
+            ..Sidecar::default()
+        };
+        let raw = serde_json::to_string(&sidecar).unwrap();
+        assert_eq!(
+            raw,
+            r#"{"title":"Hack","generator":{"name":"upstool","version":"1.2.3"}}"#
+        );
+        assert_eq!(serde_json::from_str::<Sidecar>(&raw).unwrap(), sidecar);
+
+        let mut unknown: Sidecar = serde_json::from_str(r#"{"notes":"hi"}"#).unwrap();
+        unknown.author = Some("X".into());
+        assert_eq!(
+            serde_json::to_string(&unknown).unwrap(),
+            r#"{"author":"X","notes":"hi"}"#
+        );
+    }
+}
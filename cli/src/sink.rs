@@ -0,0 +1,517 @@
+//! Destinations for patched outputs, so hosted patching services can stream results straight to
+//! object storage instead of local files.
+//!
+//! An [`OutputSink`] creates outputs by name, and the [`SinkWriter`]s it returns are written to
+//! like files. [`FileSink`] writes to a local directory and is always available. With the `http`
+//! feature [`HttpSink`] uploads outputs with HTTP PUT requests, and with the `s3` feature
+//! [`S3Sink`] uploads them to an S3 bucket. [`open_sink`] picks one from a URL.
+//!
+//! Uploads go through [ureq](https://docs.rs/ureq), with TLS from rustls for `https://` URLs and
+//! S3 endpoints, checked against the Mozilla root certificates. `http://` URLs are sent in plain
+//! text, use them with storage on a trusted network.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::io::Write;
+//! use ups_cli::sink::{open_sink, OutputSink};
+//!
+//! let sink = open_sink("patched")?;
+//! let mut writer = sink.create("game.gba", 5)?;
+//! writer.write_all(b"hello")?;
+//! let location = writer.finish()?;
+//! # Ok::<_, std::io::Error>(())
+//! ```
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "s3")]
+pub use s3::S3Sink;
+
+/// Destination for outputs, see the [module docs](self).
+pub trait OutputSink {
+    /// Start writing the output `name`, which is exactly `size` bytes long.
+    fn create(&self, name: &str, size: u64) -> io::Result<Box<dyn SinkWriter>>;
+}
+
+/// Output being written to an [`OutputSink`].
+pub trait SinkWriter: Write {
+    /// Finish writing, returning where the output was written. Outputs dropped without finishing
+    /// them are discarded where possible.
+    fn finish(self: Box<Self>) -> io::Result<String>;
+}
+
+/// Open the sink for `url`:
+///
+/// - `https://HOST[:PORT][/PREFIX]` or `http://...` uploads to `PREFIX/NAME` with the `http`
+///   feature.
+/// - `s3://BUCKET[/PREFIX]` uploads to `PREFIX/NAME` in the bucket with the `s3` feature, see
+///   [`S3Sink::from_env`].
+/// - `file://DIR` or any other path writes to that directory.
+pub fn open_sink(url: &str) -> io::Result<Box<dyn OutputSink>> {
+    if let Some(dir) = url.strip_prefix("file://") {
+        return Ok(Box::new(FileSink::new(dir)));
+    }
+    if url.starts_with("https://") || url.starts_with("http://") {
+        #[cfg(feature = "http")]
+        return Ok(Box::new(HttpSink::new(url)?));
+        #[cfg(not(feature = "http"))]
+        return Err(unsupported("http(s)://", "http"));
+    }
+    if let Some(_bucket) = url.strip_prefix("s3://") {
+        #[cfg(feature = "s3")]
+        return Ok(Box::new(S3Sink::from_env(_bucket)?));
+        #[cfg(not(feature = "s3"))]
+        return Err(unsupported("s3://", "s3"));
+    }
+    if url.contains("://") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported URL scheme in \"{}\"", url),
+        ));
+    }
+    Ok(Box::new(FileSink::new(url)))
+}
+
+#[cfg(not(feature = "s3"))]
+fn unsupported(scheme: &str, feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{} URLs require upstool to be built with the `{}` feature",
+            scheme, feature
+        ),
+    )
+}
+
+/// Writes outputs to files in a directory, creating it if needed. Outputs are written to a
+/// temporary file first and renamed once finished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSink {
+    dir: PathBuf,
+}
+
+impl FileSink {
+    /// Sink writing to `dir`.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        FileSink { dir: dir.into() }
+    }
+}
+
+impl OutputSink for FileSink {
+    fn create(&self, name: &str, _size: u64) -> io::Result<Box<dyn SinkWriter>> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(name);
+        let tmp = self.dir.join(format!(".{}.upstool-tmp", name));
+        let file = BufWriter::new(File::create(&tmp)?);
+        Ok(Box::new(FileSinkWriter {
+            file: Some(file),
+            tmp,
+            path,
+        }))
+    }
+}
+
+struct FileSinkWriter {
+    file: Option<BufWriter<File>>,
+    tmp: PathBuf,
+    path: PathBuf,
+}
+
+impl Write for FileSinkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().expect("file taken on finish").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().expect("file taken on finish").flush()
+    }
+}
+
+impl SinkWriter for FileSinkWriter {
+    fn finish(mut self: Box<Self>) -> io::Result<String> {
+        let file = self.file.take().expect("file taken on finish");
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        Ok(self.path.display().to_string())
+    }
+}
+
+impl Drop for FileSinkWriter {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// Uploads outputs with HTTP PUT requests to `PREFIX/NAME`, e.g. to a WebDAV server or a storage
+/// service's upload endpoint.
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpSink {
+    url: HttpUrl,
+}
+
+#[cfg(feature = "http")]
+impl HttpSink {
+    /// Sink uploading under `url`, `https://HOST[:PORT][/PREFIX]` or `http://...`.
+    pub fn new(url: &str) -> io::Result<Self> {
+        Ok(HttpSink {
+            url: HttpUrl::parse(url)?,
+        })
+    }
+}
+
+#[cfg(feature = "http")]
+impl OutputSink for HttpSink {
+    fn create(&self, name: &str, size: u64) -> io::Result<Box<dyn SinkWriter>> {
+        let path = format!("{}/{}", self.url.path, percent_encode(name));
+        Ok(Box::new(HttpPut::start(self.url.to_url(&path), size, &[])))
+    }
+}
+
+// Parsed `http(s)://HOST[:PORT][/PATH]` URL, `path` doesn't end with "/".
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HttpUrl {
+    pub(crate) tls: bool,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) path: String,
+}
+
+#[cfg(feature = "http")]
+impl HttpUrl {
+    pub(crate) fn parse(url: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid HTTP URL \"{}\"", url),
+            )
+        };
+        let (tls, rest) = match url.strip_prefix("https://") {
+            Some(rest) => (true, rest),
+            None => (false, url.strip_prefix("http://").ok_or_else(invalid)?),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            }
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(HttpUrl {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    // Value for the `Host` header, without the default port for the scheme.
+    pub(crate) fn host_header(&self) -> String {
+        if self.port == if self.tls { 443 } else { 80 } {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    pub(crate) fn to_url(&self, path: &str) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{}://{}{}", scheme, self.host_header(), path)
+    }
+}
+
+// Streaming PUT request with a known size. The request runs on its own thread, reading the body
+// written to the `HttpPut` from a channel.
+#[cfg(feature = "http")]
+pub(crate) struct HttpPut {
+    body: Option<std::sync::mpsc::SyncSender<Vec<u8>>>,
+    request: Option<std::thread::JoinHandle<io::Result<()>>>,
+    url: String,
+    remaining: u64,
+}
+
+#[cfg(feature = "http")]
+impl HttpPut {
+    // Start the request, `headers` are sent along with `Host` and `Content-Length`.
+    pub(crate) fn start(url: String, size: u64, headers: &[(&str, String)]) -> Self {
+        let mut request = ureq::put(&url).set("Content-Length", &size.to_string());
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        let (sender, receiver) = std::sync::mpsc::sync_channel(4);
+        let body = ChannelReader {
+            receiver,
+            chunk: Vec::new(),
+            pos: 0,
+            remaining: size,
+        };
+        let request_url = url.clone();
+        let request = std::thread::spawn(move || match request.send(body) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, response)) => Err(io::Error::other(format!(
+                "upload to {} failed: {} {}",
+                request_url,
+                code,
+                response.status_text()
+            ))),
+            Err(e) => Err(io::Error::other(format!(
+                "upload to {} failed: {}",
+                request_url, e
+            ))),
+        });
+        HttpPut {
+            body: Some(sender),
+            request: Some(request),
+            url,
+            remaining: size,
+        }
+    }
+
+    // Wait for the response, after the whole body was sent or the request failed.
+    fn response(&mut self) -> io::Result<()> {
+        self.body = None;
+        match self.request.take().map(|request| request.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other(format!("upload to {} panicked", self.url))),
+            None => Err(io::Error::other(format!("upload to {} failed", self.url))),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl Write for HttpPut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "output is larger than its declared size",
+            ));
+        }
+        let sent = match &self.body {
+            Some(body) => body.send(buf.to_vec()).is_ok(),
+            None => false,
+        };
+        if !sent {
+            // The request ended early, e.g. the connection failed.
+            self.response()?;
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("upload to {} ended early", self.url),
+            ));
+        }
+        self.remaining -= buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "http")]
+impl SinkWriter for HttpPut {
+    fn finish(mut self: Box<Self>) -> io::Result<String> {
+        if self.remaining != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "output is smaller than its declared size",
+            ));
+        }
+        self.response()?;
+        Ok(std::mem::take(&mut self.url))
+    }
+}
+
+// Request body received from an `HttpPut`. The body ends with an error if the `HttpPut` is dropped
+// before writing `remaining` bytes, so the request is aborted instead of completed.
+#[cfg(feature = "http")]
+struct ChannelReader {
+    receiver: std::sync::mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    remaining: u64,
+}
+
+#[cfg(feature = "http")]
+impl io::Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "output discarded before it was finished",
+                    ))
+                }
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+// Percent-encode everything but unreserved characters, as S3 expects for object keys.
+#[cfg(feature = "http")]
+pub(crate) fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_file_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = open_sink(&dir.path().join("out").to_string_lossy()).unwrap();
+
+        let mut writer = sink.create("game (hack).gba", 5).unwrap();
+        writer.write_all(b"hello").unwrap();
+        let location = writer.finish().unwrap();
+        let path = dir.path().join("out").join("game (hack).gba");
+        assert_eq!(location, path.display().to_string());
+        assert_eq!(fs::read(&path).unwrap(), b"hello");
+
+        // Unfinished outputs are discarded.
+        let mut writer = sink.create("other.gba", 5).unwrap();
+        writer.write_all(b"hel").unwrap();
+        drop(writer);
+        assert_eq!(fs::read_dir(dir.path().join("out")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_open_sink_unsupported() {
+        let err = open_sink("ftp://example.com/roms").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        #[cfg(not(feature = "http"))]
+        assert!(open_sink("http://example.com").is_err());
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_http_url() {
+        let url = HttpUrl::parse("http://localhost:9000/roms/").unwrap();
+        assert_eq!(
+            url,
+            HttpUrl {
+                tls: false,
+                host: "localhost".into(),
+                port: 9000,
+                path: "/roms".into(),
+            }
+        );
+        assert_eq!(url.host_header(), "localhost:9000");
+        let url = HttpUrl::parse("http://[::1]").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("[::1]", 80));
+        let url = HttpUrl::parse("https://example.com:443/roms").unwrap();
+        assert_eq!((url.tls, url.port), (true, 443));
+        assert_eq!(url.to_url("/roms/a"), "https://example.com/roms/a");
+        assert!(HttpUrl::parse("http://:80/").is_err());
+        assert!(HttpUrl::parse("ftp://example.com").is_err());
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_http_sink() {
+        use std::io::{BufRead, BufReader, Read};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push(line.trim_end().to_string());
+            }
+            let mut body = vec![0; 5];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 201 Created\r\n\r\n")
+                .unwrap();
+            (head, body)
+        });
+
+        let sink = open_sink(&format!("http://127.0.0.1:{}/roms", port)).unwrap();
+        let mut writer = sink.create("game (hack).gba", 5).unwrap();
+        writer.write_all(b"hello").unwrap();
+        assert_eq!(
+            writer.finish().unwrap(),
+            format!("http://127.0.0.1:{}/roms/game%20%28hack%29.gba", port)
+        );
+        let (head, body) = server.join().unwrap();
+        assert_eq!(head[0], "PUT /roms/game%20%28hack%29.gba HTTP/1.1");
+        assert!(head.contains(&"Content-Length: 5".to_string()));
+        assert_eq!(body, b"hello");
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_http_sink_error() {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while line != "\r\n" {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+            }
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let sink = open_sink(&format!("http://127.0.0.1:{}", port)).unwrap();
+        let mut writer = sink.create("game.gba", 5).unwrap();
+        // The body may or may not be sent before the response arrives.
+        let written = writer.write_all(b"hello");
+        let err = written.and_then(|_| writer.finish()).unwrap_err();
+        assert!(err.to_string().contains("403"), "{}", err);
+        server.join().unwrap();
+    }
+}
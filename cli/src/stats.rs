@@ -0,0 +1,112 @@
+//! Local usage statistics for `--stats-file`.
+//!
+//! Each patch, revert or generate run appends a line with a JSON object to the statistics file,
+//! so throughput can be tracked over time by summing or plotting the lines. Records are anonymous:
+//! they hold counts, sizes and durations but no file names or checksums. Nothing is sent anywhere.
+//!
+//! ```text
+//! {"time":1700000000,"version":"0.1.0","command":"patch","direction":"apply","patches":1,...}
+//! ```
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use crate::Metrics;
+
+/// One line of the statistics file.
+#[derive(Debug, Clone)]
+pub struct StatsRecord<'a> {
+    /// Seconds since the Unix epoch when the run finished.
+    pub time: u64,
+    /// Metrics of the run, only the anonymous fields are recorded.
+    pub metrics: &'a Metrics,
+}
+
+impl<'a> StatsRecord<'a> {
+    /// Record for `metrics` of a run finishing now.
+    pub fn new(metrics: &'a Metrics) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        StatsRecord { time, metrics }
+    }
+}
+
+impl Serialize for StatsRecord<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let metrics = self.metrics;
+        let mut s = serializer.serialize_struct("StatsRecord", 10)?;
+        s.serialize_field("time", &self.time)?;
+        s.serialize_field("version", env!("CARGO_PKG_VERSION"))?;
+        s.serialize_field("command", metrics.command)?;
+        s.serialize_field("direction", &metrics.direction)?;
+        s.serialize_field("patches", &metrics.patches)?;
+        s.serialize_field("blocks", &metrics.blocks)?;
+        s.serialize_field("bytes_changed", &metrics.bytes_changed)?;
+        s.serialize_field("input_size", &metrics.input_size)?;
+        s.serialize_field("output_size", &metrics.output_size)?;
+        s.serialize_field("duration_secs", &metrics.duration.as_secs_f64())?;
+        s.end()
+    }
+}
+
+/// Append a record of `metrics` to the statistics file at `path`, creating it if needed.
+///
+/// The line is written at once in append mode, so concurrent runs don't interleave records.
+pub fn append(path: &Path, metrics: &Metrics) -> io::Result<()> {
+    let mut line = serde_json::to_vec(&StatsRecord::new(metrics))?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&line)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use ups::{Checksum, PatchDirection};
+
+    #[test]
+    fn test_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.jsonl");
+        let metrics = Metrics {
+            command: "patch",
+            direction: Some(PatchDirection::Apply),
+            patch: PathBuf::from("secret hack.ups"),
+            patches: 1,
+            blocks: Some(3),
+            bytes_changed: Some(10),
+            input_size: 100,
+            output_size: Some(120),
+            output_crc32: Some(Checksum(0xdead_beef)),
+            output: Some(PathBuf::from("secret rom.gba")),
+            duration: Duration::from_millis(1500),
+        };
+        append(&path, &metrics).unwrap();
+        append(&path, &metrics).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("secret"));
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["command"], "patch");
+        assert_eq!(lines[0]["direction"], "apply");
+        assert_eq!(lines[0]["input_size"], 100);
+        assert_eq!(lines[0]["duration_secs"], 1.5);
+        assert!(lines[0].get("output_crc32").is_none());
+    }
+}
@@ -0,0 +1,1020 @@
+//! Patching files as a transaction: the output file is either fully written and verified, or left
+//! as it was.
+//!
+//! [`apply_transaction`] goes through these states, reporting each one with a
+//! [`TransactionEvent`]:
+//!
+//! 1. **Preflight**: the output location is probed for write access, so read-only or locked
+//!    outputs fail before any expensive work, and the input is checked against the patch before
+//!    writing anything.
+//! 2. **Write**: the output is written to a temporary file next to the output file, or in
+//!    `--tmp-dir`. Long runs of zeroes are skipped with seeks so filesystems supporting sparse
+//!    files don't allocate them, e.g. for ROMs extended with padding.
+//! 3. **Backup**: an existing output file is hard linked (or copied) to a backup file.
+//! 4. **Commit**: the temporary file is renamed over the output file, atomically. It gets the
+//!    permissions of the file it replaces first. When the output is a symlink, the file it points
+//!    to is replaced and the link is kept.
+//! 5. **Verify**: with `--verify-output`, the output file is read back and its checksum checked.
+//!
+//! Failures before the commit remove the temporary file. Failures after it restore the backup
+//! (**Rollback**). The backup is removed once the transaction is done. Output to stdout can't be
+//! rolled back, so it's written directly after the preflight.
+//!
+//! When the input is stdin or the output is stdout, UPS patches are applied to the input in chunks
+//! as it's read instead (**Stream**), so chained runs like
+//! `upstool patch a.ups - - | upstool patch b.ups - out.gba` only hold the patches and a chunk of
+//! each file in memory. Input files are read twice, once for the preflight and once to patch
+//! them. Stdin can't be read twice, so it's checked against the patch once it's all read: output
+//! files are only committed if it matches, output to stdout was already written and the run fails
+//! afterwards. With `--upload`, the output is
+//! streamed to an [`OutputSink`](crate::sink::OutputSink) after the preflight instead
+//! (**Upload**); sinks only make it visible once it's complete.
+//!
+//! The commit and rollback are atomic renames, except when `--tmp-dir` is on another filesystem
+//! or the output directory isn't writable: the files are copied over the output instead.
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use ups::codec::PatchCodec;
+use ups::softpatch;
+use ups::{
+    Checksum, ChunkedPatcher, Patch, PatchDirection, SparseWriter, UpsPatchErrors, UpsWriteError,
+};
+
+use crate::sink;
+use crate::{
+    check_clobber, check_stdout, confirm_overwrite, file_path, foreign_patch_format, output_path,
+    parse_foreign_patch_arg, parse_patch_arg, patch_name, read_input, verify_output, write_output,
+    Metrics, PatchArgs, RunError,
+};
+
+/// Size of the input chunks read when streaming, see the [module docs](self).
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Progress of [`apply_transaction`], see the [module docs](self) for the states.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionEvent {
+    /// The input was checked against the patch, or the patch chain for `--auto`. Nothing was
+    /// written yet.
+    Preflight,
+    /// The output was written to the temporary file `path`.
+    Written { path: PathBuf, size: usize },
+    /// The existing output file was saved to `path`.
+    BackedUp { path: PathBuf },
+    /// The output file was replaced.
+    Committed { path: PathBuf },
+    /// The output file was read back and has the expected checksum.
+    Verified { path: PathBuf, checksum: Checksum },
+    /// The transaction failed after the commit and the output file was restored from its backup,
+    /// or removed if it didn't exist before.
+    RolledBack { path: PathBuf },
+    /// With `--upload`, the output was streamed to its sink and is now at `location`, a path or
+    /// URL.
+    Uploaded { location: String },
+}
+
+/// Apply or revert a patch as a transaction, see the [module docs](self). `on_event` is called on
+/// every state change.
+pub fn apply_transaction<F: FnMut(TransactionEvent)>(
+    args: &PatchArgs,
+    mut on_event: F,
+) -> Result<Metrics, RunError> {
+    let start = Instant::now();
+    let output = output_path(args)?;
+    if let Some(url) = &args.options.upload {
+        return upload(args, url, output, start, on_event);
+    }
+    if output.is_none() {
+        if args.options.verify_output {
+            return Err(RunError::Usage(
+                "--verify-output requires an output file".into(),
+            ));
+        }
+        check_stdout(args.options.force_stdout)?;
+    }
+    if let Some(p) = &output {
+        if !args.options.patch_inline {
+            check_clobber(p, &args.options.patch, "patch", "")?;
+        }
+        if !args.options.in_place {
+            if let Some(input) = file_path(&args.options.input) {
+                check_clobber(p, input, "input", ", use --in-place to patch it in place")?;
+            }
+            confirm_overwrite(p, args.options.yes)?;
+        }
+    }
+    if let Some(dir) = &args.options.output_dir {
+        fs::create_dir_all(dir).map_err(|e| {
+            RunError::Io(
+                format!("Failed to create output directory \"{}\"", dir.display()),
+                e,
+            )
+        })?;
+    }
+    // Temporary and backup files go next to the file a symlink points to, and the commit replaces
+    // that file instead of the link.
+    let output = output.map(resolve_symlink);
+    if let Some(p) = &output {
+        probe_output(p, args.options.tmp_dir.as_deref())?;
+    }
+    if is_streamed(args, &output) {
+        return stream(args, output, start, on_event);
+    }
+    let (metrics, output_data, output_checksum) = preflight(args, &output, start)?;
+    on_event(TransactionEvent::Preflight);
+
+    let output = match &output {
+        Some(p) => p,
+        None => {
+            let data = match output_data {
+                OutputData::Buffered(data) => data,
+                OutputData::Streamed(patch, input) => patch.patch(args.direction, &input)?,
+            };
+            write_output(&None, &data)?;
+            return Ok(Metrics {
+                duration: start.elapsed(),
+                ..metrics
+            });
+        }
+    };
+    let tmp = temp_path(output, "tmp", args.options.tmp_dir.as_deref());
+    write_tmp(output, &tmp, |writer| match &output_data {
+        OutputData::Buffered(data) => writer.write_all(data).map_err(write_err(output)),
+        OutputData::Streamed(patch, input) => patch
+            .patch_to_writer(args.direction, input, writer)
+            .map_err(|e| match e {
+                UpsWriteError::Io(e) => write_err(output)(e),
+                UpsWriteError::Patch(e) => RunError::Patch(e),
+            }),
+    })?;
+    on_event(TransactionEvent::Written {
+        path: tmp.clone(),
+        size: metrics.output_size.unwrap_or_default(),
+    });
+    commit(args, output, &tmp, output_checksum, &mut on_event)?;
+    Ok(Metrics {
+        duration: start.elapsed(),
+        ..metrics
+    })
+}
+
+// Preflight and stream the output to the sink for `url`, named after `output`.
+fn upload<F: FnMut(TransactionEvent)>(
+    args: &PatchArgs,
+    url: &str,
+    output: Option<PathBuf>,
+    start: Instant,
+    mut on_event: F,
+) -> Result<Metrics, RunError> {
+    let name = match output.as_deref().and_then(Path::file_name) {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => {
+            return Err(RunError::Usage(
+                "--upload requires OUTPUT or an input file to name the output".into(),
+            ))
+        }
+    };
+    if args.options.in_place || args.options.verify_output {
+        return Err(RunError::Usage(
+            "--upload can't be used with --in-place or --verify-output".into(),
+        ));
+    }
+    let upload_err = |e| {
+        RunError::Io(
+            format!("Failed to upload output \"{}\" to \"{}\"", name, url),
+            e,
+        )
+    };
+    let sink = sink::open_sink(url).map_err(upload_err)?;
+    let (metrics, output_data, _) = preflight(args, &output, start)?;
+    on_event(TransactionEvent::Preflight);
+
+    let size = metrics.output_size.unwrap_or_default() as u64;
+    let mut writer = sink.create(&name, size).map_err(upload_err)?;
+    match &output_data {
+        OutputData::Buffered(data) => writer.write_all(data).map_err(upload_err)?,
+        OutputData::Streamed(patch, input) => {
+            let mut buffered = BufWriter::new(&mut writer);
+            patch
+                .patch_to_writer(args.direction, input, &mut buffered)
+                .map_err(|e| match e {
+                    UpsWriteError::Io(e) => upload_err(e),
+                    UpsWriteError::Patch(e) => RunError::Patch(e),
+                })?;
+            buffered.flush().map_err(upload_err)?;
+        }
+    }
+    let location = writer.finish().map_err(upload_err)?;
+    on_event(TransactionEvent::Uploaded {
+        location: location.clone(),
+    });
+    Ok(Metrics {
+        output: Some(location.into()),
+        duration: start.elapsed(),
+        ..metrics
+    })
+}
+
+// Check the input against the patch, building the output in memory when it can't be streamed.
+fn preflight(
+    args: &PatchArgs,
+    output: &Option<PathBuf>,
+    start: Instant,
+) -> Result<(Metrics, OutputData, Checksum), RunError> {
+    let input_data = read_input(&args.options.input)?;
+    let input_size = input_data.len();
+
+    let foreign_format = foreign_patch_format(args);
+    if let (true, Some(format)) = (args.options.auto, foreign_format) {
+        return Err(RunError::Usage(format!(
+            "--auto only supports UPS patches, apply {} patches one at a time",
+            format.name()
+        )));
+    }
+    if args.options.auto {
+        let chain = softpatch::numbered_patches(&args.options.patch);
+        let output_data = softpatch::patch_chain(args.direction, &input_data, &chain)?;
+        let output_checksum = Checksum::from_bytes(&output_data);
+        let input_side = (input_size, Checksum::from_bytes(&input_data));
+        let output_side = (output_data.len(), output_checksum);
+        // Applying starts from the base file, reverting ends with it.
+        let base = match args.direction {
+            PatchDirection::Apply => input_side,
+            PatchDirection::Revert => output_side,
+        };
+        check_modified_output(args, output_side, input_side, base)?;
+        let metrics = Metrics {
+            command: "patch",
+            direction: Some(args.direction),
+            patch: args.options.patch.clone(),
+            patches: chain.len(),
+            blocks: None,
+            bytes_changed: None,
+            input_size: input_size as u64,
+            output_size: Some(output_data.len()),
+            output_crc32: Some(output_checksum),
+            output: output.clone(),
+            duration: start.elapsed(),
+        };
+        Ok((metrics, OutputData::Buffered(output_data), output_checksum))
+    } else {
+        let patch = match foreign_format {
+            Some(format) => parse_foreign_patch_arg(args, format, &input_data)?,
+            None => parse_patch_arg(args)?,
+        };
+        let input_checksum = Checksum::from_bytes(&input_data);
+        check_direction(args, &patch, input_size, input_checksum)?;
+        let (metrics, output_checksum) = patch_metrics(args, &patch, input_size, output, start);
+        check_modified_output(
+            args,
+            (metrics.output_size.unwrap_or_default(), output_checksum),
+            (input_size, input_checksum),
+            (patch.src_size, patch.src_checksum),
+        )?;
+        let output_data = if args.options.in_place {
+            // Same-size patches are XORed over the input without copying it.
+            let mut data = input_data;
+            patch.patch_in_place(args.direction, &mut data)?;
+            OutputData::Buffered(data)
+        } else {
+            OutputData::Streamed(patch, input_data)
+        };
+        Ok((metrics, output_data, output_checksum))
+    }
+}
+
+// Fail with `AlreadyPatched` if the input matches the output side of the patch.
+fn check_direction(
+    args: &PatchArgs,
+    patch: &Patch,
+    input_size: usize,
+    input_checksum: Checksum,
+) -> Result<(), RunError> {
+    let preflight = patch.preflight(input_size, input_checksum);
+    if matches!(preflight.direction_hint, Some(d) if d != args.direction) {
+        return Err(RunError::AlreadyPatched(args.direction));
+    }
+    Ok(())
+}
+
+// With `--refuse-unmodified-output`, fail if the output would be the input or the base file, all
+// given as (size, checksum).
+fn check_modified_output(
+    args: &PatchArgs,
+    output: (usize, Checksum),
+    input: (usize, Checksum),
+    base: (usize, Checksum),
+) -> Result<(), RunError> {
+    if !args.options.refuse_unmodified_output {
+        return Ok(());
+    }
+    if output == base {
+        return Err(RunError::UnmodifiedOutput { base: true });
+    }
+    if output == input {
+        return Err(RunError::UnmodifiedOutput { base: false });
+    }
+    Ok(())
+}
+
+// Metrics for applying or reverting `patch` to an input of `input_size` bytes, and the expected
+// output checksum.
+fn patch_metrics(
+    args: &PatchArgs,
+    patch: &Patch,
+    input_size: usize,
+    output: &Option<PathBuf>,
+    start: Instant,
+) -> (Metrics, Checksum) {
+    let (output_size, output_checksum) = match args.direction {
+        PatchDirection::Apply => (patch.dst_size, patch.dst_checksum),
+        PatchDirection::Revert => (patch.src_size, patch.src_checksum),
+    };
+    let changed: usize = patch
+        .block_offsets()
+        .changed_ranges()
+        .map(|r| r.len())
+        .sum();
+    let metrics = Metrics {
+        command: "patch",
+        direction: Some(args.direction),
+        patch: patch_name(args).to_path_buf(),
+        patches: 1,
+        blocks: Some(patch.blocks().len()),
+        bytes_changed: Some(changed),
+        input_size: input_size as u64,
+        output_size: Some(output_size),
+        output_crc32: Some(output_checksum),
+        output: output.clone(),
+        duration: start.elapsed(),
+    };
+    (metrics, output_checksum)
+}
+
+// Whether the input is patched as it's read, see the module docs.
+fn is_streamed(args: &PatchArgs, output: &Option<PathBuf>) -> bool {
+    !args.options.auto
+        && !args.options.in_place
+        && foreign_patch_format(args).is_none()
+        && (file_path(&args.options.input).is_none() || output.is_none())
+}
+
+// Stream the patched input to `output`, or stdout for `None`.
+fn stream<F: FnMut(TransactionEvent)>(
+    args: &PatchArgs,
+    output: Option<PathBuf>,
+    start: Instant,
+    mut on_event: F,
+) -> Result<Metrics, RunError> {
+    let patch = parse_patch_arg(args)?;
+    let (metrics, output_checksum) = patch_metrics(args, &patch, 0, &output, start);
+    // The input isn't read yet, but it's checked against the patch once it is.
+    let (input_size, input_checksum) = match args.direction {
+        PatchDirection::Apply => (patch.src_size, patch.src_checksum),
+        PatchDirection::Revert => (patch.dst_size, patch.dst_checksum),
+    };
+    check_modified_output(
+        args,
+        (metrics.output_size.unwrap_or_default(), output_checksum),
+        (input_size, input_checksum),
+        (patch.src_size, patch.src_checksum),
+    )?;
+    // Unlike stdin, input files can be read twice: check them before writing anything, so a
+    // mismatch doesn't leave garbage on stdout.
+    if let Some(input) = file_path(&args.options.input) {
+        check_input_file(args, &patch, input)?;
+        on_event(TransactionEvent::Preflight);
+    }
+    let input_size = match &output {
+        None => {
+            let stdout_err = |e| RunError::Io("Failed to write to output file <stdout>".into(), e);
+            let stdout = io::stdout();
+            let mut writer = BufWriter::new(stdout.lock());
+            let input_size = stream_patch(args, &patch, &mut writer, stdout_err)?;
+            writer.flush().map_err(stdout_err)?;
+            input_size
+        }
+        Some(output) => {
+            let tmp = temp_path(output, "tmp", args.options.tmp_dir.as_deref());
+            let mut input_size = 0;
+            write_tmp(output, &tmp, |writer| {
+                input_size = stream_patch(args, &patch, writer, write_err(output))?;
+                Ok(())
+            })?;
+            on_event(TransactionEvent::Written {
+                path: tmp.clone(),
+                size: metrics.output_size.unwrap_or_default(),
+            });
+            commit(args, output, &tmp, output_checksum, &mut on_event)?;
+            input_size
+        }
+    };
+    Ok(Metrics {
+        input_size: input_size as u64,
+        duration: start.elapsed(),
+        ..metrics
+    })
+}
+
+// Check the input file `path` against the patch, reading it in chunks.
+fn check_input_file(args: &PatchArgs, patch: &Patch, path: &Path) -> Result<(), RunError> {
+    let read_err = |e| {
+        RunError::Io(
+            format!("Failed to read input file \"{}\"", path.display()),
+            e,
+        )
+    };
+    let file = File::open(path).map_err(read_err)?;
+    let size = file.metadata().map_err(read_err)?.len();
+    let size = usize::try_from(size).unwrap_or(usize::MAX);
+    let checksum = Checksum::from_reader(&file).map_err(read_err)?;
+    check_direction(args, patch, size, checksum)?;
+    let errors = patch.check_input_metadata(args.direction, size, checksum);
+    UpsPatchErrors::check_errors(Vec::new(), errors)?;
+    Ok(())
+}
+
+// Patch the input in chunks as it's read, writing the output to `writer`. The input is checked
+// against the patch once it's all read, after the output was written. Returns the input size.
+fn stream_patch<W: Write, E: Fn(io::Error) -> RunError>(
+    args: &PatchArgs,
+    patch: &Patch,
+    writer: &mut W,
+    write_err: E,
+) -> Result<usize, RunError> {
+    let input_name = match file_path(&args.options.input) {
+        Some(p) => format!("\"{}\"", p.display()),
+        None => "<stdin>".into(),
+    };
+    let read_err = |e| RunError::Io(format!("Failed to read input file {}", input_name), e);
+    let mut reader: Box<dyn Read> = match file_path(&args.options.input) {
+        Some(p) => Box::new(File::open(p).map_err(read_err)?),
+        None => Box::new(io::stdin()),
+    };
+
+    let mut patcher = ChunkedPatcher::new(patch, args.direction);
+    let mut buf = vec![0; STREAM_CHUNK_SIZE];
+    let mut input_size = 0;
+    let mut input_checksum = Checksum::from_bytes(&[]);
+    // The patcher's callback can't fail, the first write error is kept for after it returns.
+    let mut written = Ok(());
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(read_err(e)),
+        };
+        input_size += len;
+        input_checksum = input_checksum.extend(&buf[..len]);
+        patcher.push(&buf[..len], |out| {
+            if written.is_ok() {
+                written = writer.write_all(out);
+            }
+        });
+        written.map_err(&write_err)?;
+        written = Ok(());
+    }
+    let finished = patcher.finish(|out| {
+        if written.is_ok() {
+            written = writer.write_all(out);
+        }
+    });
+    written.map_err(&write_err)?;
+    if let Err(errs) = finished {
+        check_direction(args, patch, input_size, input_checksum)?;
+        return Err(errs.into());
+    }
+    Ok(input_size)
+}
+
+// Write the new temporary file `tmp` for `output` with `write`, removing it on errors.
+fn write_tmp<F>(output: &Path, tmp: &Path, write: F) -> Result<(), RunError>
+where
+    F: FnOnce(&mut SparseWriter<BufWriter<File>>) -> Result<(), RunError>,
+{
+    let written = File::create(tmp).map_err(write_err(output)).and_then(|f| {
+        // The file is new, zeroes can be skipped.
+        let mut writer = SparseWriter::new(BufWriter::new(f));
+        write(&mut writer)?;
+        writer.finish().map(drop).map_err(write_err(output))?;
+        // Keep the mode of the file being replaced, e.g. executable or read-only.
+        match fs::metadata(output) {
+            Ok(metadata) => {
+                fs::set_permissions(tmp, metadata.permissions()).map_err(write_err(output))
+            }
+            Err(_) => Ok(()),
+        }
+    });
+    if written.is_err() {
+        let _ = fs::remove_file(tmp);
+    }
+    written
+}
+
+// Output built in memory, or a patch and input to stream it to a file.
+enum OutputData {
+    Buffered(Vec<u8>),
+    Streamed(Patch, Vec<u8>),
+}
+
+// Backup, commit and verify `tmp` as `output`, rolling back on errors.
+fn commit<F: FnMut(TransactionEvent)>(
+    args: &PatchArgs,
+    output: &Path,
+    tmp: &Path,
+    checksum: Checksum,
+    on_event: &mut F,
+) -> Result<(), RunError> {
+    let backup = if output.exists() {
+        let backup = temp_path(output, "backup", args.options.tmp_dir.as_deref());
+        let _ = fs::remove_file(&backup);
+        let result =
+            fs::hard_link(output, &backup).or_else(|_| fs::copy(output, &backup).map(|_| ()));
+        if let Err(e) = result {
+            let _ = fs::remove_file(tmp);
+            return Err(RunError::Io(
+                format!("Failed to back up output file \"{}\"", output.display()),
+                e,
+            ));
+        }
+        on_event(TransactionEvent::BackedUp {
+            path: backup.clone(),
+        });
+        Some(backup)
+    } else {
+        None
+    };
+
+    let result = move_file(tmp, output)
+        .map_err(write_err(output))
+        .map(|_| {
+            on_event(TransactionEvent::Committed {
+                path: output.to_path_buf(),
+            })
+        })
+        .and_then(|_| {
+            if !args.options.verify_output {
+                return Ok(());
+            }
+            verify_output(output, checksum)?;
+            on_event(TransactionEvent::Verified {
+                path: output.to_path_buf(),
+                checksum,
+            });
+            Ok(())
+        });
+
+    if result.is_err() {
+        let _ = fs::remove_file(tmp);
+        let restored = match &backup {
+            Some(backup) => move_file(backup, output),
+            None => fs::remove_file(output),
+        };
+        if restored.is_ok() {
+            on_event(TransactionEvent::RolledBack {
+                path: output.to_path_buf(),
+            });
+        }
+    } else if let Some(backup) = &backup {
+        let _ = fs::remove_file(backup);
+    }
+    result
+}
+
+// Check that `output` and the temporary files can be written, without changing anything.
+fn probe_output(output: &Path, tmp_dir: Option<&Path>) -> Result<(), RunError> {
+    if output.exists() {
+        OpenOptions::new()
+            .write(true)
+            .open(output)
+            .map_err(write_err(output))?;
+    } else if tmp_dir.is_some() {
+        // The temporary files don't tell whether the output directory is writable.
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(output)
+            .map_err(write_err(output))?;
+        let _ = fs::remove_file(output);
+    }
+    let probe = temp_path(output, "probe", tmp_dir);
+    File::create(&probe).map_err(|e| {
+        let dir = match probe.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let hint = if tmp_dir.is_none() && e.kind() == io::ErrorKind::PermissionDenied {
+            ", use --tmp-dir to put them elsewhere"
+        } else {
+            ""
+        };
+        RunError::Io(
+            format!(
+                "Failed to create temporary files in \"{}\"{}",
+                dir.display(),
+                hint
+            ),
+            e,
+        )
+    })?;
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+// `path`, or the file it points to if it's a symlink.
+fn resolve_symlink(path: PathBuf) -> PathBuf {
+    match fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            fs::canonicalize(&path).unwrap_or(path)
+        }
+        _ => path,
+    }
+}
+
+// New hidden file name next to `path` or in `tmp_dir`, e.g. `.game.gba.upstool-tmp-4242-0`. The
+// process id and a counter keep concurrent runs, and transactions in the same process, from
+// sharing temporary files.
+fn temp_path(path: &Path, kind: &str, tmp_dir: Option<&Path>) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(
+        ".upstool-{}-{}-{}",
+        kind,
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    match tmp_dir {
+        Some(dir) => dir.join(name),
+        None => path.with_file_name(name),
+    }
+}
+
+// Rename `from` to `to`, copying it over `to` if it can't be renamed, e.g. across filesystems.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    fs::rename(from, to).or_else(|rename_err| {
+        // Copying the temporary file next to the output wouldn't fix anything.
+        if from.parent() == to.parent() {
+            return Err(rename_err);
+        }
+        fs::copy(from, to)?;
+        let _ = fs::remove_file(from);
+        Ok(())
+    })
+}
+
+fn write_err(path: &Path) -> impl Fn(io::Error) -> RunError + '_ {
+    move |e| {
+        let context = if is_locked(&e) {
+            format!(
+                "Output file \"{}\" is in use by another program, e.g. an emulator, close it and \
+                 try again",
+                path.display()
+            )
+        } else if e.kind() == io::ErrorKind::PermissionDenied {
+            format!(
+                "Can't write to output file \"{}\", it's read-only or you don't have permission \
+                 to change it",
+                path.display()
+            )
+        } else {
+            format!("Failed to write to output file \"{}\"", path.display())
+        };
+        RunError::Io(context, e)
+    }
+}
+
+// Sharing and lock violations, Windows locks files opened by other programs.
+fn is_locked(e: &io::Error) -> bool {
+    cfg!(windows) && matches!(e.raw_os_error(), Some(32) | Some(33))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Whether `path` is a temporary file of `kind` for `output`, in `dir`.
+    fn is_temp_path(path: &Path, output: &Path, kind: &str, dir: &Path) -> bool {
+        let prefix = format!(
+            ".{}.upstool-{}-{}-",
+            output.file_name().unwrap().to_string_lossy(),
+            kind,
+            process::id()
+        );
+        path.parent() == Some(dir)
+            && path
+                .file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with(&prefix))
+    }
+
+    fn args(dir: &Path, output: Option<PathBuf>) -> PatchArgs {
+        let mut args = PatchArgs::new(dir.join("hack.ups"))
+            .input(dir.join("rom.bin"))
+            .verify_output(true)
+            .quiet(true)
+            .yes(true);
+        args.options.output = output;
+        args
+    }
+
+    #[test]
+    fn test_apply_transaction_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Patch::diff(b"original rom", b"patched rom!");
+        fs::write(dir.path().join("hack.ups"), patch.serialize()).unwrap();
+        fs::write(dir.path().join("rom.bin"), b"original rom").unwrap();
+        let output = dir.path().join("out.bin");
+        fs::write(&output, b"old output").unwrap();
+
+        let mut events = Vec::new();
+        apply_transaction(&args(dir.path(), Some(output.clone())), |e| events.push(e)).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"patched rom!");
+        let (tmp, backup) = match &events[1..3] {
+            [TransactionEvent::Written { path: tmp, .. }, TransactionEvent::BackedUp { path: backup }] => {
+                (tmp.clone(), backup.clone())
+            }
+            events => panic!("unexpected events {:?}", events),
+        };
+        assert!(is_temp_path(&tmp, &output, "tmp", dir.path()), "{:?}", tmp);
+        assert!(
+            is_temp_path(&backup, &output, "backup", dir.path()),
+            "{:?}",
+            backup
+        );
+        assert_eq!(
+            events,
+            vec![
+                TransactionEvent::Preflight,
+                TransactionEvent::Written {
+                    path: tmp,
+                    size: 12,
+                },
+                TransactionEvent::BackedUp { path: backup },
+                TransactionEvent::Committed {
+                    path: output.clone(),
+                },
+                TransactionEvent::Verified {
+                    path: output.clone(),
+                    checksum: patch.dst_checksum,
+                },
+            ],
+        );
+        // Only the patch, input and output are left.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_apply_transaction_padded_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut padded = b"original rom".to_vec();
+        padded.resize(1024 * 1024, 0);
+        let patch = Patch::diff(b"original rom", &padded);
+        fs::write(dir.path().join("hack.ups"), patch.serialize()).unwrap();
+        fs::write(dir.path().join("rom.bin"), b"original rom").unwrap();
+        let output = dir.path().join("out.bin");
+
+        apply_transaction(&args(dir.path(), Some(output.clone())), |_| ()).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), padded);
+    }
+
+    #[test]
+    fn test_apply_transaction_preflight_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Patch::diff(b"original rom", b"patched rom!");
+        fs::write(dir.path().join("hack.ups"), patch.serialize()).unwrap();
+        fs::write(dir.path().join("rom.bin"), b"patched rom!").unwrap();
+        let output = dir.path().join("out.bin");
+        fs::write(&output, b"old output").unwrap();
+
+        let mut events = Vec::new();
+        let result = apply_transaction(&args(dir.path(), Some(output.clone())), |e| events.push(e));
+        let err = result.unwrap_err();
+        assert!(matches!(err, RunError::AlreadyPatched(_)));
+        assert_eq!(
+            err.to_string(),
+            "this file already appears to be patched with this patch, so `upstool patch` would \
+             apply it twice, run `upstool revert` with the same arguments to restore the original"
+        );
+        assert!(events.is_empty());
+        assert_eq!(fs::read(&output).unwrap(), b"old output");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        fs::write(dir.path().join("rom.bin"), b"original rom").unwrap();
+        let revert = args(dir.path(), Some(output.clone())).direction(PatchDirection::Revert);
+        assert_eq!(
+            apply_transaction(&revert, |_| ()).unwrap_err().to_string(),
+            "this file already appears to be unpatched, so there's nothing for `upstool revert` to \
+             undo, run `upstool patch` with the same arguments to patch it"
+        );
+    }
+
+    #[test]
+    fn test_apply_transaction_refuse_unmodified_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Patch::diff(b"original rom", b"patched rom!");
+        fs::write(dir.path().join("hack.ups"), patch.serialize()).unwrap();
+        fs::write(dir.path().join("rom.bin"), b"patched rom!").unwrap();
+        let output = dir.path().join("out.bin");
+
+        let args = args(dir.path(), Some(output.clone())).refuse_unmodified_output(true);
+        let revert = args.clone().direction(PatchDirection::Revert);
+        let result = apply_transaction(&revert, |_| ());
+        assert!(matches!(
+            result,
+            Err(RunError::UnmodifiedOutput { base: true })
+        ));
+        // Streamed too.
+        let stdout = revert.output("-").force_stdout(true).verify_output(false);
+        let result = apply_transaction(&stdout, |_| ());
+        assert!(matches!(
+            result,
+            Err(RunError::UnmodifiedOutput { base: true })
+        ));
+        assert!(!output.exists());
+
+        let noop = Patch::diff(b"patched rom!", b"patched rom!");
+        fs::write(dir.path().join("hack.ups"), noop.serialize()).unwrap();
+        let result = apply_transaction(&args, |_| ());
+        assert!(matches!(result, Err(RunError::UnmodifiedOutput { .. })));
+
+        fs::write(dir.path().join("hack.ups"), patch.serialize()).unwrap();
+        fs::write(dir.path().join("rom.bin"), b"original rom").unwrap();
+        apply_transaction(&args, |_| ()).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"patched rom!");
+    }
+
+    #[test]
+    fn test_apply_transaction_tmp_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let patch = Patch::diff(b"original rom", b"patched rom!");
+        fs::write(dir.path().join("hack.ups"), patch.serialize()).unwrap();
+        fs::write(dir.path().join("rom.bin"), b"original rom").unwrap();
+        let output = dir.path().join("out.bin");
+        fs::write(&output, b"old output").unwrap();
+
+        let args = args(dir.path(), Some(output.clone())).tmp_dir(tmp_dir.path());
+        let mut events = Vec::new();
+        apply_transaction(&args, |e| events.push(e)).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"patched rom!");
+        match &events[1] {
+            TransactionEvent::Written { path, size: 12 } => {
+                assert!(
+                    is_temp_path(path, &output, "tmp", tmp_dir.path()),
+                    "{:?}",
+                    path
+                )
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+        assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_temp_path_unique() {
+        let output = Path::new("dir").join("game.gba");
+        let first = temp_path(&output, "tmp", None);
+        let second = temp_path(&output, "tmp", None);
+        assert_ne!(first, second);
+        assert!(is_temp_path(&first, &output, "tmp", Path::new("dir")));
+        assert!(is_temp_path(&second, &output, "tmp", Path::new("dir")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_transaction_in_place_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Patch::diff(b"original rom", b"patched rom!");
+        fs::write(dir.path().join("hack.ups"), patch.serialize()).unwrap();
+        let target = dir.path().join("roms").join("game.bin");
+        fs::create_dir(target.parent().unwrap()).unwrap();
+        fs::write(&target, b"original rom").unwrap();
+        let link = dir.path().join("rom.bin");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let args = args(dir.path(), None).in_place(true);
+        apply_transaction(&args, |_| ()).unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read(&target).unwrap(), b"patched rom!");
+        // Temporary files were next to the target and are gone.
+        assert_eq!(fs::read_dir(target.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_transaction_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Patch::diff(b"original rom", b"patched rom!");
+        fs::write(dir.path().join("hack.ups"), patch.serialize()).unwrap();
+        for mode in [0o750, 0o640] {
+            let rom = dir.path().join("rom.bin");
+            let output = dir.path().join("out.bin");
+            for file in [&rom, &output] {
+                fs::write(file, b"original rom").unwrap();
+                fs::set_permissions(file, fs::Permissions::from_mode(mode)).unwrap();
+            }
+
+            // To another file, then in place.
+            apply_transaction(&args(dir.path(), Some(output.clone())), |_| ()).unwrap();
+            apply_transaction(&args(dir.path(), None).in_place(true), |_| ()).unwrap();
+            for file in [&rom, &output] {
+                assert_eq!(fs::read(file).unwrap(), b"patched rom!");
+                assert_eq!(
+                    fs::metadata(file).unwrap().permissions().mode() & 0o777,
+                    mode
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_transaction_probes_output_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Patch::diff(b"original rom", b"patched rom!");
+        fs::write(dir.path().join("hack.ups"), patch.serialize()).unwrap();
+        // Already patched, but the unwritable output is reported first.
+        fs::write(dir.path().join("rom.bin"), b"patched rom!").unwrap();
+        let output = dir.path().join("out.bin");
+        fs::create_dir(&output).unwrap();
+
+        let mut events = Vec::new();
+        let result = apply_transaction(&args(dir.path(), Some(output.clone())), |e| events.push(e));
+        assert!(matches!(result, Err(RunError::Io(..))), "{:?}", result);
+        assert!(events.is_empty());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_apply_transaction_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Patch::diff(b"original rom", b"patched rom!");
+        fs::write(dir.path().join("hack.ups"), patch.serialize()).unwrap();
+        fs::write(dir.path().join("rom.bin"), b"original rom").unwrap();
+        let uploads = dir.path().join("uploads");
+
+        let mut args = args(dir.path(), None);
+        args.options.verify_output = false;
+        args.options.upload = Some(uploads.to_string_lossy().into_owned());
+        let mut events = Vec::new();
+        let metrics = apply_transaction(&args, |e| events.push(e)).unwrap();
+        let uploaded = uploads.join("rom (hack).bin");
+        assert_eq!(fs::read(&uploaded).unwrap(), b"patched rom!");
+        assert_eq!(metrics.output, Some(uploaded.clone()));
+        assert_eq!(
+            events,
+            vec![
+                TransactionEvent::Preflight,
+                TransactionEvent::Uploaded {
+                    location: uploaded.display().to_string(),
+                },
+            ],
+        );
+        // Nothing is written next to the input.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_stream_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let src: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut dst = src.clone();
+        dst[100_000] ^= 1;
+        dst.extend_from_slice(b"tail");
+        let patch = Patch::diff(&src, &dst);
+        fs::write(dir.path().join("rom.bin"), &src).unwrap();
+        let args = args(dir.path(), None);
+
+        let mut output = Vec::new();
+        let input_size = stream_patch(&args, &patch, &mut output, |e| {
+            RunError::Io("write".into(), e)
+        })
+        .unwrap();
+        assert_eq!(input_size, src.len());
+        assert_eq!(output, dst);
+
+        // The output is written before the input is checked.
+        fs::write(dir.path().join("rom.bin"), &dst).unwrap();
+        let mut output = Vec::new();
+        let err = stream_patch(&args, &patch, &mut output, |e| {
+            RunError::Io("write".into(), e)
+        })
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            RunError::AlreadyPatched(PatchDirection::Apply)
+        ));
+        assert_eq!(output.len(), dst.len());
+    }
+}
@@ -0,0 +1,95 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn upstool(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_upstool"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+// CRC32 printed by `info` on the line starting with `side`.
+fn info_crc32(info: &str, side: &str) -> String {
+    let line = info.lines().find(|l| l.starts_with(side)).unwrap();
+    line.rsplit(' ').next().unwrap().to_string()
+}
+
+#[test]
+fn test_route_from_info_checksums() {
+    let dir = tempfile::tempdir().unwrap();
+    let patches = dir.path().join("patches");
+    fs::create_dir(&patches).unwrap();
+    let src: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+    let mut dst = src.clone();
+    dst[100..104].copy_from_slice(b"hack");
+    fs::write(dir.path().join("rom.bin"), &src).unwrap();
+    fs::write(dir.path().join("hack.bin"), &dst).unwrap();
+    let patch = patches.join("hack.ups");
+    let path = |p: &Path| p.to_str().unwrap().to_string();
+    stdout(&upstool(&[
+        "generate",
+        &path(&dir.path().join("rom.bin")),
+        &path(&dir.path().join("hack.bin")),
+        &path(&patch),
+    ]));
+
+    for order in ["le", "be"] {
+        let info = stdout(&upstool(&[
+            "--checksum-order",
+            order,
+            "info",
+            &path(&patch),
+        ]));
+        let from = info_crc32(&info, "Source:");
+        let to = info_crc32(&info, "Destination:");
+        let route = stdout(&upstool(&[
+            "--checksum-order",
+            order,
+            "route",
+            &path(&patches),
+            "--from",
+            &from,
+            "--to",
+            &to,
+        ]));
+        assert!(
+            route.contains(&format!("hack.ups ({} -> {})", from, to)),
+            "{}: {}",
+            order,
+            route
+        );
+    }
+
+    // Pasted in the other byte order, the checksums don't match any patch.
+    let info = stdout(&upstool(&["info", &path(&patch)]));
+    let output = upstool(&[
+        "--checksum-order",
+        "be",
+        "route",
+        &path(&patches),
+        "--from",
+        &info_crc32(&info, "Source:"),
+        "--to",
+        &info_crc32(&info, "Destination:"),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!(
+            "No route from CRC32 {}",
+            info_crc32(&info, "Source:")
+        )),
+        "{}",
+        stderr
+    );
+}
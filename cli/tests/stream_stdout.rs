@@ -0,0 +1,41 @@
+use std::fs;
+use std::process::{Command, Output};
+
+use ups::Patch;
+
+fn upstool(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_upstool"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_stdout_empty_on_input_mismatch() {
+    let dir = tempfile::tempdir().unwrap();
+    let src: Vec<u8> = (0..256 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let mut dst = src.clone();
+    dst[1000..1004].copy_from_slice(b"hack");
+    let mut other = src.clone();
+    other[0] ^= 0xff;
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+    fs::write(path("hack.ups"), Patch::diff(&src, &dst).serialize()).unwrap();
+    fs::write(path("rom.bin"), &src).unwrap();
+    fs::write(path("hack.bin"), &dst).unwrap();
+    fs::write(path("other.bin"), &other).unwrap();
+
+    // Wrong source, then an already patched one.
+    for input in ["other.bin", "hack.bin"] {
+        let output = upstool(&["patch", &path("hack.ups"), &path(input), "-"]);
+        assert!(!output.status.success(), "{}", input);
+        assert!(output.stdout.is_empty(), "{}", input);
+    }
+
+    let output = upstool(&["patch", &path("hack.ups"), &path("rom.bin"), "-"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.stdout, dst);
+}
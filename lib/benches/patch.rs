@@ -0,0 +1,62 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use ups::{Patch, PatchDirection};
+
+fn pseudo_random(len: usize, mut x: u64) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+/// 32 MiB file where every other 256 KiB region is rewritten, i.e. a few large blocks.
+fn large_blocks() -> (Vec<u8>, Vec<u8>) {
+    const REGION: usize = 256 * 1024;
+    let src = pseudo_random(32 * 1024 * 1024, 0x2545_f491_4f6c_dd1d);
+    let noise = pseudo_random(src.len(), 0x9e37_79b9_7f4a_7c15);
+    let mut dst = src.clone();
+    for (i, (b, n)) in dst.iter_mut().zip(&noise).enumerate() {
+        if (i / REGION) % 2 == 1 {
+            *b = b.wrapping_add(n | 1);
+        }
+    }
+    (src, dst)
+}
+
+/// 4 MiB file with a short edit every 64 bytes, like a translation patch.
+fn small_blocks() -> (Vec<u8>, Vec<u8>) {
+    let src = pseudo_random(4 * 1024 * 1024, 0x2545_f491_4f6c_dd1d);
+    let mut dst = src.clone();
+    for (i, chunk) in dst.chunks_mut(64).enumerate() {
+        for b in &mut chunk[..1 + i % 8] {
+            *b = b.wrapping_add(1);
+        }
+    }
+    (src, dst)
+}
+
+fn bench_small_blocks(c: &mut Criterion) {
+    let (src, dst) = small_blocks();
+    let serialized = Patch::diff(&src, &dst).serialize();
+    let mut group = c.benchmark_group("small_blocks");
+    group.bench_function("diff", |b| b.iter(|| Patch::diff(&src, &dst)));
+    group.bench_function("parse", |b| b.iter(|| Patch::parse(&serialized).unwrap()));
+    group.finish();
+}
+
+fn bench_apply(c: &mut Criterion) {
+    let (src, dst) = large_blocks();
+    let patch = Patch::diff(&src, &dst);
+    let mut group = c.benchmark_group("apply");
+    group.sample_size(20);
+    group.bench_function("large_blocks", |b| {
+        b.iter(|| patch.patch(PatchDirection::Apply, &src).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_apply, bench_small_blocks);
+criterion_main!(benches);
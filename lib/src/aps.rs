@@ -0,0 +1,585 @@
+//! Parse and apply APS patches, in both their GBA and N64 variants.
+//!
+//! The variants only share the name and the "APS1" preamble, [`ApsPatch::parse`] tells them apart
+//! from the header:
+//!
+//! - GBA patches XOR 64 KiB blocks of the ROM, each with a CRC16 of the original and patched
+//!   block, and record the source and destination sizes.
+//! - N64 patches are lists of records writing bytes or runs of a byte, like IPS. They can hold
+//!   the cartridge ID and header CRC of the original ROM to check it.
+//!
+//! [`ApsPatch::apply`] returns [`UpsPatchErrors`] if those checks fail, and
+//! [`ApsPatch::to_ups`] converts patches to UPS patches for a given ROM.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::fs;
+//! use ups::aps::ApsPatch;
+//!
+//! let rom = fs::read("samples/rom.gba")?;
+//! let patch = ApsPatch::parse(&fs::read("samples/patch.aps")?)?;
+//! fs::write("patched.gba", patch.apply(&rom)?)?;
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! # Reference
+//!
+//! - <https://github.com/marcrobledo/RomPatcher.js/blob/master/rom-patcher-js/modules/RomPatcher.format.aps_gba.js>
+//! - <https://github.com/marcrobledo/RomPatcher.js/blob/master/rom-patcher-js/modules/RomPatcher.format.aps_n64.js>
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Formatter};
+
+use crate::{Checksum, MetadataMismatch, Patch, UpsPatchError, UpsPatchErrors};
+
+const GBA_MAGIC: &[u8] = b"APS1";
+const N64_MAGIC: &[u8] = b"APS10";
+/// Bytes XORed by each [`ApsGbaRecord`].
+pub const GBA_BLOCK_SIZE: usize = 0x10000;
+const GBA_HEADER_LEN: usize = 12;
+const N64_DESCRIPTION_LEN: usize = 50;
+// Positions of the cartridge ID and header CRCs in N64 ROMs.
+const N64_CART_ID_OFFSET: usize = 0x3c;
+const N64_CRC_OFFSET: usize = 0x10;
+
+/// APS patch contents, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ApsPatch {
+    Gba(ApsGbaPatch),
+    N64(ApsN64Patch),
+}
+
+/// GBA variant of [`ApsPatch`].
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ApsGbaPatch {
+    /// Source file size.
+    pub src_size: usize,
+    /// Destination file size.
+    pub dst_size: usize,
+    /// Blocks changed by the patch.
+    pub records: Vec<ApsGbaRecord>,
+}
+
+/// Block XORed by an [`ApsGbaPatch`].
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ApsGbaRecord {
+    /// Offset of the block.
+    pub offset: usize,
+    /// CRC16 of the original block.
+    pub src_crc16: u16,
+    /// CRC16 of the patched block.
+    pub dst_crc16: u16,
+    /// [`GBA_BLOCK_SIZE`] bytes XORed with the block.
+    pub xor_data: Vec<u8>,
+}
+
+/// N64 variant of [`ApsPatch`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ApsN64Patch {
+    /// Description from the header, without the padding.
+    pub description: String,
+    /// Header of the original ROM, if the patch was created for an N64 ROM rather than any file.
+    pub rom_header: Option<ApsN64Header>,
+    /// Destination file size.
+    pub dst_size: usize,
+    /// Records in the order they're applied.
+    pub records: Vec<ApsN64Record>,
+}
+
+/// Fields of the original ROM header in an [`ApsN64Patch`], checked before applying it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ApsN64Header {
+    /// Byte order of the original ROM, as recorded by the patcher.
+    pub format: u8,
+    /// Cartridge ID, at 0x3c in the ROM.
+    pub cart_id: [u8; 3],
+    /// Header CRCs, at 0x10 in the ROM.
+    pub crc: [u8; 8],
+}
+
+/// Write to the output in an [`ApsN64Patch`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ApsN64Record {
+    /// Write `data` at `offset`.
+    Data { offset: usize, data: Vec<u8> },
+    /// Write `len` copies of `byte` at `offset`.
+    Rle { offset: usize, byte: u8, len: u8 },
+}
+
+/// Error from [`ApsPatch::parse`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ApsParseError {
+    #[error("this doesn't seem to be an APS file: {}", .0)]
+    FormatMismatch(String),
+}
+
+impl ApsPatch {
+    /// Parse an APS patch, detecting its variant from the header.
+    pub fn parse(input: &[u8]) -> Result<Self, ApsParseError> {
+        if !input.starts_with(GBA_MAGIC) {
+            return Err(ApsParseError::FormatMismatch(
+                "invalid preamble, expected \"APS1\"".into(),
+            ));
+        }
+        // GBA source sizes can start with '0', so "APS10" may still be a GBA patch. N64 patches
+        // for ROMs have header type 1, which would be a GBA size no ROM has, otherwise the rigid
+        // GBA layout decides.
+        let n64 = input.starts_with(N64_MAGIC)
+            && (input.get(N64_MAGIC.len()) == Some(&1) || ApsGbaPatch::parse(input).is_err());
+        if n64 {
+            ApsN64Patch::parse(input).map(ApsPatch::N64)
+        } else {
+            ApsGbaPatch::parse(input).map(ApsPatch::Gba)
+        }
+    }
+
+    /// Apply the patch to `src`, see [`ApsGbaPatch::apply`] and [`ApsN64Patch::apply`].
+    pub fn apply(&self, src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors> {
+        match self {
+            ApsPatch::Gba(patch) => patch.apply(src),
+            ApsPatch::N64(patch) => patch.apply(src),
+        }
+    }
+
+    /// Equivalent UPS patch for `src`, which can be checked and reverted.
+    pub fn to_ups(&self, src: &[u8]) -> Result<Patch, UpsPatchErrors> {
+        Ok(Patch::diff(src, &self.apply(src)?))
+    }
+}
+
+impl ApsGbaPatch {
+    /// Parse the GBA variant of APS patches.
+    pub fn parse(input: &[u8]) -> Result<Self, ApsParseError> {
+        let err = |reason: &str| ApsParseError::FormatMismatch(reason.into());
+        let mut buf = input
+            .strip_prefix(GBA_MAGIC)
+            .ok_or_else(|| err("invalid preamble, expected \"APS1\""))?;
+        let header = take(&mut buf, GBA_HEADER_LEN - GBA_MAGIC.len())
+            .ok_or_else(|| err("file too small"))?;
+        let src_size = read_u32(&header[..4]);
+        let dst_size = read_u32(&header[4..]);
+        let mut records = Vec::new();
+        while !buf.is_empty() {
+            let record_err = |reason: &str| err(&format!("record {}: {}", records.len(), reason));
+            let header = take(&mut buf, 8).ok_or_else(|| record_err("truncated"))?;
+            let xor_data = take(&mut buf, GBA_BLOCK_SIZE).ok_or_else(|| record_err("truncated"))?;
+            records.push(ApsGbaRecord {
+                offset: read_u32(&header[..4]),
+                src_crc16: u16::from_le_bytes([header[4], header[5]]),
+                dst_crc16: u16::from_le_bytes([header[6], header[7]]),
+                xor_data: xor_data.to_vec(),
+            });
+        }
+        Ok(ApsGbaPatch {
+            src_size,
+            dst_size,
+            records,
+        })
+    }
+
+    /// Apply the patch to `src`.
+    ///
+    /// A source size mismatch is returned as a
+    /// [`SourceMetadataMismatch`](UpsPatchError::SourceMetadataMismatch) size error. CRC16
+    /// mismatches are returned as checksum errors for the first mismatching block of each side,
+    /// with the CRC16s in the lower bits. The output only grows past `src` as far as the records
+    /// write, a larger destination size is returned as a
+    /// [`DestMetadataMismatch`](UpsPatchError::DestMetadataMismatch) size error.
+    pub fn apply(&self, src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors> {
+        let mut errors: Vec<_> = MetadataMismatch::size(self.src_size, src.len())
+            .map(UpsPatchError::SourceMetadataMismatch)
+            .into_iter()
+            .collect();
+        let records_end = self
+            .records
+            .iter()
+            .map(|r| r.offset.saturating_add(GBA_BLOCK_SIZE))
+            .max();
+        let mut output = src.to_vec();
+        output.resize(output_size(self.dst_size, src, records_end, &mut errors), 0);
+        let mut src_mismatch = None;
+        let mut dst_mismatch = None;
+        for record in &self.records {
+            let mut block = block_at(src, record.offset);
+            check_crc16(&block, record.src_crc16, &mut src_mismatch);
+            for (b, x) in block.iter_mut().zip(&record.xor_data) {
+                *b ^= x;
+            }
+            check_crc16(&block, record.dst_crc16, &mut dst_mismatch);
+            if let Some(dst) = output.get_mut(record.offset..) {
+                let len = std::cmp::min(dst.len(), block.len());
+                dst[..len].copy_from_slice(&block[..len]);
+            }
+        }
+        errors.extend(src_mismatch.map(UpsPatchError::SourceMetadataMismatch));
+        errors.extend(dst_mismatch.map(UpsPatchError::DestMetadataMismatch));
+        UpsPatchErrors::check_errors(output, errors)
+    }
+}
+
+impl ApsN64Patch {
+    /// Parse the N64 variant of APS patches.
+    pub fn parse(input: &[u8]) -> Result<Self, ApsParseError> {
+        let err = |reason: &str| ApsParseError::FormatMismatch(reason.into());
+        let mut buf = input
+            .strip_prefix(N64_MAGIC)
+            .ok_or_else(|| err("invalid preamble, expected \"APS10\""))?;
+        let header =
+            take(&mut buf, 2 + N64_DESCRIPTION_LEN).ok_or_else(|| err("file too small"))?;
+        let has_rom_header = match header[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(err("invalid header type")),
+        };
+        if header[1] != 0 {
+            return Err(err("invalid encoding method"));
+        }
+        let description = String::from_utf8_lossy(&header[2..])
+            .trim_end_matches(|c: char| c == '\0' || c.is_whitespace())
+            .to_string();
+        let rom_header = if has_rom_header {
+            let fields = take(&mut buf, 17).ok_or_else(|| err("file too small"))?;
+            Some(ApsN64Header {
+                format: fields[0],
+                cart_id: <[u8; 3]>::try_from(&fields[1..4]).unwrap(),
+                crc: <[u8; 8]>::try_from(&fields[4..12]).unwrap(),
+            })
+        } else {
+            None
+        };
+        let dst_size = take(&mut buf, 4)
+            .map(read_u32)
+            .ok_or_else(|| err("file too small"))?;
+        let mut records = Vec::new();
+        while !buf.is_empty() {
+            let record_err = |reason: &str| err(&format!("record {}: {}", records.len(), reason));
+            let header = take(&mut buf, 5).ok_or_else(|| record_err("truncated"))?;
+            let offset = read_u32(&header[..4]);
+            let record = match header[4] {
+                0 => {
+                    let rle = take(&mut buf, 2).ok_or_else(|| record_err("truncated"))?;
+                    ApsN64Record::Rle {
+                        offset,
+                        byte: rle[0],
+                        len: rle[1],
+                    }
+                }
+                len => ApsN64Record::Data {
+                    offset,
+                    data: take(&mut buf, usize::from(len))
+                        .ok_or_else(|| record_err("truncated data"))?
+                        .to_vec(),
+                },
+            };
+            records.push(record);
+        }
+        Ok(ApsN64Patch {
+            description,
+            rom_header,
+            dst_size,
+            records,
+        })
+    }
+
+    /// Apply the patch to `src`, truncating or zero-extending it to the destination size.
+    ///
+    /// If `src` doesn't have the cartridge ID and CRCs of the ROM header, a
+    /// [`SourceMetadataMismatch`](UpsPatchError::SourceMetadataMismatch) error is returned with
+    /// the CRC32 of the expected and actual fields. The output only grows past `src` as far as
+    /// the records write, a larger destination size is returned as a
+    /// [`DestMetadataMismatch`](UpsPatchError::DestMetadataMismatch) size error.
+    pub fn apply(&self, src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors> {
+        let mut errors = self
+            .check_rom_header(src)
+            .map(UpsPatchError::SourceMetadataMismatch)
+            .into_iter()
+            .collect();
+        let records_end = self
+            .records
+            .iter()
+            .map(|r| match r {
+                ApsN64Record::Data { offset, data } => offset.saturating_add(data.len()),
+                ApsN64Record::Rle { offset, len, .. } => offset.saturating_add(usize::from(*len)),
+            })
+            .max();
+        let mut output = src.to_vec();
+        output.resize(output_size(self.dst_size, src, records_end, &mut errors), 0);
+        for record in &self.records {
+            let (offset, data) = match record {
+                ApsN64Record::Data { offset, data } => (*offset, data.clone()),
+                ApsN64Record::Rle { offset, byte, len } => {
+                    (*offset, vec![*byte; usize::from(*len)])
+                }
+            };
+            if let Some(dst) = output.get_mut(offset..) {
+                let len = std::cmp::min(dst.len(), data.len());
+                dst[..len].copy_from_slice(&data[..len]);
+            }
+        }
+        UpsPatchErrors::check_errors(output, errors)
+    }
+
+    fn check_rom_header(&self, src: &[u8]) -> Option<MetadataMismatch> {
+        let header = self.rom_header?;
+        let mut expected = header.cart_id.to_vec();
+        expected.extend_from_slice(&header.crc);
+        let mut actual = block_at(src, N64_CART_ID_OFFSET);
+        actual.truncate(header.cart_id.len());
+        actual.extend_from_slice(&block_at(src, N64_CRC_OFFSET)[..header.crc.len()]);
+        MetadataMismatch::checksum(
+            Checksum::from_bytes(&expected),
+            Checksum::from_bytes(&actual),
+        )
+    }
+}
+
+impl Debug for ApsGbaPatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("ApsGbaPatch")
+            .field("src_size", &self.src_size)
+            .field("dst_size", &self.dst_size)
+            .field("records", &self.records)
+            .finish()
+    }
+}
+
+impl Debug for ApsGbaRecord {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("ApsGbaRecord")
+            .field("offset", &self.offset)
+            .field("src_crc16", &self.src_crc16)
+            .field("dst_crc16", &self.dst_crc16)
+            .finish()
+    }
+}
+
+// Output size for `dst_size`. Patches only declare sizes, so a tiny patch could make us allocate
+// gigabytes: growing past `src` must be justified by records writing up to `records_end`.
+fn output_size(
+    dst_size: usize,
+    src: &[u8],
+    records_end: Option<usize>,
+    errors: &mut Vec<UpsPatchError>,
+) -> usize {
+    let limit = std::cmp::max(src.len(), records_end.unwrap_or(0));
+    let size = std::cmp::min(dst_size, limit);
+    errors.extend(MetadataMismatch::size(dst_size, size).map(UpsPatchError::DestMetadataMismatch));
+    size
+}
+
+// Record the first CRC16 mismatch in `mismatch`.
+fn check_crc16(block: &[u8], expected: u16, mismatch: &mut Option<MetadataMismatch>) {
+    if mismatch.is_none() {
+        *mismatch = MetadataMismatch::checksum(
+            Checksum(u32::from(expected)),
+            Checksum(u32::from(crc16(block))),
+        );
+    }
+}
+
+// GBA_BLOCK_SIZE bytes of `data` at `offset`, zero-filled past its end.
+fn block_at(data: &[u8], offset: usize) -> Vec<u8> {
+    let mut block = vec![0; GBA_BLOCK_SIZE];
+    if let Some(rest) = data.get(offset..) {
+        let len = std::cmp::min(rest.len(), GBA_BLOCK_SIZE);
+        block[..len].copy_from_slice(&rest[..len]);
+    }
+    block
+}
+
+// CRC-16/CCITT-FALSE, used by GBA patches.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &b in data {
+        crc ^= u16::from(b) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn read_u32(bytes: &[u8]) -> usize {
+    u32::from_le_bytes(<[u8; 4]>::try_from(bytes).unwrap()) as usize
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Some(head)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gba_patch(src: &[u8], dst: &[u8]) -> Vec<u8> {
+        let mut raw = GBA_MAGIC.to_vec();
+        raw.extend_from_slice(&(src.len() as u32).to_le_bytes());
+        raw.extend_from_slice(&(dst.len() as u32).to_le_bytes());
+        for offset in (0..std::cmp::max(src.len(), dst.len())).step_by(GBA_BLOCK_SIZE) {
+            let (src_block, dst_block) = (block_at(src, offset), block_at(dst, offset));
+            if src_block == dst_block {
+                continue;
+            }
+            raw.extend_from_slice(&(offset as u32).to_le_bytes());
+            raw.extend_from_slice(&crc16(&src_block).to_le_bytes());
+            raw.extend_from_slice(&crc16(&dst_block).to_le_bytes());
+            raw.extend(src_block.iter().zip(&dst_block).map(|(a, b)| a ^ b));
+        }
+        raw
+    }
+
+    fn n64_patch(rom_header: bool, dst_size: u32) -> Vec<u8> {
+        let mut raw = N64_MAGIC.to_vec();
+        raw.extend_from_slice(&[rom_header as u8, 0]);
+        let mut description = b"Test patch".to_vec();
+        description.resize(N64_DESCRIPTION_LEN, b' ');
+        raw.extend_from_slice(&description);
+        if rom_header {
+            raw.push(0);
+            raw.extend_from_slice(b"NSM");
+            raw.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+            raw.extend_from_slice(&[0; 5]);
+        }
+        raw.extend_from_slice(&dst_size.to_le_bytes());
+        // Data record, then RLE record.
+        raw.extend_from_slice(&[2, 0, 0, 0, 3]);
+        raw.extend_from_slice(b"abc");
+        raw.extend_from_slice(&[0x40, 0, 0, 0, 0, b'z', 4]);
+        raw
+    }
+
+    fn n64_rom() -> Vec<u8> {
+        let mut rom = vec![0xff; 0x50];
+        rom[N64_CRC_OFFSET..N64_CRC_OFFSET + 8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        rom[N64_CART_ID_OFFSET..N64_CART_ID_OFFSET + 3].copy_from_slice(b"NSM");
+        rom
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn test_gba() {
+        let src: Vec<u8> = (0..0x18000u32).map(|i| (i % 253) as u8).collect();
+        let mut dst = src.clone();
+        dst[0x12345] ^= 0x55;
+        dst.extend_from_slice(b"more");
+        let raw = gba_patch(&src, &dst);
+        let patch = match ApsPatch::parse(&raw).unwrap() {
+            ApsPatch::Gba(patch) => patch,
+            p => panic!("expected a GBA patch, got {:?}", p),
+        };
+        assert_eq!(patch.records.len(), 1);
+        assert_eq!(patch.records[0].offset, 0x10000);
+        assert_eq!(patch.apply(&src).unwrap(), dst);
+        let ups = ApsPatch::Gba(patch.clone()).to_ups(&src).unwrap();
+        assert_eq!(ups.revert(&dst).unwrap(), src);
+
+        let mut wrong = src.clone();
+        wrong[0x10000] ^= 1;
+        let errs = patch.apply(&wrong).unwrap_err();
+        assert!(errs.iter().any(|e| matches!(
+            e,
+            UpsPatchError::SourceMetadataMismatch(MetadataMismatch::Checksum { .. })
+        )));
+        let errs = patch.apply(&src[1..]).unwrap_err();
+        assert!(errs.iter().any(|e| matches!(
+            e,
+            UpsPatchError::SourceMetadataMismatch(MetadataMismatch::Size { .. })
+        )));
+    }
+
+    #[test]
+    fn test_gba_size_starting_with_zero_digit() {
+        let src = vec![1; 0x30];
+        let mut dst = src.clone();
+        dst[0] = 2;
+        let raw = gba_patch(&src, &dst);
+        assert!(raw.starts_with(N64_MAGIC));
+        let patch = ApsPatch::parse(&raw).unwrap();
+        assert!(matches!(patch, ApsPatch::Gba(_)));
+        assert_eq!(patch.apply(&src).unwrap(), dst);
+    }
+
+    #[test]
+    fn test_n64() {
+        let rom = n64_rom();
+        let patch = ApsPatch::parse(&n64_patch(true, 0x48)).unwrap();
+        let n64 = match &patch {
+            ApsPatch::N64(patch) => patch,
+            p => panic!("expected an N64 patch, got {:?}", p),
+        };
+        assert_eq!(n64.description, "Test patch");
+        assert_eq!(n64.rom_header.unwrap().cart_id, *b"NSM");
+        let output = patch.apply(&rom).unwrap();
+        assert_eq!(output.len(), 0x48);
+        assert_eq!(&output[2..5], b"abc");
+        assert_eq!(&output[0x40..], b"zzzz\xff\xff\xff\xff");
+
+        let mut other_rom = rom.clone();
+        other_rom[N64_CART_ID_OFFSET] = b'X';
+        let errs = patch.apply(&other_rom).unwrap_err();
+        assert!(errs.iter().any(|e| matches!(
+            e,
+            UpsPatchError::SourceMetadataMismatch(MetadataMismatch::Checksum { .. })
+        )));
+
+        // Without the ROM header any file can be patched.
+        let patch = ApsPatch::parse(&n64_patch(false, 8)).unwrap();
+        assert_eq!(patch.apply(b"01234567").unwrap(), b"01abc567");
+    }
+
+    #[test]
+    fn test_unjustified_dst_size() {
+        let mut raw = GBA_MAGIC.to_vec();
+        raw.extend_from_slice(&4u32.to_le_bytes());
+        raw.extend_from_slice(&u32::MAX.to_le_bytes());
+        let patch = ApsPatch::parse(&raw).unwrap();
+        let errs = patch.apply(b"rom!").unwrap_err();
+        assert_eq!(errs.output, b"rom!");
+        assert!(errs.iter().any(|e| matches!(
+            e,
+            UpsPatchError::DestMetadataMismatch(MetadataMismatch::Size { .. })
+        )));
+
+        let patch = ApsPatch::parse(&n64_patch(false, u32::MAX)).unwrap();
+        let errs = patch.apply(b"01234567").unwrap_err();
+        assert!(errs.output.len() < 0x100, "{} bytes", errs.output.len());
+        assert!(errs.iter().any(|e| matches!(
+            e,
+            UpsPatchError::DestMetadataMismatch(MetadataMismatch::Size { .. })
+        )));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let n64 = n64_patch(true, 0x48);
+        let gba = gba_patch(&[1; 16], &[2; 16]);
+        for raw in [
+            &b""[..],
+            b"UPS1",
+            b"APS1",
+            &n64[..n64.len() - 1],
+            &n64[..60],
+            &gba[..gba.len() - 1],
+        ] {
+            assert!(
+                matches!(ApsPatch::parse(raw), Err(ApsParseError::FormatMismatch(_))),
+                "{:?}",
+                &raw[..std::cmp::min(raw.len(), 16)]
+            );
+        }
+    }
+}
@@ -0,0 +1,695 @@
+//! Parse, apply and create BPS patches, the successor of UPS from the same author.
+//!
+//! BPS patches build the output from a list of actions copying data from the source file, the
+//! patch or the output written so far, so they're much smaller than UPS patches when data moves
+//! around. [`BpsPatch::diff`] finds those copies. Like UPS they have source, destination and patch
+//! checksums, which are checked the same way as [`Patch`]'s: [`BpsPatch::parse`] fails if the
+//! patch is corrupted and [`BpsPatch::apply`] returns [`UpsPatchErrors`] if the source or output
+//! don't match. BPS patches can't be reverted.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::fs;
+//! use ups::bps::BpsPatch;
+//!
+//! let rom = fs::read("samples/rom.bin")?;
+//! let patch = BpsPatch::parse(&fs::read("samples/patch.bps")?)?;
+//! fs::write("patched.bin", patch.apply(&rom)?)?;
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! # Reference
+//!
+//! https://github.com/blakesmith/rombp/blob/master/docs/bps_spec.md
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Formatter};
+
+use crate::{varint, Checksum, MetadataMismatch, Patch, UpsPatchError, UpsPatchErrors};
+
+const MAGIC: &[u8] = b"BPS1";
+// Source, destination and patch checksums.
+const FOOTER_LEN: usize = 12;
+
+// Shortest copy worth an action, shorter ones cost about as much as storing the bytes.
+const MIN_MATCH: usize = 4;
+// Candidates tried per hash chain, trades patch size for speed on repetitive data.
+const MAX_CHAIN: usize = 64;
+// Hash table sizes, in bits, scaled with the input size so chains stay short on varied data.
+const MIN_HASH_BITS: u32 = 12;
+const MAX_HASH_BITS: u32 = 24;
+const NO_POS: u32 = u32::MAX;
+
+/// BPS patch contents, see the [module docs](self).
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct BpsPatch {
+    /// Actions building the destination file, in order.
+    pub actions: Vec<BpsAction>,
+    /// Free-form metadata, usually empty or XML.
+    pub metadata: Vec<u8>,
+    /// Source file size.
+    pub src_size: usize,
+    /// Source file checksum.
+    pub src_checksum: Checksum,
+    /// Destination file size.
+    pub dst_size: usize,
+    /// Destination file checksum.
+    pub dst_checksum: Checksum,
+}
+
+/// Action in a [`BpsPatch`], each one appends `len` bytes to the output.
+///
+/// Copy offsets are absolute, resolved from the relative offsets in the patch file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BpsAction {
+    /// Copy bytes from the source at the current output offset.
+    SourceRead { len: usize },
+    /// Copy bytes from the patch.
+    TargetRead { data: Vec<u8> },
+    /// Copy bytes from the source at `offset`.
+    SourceCopy { offset: usize, len: usize },
+    /// Copy bytes from the output at `offset`, which may overlap the bytes being written to repeat
+    /// a pattern.
+    TargetCopy { offset: usize, len: usize },
+}
+
+/// Possible errors when parsing a BPS patch file.
+#[derive(thiserror::Error, Debug)]
+pub enum BpsParseError {
+    #[error("this doesn't seem to be a BPS file: {}", .0)]
+    FormatMismatch(String),
+    /// Calculated patch checksum doesn't match the one from the patch metadata. You can access the
+    /// patch in `parsed_patch` in case you want to ignore checksum errors.
+    #[error(
+        "checksum mismatch for patch file: expected {}, got {}",
+        .expected, .actual,
+    )]
+    PatchChecksumMismatch {
+        parsed_patch: BpsPatch,
+        expected: Checksum,
+        actual: Checksum,
+    },
+}
+
+impl BpsAction {
+    /// Number of bytes appended to the output.
+    pub fn len(&self) -> usize {
+        match self {
+            BpsAction::TargetRead { data } => data.len(),
+            BpsAction::SourceRead { len }
+            | BpsAction::SourceCopy { len, .. }
+            | BpsAction::TargetCopy { len, .. } => *len,
+        }
+    }
+
+    /// Whether the action appends nothing, never the case for parsed patches.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl BpsPatch {
+    /// Parse a BPS patch, checking copies stay within the source and the output written so far.
+    pub fn parse(input: &[u8]) -> Result<Self, BpsParseError> {
+        let err = |reason: String| BpsParseError::FormatMismatch(reason);
+        if !input.starts_with(MAGIC) {
+            return Err(err("invalid preamble, expected \"BPS1\"".into()));
+        }
+        if input.len() < MAGIC.len() + FOOTER_LEN {
+            return Err(err("file too small".into()));
+        }
+        let (body, footer) = input.split_at(input.len() - FOOTER_LEN);
+        let checksum_at = |i: usize| {
+            let bytes = <[u8; 4]>::try_from(&footer[i..i + 4]).unwrap();
+            Checksum(u32::from_le_bytes(bytes))
+        };
+        let (src_checksum, dst_checksum, patch_checksum) =
+            (checksum_at(0), checksum_at(4), checksum_at(8));
+
+        let mut buf = &body[MAGIC.len()..];
+        let mut read_header = |field: &str| {
+            varint::read(&mut buf).ok_or_else(|| err(format!("couldn't read {}", field)))
+        };
+        let src_size = read_header("source size")?;
+        let dst_size = read_header("destination size")?;
+        let metadata_len = read_header("metadata size")?;
+        if metadata_len > buf.len() {
+            return Err(err("metadata past the end of the file".into()));
+        }
+        let (metadata, mut buf) = buf.split_at(metadata_len);
+
+        let mut actions = Vec::new();
+        let mut output_len = 0usize;
+        let mut src_offset = 0usize;
+        let mut dst_offset = 0usize;
+        while !buf.is_empty() {
+            let index = actions.len();
+            let action_err = |reason: &str| err(format!("action {}: {}", index, reason));
+            let data = varint::read(&mut buf).ok_or_else(|| action_err("truncated"))?;
+            let len = (data >> 2) + 1;
+            let end = output_len
+                .checked_add(len)
+                .filter(|&end| end <= dst_size)
+                .ok_or_else(|| action_err("writes past the destination size"))?;
+            let action = match data & 3 {
+                0 => {
+                    if end > src_size {
+                        return Err(action_err("reads past the source size"));
+                    }
+                    BpsAction::SourceRead { len }
+                }
+                1 => {
+                    if len > buf.len() {
+                        return Err(action_err("data past the end of the file"));
+                    }
+                    let (data, rest) = buf.split_at(len);
+                    buf = rest;
+                    BpsAction::TargetRead {
+                        data: data.to_vec(),
+                    }
+                }
+                kind => {
+                    let (base, limit) = if kind == 2 {
+                        (&mut src_offset, src_size)
+                    } else {
+                        (&mut dst_offset, output_len)
+                    };
+                    let offset = read_relative(&mut buf, *base)
+                        .ok_or_else(|| action_err("invalid copy offset"))?;
+                    // Target copies may overlap the output being written, as long as they start
+                    // before it.
+                    let copy_end = offset.checked_add(len);
+                    let in_bounds = if kind == 2 {
+                        copy_end.is_some_and(|copy_end| copy_end <= limit)
+                    } else {
+                        copy_end.is_some() && offset < limit
+                    };
+                    if !in_bounds {
+                        return Err(action_err("copies from out of bounds"));
+                    }
+                    *base = offset + len;
+                    if kind == 2 {
+                        BpsAction::SourceCopy { offset, len }
+                    } else {
+                        BpsAction::TargetCopy { offset, len }
+                    }
+                }
+            };
+            actions.push(action);
+            output_len = end;
+        }
+        if output_len != dst_size {
+            return Err(err(format!(
+                "actions write {} bytes, expected {}",
+                output_len, dst_size
+            )));
+        }
+
+        let patch = BpsPatch {
+            actions,
+            metadata: metadata.to_vec(),
+            src_size,
+            src_checksum,
+            dst_size,
+            dst_checksum,
+        };
+        let actual = Checksum::from_bytes(&input[..input.len() - 4]);
+        if actual != patch_checksum {
+            return Err(BpsParseError::PatchChecksumMismatch {
+                parsed_patch: patch,
+                expected: patch_checksum,
+                actual,
+            });
+        }
+        Ok(patch)
+    }
+
+    /// Create a patch turning `src` into `dst`.
+    ///
+    /// Each action is the match at the current output offset saving the most bytes among the
+    /// source at the same offset, earlier source data and earlier output, found with hash chains,
+    /// so inserted, removed and moved data only costs the copy actions. Copies only start in the
+    /// first 4 GiB of each file.
+    pub fn diff(src: &[u8], dst: &[u8]) -> Self {
+        let size = std::cmp::max(src.len(), dst.len());
+        let hash_bits = (usize::BITS - size.leading_zeros()).clamp(MIN_HASH_BITS, MAX_HASH_BITS);
+        let src_chains = HashChains::new(src, src.len(), hash_bits);
+        let mut dst_chains = HashChains::new(dst, 0, hash_bits);
+        let mut actions = Vec::new();
+        let mut literal_start = 0;
+        let mut pos = 0;
+        // Source offset minus output offset of the last source copy. Data after a small change
+        // in a moved region usually continues at the same shift.
+        let mut shift = 0isize;
+        // Bases of the relative offsets, see `serialize`.
+        let mut src_base = 0;
+        let mut dst_base = 0;
+        while pos < dst.len() {
+            let rest = &dst[pos..];
+            let mut best = match src.get(pos..) {
+                Some(src_rest) => (match_len(src_rest, rest), BpsAction::SourceRead { len: 0 }),
+                None => (0, BpsAction::SourceRead { len: 0 }),
+            };
+            let shifted = (pos as isize)
+                .checked_add(shift)
+                .map(|offset| offset as usize);
+            let candidates = shifted
+                .filter(|&offset| shift != 0 && offset < src.len())
+                .into_iter()
+                .chain(src_chains.candidates(src, rest));
+            // Copies are scored by the bytes they save, minus the size of their offset.
+            for offset in candidates {
+                let len = match_len(&src[offset..], rest);
+                let gain = len.saturating_sub(relative_len(offset, src_base));
+                if gain > best.0 {
+                    best = (gain, BpsAction::SourceCopy { offset, len });
+                }
+            }
+            for offset in dst_chains.candidates(dst, rest) {
+                let len = match_len(&dst[offset..], rest);
+                let gain = len.saturating_sub(relative_len(offset, dst_base));
+                if gain > best.0 {
+                    best = (gain, BpsAction::TargetCopy { offset, len });
+                }
+            }
+
+            let (gain, action) = best;
+            if gain < MIN_MATCH {
+                dst_chains.insert(dst, pos);
+                pos += 1;
+                continue;
+            }
+            if literal_start < pos {
+                actions.push(BpsAction::TargetRead {
+                    data: dst[literal_start..pos].to_vec(),
+                });
+            }
+            let action = match action {
+                BpsAction::SourceRead { .. } => BpsAction::SourceRead { len: gain },
+                BpsAction::SourceCopy { offset, len } => {
+                    shift = offset as isize - pos as isize;
+                    src_base = offset + len;
+                    action
+                }
+                BpsAction::TargetCopy { offset, len } => {
+                    dst_base = offset + len;
+                    action
+                }
+                BpsAction::TargetRead { .. } => unreachable!("literals aren't matches"),
+            };
+            let len = action.len();
+            actions.push(action);
+            for p in pos..pos + len {
+                dst_chains.insert(dst, p);
+            }
+            pos += len;
+            literal_start = pos;
+        }
+        if literal_start < dst.len() {
+            actions.push(BpsAction::TargetRead {
+                data: dst[literal_start..].to_vec(),
+            });
+        }
+
+        BpsPatch {
+            actions,
+            metadata: Vec::new(),
+            src_size: src.len(),
+            src_checksum: Checksum::from_bytes(src),
+            dst_size: dst.len(),
+            dst_checksum: Checksum::from_bytes(dst),
+        }
+    }
+
+    /// Serialize this patch as a BPS file.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        varint::write(&mut bytes, self.src_size);
+        varint::write(&mut bytes, self.dst_size);
+        varint::write(&mut bytes, self.metadata.len());
+        bytes.extend_from_slice(&self.metadata);
+        let mut src_offset = 0;
+        let mut dst_offset = 0;
+        for action in &self.actions {
+            let (kind, len) = match action {
+                BpsAction::SourceRead { len } => (0, *len),
+                BpsAction::TargetRead { data } => (1, data.len()),
+                BpsAction::SourceCopy { len, .. } => (2, *len),
+                BpsAction::TargetCopy { len, .. } => (3, *len),
+            };
+            varint::write(&mut bytes, ((len - 1) << 2) | kind);
+            match action {
+                BpsAction::SourceRead { .. } => {}
+                BpsAction::TargetRead { data } => bytes.extend_from_slice(data),
+                BpsAction::SourceCopy { offset, len } => {
+                    write_relative(&mut bytes, *offset, src_offset);
+                    src_offset = offset + len;
+                }
+                BpsAction::TargetCopy { offset, len } => {
+                    write_relative(&mut bytes, *offset, dst_offset);
+                    dst_offset = offset + len;
+                }
+            }
+        }
+        bytes.extend_from_slice(&self.src_checksum.0.to_le_bytes());
+        bytes.extend_from_slice(&self.dst_checksum.0.to_le_bytes());
+        let checksum = Checksum::from_bytes(&bytes);
+        bytes.extend_from_slice(&checksum.0.to_le_bytes());
+        bytes
+    }
+
+    /// Apply patch to source data. Returns the contents of the patched file.
+    ///
+    /// Source size and checksum mismatches are returned as
+    /// [`SourceMetadataMismatch`](UpsPatchError::SourceMetadataMismatch) errors and output ones as
+    /// [`DestMetadataMismatch`](UpsPatchError::DestMetadataMismatch). Sources shorter than
+    /// expected are read as if padded with zeroes.
+    pub fn apply(&self, src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors> {
+        let mut errors = Vec::new();
+        if let Some(err) = MetadataMismatch::size(self.src_size, src.len()) {
+            errors.push(UpsPatchError::SourceMetadataMismatch(err));
+        }
+        if let Some(err) = MetadataMismatch::checksum(self.src_checksum, Checksum::from_bytes(src))
+        {
+            errors.push(UpsPatchError::SourceMetadataMismatch(err));
+        }
+
+        let src = if src.len() < self.src_size {
+            let mut padded = src.to_vec();
+            padded.resize(self.src_size, 0);
+            Cow::Owned(padded)
+        } else {
+            Cow::Borrowed(src)
+        };
+        let mut output = Vec::with_capacity(self.dst_size);
+        for action in &self.actions {
+            match action {
+                BpsAction::SourceRead { len } => {
+                    let start = output.len();
+                    output.extend_from_slice(&src[start..start + len]);
+                }
+                BpsAction::TargetRead { data } => output.extend_from_slice(data),
+                BpsAction::SourceCopy { offset, len } => {
+                    output.extend_from_slice(&src[*offset..offset + len]);
+                }
+                // Byte by byte, the copy may read what it just wrote.
+                BpsAction::TargetCopy { offset, len } => {
+                    for i in *offset..offset + len {
+                        output.push(output[i]);
+                    }
+                }
+            }
+        }
+
+        if let Some(err) =
+            MetadataMismatch::checksum(self.dst_checksum, Checksum::from_bytes(&output))
+        {
+            errors.push(UpsPatchError::DestMetadataMismatch(err));
+        }
+        UpsPatchErrors::check_errors(output, errors)
+    }
+
+    /// Equivalent UPS patch for `src`, which can be reverted.
+    pub fn to_ups(&self, src: &[u8]) -> Result<Patch, UpsPatchErrors> {
+        Ok(Patch::diff(src, &self.apply(src)?))
+    }
+}
+
+impl Debug for BpsPatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("BpsPatch")
+            .field("actions", &self.actions.len())
+            .field("metadata", &String::from_utf8_lossy(&self.metadata))
+            .field("src_size", &self.src_size)
+            .field("src_checksum", &self.src_checksum)
+            .field("dst_size", &self.dst_size)
+            .field("dst_checksum", &self.dst_checksum)
+            .finish()
+    }
+}
+
+// Relative offsets are varints with the sign in the lowest bit, `base` plus the offset must not
+// be negative.
+fn read_relative(buf: &mut &[u8], base: usize) -> Option<usize> {
+    let data = varint::read(buf)?;
+    if data & 1 == 0 {
+        base.checked_add(data >> 1)
+    } else {
+        base.checked_sub(data >> 1)
+    }
+}
+
+fn write_relative(buf: &mut Vec<u8>, offset: usize, base: usize) {
+    if offset >= base {
+        varint::write(buf, (offset - base) << 1);
+    } else {
+        varint::write(buf, ((base - offset) << 1) | 1);
+    }
+}
+
+// Encoded size of the relative offset `write_relative` writes.
+fn relative_len(offset: usize, base: usize) -> usize {
+    varint::encoded_len(offset.abs_diff(base) << 1)
+}
+
+fn match_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+// Positions of `MIN_MATCH`-byte prefixes by hash, each chain from the latest position back.
+struct HashChains {
+    heads: Vec<u32>,
+    prev: Vec<u32>,
+    bits: u32,
+}
+
+impl HashChains {
+    // Chains for `data` with the first `len` positions inserted, in a table of `2^bits` chains.
+    fn new(data: &[u8], len: usize, bits: u32) -> Self {
+        let mut chains = HashChains {
+            heads: vec![NO_POS; 1 << bits],
+            prev: vec![NO_POS; std::cmp::min(data.len(), NO_POS as usize)],
+            bits,
+        };
+        for pos in 0..len {
+            chains.insert(data, pos);
+        }
+        chains
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos < self.prev.len() && pos + MIN_MATCH <= data.len() {
+            let hash = self.hash(&data[pos..]);
+            self.prev[pos] = self.heads[hash];
+            self.heads[hash] = pos as u32;
+        }
+    }
+
+    // Positions which may start with the same bytes as `rest`.
+    fn candidates<'a>(
+        &'a self,
+        data: &'a [u8],
+        rest: &'a [u8],
+    ) -> impl Iterator<Item = usize> + 'a {
+        let head = if rest.len() >= MIN_MATCH {
+            self.heads[self.hash(rest)]
+        } else {
+            NO_POS
+        };
+        let valid = |pos: u32| Some(pos).filter(|&pos| pos != NO_POS);
+        std::iter::successors(valid(head), move |&pos| valid(self.prev[pos as usize]))
+            .take(MAX_CHAIN)
+            .map(|pos| pos as usize)
+            .filter(move |&pos| data[pos..pos + MIN_MATCH] == rest[..MIN_MATCH])
+    }
+
+    fn hash(&self, bytes: &[u8]) -> usize {
+        let prefix = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        (prefix.wrapping_mul(0x9e37_79b1) >> (32 - self.bits)) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    // Minimal BPS encoder for the tests, `actions` are `(command, len, payload)` with the raw
+    // relative offset or data as payload.
+    fn encode(src: &[u8], dst: &[u8], actions: &[(usize, usize, &[u8])]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        varint::write(&mut out, src.len());
+        varint::write(&mut out, dst.len());
+        varint::write(&mut out, 0);
+        for &(command, len, payload) in actions {
+            varint::write(&mut out, ((len - 1) << 2) | command);
+            out.extend_from_slice(payload);
+        }
+        out.extend_from_slice(&Checksum::from_bytes(src).0.to_le_bytes());
+        out.extend_from_slice(&Checksum::from_bytes(dst).0.to_le_bytes());
+        let checksum = Checksum::from_bytes(&out);
+        out.extend_from_slice(&checksum.0.to_le_bytes());
+        out
+    }
+
+    fn relative(offset: isize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let sign = usize::from(offset < 0);
+        varint::write(&mut out, (offset.unsigned_abs() << 1) | sign);
+        out
+    }
+
+    fn sample() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let src = b"hello world".to_vec();
+        let dst = b"hello, abababab worldhello ".to_vec();
+        let raw = encode(
+            &src,
+            &dst,
+            &[
+                (0, 5, &[]),
+                (1, 4, b", ab"),
+                (3, 6, &relative(7)),
+                (2, 6, &relative(5)),
+                (2, 6, &relative(-11)),
+            ],
+        );
+        (src, dst, raw)
+    }
+
+    #[test]
+    fn test_parse_and_apply() {
+        let (src, dst, raw) = sample();
+        let patch = BpsPatch::parse(&raw).unwrap();
+        assert_eq!(
+            patch.actions,
+            vec![
+                BpsAction::SourceRead { len: 5 },
+                BpsAction::TargetRead {
+                    data: b", ab".to_vec()
+                },
+                BpsAction::TargetCopy { offset: 7, len: 6 },
+                BpsAction::SourceCopy { offset: 5, len: 6 },
+                BpsAction::SourceCopy { offset: 0, len: 6 },
+            ],
+        );
+        assert_eq!(patch.src_size, src.len());
+        assert_eq!(patch.dst_checksum, Checksum::from_bytes(&dst));
+        assert_eq!(patch.apply(&src).unwrap(), dst);
+
+        let ups = patch.to_ups(&src).unwrap();
+        assert_eq!(ups.revert(&dst).unwrap(), src);
+    }
+
+    #[test]
+    fn test_apply_wrong_source() {
+        let (src, _, raw) = sample();
+        let patch = BpsPatch::parse(&raw).unwrap();
+        let errs = patch.apply(b"HELLO WORLD").unwrap_err();
+        assert_eq!(errs.output.len(), patch.dst_size);
+        assert!(errs.iter().any(|e| matches!(
+            e,
+            UpsPatchError::SourceMetadataMismatch(MetadataMismatch::Checksum { .. })
+        )));
+        assert!(errs
+            .iter()
+            .any(|e| matches!(e, UpsPatchError::DestMetadataMismatch(_))));
+
+        // Short sources are padded instead of panicking.
+        let errs = patch.apply(&src[..3]).unwrap_err();
+        assert_eq!(errs.output.len(), patch.dst_size);
+    }
+
+    #[test]
+    fn test_parse_checksum_mismatch() {
+        let (_, _, mut raw) = sample();
+        let len = raw.len();
+        raw[len - 1] ^= 0xff;
+        assert!(matches!(
+            BpsPatch::parse(&raw),
+            Err(BpsParseError::PatchChecksumMismatch { .. }),
+        ));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let src = b"abcd";
+        let dst = b"abcdabcd";
+        for actions in [
+            // Source read past the source size.
+            &[(0, 8, &b""[..])][..],
+            // Target copy from data not written yet.
+            &[(0, 4, b""), (3, 4, &relative(4))],
+            // Source copy before the start.
+            &[(0, 4, b""), (2, 4, &relative(-1))],
+            // Output shorter than the destination size.
+            &[(0, 4, b"")],
+        ] {
+            let raw = encode(src, dst, actions);
+            assert!(
+                matches!(BpsPatch::parse(&raw), Err(BpsParseError::FormatMismatch(_))),
+                "{:?}",
+                actions,
+            );
+        }
+        assert!(matches!(
+            BpsPatch::parse(b"UPS1"),
+            Err(BpsParseError::FormatMismatch(_)),
+        ));
+    }
+
+    #[test]
+    fn test_diff_roundtrip() {
+        let (src, dst, _) = sample();
+        let patch = BpsPatch::diff(&src, &dst);
+        let parsed = BpsPatch::parse(&patch.serialize()).unwrap();
+        assert_eq!(parsed, patch);
+        assert_eq!(parsed.apply(&src).unwrap(), dst);
+
+        for (src, dst) in [(&b""[..], &b""[..]), (b"abc", b""), (b"", b"abcabcabc")] {
+            let patch = BpsPatch::parse(&BpsPatch::diff(src, dst).serialize()).unwrap();
+            assert_eq!(patch.apply(src).unwrap(), dst);
+        }
+    }
+
+    #[test]
+    fn test_diff_shifted_data() {
+        let src: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut dst = src.clone();
+        dst.splice(50_000..50_000, b"inserted".iter().copied());
+        dst.drain(1000..1010);
+        let patch = BpsPatch::diff(&src, &dst);
+        let serialized = patch.serialize();
+        assert_eq!(
+            BpsPatch::parse(&serialized).unwrap().apply(&src).unwrap(),
+            dst
+        );
+        // UPS stores the whole shifted region.
+        assert!(serialized.len() < 100, "{} bytes", serialized.len());
+        assert!(Patch::diff(&src, &dst).serialize().len() > 10_000);
+    }
+
+    proptest! {
+        #[test]
+        fn test_diff_matches_dst(
+            src in vec(0..4u8, 0..2000),
+            pieces in vec((any::<bool>(), 0..2000usize, 1..100usize), 0..20),
+        ) {
+            // Destination made of source slices and runs of new data, so there's something to copy.
+            let mut dst = Vec::new();
+            for (from_src, start, len) in pieces {
+                if from_src && !src.is_empty() {
+                    let start = start % src.len();
+                    dst.extend_from_slice(&src[start..std::cmp::min(start + len, src.len())]);
+                } else {
+                    dst.extend((0..len).map(|i| (start + i) as u8));
+                }
+            }
+            let patch = BpsPatch::parse(&BpsPatch::diff(&src, &dst).serialize()).unwrap();
+            prop_assert_eq!(patch.apply(&src).unwrap(), dst);
+        }
+    }
+}
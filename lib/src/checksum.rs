@@ -1,20 +1,150 @@
 use std::fmt::{self, Debug, Display, Formatter, LowerHex, UpperHex};
+use std::io::{self, Read};
 
 use crc32fast::Hasher;
 
 /// A CRC-32 checksum.
+///
+/// `Display` and the hex formats print its bytes in the order they're stored in UPS patches, see
+/// [`display`](Checksum::display) for the other order.
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Checksum(pub u32);
 
 impl Checksum {
     /// Calculate `data` checksum.
     pub fn from_bytes(data: &[u8]) -> Self {
         let mut hasher = Hasher::new();
-        hasher.update(&data);
+        hasher.update(data);
         Checksum(hasher.finalize())
     }
+
+    /// Calculate the checksum of everything read from `reader`, without holding it in memory.
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut hasher = Hasher::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(Checksum(hasher.finalize())),
+                Ok(n) => hasher.update(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Checksum of `a` followed by `b`, given the checksums of both and the length of `b`.
+    ///
+    /// This allows hashing pieces of some data independently, e.g. in parallel, and getting the
+    /// checksum of the whole without reading it again.
+    pub fn combine(a: Checksum, b: Checksum, len_b: usize) -> Self {
+        let mut hasher = Hasher::new_with_initial(a.0);
+        hasher.combine(&Hasher::new_with_initial_len(b.0, len_b as u64));
+        Checksum(hasher.finalize())
+    }
+
+    /// Checksum of the data hashed by `self` followed by `len` zero bytes, in `O(log len)` time.
+    pub fn extend_with_zeros(self, len: usize) -> Self {
+        // Combining with a zero checksum shifts the CRC register as if it was fed `len` zeros. The
+        // register is the checksum without its final inversion, hence the NOTs around the shift.
+        let mut hasher = Hasher::new_with_initial(!self.0);
+        hasher.combine(&Hasher::new_with_initial_len(0, len as u64));
+        Checksum(!hasher.finalize())
+    }
+
+    /// Checksum of the data hashed by `self` followed by `data`.
+    pub fn extend(self, data: &[u8]) -> Self {
+        let mut hasher = Hasher::new_with_initial(self.0);
+        hasher.update(data);
+        Checksum(hasher.finalize())
+    }
+
+    /// Display the checksum with its bytes in `order`, e.g. `0x1716A644` in
+    /// [`BigEndian`](ChecksumOrder::BigEndian) for `0x44A61617` in
+    /// [`LittleEndian`](ChecksumOrder::LittleEndian).
+    pub fn display(self, order: ChecksumOrder) -> ChecksumDisplay {
+        ChecksumDisplay {
+            checksum: self,
+            order,
+        }
+    }
+
+    /// Checksum of the data hashed by `self` without `suffix`, which must be its last bytes.
+    /// Inverse of [`extend`](Checksum::extend).
+    pub fn remove_suffix(self, suffix: &[u8]) -> Self {
+        // Run the bitwise CRC register updates backwards. Forwards, a register with its low bit
+        // set is shifted right and XORed with the polynomial, which sets the high bit.
+        let mut register = !self.0;
+        for byte in suffix.iter().rev() {
+            for _ in 0..8 {
+                register = if register & 0x8000_0000 != 0 {
+                    ((register ^ CRC32_POLY) << 1) | 1
+                } else {
+                    register << 1
+                };
+            }
+            register ^= u32::from(*byte);
+        }
+        Checksum(!register)
+    }
+}
+
+/// Byte order to display a [`Checksum`] in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ChecksumOrder {
+    /// Least significant byte first, the order of the bytes in UPS patch files and of `Display`.
+    #[default]
+    LittleEndian,
+    /// Most significant byte first, the CRC32 value as shown by No-Intro, emulators and most
+    /// hashing tools.
+    BigEndian,
+}
+
+/// Checksum formatted in a given byte order, see [`Checksum::display`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumDisplay {
+    checksum: Checksum,
+    order: ChecksumOrder,
+}
+
+impl Display for ChecksumDisplay {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.order {
+            ChecksumOrder::LittleEndian => UpperHex::fmt(&self.checksum, f),
+            ChecksumOrder::BigEndian => write!(f, "0x{:08X}", self.checksum.0),
+        }
+    }
+}
+
+/// Serializes a checksum as hex strings in both byte orders, for comparing by eye, e.g.
+/// `{"le": "0x44A61617", "be": "0x1716A644"}`. See [`ChecksumOrder`].
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Copy)]
+pub struct SerializeChecksumHex(pub Checksum);
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for SerializeChecksumHex {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("ChecksumHex", 2)?;
+        s.serialize_field(
+            "le",
+            &self.0.display(ChecksumOrder::LittleEndian).to_string(),
+        )?;
+        s.serialize_field("be", &self.0.display(ChecksumOrder::BigEndian).to_string())?;
+        s.end()
+    }
 }
 
+/// Reversed CRC-32 polynomial.
+const CRC32_POLY: u32 = 0xedb8_8320;
+
 impl Debug for Checksum {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "Checksum({:x})", self)
@@ -46,3 +176,75 @@ impl UpperHex for Checksum {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let checksum = Checksum(0x1716_a644);
+        assert_eq!(checksum.to_string(), "0x44A61617");
+        assert_eq!(
+            checksum.display(ChecksumOrder::LittleEndian).to_string(),
+            "0x44A61617"
+        );
+        assert_eq!(
+            checksum.display(ChecksumOrder::BigEndian).to_string(),
+            "0x1716A644"
+        );
+        assert_eq!(
+            Checksum(0xff).display(ChecksumOrder::BigEndian).to_string(),
+            "0x000000FF"
+        );
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(
+            Checksum::from_reader(&data[..]).unwrap(),
+            Checksum::from_bytes(&data),
+        );
+        assert_eq!(
+            Checksum::from_reader(io::empty()).unwrap(),
+            Checksum::from_bytes(&[])
+        );
+    }
+
+    #[test]
+    fn test_combine() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        for split in [0, 1, 1234, 5000] {
+            let (a, b) = data.split_at(split);
+            assert_eq!(
+                Checksum::combine(Checksum::from_bytes(a), Checksum::from_bytes(b), b.len()),
+                Checksum::from_bytes(&data),
+            );
+        }
+    }
+
+    #[test]
+    fn test_extend_and_remove_suffix() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        for split in [0, 1, 999, 1000] {
+            let (prefix, suffix) = data.split_at(split);
+            let prefix_checksum = Checksum::from_bytes(prefix);
+            let checksum = Checksum::from_bytes(&data);
+            assert_eq!(prefix_checksum.extend(suffix), checksum);
+            assert_eq!(checksum.remove_suffix(suffix), prefix_checksum);
+        }
+    }
+
+    #[test]
+    fn test_extend_with_zeros() {
+        for (prefix, zeros) in [(&b""[..], 0), (b"", 100), (b"abc", 0), (b"abc", 70_000)] {
+            let mut data = prefix.to_vec();
+            data.resize(prefix.len() + zeros, 0);
+            assert_eq!(
+                Checksum::from_bytes(prefix).extend_with_zeros(zeros),
+                Checksum::from_bytes(&data),
+            );
+        }
+    }
+}
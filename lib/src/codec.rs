@@ -0,0 +1,305 @@
+//! Pluggable patch formats.
+//!
+//! [`PatchCodec`] is the common interface of patch formats: detecting and parsing patches, and
+//! diffing files for formats which can be written. Parsed patches are [`CodecPatch`]es, which can be
+//! applied, reverted and serialized when the format allows it, and converted to UPS patches.
+//!
+//! Every [`PatchFormat`] is a codec, and [`Patch`] and [`AnyPatch`] are codec patches. Downstream
+//! tools can implement the traits for their own formats and add them to a [`CodecRegistry`], to
+//! handle them along with the built-in formats.
+//!
+//! ## Example
+//!
+//! ```
+//! use ups::codec::CodecRegistry;
+//!
+//! let registry = CodecRegistry::new();
+//! let codec = registry.by_extension("ips").unwrap();
+//! assert_eq!(codec.name(), "IPS");
+//!
+//! let patch = registry.parse(b"PATCH\x00\x00\x01\x00\x02hiEOF")?;
+//! assert_eq!(patch.apply(b"abcd")?, b"ahid");
+//! assert!(patch.revert(b"ahid").is_none());
+//!
+//! # Ok::<_, ups::codec::CodecError>(())
+//! ```
+use std::error::Error;
+use std::fmt::Debug;
+
+use crate::bps::BpsPatch;
+use crate::format::parse_as;
+use crate::{detect_format, AnyParseError, AnyPatch, Patch, PatchFormat, UpsPatchErrors};
+
+/// Error from [`PatchCodec::parse`], each format has its own error type.
+pub type CodecError = Box<dyn Error + Send + Sync>;
+
+/// Patch format, see the [module docs](self).
+pub trait PatchCodec: Send + Sync {
+    /// Display name of the format, e.g. "IPS".
+    fn name(&self) -> &str;
+
+    /// Lowercase file extensions of the format, without the dot.
+    fn extensions(&self) -> &[&str];
+
+    /// Whether `raw_patch` looks like a patch in this format, usually going by its magic bytes.
+    /// It may still fail to parse.
+    fn detect(&self, raw_patch: &[u8]) -> bool;
+
+    /// Parse a patch in this format.
+    fn parse(&self, raw_patch: &[u8]) -> Result<Box<dyn CodecPatch>, CodecError>;
+
+    /// Patch turning `src` into `dst`, `None` for formats this crate can't write.
+    fn diff(&self, src: &[u8], dst: &[u8]) -> Option<Box<dyn CodecPatch>> {
+        let _ = (src, dst);
+        None
+    }
+}
+
+/// Patch parsed by a [`PatchCodec`].
+pub trait CodecPatch: Debug + Send + Sync {
+    /// Apply the patch to `src`.
+    fn apply(&self, src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors>;
+
+    /// Revert the patch from `dst`, `None` if the patch doesn't have the data to do so.
+    fn revert(&self, dst: &[u8]) -> Option<Result<Vec<u8>, UpsPatchErrors>> {
+        let _ = dst;
+        None
+    }
+
+    /// Serialize the patch in its format, `None` for formats this crate can't write.
+    fn serialize(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Equivalent UPS patch for `src`.
+    fn to_ups(&self, src: &[u8]) -> Result<Patch, UpsPatchErrors>;
+}
+
+impl PatchCodec for PatchFormat {
+    fn name(&self) -> &str {
+        match self {
+            PatchFormat::Vcdiff => "VCDIFF",
+            _ => self.display_name(),
+        }
+    }
+
+    fn extensions(&self) -> &[&str] {
+        match self {
+            PatchFormat::Ups => &["ups"],
+            PatchFormat::Ips => &["ips"],
+            PatchFormat::Ebp => &["ebp"],
+            PatchFormat::Bps => &["bps"],
+            PatchFormat::Ppf => &["ppf"],
+            PatchFormat::Aps => &["aps"],
+            PatchFormat::Ninja => &["rup"],
+            PatchFormat::Vcdiff => &["xdelta", "vcdiff"],
+        }
+    }
+
+    fn detect(&self, raw_patch: &[u8]) -> bool {
+        detect_format(raw_patch) == Some(*self)
+    }
+
+    fn parse(&self, raw_patch: &[u8]) -> Result<Box<dyn CodecPatch>, CodecError> {
+        Ok(Box::new(parse_as(raw_patch, *self)?))
+    }
+
+    fn diff(&self, src: &[u8], dst: &[u8]) -> Option<Box<dyn CodecPatch>> {
+        match self {
+            PatchFormat::Ups => Some(Box::new(Patch::diff(src, dst))),
+            PatchFormat::Bps => Some(Box::new(AnyPatch::Bps(BpsPatch::diff(src, dst)))),
+            _ => None,
+        }
+    }
+}
+
+impl CodecPatch for Patch {
+    fn apply(&self, src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors> {
+        Patch::apply(self, src)
+    }
+
+    fn revert(&self, dst: &[u8]) -> Option<Result<Vec<u8>, UpsPatchErrors>> {
+        Some(Patch::revert(self, dst))
+    }
+
+    fn serialize(&self) -> Option<Vec<u8>> {
+        Some(Patch::serialize(self))
+    }
+
+    fn to_ups(&self, _src: &[u8]) -> Result<Patch, UpsPatchErrors> {
+        Ok(self.clone())
+    }
+}
+
+impl CodecPatch for AnyPatch {
+    fn apply(&self, src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors> {
+        AnyPatch::apply(self, src)
+    }
+
+    fn revert(&self, dst: &[u8]) -> Option<Result<Vec<u8>, UpsPatchErrors>> {
+        match self {
+            AnyPatch::Ups(patch) => Some(patch.revert(dst)),
+            AnyPatch::Ppf(patch) => patch.revert(dst),
+            _ => None,
+        }
+    }
+
+    fn serialize(&self) -> Option<Vec<u8>> {
+        match self {
+            AnyPatch::Ups(patch) => Some(patch.serialize()),
+            AnyPatch::Bps(patch) => Some(patch.serialize()),
+            _ => None,
+        }
+    }
+
+    fn to_ups(&self, src: &[u8]) -> Result<Patch, UpsPatchErrors> {
+        AnyPatch::to_ups(self, src)
+    }
+}
+
+/// Set of [`PatchCodec`]s to look formats up by extension or contents.
+pub struct CodecRegistry {
+    codecs: Vec<Box<dyn PatchCodec>>,
+}
+
+impl CodecRegistry {
+    /// Registry with every [`PatchFormat`].
+    pub fn new() -> Self {
+        let mut registry = CodecRegistry::empty();
+        for format in PatchFormat::ALL {
+            registry.codecs.push(Box::new(format));
+        }
+        registry
+    }
+
+    /// Registry without any format.
+    pub fn empty() -> Self {
+        CodecRegistry { codecs: Vec::new() }
+    }
+
+    /// Add `codec`. Lookups try codecs in the order they were registered.
+    pub fn register<C: PatchCodec + 'static>(&mut self, codec: C) -> &mut Self {
+        self.codecs.push(Box::new(codec));
+        self
+    }
+
+    /// Registered codecs, in lookup order.
+    pub fn iter(&self) -> impl Iterator<Item = &dyn PatchCodec> {
+        self.codecs.iter().map(|codec| &**codec)
+    }
+
+    /// First codec with the extension `ext`, ignoring case.
+    pub fn by_extension(&self, ext: &str) -> Option<&dyn PatchCodec> {
+        self.iter().find(|codec| {
+            codec
+                .extensions()
+                .iter()
+                .any(|codec_ext| ext.eq_ignore_ascii_case(codec_ext))
+        })
+    }
+
+    /// First codec detecting `raw_patch`.
+    pub fn detect(&self, raw_patch: &[u8]) -> Option<&dyn PatchCodec> {
+        self.iter().find(|codec| codec.detect(raw_patch))
+    }
+
+    /// Parse `raw_patch` with the codec detecting it.
+    pub fn parse(&self, raw_patch: &[u8]) -> Result<Box<dyn CodecPatch>, CodecError> {
+        let codec = self.detect(raw_patch).ok_or(AnyParseError::UnknownFormat)?;
+        codec.parse(raw_patch)
+    }
+}
+
+impl Default for CodecRegistry {
+    fn default() -> Self {
+        CodecRegistry::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Format storing the output as is, applying to any file.
+    #[derive(Debug)]
+    struct Raw(Vec<u8>);
+
+    struct RawCodec;
+
+    impl PatchCodec for RawCodec {
+        fn name(&self) -> &str {
+            "RAW"
+        }
+
+        fn extensions(&self) -> &[&str] {
+            &["raw"]
+        }
+
+        fn detect(&self, raw_patch: &[u8]) -> bool {
+            raw_patch.starts_with(b"RAW")
+        }
+
+        fn parse(&self, raw_patch: &[u8]) -> Result<Box<dyn CodecPatch>, CodecError> {
+            Ok(Box::new(Raw(raw_patch[3..].to_vec())))
+        }
+    }
+
+    impl CodecPatch for Raw {
+        fn apply(&self, _src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors> {
+            Ok(self.0.clone())
+        }
+
+        fn to_ups(&self, src: &[u8]) -> Result<Patch, UpsPatchErrors> {
+            Ok(Patch::diff(src, &self.0))
+        }
+    }
+
+    #[test]
+    fn test_builtin_codecs() {
+        let registry = CodecRegistry::new();
+        let names: Vec<_> = registry.iter().map(|codec| codec.name()).collect();
+        assert_eq!(
+            names,
+            ["UPS", "IPS", "EBP", "BPS", "PPF", "APS", "NINJA", "VCDIFF"]
+        );
+        assert_eq!(registry.by_extension("XDELTA").unwrap().name(), "VCDIFF");
+        assert!(registry.by_extension("zip").is_none());
+
+        for format in [PatchFormat::Ups, PatchFormat::Bps] {
+            let patch = format.diff(b"abcd", b"abCD!").unwrap();
+            assert_eq!(patch.apply(b"abcd").unwrap(), b"abCD!");
+            let raw = patch.serialize().unwrap();
+            let codec = registry.detect(&raw).unwrap();
+            assert_eq!(codec.name(), format.name());
+            let parsed = codec.parse(&raw).unwrap();
+            assert_eq!(parsed.apply(b"abcd").unwrap(), b"abCD!");
+            assert_eq!(
+                parsed.to_ups(b"abcd").unwrap().apply(b"abcd").unwrap(),
+                b"abCD!"
+            );
+        }
+        let ups = registry
+            .parse(&Patch::diff(b"abcd", b"abCD").serialize())
+            .unwrap();
+        assert_eq!(ups.revert(b"abCD").unwrap().unwrap(), b"abcd");
+        assert!(PatchFormat::Ips.diff(b"abcd", b"abCD").is_none());
+
+        assert!(registry.parse(b"hello").is_err());
+        assert!(PatchFormat::Bps.parse(b"UPS1").is_err());
+    }
+
+    #[test]
+    fn test_register() {
+        let mut registry = CodecRegistry::empty();
+        registry.register(RawCodec);
+        assert!(registry.detect(b"UPS1").is_none());
+        registry.register(PatchFormat::Ups);
+        assert_eq!(registry.by_extension("raw").unwrap().name(), "RAW");
+
+        let patch = registry.parse(b"RAWhello").unwrap();
+        assert_eq!(patch.apply(b"bye").unwrap(), b"hello");
+        assert!(patch.revert(b"hello").is_none());
+        assert!(patch.serialize().is_none());
+        assert_eq!(patch.to_ups(b"bye").unwrap(), Patch::diff(b"bye", b"hello"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Convert patches between formats.
+//!
+//! This crate writes UPS and BPS patches, so those are the formats patches can be converted to.
+//! Most conversions need the source file: UPS patches only store the XOR of the changed bytes,
+//! and other formats don't store the same data, e.g. IPS patches overwrite bytes without knowing
+//! the original ones. The converted patch only applies to that source file, even if the original
+//! patch, like IPS patches, applied to any file.
+//!
+//! Metadata the target format can't hold, like the title of an EBP patch or the description of a
+//! PPF patch, fails the conversion unless [`Converter::allow_lossy`] is set.
+//!
+//! ## Example
+//!
+//! ```
+//! use ups::convert::{ConvertError, Converter};
+//! use ups::{parse_any, Patch, PatchFormat};
+//!
+//! let ips = parse_any(b"PATCH\x00\x00\x01\x00\x02hiEOF")?;
+//! let raw_ups = Converter::new()
+//!     .source(b"abcd")
+//!     .convert(&ips, PatchFormat::Ups)?;
+//! assert_eq!(Patch::parse(&raw_ups)?.apply(b"abcd")?, b"ahid");
+//!
+//! assert!(matches!(
+//!     Converter::new().convert(&ips, PatchFormat::Ups),
+//!     Err(ConvertError::SourceNeeded { .. })
+//! ));
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+use crate::aps::ApsPatch;
+use crate::bps::BpsPatch;
+use crate::{AnyPatch, PatchFormat, UpsPatchErrors};
+
+/// Error from [`Converter::convert`].
+#[derive(thiserror::Error, Debug)]
+pub enum ConvertError {
+    /// Patches can't be written in the target format.
+    #[error("{} patches can't be written, convert to UPS or BPS instead", .0)]
+    Unsupported(PatchFormat),
+    /// Converting the patch needs the source file, see [`Converter::source`].
+    #[error("converting {} patches to {} needs the source file", .from, .to)]
+    SourceNeeded { from: PatchFormat, to: PatchFormat },
+    /// The target format can't hold `lost`, e.g. "metadata", see [`Converter::allow_lossy`].
+    #[error("{} patches can't hold the {} of this {} patch", .to, .lost, .from)]
+    Lossy {
+        from: PatchFormat,
+        to: PatchFormat,
+        lost: &'static str,
+    },
+    /// The patch doesn't apply to the source file.
+    #[error(transparent)]
+    Patch(#[from] UpsPatchErrors),
+}
+
+/// Converts patches between formats, see the [module docs](self).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Converter<'a> {
+    src: Option<&'a [u8]>,
+    allow_lossy: bool,
+}
+
+impl<'a> Converter<'a> {
+    /// Converter without a source file, which can only convert patches to their own format.
+    pub fn new() -> Self {
+        Converter::default()
+    }
+
+    /// Source file the patch applies to.
+    pub fn source(mut self, src: &'a [u8]) -> Self {
+        self.src = Some(src);
+        self
+    }
+
+    /// Drop data the target format can't hold instead of failing.
+    pub fn allow_lossy(mut self, allow_lossy: bool) -> Self {
+        self.allow_lossy = allow_lossy;
+        self
+    }
+
+    /// Serialize `patch` as a `target` patch.
+    pub fn convert(&self, patch: &AnyPatch, target: PatchFormat) -> Result<Vec<u8>, ConvertError> {
+        let from = patch.format();
+        match (patch, target) {
+            (AnyPatch::Ups(patch), PatchFormat::Ups) => return Ok(patch.serialize()),
+            (AnyPatch::Bps(patch), PatchFormat::Bps) => return Ok(patch.serialize()),
+            (_, PatchFormat::Ups | PatchFormat::Bps) => {}
+            _ => return Err(ConvertError::Unsupported(target)),
+        }
+        if let (Some(lost), false) = (lost_data(patch), self.allow_lossy) {
+            return Err(ConvertError::Lossy {
+                from,
+                to: target,
+                lost,
+            });
+        }
+        let src = self
+            .src
+            .ok_or(ConvertError::SourceNeeded { from, to: target })?;
+        Ok(match target {
+            PatchFormat::Bps => BpsPatch::diff(src, &patch.apply(src)?).serialize(),
+            _ => patch.to_ups(src)?.serialize(),
+        })
+    }
+}
+
+// Data of `patch` lost by converting it to another format, metadata isn't carried over.
+fn lost_data(patch: &AnyPatch) -> Option<&'static str> {
+    match patch {
+        AnyPatch::Ebp(patch) if patch.metadata.is_some() => Some("metadata"),
+        AnyPatch::Bps(patch) if !patch.metadata.is_empty() => Some("metadata"),
+        AnyPatch::Ppf(patch) if patch.file_id.is_some() => Some("FILE_ID.DIZ"),
+        AnyPatch::Ppf(patch) if !patch.description.is_empty() => Some("description"),
+        AnyPatch::Aps(ApsPatch::N64(patch)) if !patch.description.is_empty() => Some("description"),
+        AnyPatch::Ninja(patch) if patch.metadata != Default::default() => Some("metadata"),
+        #[cfg(feature = "vcdiff")]
+        AnyPatch::Vcdiff(patch) if patch.app_header.is_some() => Some("application header"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse_any, Patch};
+
+    #[test]
+    fn test_convert() {
+        let src = b"The quick brown fox";
+        let dst = b"The quick red fox!!";
+        let ups = AnyPatch::Ups(Patch::diff(src, dst));
+        let bps = AnyPatch::Bps(BpsPatch::diff(src, dst));
+        let ips = parse_any(b"PATCH\x00\x00\x0a\x00\x03redEOF").unwrap();
+        let converter = Converter::new().source(src);
+
+        for patch in [&ups, &bps, &ips] {
+            for target in [PatchFormat::Ups, PatchFormat::Bps] {
+                let raw = converter.convert(patch, target).unwrap();
+                let converted = parse_any(&raw).unwrap();
+                assert_eq!(converted.format(), target);
+                assert_eq!(converted.apply(src).unwrap(), patch.apply(src).unwrap());
+            }
+        }
+        // Same format, nothing to compute.
+        assert_eq!(
+            Converter::new().convert(&ups, PatchFormat::Ups).unwrap(),
+            Patch::diff(src, dst).serialize()
+        );
+
+        assert!(matches!(
+            converter.convert(&ups, PatchFormat::Ips),
+            Err(ConvertError::Unsupported(PatchFormat::Ips))
+        ));
+        assert!(matches!(
+            Converter::new().convert(&ups, PatchFormat::Bps),
+            Err(ConvertError::SourceNeeded {
+                from: PatchFormat::Ups,
+                to: PatchFormat::Bps
+            })
+        ));
+        assert!(matches!(
+            Converter::new()
+                .source(b"The slow brown fox")
+                .convert(&ups, PatchFormat::Bps),
+            Err(ConvertError::Patch(_))
+        ));
+    }
+
+    #[test]
+    fn test_convert_lossy() {
+        let ebp = parse_any(b"PATCH\x00\x00\x01\x00\x02hiEOF{\"title\": \"Hi\"}").unwrap();
+        let converter = Converter::new().source(b"abcd");
+        assert!(matches!(
+            converter.convert(&ebp, PatchFormat::Ups),
+            Err(ConvertError::Lossy {
+                from: PatchFormat::Ebp,
+                to: PatchFormat::Ups,
+                lost: "metadata"
+            })
+        ));
+        let raw = converter
+            .allow_lossy(true)
+            .convert(&ebp, PatchFormat::Ups)
+            .unwrap();
+        assert_eq!(Patch::parse(&raw).unwrap().apply(b"abcd").unwrap(), b"ahid");
+    }
+}
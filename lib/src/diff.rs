@@ -0,0 +1,783 @@
+//! Diff analysis helpers.
+//!
+//! UPS can only express byte substitutions: every changed byte is stored XORed with the byte at
+//! the same position in the other file. When data is inserted or deleted, everything after it is
+//! shifted and the patch ends up storing the whole shifted region. [`find_realignment`] detects
+//! these cases so tools can warn about them, and [`DiffReport`] summarizes how well UPS fits a
+//! change set.
+//!
+//! [`diff_to_writer`] computes patches for files too large to fit in memory, and
+//! [`patch_file_to_writer`] applies them.
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crc32fast::Hasher;
+use memchr::memchr;
+
+use crate::{varint, ByteSize, Checksum, Patch, PatchDirection};
+
+/// Window size for the rolling hash used to find realignments.
+const WINDOW: usize = 32;
+/// Number of windows after the first difference which are searched for in the source.
+const MAX_PROBES: usize = 64;
+/// Minimum length of a shifted match to be reported.
+const MIN_MATCH: usize = 256;
+/// Base for the polynomial rolling hash.
+const BASE: u64 = 0x0100_0000_01b3;
+
+/// Region where `dst` matches `src` at a different offset, found by [`find_realignment`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Realignment {
+    /// Start of the matching region in the source.
+    pub src_offset: usize,
+    /// Start of the matching region in the destination.
+    pub dst_offset: usize,
+    /// Length of the matching region.
+    pub len: usize,
+}
+
+impl Realignment {
+    /// How many bytes the data was shifted by, positive for insertions.
+    pub fn shift(&self) -> i64 {
+        self.dst_offset as i64 - self.src_offset as i64
+    }
+}
+
+/// Find the first point after the files start differing where `dst` realigns with `src` at a
+/// different offset, i.e. where data was inserted or deleted.
+///
+/// This uses a rolling hash over `src`, so it's linear on the file sizes. Only the region right
+/// after the first difference is probed, and matches of repeated bytes (e.g. padding) are ignored.
+pub fn find_realignment(src: &[u8], dst: &[u8]) -> Option<Realignment> {
+    let first_diff = src.iter().zip(dst).position(|(a, b)| a != b)?;
+
+    // Probe windows of `dst` after the first difference, keyed by hash.
+    let mut probes: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut dst_offset = first_diff;
+    while probes.len() < MAX_PROBES && dst_offset + WINDOW <= dst.len() {
+        let window = &dst[dst_offset..dst_offset + WINDOW];
+        if window.iter().any(|b| *b != window[0]) {
+            probes.entry(hash(window)).or_default().push(dst_offset);
+        }
+        dst_offset += WINDOW;
+    }
+    if probes.is_empty() || src.len() < WINDOW {
+        return None;
+    }
+
+    let base_pow = (1..WINDOW).fold(1u64, |acc, _| acc.wrapping_mul(BASE));
+    let mut best: Option<Realignment> = None;
+    let mut h = hash(&src[..WINDOW]);
+    for src_offset in 0..=src.len() - WINDOW {
+        if src_offset > 0 {
+            let removed = u64::from(src[src_offset - 1]).wrapping_mul(base_pow);
+            h = h
+                .wrapping_sub(removed)
+                .wrapping_mul(BASE)
+                .wrapping_add(u64::from(src[src_offset + WINDOW - 1]));
+        }
+        let candidates = match probes.get(&h) {
+            Some(c) => c,
+            None => continue,
+        };
+        for &dst_offset in candidates {
+            if dst_offset == src_offset || matches!(best, Some(b) if b.dst_offset <= dst_offset) {
+                continue;
+            }
+            // Extend the match backwards, probes are only aligned to `WINDOW`.
+            let back = src[..src_offset]
+                .iter()
+                .rev()
+                .zip(dst[first_diff..dst_offset].iter().rev())
+                .take_while(|(a, b)| a == b)
+                .count();
+            let len = src[src_offset..]
+                .iter()
+                .zip(&dst[dst_offset..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if back + len >= MIN_MATCH {
+                best = Some(Realignment {
+                    src_offset: src_offset - back,
+                    dst_offset: dst_offset - back,
+                    len: back + len,
+                });
+            }
+        }
+    }
+    best
+}
+
+/// Estimated cost in bytes of a copy command in formats supporting them, e.g. BPS or xdelta.
+const COPY_COST: usize = 16;
+/// Minimum estimated size reduction for [`DiffReport::recommendation`] to suggest another format.
+const MIN_GAIN: usize = 2;
+
+/// Metrics about a patch generated from `src` and `dst`, see [`DiffReport::new`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffReport {
+    /// Size of the serialized patch.
+    pub patch_size: usize,
+    /// Number of bytes which differ between the files, every appended byte counts as changed.
+    pub changed_bytes: usize,
+    /// Shannon entropy of the stored XOR data in bits per byte, between 0 and 8. High entropy
+    /// means the patch won't compress well.
+    pub xor_entropy: f64,
+    /// Number of bytes in the destination past the end of the source.
+    pub appended_bytes: usize,
+    /// Shifted data found by [`find_realignment`].
+    pub realignment: Option<Realignment>,
+    /// Changed bytes inside the realigned region, which a format with copy commands wouldn't
+    /// need to store.
+    pub shifted_bytes: usize,
+}
+
+/// Suggestion to use a different patch format, from [`DiffReport::recommendation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recommendation {
+    /// Estimated patch size using a format with copy commands.
+    pub estimated_size: usize,
+    /// How many times smaller the patch would be, rounded down.
+    pub gain: usize,
+}
+
+impl fmt::Display for Recommendation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "this change set would be ~{}x smaller as BPS/xdelta",
+            self.gain
+        )
+    }
+}
+
+impl DiffReport {
+    /// Compute metrics for `patch`, which must have been generated from `src` and `dst`.
+    pub fn new(src: &[u8], dst: &[u8], patch: &Patch) -> Self {
+        let mut histogram = [0usize; 256];
+        for block in &patch.blocks {
+            // Skip the terminator, it's not part of the changes
+            for b in &block.xor_data[..block.xor_data.len().saturating_sub(1)] {
+                histogram[*b as usize] += 1;
+            }
+        }
+        let xor_bytes: usize = histogram.iter().sum();
+        let xor_entropy = histogram
+            .iter()
+            .filter(|n| **n > 0)
+            .map(|n| {
+                let p = *n as f64 / xor_bytes as f64;
+                -p * p.log2()
+            })
+            .sum();
+
+        let realignment = find_realignment(src, dst);
+        let shifted_bytes = realignment.map_or(0, |r| {
+            let region = &dst[r.dst_offset..r.dst_offset + r.len];
+            let src_region = src.get(r.dst_offset..).unwrap_or(&[]);
+            let unchanged = region
+                .iter()
+                .zip(src_region)
+                .filter(|(a, b)| a == b)
+                .count();
+            r.len - unchanged
+        });
+
+        // Appended zeros aren't stored as XOR data, so count changes from the files themselves.
+        let appended_bytes = dst.len().saturating_sub(src.len());
+        let changed_bytes = src.iter().zip(dst).filter(|(a, b)| a != b).count() + appended_bytes;
+
+        DiffReport {
+            patch_size: patch.serialize().len(),
+            changed_bytes,
+            xor_entropy,
+            appended_bytes,
+            realignment,
+            shifted_bytes,
+        }
+    }
+
+    /// Ratio of changed bytes which were appended past the end of the source, between 0 and 1.
+    /// Appended data is stored verbatim by every format.
+    pub fn appended_ratio(&self) -> f64 {
+        if self.changed_bytes == 0 {
+            0.0
+        } else {
+            self.appended_bytes as f64 / self.changed_bytes as f64
+        }
+    }
+
+    /// Suggest a format with copy commands if it would result in a much smaller patch.
+    pub fn recommendation(&self) -> Option<Recommendation> {
+        self.realignment?;
+        let estimated_size =
+            (self.patch_size - self.shifted_bytes.min(self.patch_size)) + COPY_COST;
+        let gain = self.patch_size / estimated_size;
+        if gain >= MIN_GAIN {
+            Some(Recommendation {
+                estimated_size,
+                gain,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "patch size: {}", ByteSize(self.patch_size))?;
+        writeln!(
+            f,
+            "changed bytes: {} (XOR entropy {:.2} bits/byte)",
+            self.changed_bytes, self.xor_entropy
+        )?;
+        writeln!(
+            f,
+            "appended data: {} ({:.0}% of changes)",
+            ByteSize(self.appended_bytes),
+            self.appended_ratio() * 100.0
+        )?;
+        match self.realignment {
+            Some(r) => write!(
+                f,
+                "shifted data: {} at offset {} shifted by {:+} bytes",
+                ByteSize(r.len),
+                r.dst_offset,
+                r.shift()
+            )?,
+            None => write!(f, "shifted data: none")?,
+        }
+        if let Some(recommendation) = self.recommendation() {
+            write!(f, "\n{}", recommendation)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compute a patch between `src` and `dst` and serialize it to `out`, holding at most
+/// `window_size` bytes of each file in memory at once.
+///
+/// The result is byte-identical to [`Patch::diff`](crate::Patch::diff) followed by
+/// [`Patch::serialize`](crate::Patch::serialize). File sizes must be known upfront since they're
+/// part of the patch header, readers returning a different amount of data result in an
+/// [`io::ErrorKind::InvalidData`] error. Sizes are `u64` so files larger than the address space,
+/// e.g. disc images on 32-bit platforms, can be diffed too.
+///
+/// # Panics
+///
+/// Panics if `window_size` is 0.
+pub fn diff_to_writer<S: Read, D: Read, W: Write>(
+    mut src: S,
+    src_size: u64,
+    mut dst: D,
+    dst_size: u64,
+    window_size: usize,
+    out: W,
+) -> io::Result<()> {
+    assert!(window_size > 0, "window_size must be positive");
+    let mut out = HashingWriter {
+        inner: out,
+        hasher: Hasher::new(),
+    };
+    let mut header = b"UPS1".to_vec();
+    varint::write_u64(&mut header, src_size);
+    varint::write_u64(&mut header, dst_size);
+    out.write_all(&header)?;
+
+    let mut src_hasher = Hasher::new();
+    let mut dst_hasher = Hasher::new();
+    let mut src_buf = vec![0; window_size];
+    let mut dst_buf = vec![0; window_size];
+    let mut encoder = BlockEncoder::default();
+    let total = std::cmp::max(src_size, dst_size);
+    let mut pos = 0u64;
+    while pos < total {
+        let len = window_len(window_size, total - pos);
+        let src_len = read_window(&mut src, &mut src_buf[..len], src_size.saturating_sub(pos))?;
+        let dst_len = read_window(&mut dst, &mut dst_buf[..len], dst_size.saturating_sub(pos))?;
+        src_hasher.update(&src_buf[..src_len]);
+        dst_hasher.update(&dst_buf[..dst_len]);
+        // Bytes past the end of either file count as zeroes.
+        for b in &mut src_buf[src_len..len] {
+            *b = 0;
+        }
+        for b in &mut dst_buf[dst_len..len] {
+            *b = 0;
+        }
+        for (s, d) in src_buf[..len].iter_mut().zip(&dst_buf[..len]) {
+            *s ^= d;
+        }
+        encoder.push(pos, &src_buf[..len], &mut out)?;
+        pos += len as u64;
+    }
+    for reader_has_more in [
+        src.read(&mut src_buf[..1])? > 0,
+        dst.read(&mut dst_buf[..1])? > 0,
+    ] {
+        if reader_has_more {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "input is longer than its declared size",
+            ));
+        }
+    }
+    encoder.finish(&mut out)?;
+
+    out.write_all(&src_hasher.finalize().to_le_bytes())?;
+    out.write_all(&dst_hasher.finalize().to_le_bytes())?;
+    let patch_checksum = Checksum(out.hasher.clone().finalize());
+    out.inner.write_all(&patch_checksum.0.to_le_bytes())
+}
+
+/// Apply or revert the serialized UPS patch `raw_patch` on `input`, writing the output to `out`
+/// and holding at most `window_size` bytes of the input in memory at once.
+///
+/// This is the counterpart of [`diff_to_writer`]: sizes and offsets are read as `u64`, so patches
+/// for files larger than the address space, e.g. disc images on 32-bit platforms, can be applied
+/// even though [`Patch::parse`] fails for them. Malformed patches, and inputs or outputs not
+/// matching the patch sizes and checksums, result in an [`io::ErrorKind::InvalidData`] error.
+/// `out` may have received data by then.
+///
+/// # Panics
+///
+/// Panics if `window_size` is 0.
+pub fn patch_file_to_writer<R: Read, W: Write>(
+    raw_patch: &[u8],
+    direction: PatchDirection,
+    mut input: R,
+    window_size: usize,
+    mut out: W,
+) -> io::Result<()> {
+    assert!(window_size > 0, "window_size must be positive");
+    let (mut body, checksums) = match raw_patch.strip_prefix(b"UPS1") {
+        Some(rest) if rest.len() >= 12 => rest.split_at(rest.len() - 12),
+        _ => return Err(invalid_data("not a UPS patch")),
+    };
+    let read_checksum = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    if crc32fast::hash(&raw_patch[..raw_patch.len() - 4]) != read_checksum(&checksums[8..]) {
+        return Err(invalid_data("patch checksum mismatch"));
+    }
+    let src_size = read_varint(&mut body)?;
+    let dst_size = read_varint(&mut body)?;
+    let (src_checksum, dst_checksum) = (
+        read_checksum(&checksums[..4]),
+        read_checksum(&checksums[4..8]),
+    );
+    let (input_size, input_checksum, output_size, output_checksum) = match direction {
+        PatchDirection::Apply => (src_size, src_checksum, dst_size, dst_checksum),
+        PatchDirection::Revert => (dst_size, dst_checksum, src_size, src_checksum),
+    };
+
+    let mut blocks = XorStream::new(body)?;
+    let mut input_hasher = Hasher::new();
+    let mut output_hasher = Hasher::new();
+    let mut buf = vec![0; window_size];
+    let total = std::cmp::max(input_size, output_size);
+    let mut pos = 0u64;
+    while pos < total {
+        let len = window_len(window_size, total - pos);
+        let input_len = read_window(&mut input, &mut buf[..len], input_size.saturating_sub(pos))?;
+        input_hasher.update(&buf[..input_len]);
+        let output_len = window_len(len, output_size.saturating_sub(pos));
+        // Bytes past the end of the input count as zeroes.
+        if input_len < output_len {
+            for b in &mut buf[input_len..output_len] {
+                *b = 0;
+            }
+        }
+        blocks.xor(&mut buf[..output_len])?;
+        output_hasher.update(&buf[..output_len]);
+        out.write_all(&buf[..output_len])?;
+        pos += len as u64;
+    }
+    if input.read(&mut buf[..1])? > 0 {
+        return Err(invalid_data("input is longer than its declared size"));
+    }
+    if input_hasher.finalize() != input_checksum {
+        return Err(invalid_data("input checksum doesn't match the patch"));
+    }
+    if output_hasher.finalize() != output_checksum {
+        return Err(invalid_data("output checksum doesn't match the patch"));
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_varint(buf: &mut &[u8]) -> io::Result<u64> {
+    varint::read_u64(buf).ok_or_else(|| invalid_data("truncated or overflowing varint in patch"))
+}
+
+// Decodes the XOR bytes of the blocks in a serialized patch body, carrying state across windows.
+struct XorStream<'a> {
+    // Rest of the patch body, starting inside the current block when `gap` is 0.
+    body: &'a [u8],
+    // Unchanged bytes before the next block, `u64::MAX` after the last one.
+    gap: u64,
+}
+
+impl<'a> XorStream<'a> {
+    fn new(mut body: &'a [u8]) -> io::Result<Self> {
+        let gap = if body.is_empty() {
+            u64::MAX
+        } else {
+            read_varint(&mut body)?
+        };
+        Ok(XorStream { body, gap })
+    }
+
+    // XOR the next `buf.len()` output bytes with the block data.
+    fn xor(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let mut i = 0;
+        while i < buf.len() {
+            if self.gap > 0 {
+                let skip = window_len(buf.len() - i, self.gap);
+                i += skip;
+                self.gap -= skip as u64;
+                continue;
+            }
+            let data = &self.body[..std::cmp::min(self.body.len(), buf.len() - i)];
+            // Include the terminator.
+            let (len, terminated) = match memchr(0, data) {
+                Some(z) => (z + 1, true),
+                None => (data.len(), false),
+            };
+            for (b, x) in buf[i..i + len].iter_mut().zip(data) {
+                *b ^= x;
+            }
+            i += len;
+            self.body = &self.body[len..];
+            // The last block may be missing its terminator.
+            if self.body.is_empty() {
+                self.gap = u64::MAX;
+            } else if terminated {
+                self.gap = read_varint(&mut self.body)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// `min(window_size, remaining)` without truncating `remaining` on 32-bit platforms.
+fn window_len(window_size: usize, remaining: u64) -> usize {
+    usize::try_from(remaining).map_or(window_size, |r| std::cmp::min(window_size, r))
+}
+
+// Reads exactly `min(buf.len(), remaining)` bytes, returning how many were read.
+fn read_window<R: Read>(reader: &mut R, buf: &mut [u8], remaining: u64) -> io::Result<usize> {
+    let len = window_len(buf.len(), remaining);
+    reader.read_exact(&mut buf[..len]).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "input is shorter than its declared size",
+            )
+        } else {
+            e
+        }
+    })?;
+    Ok(len)
+}
+
+// Encodes a stream of XOR bytes into canonical UPS blocks, carrying state across windows.
+#[derive(Default)]
+struct BlockEncoder {
+    // Position right after the previous block's terminator.
+    prev_end: u64,
+    in_block: bool,
+}
+
+impl BlockEncoder {
+    // Encode XOR bytes for the window starting at `start`.
+    fn push<W: Write>(&mut self, start: u64, xor: &[u8], out: &mut W) -> io::Result<()> {
+        let mut i = 0;
+        while i < xor.len() {
+            if self.in_block {
+                match memchr(0, &xor[i..]) {
+                    Some(z) => {
+                        // Include the terminator.
+                        out.write_all(&xor[i..=i + z])?;
+                        self.in_block = false;
+                        i += z + 1;
+                        self.prev_end = start + i as u64;
+                    }
+                    None => {
+                        out.write_all(&xor[i..])?;
+                        i = xor.len();
+                    }
+                }
+            } else {
+                match xor[i..].iter().position(|b| *b != 0) {
+                    Some(skip) => {
+                        i += skip;
+                        let mut offset = Vec::new();
+                        varint::write_u64(&mut offset, start + i as u64 - self.prev_end);
+                        out.write_all(&offset)?;
+                        self.in_block = true;
+                    }
+                    None => break,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        if self.in_block {
+            self.in_block = false;
+            out.write_all(&[0])?;
+        }
+        Ok(())
+    }
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn hash(window: &[u8]) -> u64 {
+    window.iter().fold(0u64, |h, b| {
+        h.wrapping_mul(BASE).wrapping_add(u64::from(*b))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use crate::util::ProptestUnwrapExt;
+    use crate::Patch;
+
+    fn pseudo_random(len: usize) -> Vec<u8> {
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn test_diff_to_writer_matches_diff(
+            src in vec(any::<u8>(), 0..64),
+            dst in vec(any::<u8>(), 0..64),
+            window_size in 1..16usize,
+        ) {
+            let mut streamed = Vec::new();
+            let (src_size, dst_size) = (src.len() as u64, dst.len() as u64);
+            diff_to_writer(&src[..], src_size, &dst[..], dst_size, window_size, &mut streamed)
+                .prop_unwrap()?;
+            prop_assert_eq!(streamed, Patch::diff(&src, &dst).serialize());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_patch_file_to_writer_matches_patch(
+            src in vec(any::<u8>(), 0..64),
+            dst in vec(any::<u8>(), 0..64),
+            window_size in 1..16usize,
+        ) {
+            let patch = Patch::diff(&src, &dst);
+            let raw_patch = patch.serialize();
+            for (direction, input, output) in [
+                (PatchDirection::Apply, &src, &dst),
+                (PatchDirection::Revert, &dst, &src),
+            ] {
+                let mut streamed = Vec::new();
+                patch_file_to_writer(&raw_patch, direction, &input[..], window_size, &mut streamed)
+                    .prop_unwrap()?;
+                prop_assert_eq!(&streamed, output);
+            }
+        }
+    }
+
+    #[test]
+    fn test_patch_file_to_writer_checks_input() {
+        let raw_patch = Patch::diff(b"abc", b"aXcd").serialize();
+        let apply = |input: &[u8]| {
+            patch_file_to_writer(&raw_patch, PatchDirection::Apply, input, 2, io::sink())
+                .unwrap_err()
+                .kind()
+        };
+        assert_eq!(apply(b"ab"), io::ErrorKind::InvalidData);
+        assert_eq!(apply(b"abcd"), io::ErrorKind::InvalidData);
+        assert_eq!(apply(b"xyz"), io::ErrorKind::InvalidData);
+
+        let mut corrupted = raw_patch.clone();
+        corrupted[6] ^= 1;
+        let err = patch_file_to_writer(
+            &corrupted,
+            PatchDirection::Apply,
+            &b"abc"[..],
+            2,
+            io::sink(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_diff_to_writer_checks_sizes() {
+        let err = diff_to_writer(&b"abc"[..], 4, &b"abc"[..], 3, 2, io::sink()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = diff_to_writer(&b"abc"[..], 3, &b"abcd"[..], 3, 2, io::sink()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    // Synthetic file of `len` zero bytes except for `marks`, without allocating it.
+    struct SparseFile {
+        pos: u64,
+        len: u64,
+        marks: Vec<(u64, u8)>,
+    }
+
+    impl Read for SparseFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = window_len(buf.len(), self.len - self.pos);
+            let end = self.pos + n as u64;
+            buf[..n].iter_mut().for_each(|b| *b = 0);
+            for &(offset, byte) in &self.marks {
+                if (self.pos..end).contains(&offset) {
+                    buf[(offset - self.pos) as usize] = byte;
+                }
+            }
+            self.pos = end;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    #[ignore = "slow, diffs 10 GiB of data"]
+    fn test_diff_to_writer_larger_than_4gib() {
+        const GIB: u64 = 1 << 30;
+        let src_size = 5 * GIB;
+        let dst_size = 5 * GIB + 2;
+        let sparse = |len, marks| SparseFile { pos: 0, len, marks };
+        let src = sparse(src_size, vec![(GIB, 1), (4 * GIB + 7, 2)]);
+        let dst = sparse(dst_size, vec![(4 * GIB + 7, 3), (dst_size - 1, 4)]);
+        let mut patch = Vec::new();
+        diff_to_writer(src, src_size, dst, dst_size, 64 << 20, &mut patch).unwrap();
+
+        // Parse with `varint::read_u64` so the test also runs on 32-bit platforms.
+        let mut body = &patch[4..patch.len() - 12];
+        let mut read = || varint::read_u64(&mut body).unwrap();
+        assert_eq!(read(), src_size);
+        assert_eq!(read(), dst_size);
+        assert_eq!(read(), GIB);
+        assert_eq!(body[..2], [1, 0]);
+        body = &body[2..];
+        let mut read = || varint::read_u64(&mut body).unwrap();
+        assert_eq!(read(), 3 * GIB + 5);
+        assert_eq!(body[..2], [1, 0]);
+        body = &body[2..];
+        let mut read = || varint::read_u64(&mut body).unwrap();
+        assert_eq!(read(), dst_size - 1 - (4 * GIB + 9));
+        assert_eq!(body, [4, 0]);
+
+        // Applying and reverting check the output checksums, so succeeding means the outputs match.
+        let src = sparse(src_size, vec![(GIB, 1), (4 * GIB + 7, 2)]);
+        patch_file_to_writer(&patch, PatchDirection::Apply, src, 64 << 20, io::sink()).unwrap();
+        let dst = sparse(dst_size, vec![(4 * GIB + 7, 3), (dst_size - 1, 4)]);
+        patch_file_to_writer(&patch, PatchDirection::Revert, dst, 64 << 20, io::sink()).unwrap();
+    }
+
+    #[test]
+    fn test_insertion() {
+        let src = pseudo_random(8192);
+        let mut dst = src[..1000].to_vec();
+        dst.extend_from_slice(b"inserted");
+        dst.extend_from_slice(&src[1000..]);
+
+        let r = find_realignment(&src, &dst).unwrap();
+        assert_eq!(r.shift(), 8);
+        assert_eq!(
+            &src[r.src_offset..r.src_offset + r.len],
+            &dst[r.dst_offset..][..r.len]
+        );
+        assert_eq!(r.dst_offset, 1008);
+    }
+
+    #[test]
+    fn test_deletion() {
+        let src = pseudo_random(8192);
+        let mut dst = src[..3000].to_vec();
+        dst.extend_from_slice(&src[3100..]);
+
+        let r = find_realignment(&src, &dst).unwrap();
+        assert_eq!(r.shift(), -100);
+    }
+
+    #[test]
+    fn test_no_realignment() {
+        let src = pseudo_random(8192);
+        let mut dst = src.clone();
+        for b in &mut dst[2000..2100] {
+            *b ^= 0xff;
+        }
+        assert_eq!(find_realignment(&src, &dst), None);
+        assert_eq!(find_realignment(&src, &src), None);
+        // Shifted padding shouldn't count
+        assert_eq!(find_realignment(&[0; 4096], &[1; 4096]), None);
+    }
+
+    #[test]
+    fn test_report() {
+        let src = pseudo_random(8192);
+        let mut dst = src[..1000].to_vec();
+        dst.extend_from_slice(b"inserted");
+        dst.extend_from_slice(&src[1000..]);
+        let report = DiffReport::new(&src, &dst, &Patch::diff(&src, &dst));
+        assert_eq!(report.appended_bytes, 8);
+        assert!(report.xor_entropy > 7.0);
+        assert!(report.realignment.is_some());
+        let recommendation = report.recommendation().unwrap();
+        assert!(recommendation.gain >= 8, "{:?}", report);
+
+        let mut dst = src.clone();
+        for b in &mut dst[2000..2100] {
+            *b ^= 0x01;
+        }
+        let report = DiffReport::new(&src, &dst, &Patch::diff(&src, &dst));
+        assert_eq!(report.changed_bytes, 100);
+        assert_eq!(report.xor_entropy, 0.0);
+        assert_eq!(report.appended_ratio(), 0.0);
+        assert_eq!(report.recommendation(), None);
+
+        // Appended zeros aren't stored in the XOR data but still count as changes.
+        let mut dst = src.clone();
+        dst[0] ^= 0xff;
+        dst.extend_from_slice(&[0; 100]);
+        let report = DiffReport::new(&src, &dst, &Patch::diff(&src, &dst));
+        assert_eq!(report.changed_bytes, 101);
+        assert_eq!(report.appended_bytes, 100);
+        assert!(report.appended_ratio() <= 1.0, "{:?}", report);
+        let report = DiffReport::new(b"", &[0; 16], &Patch::diff(b"", &[0; 16]));
+        assert_eq!(report.appended_ratio(), 1.0);
+    }
+}
@@ -0,0 +1,312 @@
+//! Diagnostics for patches which don't apply.
+//!
+//! [`diagnose`] runs every heuristic this crate knows about on a patch and a ROM: patch format
+//! sniffing, copier headers, N64 byte orders, checksums against both sides of the patch and size
+//! analysis. The resulting [`Finding`]s are ranked so the most likely explanation comes first.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::fs;
+//!
+//! let diagnosis = ups::doctor::diagnose(&fs::read("hack.ups")?, &fs::read("game.sfc")?);
+//! println!("{}", diagnosis);
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+use std::fmt::{self, Display, Formatter};
+
+use crate::transform::{InputTransform, N64ByteOrder, N64Format, SnesHeader};
+use crate::{detect_format, ByteSize, Checksum, Patch, PatchDirection, PatchFormat, UpsParseError};
+
+/// How relevant a [`Finding`] is, from most to least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Rank {
+    /// Found a change to the ROM which makes the patch apply.
+    Solution,
+    /// Probable cause for the patch not applying.
+    Likely,
+    /// Might be related, worth checking.
+    Hint,
+    /// Nothing wrong.
+    Ok,
+}
+
+/// Single result from [`diagnose`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub rank: Rank,
+    /// What was found.
+    pub summary: String,
+    /// What to try, if there's anything to do about it.
+    pub suggestion: Option<String>,
+}
+
+/// Findings from [`diagnose`], most relevant first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnosis {
+    pub findings: Vec<Finding>,
+}
+
+impl Diagnosis {
+    /// Whether the patch applies to the ROM as is. There may still be hints about the patch.
+    pub fn applies(&self) -> bool {
+        self.findings.iter().any(|f| f.rank == Rank::Ok)
+    }
+
+    fn push(&mut self, rank: Rank, summary: String, suggestion: Option<String>) {
+        self.findings.push(Finding {
+            rank,
+            summary,
+            suggestion,
+        });
+    }
+}
+
+impl Display for Rank {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Rank::Solution => "solution",
+            Rank::Likely => "likely",
+            Rank::Hint => "hint",
+            Rank::Ok => "ok",
+        })
+    }
+}
+
+impl Display for Diagnosis {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, finding) in self.findings.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "[{}] {}", finding.rank, finding.summary)?;
+            if let Some(suggestion) = &finding.suggestion {
+                write!(f, "\n    try: {}", suggestion)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Explain why `raw_patch` does or doesn't apply to `rom`, see the [module docs](self).
+pub fn diagnose(raw_patch: &[u8], rom: &[u8]) -> Diagnosis {
+    let mut diagnosis = Diagnosis {
+        findings: Vec::new(),
+    };
+    if let Some(format) = detect_format(raw_patch).filter(|f| *f != PatchFormat::Ups) {
+        diagnosis.push(
+            Rank::Likely,
+            format!("the patch is a {} patch, not UPS", format),
+            Some(format!("use a patcher supporting {}", format)),
+        );
+        return diagnosis;
+    }
+    let patch = match Patch::parse_with_warnings(raw_patch) {
+        Ok((p, warnings)) => {
+            for warning in warnings {
+                diagnosis.push(
+                    Rank::Hint,
+                    format!("the patch may be broken: {}", warning),
+                    Some(
+                        "report it to the patch author, their patch generator may be buggy".into(),
+                    ),
+                );
+            }
+            p
+        }
+        Err(UpsParseError::PatchChecksumMismatch { parsed_patch, .. }) => {
+            diagnosis.push(
+                Rank::Likely,
+                "the patch file is corrupted, its checksum doesn't match".into(),
+                Some("download the patch again".into()),
+            );
+            parsed_patch
+        }
+        Err(e) => {
+            diagnosis.push(
+                Rank::Likely,
+                format!("the patch file is invalid: {}", e),
+                Some("download the patch again".into()),
+            );
+            return diagnosis;
+        }
+    };
+
+    check_rom(&mut diagnosis, &patch, rom);
+    diagnosis.findings.sort_by_key(|f| f.rank);
+    diagnosis
+}
+
+fn check_rom(diagnosis: &mut Diagnosis, patch: &Patch, rom: &[u8]) {
+    let requirements = patch.requirements();
+    match matching_side(patch, rom) {
+        Some(PatchDirection::Apply) => {
+            diagnosis.push(
+                Rank::Ok,
+                "the ROM matches the patch source, the patch should apply".into(),
+                None,
+            );
+            return;
+        }
+        Some(PatchDirection::Revert) => {
+            diagnosis.push(
+                Rank::Likely,
+                "the ROM is already patched with this patch".into(),
+                Some("revert the patch to get the original ROM back".into()),
+            );
+            return;
+        }
+        None => (),
+    }
+
+    let mut transforms: Vec<(Box<dyn InputTransform>, String)> = Vec::new();
+    if SnesHeader::detect(rom) {
+        transforms.push((
+            Box::new(SnesHeader),
+            "the ROM has a 512-byte copier header".into(),
+        ));
+    }
+    match N64ByteOrder::detect(rom) {
+        Some(N64Format::Z64) | None => (),
+        Some(format) => transforms.push((
+            Box::new(N64ByteOrder),
+            format!("the ROM is in the {:?} N64 byte order", format),
+        )),
+    }
+    for (transform, summary) in transforms {
+        let mut transformed = rom.to_vec();
+        transform.transform(&mut transformed);
+        if matching_side(patch, &transformed) == Some(PatchDirection::Apply) {
+            diagnosis.push(
+                Rank::Solution,
+                format!("{}, the patch applies without it", summary),
+                Some(format!("apply the {} transform first", transform.name())),
+            );
+        } else {
+            diagnosis.push(Rank::Hint, summary, None);
+        }
+    }
+
+    if rom.len() > patch.src_size
+        && Checksum::from_bytes(&rom[..patch.src_size]) == patch.src_checksum
+    {
+        diagnosis.push(
+            Rank::Solution,
+            format!(
+                "the ROM has {} of extra data at the end, the patch applies without it",
+                ByteSize(rom.len() - patch.src_size),
+            ),
+            Some(format!("trim the ROM to {} bytes", patch.src_size)),
+        );
+    } else if rom.len() == patch.src_size {
+        diagnosis.push(
+            Rank::Likely,
+            "the ROM has the expected size but different contents: it's probably another \
+             revision or region, or a bad dump"
+                .into(),
+            Some(format!("find a ROM matching {}", requirements.src)),
+        );
+    } else {
+        diagnosis.push(
+            Rank::Hint,
+            format!(
+                "the ROM is {}, the patch expects {}",
+                ByteSize(rom.len()),
+                requirements.src,
+            ),
+            Some(format!("find a ROM matching {}", requirements.src)),
+        );
+    }
+}
+
+fn matching_side(patch: &Patch, rom: &[u8]) -> Option<PatchDirection> {
+    patch
+        .preflight(rom.len(), Checksum::from_bytes(rom))
+        .direction_hint
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rom() -> Vec<u8> {
+        (0..4096u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn patch_for(src: &[u8]) -> Vec<u8> {
+        let mut dst = src.to_vec();
+        dst[100..110].copy_from_slice(b"patched!!!");
+        Patch::diff(src, &dst).serialize()
+    }
+
+    #[test]
+    fn test_applies() {
+        let rom = rom();
+        let diagnosis = diagnose(&patch_for(&rom), &rom);
+        assert!(diagnosis.applies());
+    }
+
+    #[test]
+    fn test_block_past_end() {
+        let rom = rom();
+        let mut patch = Patch::parse(&patch_for(&rom)).unwrap();
+        patch.blocks.push(crate::Block {
+            offset: 10_000,
+            xor_data: vec![1, 0].into(),
+        });
+        let diagnosis = diagnose(&patch.serialize(), &rom);
+        assert!(diagnosis.applies());
+        assert_eq!(diagnosis.findings[0].rank, Rank::Hint);
+        assert!(diagnosis.findings[0]
+            .summary
+            .contains("block 1 starts at offset 10111"));
+    }
+
+    #[test]
+    fn test_wrong_format() {
+        let diagnosis = diagnose(b"PATCH\0\0\0EOF", &rom());
+        assert_eq!(diagnosis.findings.len(), 1);
+        assert!(diagnosis.findings[0].summary.contains("IPS"));
+    }
+
+    #[test]
+    fn test_already_patched() {
+        let rom = rom();
+        let raw_patch = patch_for(&rom);
+        let patched = Patch::parse(&raw_patch).unwrap().apply(&rom).unwrap();
+        let diagnosis = diagnose(&raw_patch, &patched);
+        assert_eq!(diagnosis.findings[0].rank, Rank::Likely);
+        assert!(diagnosis.findings[0].summary.contains("already patched"));
+    }
+
+    #[test]
+    fn test_snes_header() {
+        let rom = rom();
+        let mut headered = vec![0; 512];
+        headered.extend_from_slice(&rom);
+        let diagnosis = diagnose(&patch_for(&rom), &headered);
+        assert_eq!(diagnosis.findings[0].rank, Rank::Solution);
+        assert!(diagnosis.findings[0].summary.contains("copier header"));
+    }
+
+    #[test]
+    fn test_overdump() {
+        let rom = rom();
+        let mut overdump = rom.clone();
+        overdump.extend_from_slice(&[0xff; 1000]);
+        let diagnosis = diagnose(&patch_for(&rom), &overdump);
+        assert_eq!(diagnosis.findings[0].rank, Rank::Solution);
+        assert!(diagnosis.findings[0].summary.contains("extra data"));
+    }
+
+    #[test]
+    fn test_other_revision() {
+        let rom = rom();
+        let mut other = rom.clone();
+        other[0] ^= 1;
+        let diagnosis = diagnose(&patch_for(&rom), &other);
+        assert_eq!(diagnosis.findings[0].rank, Rank::Likely);
+        assert!(!diagnosis.applies());
+    }
+}
@@ -0,0 +1,280 @@
+//! Parse and apply EBP patches, the `.ebp` files of the EarthBound hacking community.
+//!
+//! EBP patches are IPS patches followed by a JSON object with metadata about the hack, like its
+//! title and author, as written by EBPatcher and CoilSnake. Patches without metadata are plain IPS
+//! patches. Like IPS they have no checksums, [`EbpPatch::to_ups`] converts them to UPS patches for
+//! a given source file.
+//!
+//! ## Example
+//!
+//! ```
+//! use ups::ebp::EbpPatch;
+//!
+//! // Overwrite 2 bytes at offset 1.
+//! let raw_patch = b"PATCH\x00\x00\x01\x00\x02hiEOF{\"title\": \"Hi\", \"author\": \"Ann\"}";
+//! let patch = EbpPatch::parse(raw_patch)?;
+//! assert_eq!(patch.metadata.as_ref().unwrap().title, "Hi");
+//! assert_eq!(patch.apply(b"abcd"), b"ahid");
+//!
+//! # Ok::<_, ups::ebp::EbpParseError>(())
+//! ```
+use std::collections::BTreeMap;
+
+use crate::ips::{self, IpsParseError, IpsPatch};
+use crate::Patch;
+
+/// EBP patch contents, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EbpPatch {
+    /// IPS patch applied to the ROM.
+    pub ips: IpsPatch,
+    /// Metadata after the IPS patch, `None` for patches without it.
+    pub metadata: Option<EbpMetadata>,
+}
+
+/// Metadata of an [`EbpPatch`]. Fields missing from the JSON object are empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EbpMetadata {
+    pub title: String,
+    pub author: String,
+    /// Free-form description, may span multiple lines.
+    pub description: String,
+    /// Tool that made the patch, e.g. "EBPatcher".
+    pub patcher: String,
+    /// Other string and scalar fields of the JSON object, with scalars like numbers kept as
+    /// written.
+    pub other: BTreeMap<String, String>,
+}
+
+/// Error from [`EbpPatch::parse`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum EbpParseError {
+    #[error("this doesn't seem to be an EBP file: {}", .0)]
+    FormatMismatch(String),
+}
+
+impl From<IpsParseError> for EbpParseError {
+    fn from(err: IpsParseError) -> Self {
+        match err {
+            IpsParseError::FormatMismatch(reason) => EbpParseError::FormatMismatch(reason),
+        }
+    }
+}
+
+impl EbpPatch {
+    /// Parse an EBP patch.
+    pub fn parse(input: &[u8]) -> Result<Self, EbpParseError> {
+        let (records, rest) = ips::parse_records(input)?;
+        let ips = IpsPatch {
+            records,
+            truncate: None,
+        };
+        let metadata = match std::str::from_utf8(rest) {
+            Ok(json) if json.trim().is_empty() => None,
+            Ok(json) => Some(parse_metadata(json).map_err(|reason| {
+                EbpParseError::FormatMismatch(format!("invalid metadata: {}", reason))
+            })?),
+            Err(_) => return Err(EbpParseError::FormatMismatch("metadata isn't UTF-8".into())),
+        };
+        Ok(EbpPatch { ips, metadata })
+    }
+
+    /// Apply the patch to `src`, see [`IpsPatch::apply`].
+    pub fn apply(&self, src: &[u8]) -> Vec<u8> {
+        self.ips.apply(src)
+    }
+
+    /// Equivalent UPS patch for `src`, which can be checked and reverted.
+    pub fn to_ups(&self, src: &[u8]) -> Patch {
+        self.ips.to_ups(src)
+    }
+}
+
+// Parse the metadata JSON object. Patchers only write flat objects, nested objects and arrays
+// aren't supported.
+fn parse_metadata(json: &str) -> Result<EbpMetadata, String> {
+    let mut parser = Json { rest: json };
+    let mut metadata = EbpMetadata::default();
+    parser.expect('{')?;
+    if !parser.eat('}') {
+        loop {
+            let key = parser.string()?;
+            parser.expect(':')?;
+            let value = parser.value()?;
+            match key.as_str() {
+                "title" => metadata.title = value,
+                "author" => metadata.author = value,
+                "description" => metadata.description = value,
+                "patcher" => metadata.patcher = value,
+                _ => {
+                    metadata.other.insert(key, value);
+                }
+            }
+            if parser.eat('}') {
+                break;
+            }
+            parser.expect(',')?;
+        }
+    }
+    if !parser.rest.trim().is_empty() {
+        return Err("unexpected data after the JSON object".into());
+    }
+    Ok(metadata)
+}
+
+struct Json<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Json<'a> {
+    // Skip whitespace and `c` if it's next.
+    fn eat(&mut self, c: char) -> bool {
+        self.rest = self.rest.trim_start();
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected {:?}", c))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        let mut chars = self.rest.char_indices();
+        loop {
+            let (i, c) = chars.next().ok_or("unterminated string")?;
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 1..];
+                    return Ok(out);
+                }
+                '\\' => {
+                    let (_, escape) = chars.next().ok_or("unterminated string")?;
+                    out.push(match escape {
+                        '"' | '\\' | '/' => escape,
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => {
+                            let unit = hex4(&mut chars)?;
+                            let code = if (0xd800..0xdc00).contains(&unit)
+                                && chars.as_str().starts_with("\\u")
+                            {
+                                chars.nth(1);
+                                match hex4(&mut chars)? {
+                                    low @ 0xdc00..=0xdfff => {
+                                        0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00)
+                                    }
+                                    _ => 0xfffd,
+                                }
+                            } else {
+                                unit
+                            };
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => return Err(format!("invalid escape {:?}", escape)),
+                    });
+                }
+                c if c < ' ' => return Err("control character in string".into()),
+                c => out.push(c),
+            }
+        }
+    }
+
+    // String value, or other scalar value as written.
+    fn value(&mut self) -> Result<String, String> {
+        self.rest = self.rest.trim_start();
+        if self.rest.starts_with('"') {
+            return self.string();
+        }
+        let end = self
+            .rest
+            .find(|c: char| c == ',' || c == '}' || c.is_whitespace())
+            .unwrap_or(self.rest.len());
+        let (value, rest) = self.rest.split_at(end);
+        let is_number = !value.is_empty()
+            && value
+                .chars()
+                .all(|c| c.is_ascii_digit() || "+-.eE".contains(c));
+        if !is_number && !["true", "false", "null"].contains(&value) {
+            return Err(format!("unsupported value {:?}", value));
+        }
+        self.rest = rest;
+        Ok(value.to_string())
+    }
+}
+
+// 4 hex digits of a \u escape.
+fn hex4(chars: &mut std::str::CharIndices) -> Result<u32, String> {
+    let digits: String = chars.take(4).map(|(_, c)| c).collect();
+    match u32::from_str_radix(&digits, 16) {
+        Ok(unit) if digits.len() == 4 => Ok(unit),
+        _ => Err(format!("invalid unicode escape {:?}", digits)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ips::IpsRecord;
+
+    #[test]
+    fn test_parse() {
+        let raw =
+            b"PATCH\x00\x00\x01\x00\x02hiEOF\n{\"patcher\": \"EBPatcher\", \"author\": \"Ann\", \
+            \"title\": \"Hi \\u00e9\\ud83d\\ude00\", \"description\": \"a\\nb\", \"version\": 2}\n";
+        let patch = EbpPatch::parse(raw).unwrap();
+        assert_eq!(
+            patch.ips.records,
+            vec![IpsRecord::Data {
+                offset: 1,
+                data: b"hi".to_vec()
+            }]
+        );
+        let metadata = patch.metadata.as_ref().unwrap();
+        assert_eq!(metadata.title, "Hi \u{e9}\u{1f600}");
+        assert_eq!(metadata.author, "Ann");
+        assert_eq!(metadata.description, "a\nb");
+        assert_eq!(metadata.patcher, "EBPatcher");
+        assert_eq!(metadata.other.get("version").map(String::as_str), Some("2"));
+        assert_eq!(patch.apply(b"abcd"), b"ahid");
+
+        let plain = EbpPatch::parse(b"PATCH\x00\x00\x01\x00\x02hiEOF").unwrap();
+        assert_eq!(plain.metadata, None);
+        assert_eq!(plain.ips.records, patch.ips.records);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for raw in [
+            &b"IPS"[..],
+            b"PATCH\x00\x00\x01\x00\x05abEOF",
+            b"PATCH\x00\x00\x01\x00\x01aEOF\x00\x00\x03",
+            b"PATCHEOF{\"title\": \"a}",
+            b"PATCHEOF{\"title\": [1]}",
+            b"PATCHEOF{\"title\" \"a\"}",
+            b"PATCHEOF{\"title\": \"\\x\"}",
+            b"PATCHEOF{} {}",
+            b"PATCHEOF{\"title\": \"\xff\"}",
+        ] {
+            assert!(
+                matches!(EbpPatch::parse(raw), Err(EbpParseError::FormatMismatch(_))),
+                "{:?}",
+                String::from_utf8_lossy(raw)
+            );
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::aps::{ApsParseError, ApsPatch};
+use crate::bps::{BpsParseError, BpsPatch};
+use crate::ebp::{EbpParseError, EbpPatch};
+use crate::ips::{IpsParseError, IpsPatch};
+use crate::ninja::{NinjaParseError, NinjaPatch};
+use crate::ppf::{PpfParseError, PpfPatch};
+#[cfg(feature = "vcdiff")]
+use crate::vcdiff::{VcdiffParseError, VcdiffPatch};
+use crate::{Patch, UpsParseError, UpsPatchErrors};
+
+/// Patch format supported by this crate, from [`detect_format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum PatchFormat {
+    Ups,
+    Ips,
+    /// IPS patch with JSON metadata, see [`ebp`](crate::ebp).
+    Ebp,
+    Bps,
+    Ppf,
+    /// APS patch, either the GBA or the N64 variant.
+    Aps,
+    Ninja,
+    /// VCDIFF, the format of xdelta3. Detected without the `vcdiff` feature, but only parsed
+    /// with it.
+    Vcdiff,
+}
+
+/// Patch parsed by [`parse_any`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AnyPatch {
+    Ups(Patch),
+    Ips(IpsPatch),
+    Ebp(EbpPatch),
+    Bps(BpsPatch),
+    Ppf(PpfPatch),
+    Aps(ApsPatch),
+    Ninja(NinjaPatch),
+    #[cfg(feature = "vcdiff")]
+    Vcdiff(VcdiffPatch),
+}
+
+/// Error from [`parse_any`].
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum AnyParseError {
+    #[error("unknown patch format")]
+    UnknownFormat,
+    #[error("{} patches aren't supported in this build", .0)]
+    Disabled(PatchFormat),
+    #[error(transparent)]
+    Ups(#[from] UpsParseError),
+    #[error(transparent)]
+    Ips(#[from] IpsParseError),
+    #[error(transparent)]
+    Ebp(#[from] EbpParseError),
+    #[error(transparent)]
+    Bps(#[from] BpsParseError),
+    #[error(transparent)]
+    Ppf(#[from] PpfParseError),
+    #[error(transparent)]
+    Aps(#[from] ApsParseError),
+    #[error(transparent)]
+    Ninja(#[from] NinjaParseError),
+    #[cfg(feature = "vcdiff")]
+    #[error(transparent)]
+    Vcdiff(#[from] VcdiffParseError),
+}
+
+impl PatchFormat {
+    /// Every format, in the order [`detect_format`] checks them.
+    pub const ALL: [PatchFormat; 8] = [
+        PatchFormat::Ups,
+        PatchFormat::Ips,
+        PatchFormat::Ebp,
+        PatchFormat::Bps,
+        PatchFormat::Ppf,
+        PatchFormat::Aps,
+        PatchFormat::Ninja,
+        PatchFormat::Vcdiff,
+    ];
+}
+
+impl Display for PatchFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.display_name())
+    }
+}
+
+impl PatchFormat {
+    pub(crate) fn display_name(self) -> &'static str {
+        match self {
+            PatchFormat::Ups => "UPS",
+            PatchFormat::Ips => "IPS",
+            PatchFormat::Ebp => "EBP",
+            PatchFormat::Bps => "BPS",
+            PatchFormat::Ppf => "PPF",
+            PatchFormat::Aps => "APS",
+            PatchFormat::Ninja => "NINJA",
+            PatchFormat::Vcdiff => "xdelta (VCDIFF)",
+        }
+    }
+}
+
+/// Format of `raw_patch` going by its magic bytes, `None` if it isn't a known format.
+///
+/// Only the start of the patch is checked, except for IPS patches which are detected as EBP if
+/// they end with a JSON object. The patch may still fail to parse.
+///
+/// ```
+/// use ups::{detect_format, Patch, PatchFormat};
+///
+/// let raw_patch = Patch::diff(b"abc", b"abd").serialize();
+/// assert_eq!(detect_format(&raw_patch), Some(PatchFormat::Ups));
+/// assert_eq!(detect_format(b"PATCH\x00\x00\x01\x00\x02hiEOF"), Some(PatchFormat::Ips));
+/// assert_eq!(detect_format(b"hello"), None);
+/// ```
+pub fn detect_format(raw_patch: &[u8]) -> Option<PatchFormat> {
+    let magics: [(&[u8], PatchFormat); 7] = [
+        (b"UPS1", PatchFormat::Ups),
+        (b"PATCH", PatchFormat::Ips),
+        (b"BPS1", PatchFormat::Bps),
+        (b"PPF", PatchFormat::Ppf),
+        (b"APS1", PatchFormat::Aps),
+        (b"NINJA2", PatchFormat::Ninja),
+        (&[0xd6, 0xc3, 0xc4], PatchFormat::Vcdiff),
+    ];
+    let format = magics
+        .iter()
+        .find(|(magic, _)| raw_patch.starts_with(magic))
+        .map(|(_, format)| *format)?;
+    let ends_with_json = raw_patch.iter().rev().find(|b| !b.is_ascii_whitespace()) == Some(&b'}');
+    if format == PatchFormat::Ips && ends_with_json {
+        return Some(PatchFormat::Ebp);
+    }
+    Some(format)
+}
+
+/// Parse a patch in any format this crate supports, detected with [`detect_format`].
+///
+/// ```
+/// use ups::{parse_any, AnyPatch};
+///
+/// let patch = parse_any(b"PATCH\x00\x00\x01\x00\x02hiEOF")?;
+/// assert!(matches!(patch, AnyPatch::Ips(_)));
+/// assert_eq!(patch.apply(b"abcd")?, b"ahid");
+///
+/// # Ok::<_, Box<dyn std::error::Error>>(())
+/// ```
+pub fn parse_any(raw_patch: &[u8]) -> Result<AnyPatch, AnyParseError> {
+    let format = detect_format(raw_patch).ok_or(AnyParseError::UnknownFormat)?;
+    parse_as(raw_patch, format)
+}
+
+// Parse `raw_patch` as a `format` patch, regardless of its magic bytes.
+pub(crate) fn parse_as(raw_patch: &[u8], format: PatchFormat) -> Result<AnyPatch, AnyParseError> {
+    Ok(match format {
+        PatchFormat::Ups => AnyPatch::Ups(Patch::parse(raw_patch)?),
+        PatchFormat::Ips => AnyPatch::Ips(IpsPatch::parse(raw_patch)?),
+        PatchFormat::Ebp => AnyPatch::Ebp(EbpPatch::parse(raw_patch)?),
+        PatchFormat::Bps => AnyPatch::Bps(BpsPatch::parse(raw_patch)?),
+        PatchFormat::Ppf => AnyPatch::Ppf(PpfPatch::parse(raw_patch)?),
+        PatchFormat::Aps => AnyPatch::Aps(ApsPatch::parse(raw_patch)?),
+        PatchFormat::Ninja => AnyPatch::Ninja(NinjaPatch::parse(raw_patch)?),
+        #[cfg(feature = "vcdiff")]
+        PatchFormat::Vcdiff => AnyPatch::Vcdiff(VcdiffPatch::parse(raw_patch)?),
+        #[cfg(not(feature = "vcdiff"))]
+        PatchFormat::Vcdiff => return Err(AnyParseError::Disabled(format)),
+    })
+}
+
+impl AnyPatch {
+    /// Format of the patch.
+    pub fn format(&self) -> PatchFormat {
+        match self {
+            AnyPatch::Ups(_) => PatchFormat::Ups,
+            AnyPatch::Ips(_) => PatchFormat::Ips,
+            AnyPatch::Ebp(_) => PatchFormat::Ebp,
+            AnyPatch::Bps(_) => PatchFormat::Bps,
+            AnyPatch::Ppf(_) => PatchFormat::Ppf,
+            AnyPatch::Aps(_) => PatchFormat::Aps,
+            AnyPatch::Ninja(_) => PatchFormat::Ninja,
+            #[cfg(feature = "vcdiff")]
+            AnyPatch::Vcdiff(_) => PatchFormat::Vcdiff,
+        }
+    }
+
+    /// Apply the patch to `src`. Formats without checksums, like IPS, never fail.
+    pub fn apply(&self, src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors> {
+        match self {
+            AnyPatch::Ups(patch) => patch.apply(src),
+            AnyPatch::Ips(patch) => Ok(patch.apply(src)),
+            AnyPatch::Ebp(patch) => Ok(patch.apply(src)),
+            AnyPatch::Bps(patch) => patch.apply(src),
+            AnyPatch::Ppf(patch) => patch.apply(src),
+            AnyPatch::Aps(patch) => patch.apply(src),
+            AnyPatch::Ninja(patch) => patch.apply(src),
+            #[cfg(feature = "vcdiff")]
+            AnyPatch::Vcdiff(patch) => patch.apply(src),
+        }
+    }
+
+    /// Equivalent UPS patch for `src`. UPS patches are returned as is, without checking they
+    /// apply to `src`.
+    pub fn to_ups(&self, src: &[u8]) -> Result<Patch, UpsPatchErrors> {
+        match self {
+            AnyPatch::Ups(patch) => Ok(patch.clone()),
+            AnyPatch::Ips(patch) => Ok(patch.to_ups(src)),
+            AnyPatch::Ebp(patch) => Ok(patch.to_ups(src)),
+            AnyPatch::Bps(patch) => patch.to_ups(src),
+            AnyPatch::Ppf(patch) => patch.to_ups(src),
+            AnyPatch::Aps(patch) => patch.to_ups(src),
+            AnyPatch::Ninja(patch) => patch.to_ups(src),
+            #[cfg(feature = "vcdiff")]
+            AnyPatch::Vcdiff(patch) => patch.to_ups(src),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_detect_format() {
+        let cases: [(&[u8], Option<PatchFormat>); 11] = [
+            (b"UPS1\x00\x00", Some(PatchFormat::Ups)),
+            (b"PATCHEOF", Some(PatchFormat::Ips)),
+            (b"PATCHEOF{\"title\": \"a\"}\n", Some(PatchFormat::Ebp)),
+            (b"BPS1", Some(PatchFormat::Bps)),
+            (b"PPF30", Some(PatchFormat::Ppf)),
+            (b"APS1\x00", Some(PatchFormat::Aps)),
+            (b"APS10", Some(PatchFormat::Aps)),
+            (b"NINJA2\x01", Some(PatchFormat::Ninja)),
+            (b"\xd6\xc3\xc4\x00", Some(PatchFormat::Vcdiff)),
+            (b"UPS", None),
+            (b"", None),
+        ];
+        for (raw, format) in cases {
+            assert_eq!(
+                detect_format(raw),
+                format,
+                "{:?}",
+                String::from_utf8_lossy(raw)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_any() {
+        let raw = Patch::diff(b"abcd", b"abCD").serialize();
+        let patch = parse_any(&raw).unwrap();
+        assert_eq!(patch.format(), PatchFormat::Ups);
+        assert_eq!(patch.apply(b"abcd").unwrap(), b"abCD");
+        assert_eq!(patch.to_ups(b"abcd").unwrap(), Patch::parse(&raw).unwrap());
+
+        let patch = parse_any(b"PATCH\x00\x00\x01\x00\x02hiEOF{\"title\": \"Hi\"}").unwrap();
+        assert_eq!(patch.format(), PatchFormat::Ebp);
+        assert_eq!(patch.apply(b"abcd").unwrap(), b"ahid");
+        assert_eq!(
+            patch.to_ups(b"abcd").unwrap(),
+            Patch::diff(b"abcd", b"ahid")
+        );
+
+        assert!(matches!(
+            parse_any(b"hello"),
+            Err(AnyParseError::UnknownFormat)
+        ));
+        assert!(matches!(parse_any(b"BPS1"), Err(AnyParseError::Bps(_))));
+        #[cfg(not(feature = "vcdiff"))]
+        assert!(matches!(
+            parse_any(b"\xd6\xc3\xc4\x00"),
+            Err(AnyParseError::Disabled(PatchFormat::Vcdiff))
+        ));
+    }
+}
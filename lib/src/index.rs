@@ -0,0 +1,314 @@
+//! Index of patch metadata, used to look up patches by file checksums without keeping the
+//! patches themselves in memory.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::fs;
+//! use ups::index::PatchIndex;
+//! use ups::Checksum;
+//!
+//! let index = PatchIndex::from_dir("patches")?;
+//! let rom = fs::read("game.gba")?;
+//! for candidate in index.find_for_source(Checksum::from_bytes(&rom)) {
+//!     println!("{}: {:?}", candidate.entry.path.display(), candidate.kind);
+//! }
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{Checksum, Patch, Requirement, Requirements};
+
+/// Metadata for every indexed patch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PatchIndex {
+    entries: Vec<IndexEntry>,
+}
+
+/// Metadata for a single patch in a [`PatchIndex`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IndexEntry {
+    /// Path to the patch file.
+    pub path: PathBuf,
+    /// Source file size.
+    pub src_size: usize,
+    /// Source file checksum.
+    pub src_checksum: Checksum,
+    /// Destination file size.
+    pub dst_size: usize,
+    /// Destination file checksum.
+    pub dst_checksum: Checksum,
+}
+
+/// Result from [`PatchIndex::find_for_source`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate<'a> {
+    pub entry: &'a IndexEntry,
+    pub kind: MatchKind,
+}
+
+/// How a file matched an [`IndexEntry`], in ranking order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MatchKind {
+    /// The file is the patch source, the patch can be applied to it.
+    Source,
+    /// The file is the patch destination, it has already been patched with it.
+    AlreadyPatched,
+}
+
+impl IndexEntry {
+    /// Size and checksum required for the source and destination files, like
+    /// [`Patch::requirements`].
+    pub fn requirements(&self) -> Requirements {
+        Requirements {
+            src: Requirement {
+                size: self.src_size,
+                crc32: self.src_checksum,
+            },
+            dst: Requirement {
+                size: self.dst_size,
+                crc32: self.dst_checksum,
+            },
+        }
+    }
+}
+
+impl PatchIndex {
+    /// Create an empty index.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index every parseable `.ups` file in `dir`, non-recursively, inserting them sorted by path.
+    /// Files which fail to parse are skipped.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) == Some("ups") && path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        let mut index = PatchIndex::new();
+        for path in paths {
+            if let Ok(patch) = Patch::parse(&fs::read(&path)?) {
+                index.insert(path, &patch);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Add `patch` located at `path` to the index.
+    pub fn insert<P: Into<PathBuf>>(&mut self, path: P, patch: &Patch) {
+        self.entries.push(IndexEntry {
+            path: path.into(),
+            src_size: patch.src_size,
+            src_checksum: patch.src_checksum,
+            dst_size: patch.dst_size,
+            dst_checksum: patch.dst_checksum,
+        });
+    }
+
+    /// All indexed patches, in insertion order.
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// Find patches related to a file with the given checksum. Patches which apply to it come
+    /// first, followed by patches it has already been patched with. Order is otherwise kept from
+    /// [`entries`](PatchIndex::entries).
+    pub fn find_for_source(&self, checksum: Checksum) -> Vec<Candidate<'_>> {
+        let mut candidates: Vec<_> = self
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                let kind = if entry.src_checksum == checksum {
+                    MatchKind::Source
+                } else if entry.dst_checksum == checksum {
+                    MatchKind::AlreadyPatched
+                } else {
+                    return None;
+                };
+                Some(Candidate { entry, kind })
+            })
+            .collect();
+        // Stable sort keeps entry order within each kind.
+        candidates.sort_by_key(|c| c.kind);
+        candidates
+    }
+
+    /// Shortest sequence of patches turning a file with checksum `from` into one with checksum
+    /// `to`, e.g. to upgrade a hack through intermediate versions. Patches are only applied, never
+    /// reverted. Among routes of the same length, the one using earlier entries wins.
+    ///
+    /// Each patch must apply to the output of the previous one, see
+    /// [`Requirements::can_compose`], so patches whose source size doesn't match the previous
+    /// output are skipped.
+    ///
+    /// Returns an empty route if `from` and `to` are equal and `None` if there's no route.
+    pub fn route(&self, from: Checksum, to: Checksum) -> Option<Vec<&IndexEntry>> {
+        if from == to {
+            return Some(Vec::new());
+        }
+        let mut by_source: HashMap<Requirement, Vec<&IndexEntry>> = HashMap::new();
+        for entry in &self.entries {
+            by_source
+                .entry(entry.requirements().src)
+                .or_default()
+                .push(entry);
+        }
+        // Patch reaching each file first, breadth-first so routes are as short as possible. Files
+        // are keyed by size too: the same checksum can be reached with different sizes, and only
+        // some of them may continue the route.
+        let mut reached: HashMap<Requirement, &IndexEntry> = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(None);
+        while let Some(file) = queue.pop_front() {
+            let entries: Vec<&IndexEntry> = match file {
+                // The size of `from` isn't known, the first patch can be any for its checksum.
+                None => self
+                    .entries
+                    .iter()
+                    .filter(|e| e.src_checksum == from)
+                    .collect(),
+                Some(file) => by_source.get(&file).cloned().unwrap_or_default(),
+            };
+            for entry in entries {
+                let dst = entry.requirements().dst;
+                if let Entry::Vacant(vacant) = reached.entry(dst) {
+                    vacant.insert(entry);
+                    if dst.crc32 == to {
+                        // Patches for `from` are all first steps, earlier steps never start there.
+                        let mut route = vec![entry];
+                        let mut current = entry;
+                        while current.src_checksum != from {
+                            current = reached[&current.requirements().src];
+                            route.push(current);
+                        }
+                        route.reverse();
+                        return Some(route);
+                    }
+                    queue.push_back(Some(dst));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_for_source_ranks_source_matches_first() {
+        let rom = b"clean rom";
+        let mut index = PatchIndex::new();
+        index.insert("older.ups", &Patch::diff(b"older rom", rom));
+        index.insert("unrelated.ups", &Patch::diff(b"a", b"b"));
+        index.insert("hack.ups", &Patch::diff(rom, b"hacked rom"));
+        index.insert("translation.ups", &Patch::diff(rom, b"translated rom"));
+
+        let found: Vec<_> = index
+            .find_for_source(Checksum::from_bytes(rom))
+            .into_iter()
+            .map(|c| (c.entry.path.to_str().unwrap(), c.kind))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("hack.ups", MatchKind::Source),
+                ("translation.ups", MatchKind::Source),
+                ("older.ups", MatchKind::AlreadyPatched),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_route() {
+        let mut index = PatchIndex::new();
+        index.insert("v1.ups", &Patch::diff(b"rom", b"hack v1"));
+        index.insert("v1-v2.ups", &Patch::diff(b"hack v1", b"hack v2"));
+        index.insert("v2-v3.ups", &Patch::diff(b"hack v2", b"hack v3"));
+        index.insert("v1-v3.ups", &Patch::diff(b"hack v1", b"hack v3"));
+        index.insert("v3-v1.ups", &Patch::diff(b"hack v3", b"hack v1"));
+        index.insert("other.ups", &Patch::diff(b"other rom", b"other hack"));
+        let route = |from: &[u8], to: &[u8]| {
+            index
+                .route(Checksum::from_bytes(from), Checksum::from_bytes(to))
+                .map(|r| {
+                    r.iter()
+                        .map(|e| e.path.to_str().unwrap())
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        assert_eq!(route(b"rom", b"hack v3"), Some(vec!["v1.ups", "v1-v3.ups"]));
+        assert_eq!(route(b"rom", b"hack v2"), Some(vec!["v1.ups", "v1-v2.ups"]));
+        assert_eq!(
+            route(b"hack v3", b"hack v2"),
+            Some(vec!["v3-v1.ups", "v1-v2.ups"])
+        );
+        assert_eq!(route(b"rom", b"rom"), Some(vec![]));
+        assert_eq!(route(b"hack v1", b"rom"), None);
+        assert_eq!(route(b"rom", b"other hack"), None);
+
+        // Same checksum as "hack v2" but for a different size, e.g. a header added after patching.
+        let mut resized = Patch::diff(b"hack v2", b"hack v4");
+        resized.src_size += 512;
+        index.insert("v2-v4.ups", &resized);
+        let route = |from: &[u8], to: &[u8]| {
+            index.route(Checksum::from_bytes(from), Checksum::from_bytes(to))
+        };
+        assert!(route(b"hack v1", b"hack v4").is_none());
+    }
+
+    #[test]
+    fn test_route_same_checksum_different_sizes() {
+        // "hack v1" is reached with two sizes, only the second one continues to "hack v2".
+        let mut short = Patch::diff(b"rom", b"hack v1");
+        short.dst_size -= 1;
+        let mut long = Patch::diff(b"other rom", b"hack v1");
+        long.src_size = 3;
+        long.src_checksum = Checksum::from_bytes(b"rom");
+        let mut index = PatchIndex::new();
+        index.insert("short.ups", &short);
+        index.insert("long.ups", &long);
+        index.insert("v1-v2.ups", &Patch::diff(b"hack v1", b"hack v2"));
+
+        let route = index
+            .route(
+                Checksum::from_bytes(b"rom"),
+                Checksum::from_bytes(b"hack v2"),
+            )
+            .unwrap();
+        let paths: Vec<_> = route.iter().map(|e| e.path.to_str().unwrap()).collect();
+        assert_eq!(paths, ["long.ups", "v1-v2.ups"]);
+    }
+
+    #[test]
+    fn test_from_dir_skips_invalid_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Patch::diff(b"src", b"dst");
+        fs::write(dir.path().join("d.ups"), patch.serialize()).unwrap();
+        fs::write(dir.path().join("a.ups"), patch.serialize()).unwrap();
+        fs::write(dir.path().join("b.ups"), b"garbage").unwrap();
+        fs::write(dir.path().join("c.txt"), patch.serialize()).unwrap();
+
+        let mut index = PatchIndex::from_dir(dir.path()).unwrap();
+        let paths: Vec<_> = index.entries().iter().map(|e| e.path.clone()).collect();
+        assert_eq!(paths, [dir.path().join("a.ups"), dir.path().join("d.ups")]);
+        assert_eq!(index.entries()[0].dst_checksum, patch.dst_checksum);
+        // Later insertions go last, whatever their path.
+        index.insert("0.ups", &patch);
+        assert_eq!(index.entries()[2].path, Path::new("0.ups"));
+    }
+}
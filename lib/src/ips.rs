@@ -0,0 +1,239 @@
+//! Parse and apply IPS patches, the other common ROM hack patch format.
+//!
+//! IPS patches are lists of records overwriting bytes at 24-bit offsets, optionally followed by a
+//! size to truncate the output to. Unlike UPS they have no checksums, so there's no way to check
+//! the patch is applied to the right file, and they can't be reverted. [`IpsPatch::to_ups`]
+//! converts them to UPS patches for a given source file.
+//!
+//! ## Example
+//!
+//! ```
+//! use ups::ips::IpsPatch;
+//!
+//! // Overwrite 2 bytes at offset 1.
+//! let raw_patch = b"PATCH\x00\x00\x01\x00\x02hiEOF";
+//! let patch = IpsPatch::parse(raw_patch)?;
+//! assert_eq!(patch.apply(b"abcd"), b"ahid");
+//!
+//! # Ok::<_, ups::ips::IpsParseError>(())
+//! ```
+use crate::{Checksum, Patch};
+
+const MAGIC: &[u8] = b"PATCH";
+const EOF: &[u8] = b"EOF";
+
+/// IPS patch contents, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IpsPatch {
+    /// Records in the order they're applied, later ones overwrite earlier ones where they overlap.
+    pub records: Vec<IpsRecord>,
+    /// Size to truncate the output to, from the Lunar IPS extension.
+    pub truncate: Option<usize>,
+}
+
+/// Write to the output in an [`IpsPatch`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum IpsRecord {
+    /// Overwrite bytes at `offset` with `data`.
+    Data { offset: usize, data: Vec<u8> },
+    /// Overwrite `len` bytes at `offset` with `byte`.
+    Rle { offset: usize, len: usize, byte: u8 },
+}
+
+/// Error from [`IpsPatch::parse`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum IpsParseError {
+    #[error("this doesn't seem to be an IPS file: {}", .0)]
+    FormatMismatch(String),
+}
+
+impl IpsRecord {
+    /// Offset of the first byte written.
+    pub fn offset(&self) -> usize {
+        match self {
+            IpsRecord::Data { offset, .. } | IpsRecord::Rle { offset, .. } => *offset,
+        }
+    }
+
+    /// Number of bytes written.
+    pub fn len(&self) -> usize {
+        match self {
+            IpsRecord::Data { data, .. } => data.len(),
+            IpsRecord::Rle { len, .. } => *len,
+        }
+    }
+
+    /// Whether the record writes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl IpsPatch {
+    /// Parse an IPS patch.
+    pub fn parse(input: &[u8]) -> Result<Self, IpsParseError> {
+        let err = |reason: &str| IpsParseError::FormatMismatch(reason.into());
+        let (records, input) = parse_records(input)?;
+        let truncate = match input.len() {
+            0 => None,
+            3 => Some(be(input)),
+            _ => return Err(err("unexpected data after the \"EOF\" marker")),
+        };
+        Ok(IpsPatch { records, truncate })
+    }
+
+    /// Size of the output for a source file of `src_size` bytes.
+    pub fn output_size(&self, src_size: usize) -> usize {
+        if let Some(size) = self.truncate {
+            return size;
+        }
+        self.records
+            .iter()
+            .map(|r| r.offset().saturating_add(r.len()))
+            .fold(src_size, std::cmp::max)
+    }
+
+    /// Apply the patch to `src`. Records past the end of `src` extend it, filling the gap with
+    /// zeroes.
+    pub fn apply(&self, src: &[u8]) -> Vec<u8> {
+        let mut output = src.to_vec();
+        output.resize(self.output_size(src.len()), 0);
+        for record in &self.records {
+            let start = std::cmp::min(record.offset(), output.len());
+            let end = std::cmp::min(record.offset().saturating_add(record.len()), output.len());
+            match record {
+                IpsRecord::Data { data, .. } => {
+                    output[start..end].copy_from_slice(&data[..end - start]);
+                }
+                IpsRecord::Rle { byte, .. } => {
+                    output[start..end].iter_mut().for_each(|b| *b = *byte);
+                }
+            }
+        }
+        output
+    }
+
+    /// Equivalent UPS patch for `src`, which can be checked and reverted.
+    pub fn to_ups(&self, src: &[u8]) -> Patch {
+        let dst = self.apply(src);
+        let patch = Patch::diff(src, &dst);
+        debug_assert_eq!(patch.dst_checksum, Checksum::from_bytes(&dst));
+        patch
+    }
+}
+
+// Parse the preamble and records of an IPS patch, returning the data after the "EOF" marker.
+pub(crate) fn parse_records(input: &[u8]) -> Result<(Vec<IpsRecord>, &[u8]), IpsParseError> {
+    let err = |reason: &str| IpsParseError::FormatMismatch(reason.into());
+    let mut input = input
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| err("invalid preamble, expected \"PATCH\""))?;
+    let mut records = Vec::new();
+    loop {
+        let offset = take(&mut input, 3).ok_or_else(|| err("missing \"EOF\" marker"))?;
+        if offset == EOF {
+            break;
+        }
+        let offset = be(offset);
+        let size = take(&mut input, 2).ok_or_else(|| err("truncated record"))?;
+        let record = match be(size) {
+            0 => {
+                let rle = take(&mut input, 3).ok_or_else(|| err("truncated RLE record"))?;
+                IpsRecord::Rle {
+                    offset,
+                    len: be(&rle[..2]),
+                    byte: rle[2],
+                }
+            }
+            size => IpsRecord::Data {
+                offset,
+                data: take(&mut input, size)
+                    .ok_or_else(|| err("truncated record data"))?
+                    .to_vec(),
+            },
+        };
+        records.push(record);
+    }
+    Ok((records, input))
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Some(head)
+}
+
+// Big-endian number, for the 2 and 3 byte fields.
+fn be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, &b| (n << 8) | usize::from(b))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let raw = b"PATCH\x00\x00\x02\x00\x03abc\x00\x00\x08\x00\x00\x00\x04zEOF";
+        let patch = IpsPatch::parse(raw).unwrap();
+        assert_eq!(
+            patch,
+            IpsPatch {
+                records: vec![
+                    IpsRecord::Data {
+                        offset: 2,
+                        data: b"abc".to_vec(),
+                    },
+                    IpsRecord::Rle {
+                        offset: 8,
+                        len: 4,
+                        byte: b'z',
+                    },
+                ],
+                truncate: None,
+            },
+        );
+        assert_eq!(patch.apply(b"0123"), b"01abc\0\0\0zzzz");
+        assert_eq!(patch.output_size(4), 12);
+        assert_eq!(patch.apply(b"0123456789abcdef"), b"01abc567zzzzcdef");
+    }
+
+    #[test]
+    fn test_parse_truncate() {
+        let raw = b"PATCH\x00\x00\x00\x00\x01xEOF\x00\x00\x03";
+        let patch = IpsPatch::parse(raw).unwrap();
+        assert_eq!(patch.truncate, Some(3));
+        assert_eq!(patch.apply(b"abcdef"), b"xbc");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for raw in [
+            &b""[..],
+            b"UPS1",
+            b"PATCH",
+            b"PATCH\x00\x00\x01\x00",
+            b"PATCH\x00\x00\x01\x00\x05abEOF",
+            b"PATCH\x00\x00\x01\x00\x00\x00EOF",
+            b"PATCHEOF\x00",
+        ] {
+            assert!(
+                matches!(IpsPatch::parse(raw), Err(IpsParseError::FormatMismatch(_))),
+                "{:?}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn test_to_ups() {
+        let raw = b"PATCH\x00\x00\x01\x00\x02hi\x00\x00\x06\x00\x00\x00\x02!EOF";
+        let ips = IpsPatch::parse(raw).unwrap();
+        let ups = ips.to_ups(b"abcd");
+        assert_eq!(ups.apply(b"abcd").unwrap(), b"ahid\0\0!!");
+        assert_eq!(ups.revert(b"ahid\0\0!!").unwrap(), b"abcd");
+    }
+}
@@ -2,7 +2,36 @@
 //!
 //! ## Note
 //! This crate was not designed to handle large files, it reads entire files into memory at once
-//! and keeps this data around to apply patches.
+//! and keeps this data around to apply patches. Sizes and offsets of in-memory patches are
+//! `usize`, so patches for files larger than the address space, e.g. over 4 GiB on 32-bit
+//! platforms, fail to parse. Streaming APIs like [`diff::diff_to_writer`],
+//! [`diff::patch_file_to_writer`] and [`varint::read_u64`] take `u64` sizes and work with files of
+//! any size.
+//!
+//! ## Safety
+//! This crate contains no `unsafe` code, which is enforced with `#![forbid(unsafe_code)]`. Any
+//! future fast path needing `unsafe` must live in a single audited module behind an opt-in
+//! feature, keeping the default build free of it.
+//!
+//! ## Other formats
+//! Modules like [`ips`], [`bps`] and [`ppf`] parse and apply other patch formats, and convert them
+//! to UPS patches for a given file. [`parse_any`] picks the format from the patch's magic bytes
+//! when it isn't known ahead of time. The [`codec`] traits handle every format generically, and
+//! let other crates add their own, and [`convert`] re-expresses patches in another format.
+//!
+//! ## Features
+//! - `serde`: `Serialize` for error types and patch metadata.
+//! - `rayon`: XOR large blocks on multiple threads in [`Patch::patch`].
+//! - `vcdiff`: parse and apply VCDIFF (xdelta3) patches with the `vcdiff` module.
+//! - `zstd`: [`Patch::compressibility`] compresses the XOR data with zstd, using the pure Rust
+//!   `ruzstd` encoder, instead of estimating its compressed size.
+//! - `trace`: debugging aid, `Patch::patch_traced` reports where each block is written, to
+//!   compare with other patchers.
+//!
+//! Features of this crate only pull in pure Rust dependencies, so any of them can be enabled for
+//! WASM and embedded targets. Formats behind features should be implemented on `std` or pure Rust
+//! crates rather than bindings to native libraries. This doesn't extend to `upstool`, whose
+//! `http` and `s3` features use rustls and build C and assembly code for its cryptography.
 //!
 //! ## Example
 //!
@@ -18,10 +47,46 @@
 //!
 //! # Ok::<_, Box<dyn std::error::Error>>(())
 //! ```
+#![forbid(unsafe_code)]
+
+pub mod aps;
+pub mod bps;
 mod checksum;
+pub mod codec;
+pub mod convert;
+pub mod diff;
+pub mod doctor;
+pub mod ebp;
+mod format;
+pub mod index;
+pub mod ips;
+pub mod ninja;
 mod patch;
+pub mod ppf;
+pub mod runtime;
+pub mod scrub;
+pub mod softpatch;
+mod sparse;
+pub mod store;
+mod text;
+pub mod transform;
 mod util;
-mod varint;
+pub mod varint;
+#[cfg(feature = "vcdiff")]
+pub mod vcdiff;
 
-pub use checksum::Checksum;
-pub use patch::{Block, Patch, PatchDirection, UpsParseError, UpsPatchError, UpsPatchErrors};
+#[cfg(feature = "serde")]
+pub use checksum::SerializeChecksumHex;
+pub use checksum::{Checksum, ChecksumDisplay, ChecksumOrder};
+pub use format::{detect_format, parse_any, AnyParseError, AnyPatch, PatchFormat};
+#[cfg(feature = "trace")]
+pub use patch::TraceEntry;
+pub use patch::{
+    ApplyCost, Block, BlockEditError, BlockMut, BlockOffsets, BlocksMut, BuilderError,
+    ChunkedPatcher, ComposeError, Compressibility, DivergenceKind, EngineDivergence,
+    MetadataMismatch, ParseProgress, ParseWarning, Patch, PatchBuilder, PatchDirection,
+    PatchEngine, PatchedReader, Preflight, Requirement, Requirements, SharedPatch, SplitError,
+    UpsParseError, UpsPatchError, UpsPatchErrors, UpsWriteError, PARSE_PROGRESS_INTERVAL,
+};
+pub use sparse::{SparseWriter, SPARSE_BLOCK_SIZE};
+pub use util::ByteSize;
@@ -0,0 +1,465 @@
+//! Parse and apply NINJA 2.0 patches, the `.rup` files of Derrick Sobodash's NINJA patcher.
+//!
+//! NINJA patches start with a fixed-size header of metadata fields, like the title and author of
+//! the hack, followed by commands opening a file and XORing data into it. Files have MD5 hashes of
+//! the original and patched file, and bytes past the end of the smaller one are stored as-is.
+//! [`NinjaPatch::apply`] returns [`UpsPatchErrors`] if the source or output don't match, and
+//! [`NinjaPatch::to_ups`] converts patches to UPS patches for a given file.
+//!
+//! Only single-file patches are supported, NINJA's multi-file patches for CD images and archives
+//! fail to parse with [`NinjaParseError::Unsupported`].
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::fs;
+//! use ups::ninja::NinjaPatch;
+//!
+//! let rom = fs::read("samples/rom.sfc")?;
+//! let patch = NinjaPatch::parse(&fs::read("samples/patch.rup")?)?;
+//! println!("{} by {}", patch.metadata.title, patch.metadata.author);
+//! fs::write("patched.sfc", patch.apply(&rom)?)?;
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! # Reference
+//!
+//! https://www.romhacking.net/documents/746/
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Formatter};
+
+use crate::{Checksum, MetadataMismatch, Patch, UpsPatchError, UpsPatchErrors};
+
+const MAGIC: &[u8] = b"NINJA2";
+const HEADER_LEN: usize = 0x800;
+// Lengths of the metadata fields after the encoding byte, in header order.
+const FIELD_LENS: [usize; 8] = [84, 11, 256, 48, 48, 8, 512, 1074];
+
+const COMMAND_END: u8 = 0;
+const COMMAND_OPEN: u8 = 1;
+const COMMAND_XOR: u8 = 2;
+
+/// NINJA patch contents, see the [module docs](self).
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct NinjaPatch {
+    /// Metadata fields from the header.
+    pub metadata: NinjaMetadata,
+    /// Name of the patched file, empty for patches made for a single file.
+    pub file_name: String,
+    /// Kind of file the patch was made for, e.g. 3 for SNES ROMs. See the reference in the
+    /// [module docs](self) for the values.
+    pub file_type: u8,
+    /// Source file size.
+    pub src_size: usize,
+    /// Destination file size.
+    pub dst_size: usize,
+    /// MD5 hash of the source file.
+    pub src_md5: [u8; 16],
+    /// MD5 hash of the destination file.
+    pub dst_md5: [u8; 16],
+    /// Bytes past the end of the smaller file: the end of the destination if it's larger, of the
+    /// source otherwise.
+    pub overflow: Vec<u8>,
+    /// XOR records in the order they're applied.
+    pub records: Vec<NinjaRecord>,
+}
+
+/// Metadata fields of a [`NinjaPatch`], without padding. Fields not filled by the author are
+/// empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct NinjaMetadata {
+    pub author: String,
+    pub version: String,
+    pub title: String,
+    pub genre: String,
+    pub language: String,
+    /// Release date as YYYYMMDD.
+    pub date: String,
+    pub website: String,
+    /// Free-form description, may span multiple lines.
+    pub info: String,
+}
+
+/// Data XORed into the file by a [`NinjaPatch`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NinjaRecord {
+    /// Offset of the first byte changed.
+    pub offset: usize,
+    /// Bytes XORed with the file.
+    pub xor_data: Vec<u8>,
+}
+
+/// Error from [`NinjaPatch::parse`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum NinjaParseError {
+    #[error("this doesn't seem to be a NINJA 2.0 file: {}", .0)]
+    FormatMismatch(String),
+    #[error("this NINJA patch uses an unsupported feature: {}", .0)]
+    Unsupported(String),
+}
+
+impl NinjaPatch {
+    /// Parse a NINJA 2.0 patch.
+    pub fn parse(input: &[u8]) -> Result<Self, NinjaParseError> {
+        let err = |reason: &str| NinjaParseError::FormatMismatch(reason.into());
+        if !input.starts_with(MAGIC) {
+            return Err(err("invalid preamble, expected \"NINJA2\""));
+        }
+        if input.len() < HEADER_LEN {
+            return Err(err("file too small"));
+        }
+        let metadata = parse_metadata(input[MAGIC.len()], &input[MAGIC.len() + 1..HEADER_LEN]);
+        let mut buf = &input[HEADER_LEN..];
+
+        let truncated = || err("truncated command");
+        if take(&mut buf, 1) != Some(&[COMMAND_OPEN][..]) {
+            return Err(err("expected a file to patch"));
+        }
+        let name_len = take(&mut buf, 1).ok_or_else(truncated)?[0];
+        let file_name = take(&mut buf, usize::from(name_len)).ok_or_else(truncated)?;
+        let file_type = take(&mut buf, 1).ok_or_else(truncated)?[0];
+        let src_size = read_number(&mut buf)?;
+        let dst_size = read_number(&mut buf)?;
+        let md5s = take(&mut buf, 32).ok_or_else(truncated)?;
+        let overflow = if src_size != dst_size {
+            // 'M' for data from the modified file, 'A' from the original one.
+            let expected = if dst_size > src_size { b'M' } else { b'A' };
+            if take(&mut buf, 1) != Some(&[expected][..]) {
+                return Err(err("invalid overflow data"));
+            }
+            let len = read_number(&mut buf)?;
+            if len != src_size.abs_diff(dst_size) {
+                return Err(err("invalid overflow size"));
+            }
+            take(&mut buf, len).ok_or_else(truncated)?.to_vec()
+        } else {
+            Vec::new()
+        };
+
+        let mut records = Vec::new();
+        loop {
+            match take(&mut buf, 1).map(|c| c[0]) {
+                Some(COMMAND_XOR) => {
+                    let offset = read_number(&mut buf)?;
+                    let len = read_number(&mut buf)?;
+                    let xor_data = take(&mut buf, len).ok_or_else(truncated)?;
+                    if offset.checked_add(len).is_none() {
+                        return Err(err("offset too large"));
+                    }
+                    records.push(NinjaRecord {
+                        offset,
+                        xor_data: xor_data.to_vec(),
+                    });
+                }
+                Some(COMMAND_END) | None => break,
+                Some(COMMAND_OPEN) => {
+                    return Err(NinjaParseError::Unsupported(
+                        "patches for multiple files".into(),
+                    ))
+                }
+                Some(_) => return Err(err("invalid command")),
+            }
+        }
+        Ok(NinjaPatch {
+            metadata,
+            file_name: String::from_utf8_lossy(file_name).into_owned(),
+            file_type,
+            src_size,
+            dst_size,
+            src_md5: <[u8; 16]>::try_from(&md5s[..16]).unwrap(),
+            dst_md5: <[u8; 16]>::try_from(&md5s[16..]).unwrap(),
+            overflow,
+            records,
+        })
+    }
+
+    /// Apply the patch to `src`.
+    ///
+    /// MD5 mismatches are returned as
+    /// [`SourceMetadataMismatch`](UpsPatchError::SourceMetadataMismatch) and
+    /// [`DestMetadataMismatch`](UpsPatchError::DestMetadataMismatch) checksum errors with the CRC32
+    /// of the expected and actual MD5 hashes.
+    pub fn apply(&self, src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors> {
+        let mut errors = Vec::new();
+        if let Some(err) = MetadataMismatch::size(self.src_size, src.len())
+            .or_else(|| md5_mismatch(self.src_md5, src))
+        {
+            errors.push(UpsPatchError::SourceMetadataMismatch(err));
+        }
+
+        let mut output = src.to_vec();
+        output.resize(self.dst_size, 0);
+        if self.dst_size > self.src_size {
+            let start = std::cmp::min(src.len(), self.src_size);
+            output[start..start + self.overflow.len()].copy_from_slice(&self.overflow);
+        }
+        for record in &self.records {
+            if let Some(dst) = output.get_mut(record.offset..) {
+                for (b, x) in dst.iter_mut().zip(&record.xor_data) {
+                    *b ^= x;
+                }
+            }
+        }
+        errors.extend(md5_mismatch(self.dst_md5, &output).map(UpsPatchError::DestMetadataMismatch));
+        UpsPatchErrors::check_errors(output, errors)
+    }
+
+    /// Equivalent UPS patch for `src`, which can be checked and reverted.
+    pub fn to_ups(&self, src: &[u8]) -> Result<Patch, UpsPatchErrors> {
+        Ok(Patch::diff(src, &self.apply(src)?))
+    }
+}
+
+impl Debug for NinjaPatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("NinjaPatch")
+            .field("metadata", &self.metadata)
+            .field("file_name", &self.file_name)
+            .field("file_type", &self.file_type)
+            .field("src_size", &self.src_size)
+            .field("dst_size", &self.dst_size)
+            .field("overflow", &self.overflow.len())
+            .field("records", &self.records.len())
+            .finish()
+    }
+}
+
+// Metadata fields, in UTF-8 for encoding 1 and in the author's code page otherwise, read as
+// Latin-1.
+fn parse_metadata(encoding: u8, mut fields: &[u8]) -> NinjaMetadata {
+    let mut values = FIELD_LENS.iter().map(|&len| {
+        let (field, rest) = fields.split_at(len);
+        fields = rest;
+        let field = field.split(|&b| b == 0).next().unwrap_or_default();
+        let text = if encoding == 1 {
+            String::from_utf8_lossy(field).into_owned()
+        } else {
+            field.iter().map(|&b| char::from(b)).collect()
+        };
+        text.trim_end().to_string()
+    });
+    let mut next = || values.next().unwrap_or_default();
+    NinjaMetadata {
+        author: next(),
+        version: next(),
+        title: next(),
+        genre: next(),
+        language: next(),
+        date: next(),
+        website: next(),
+        info: next(),
+    }
+}
+
+// Number stored as its length in bytes followed by its bytes, least significant first.
+fn read_number(buf: &mut &[u8]) -> Result<usize, NinjaParseError> {
+    let err = |reason: &str| NinjaParseError::FormatMismatch(reason.into());
+    let len = take(buf, 1).ok_or_else(|| err("truncated command"))?[0];
+    let bytes = take(buf, usize::from(len)).ok_or_else(|| err("truncated command"))?;
+    let mut value = 0u64;
+    for (i, &b) in bytes.iter().enumerate() {
+        if b != 0 {
+            let shifted = u64::from(b).checked_shl(8 * i as u32).filter(|_| i < 8);
+            value |= shifted.ok_or_else(|| err("number too large"))?;
+        }
+    }
+    usize::try_from(value).map_err(|_| err("number too large"))
+}
+
+fn md5_mismatch(expected: [u8; 16], data: &[u8]) -> Option<MetadataMismatch> {
+    MetadataMismatch::checksum(
+        Checksum::from_bytes(&expected),
+        Checksum::from_bytes(&md5(data)),
+    )
+}
+
+// Per-round constants from RFC 1321, the integer part of 2^32 * |sin(i)| for i in 1..=64.
+#[rustfmt::skip]
+const MD5_K: [u32; 64] = [
+    0xd76a_a478, 0xe8c7_b756, 0x2420_70db, 0xc1bd_ceee,
+    0xf57c_0faf, 0x4787_c62a, 0xa830_4613, 0xfd46_9501,
+    0x6980_98d8, 0x8b44_f7af, 0xffff_5bb1, 0x895c_d7be,
+    0x6b90_1122, 0xfd98_7193, 0xa679_438e, 0x49b4_0821,
+    0xf61e_2562, 0xc040_b340, 0x265e_5a51, 0xe9b6_c7aa,
+    0xd62f_105d, 0x0244_1453, 0xd8a1_e681, 0xe7d3_fbc8,
+    0x21e1_cde6, 0xc337_07d6, 0xf4d5_0d87, 0x455a_14ed,
+    0xa9e3_e905, 0xfcef_a3f8, 0x676f_02d9, 0x8d2a_4c8a,
+    0xfffa_3942, 0x8771_f681, 0x6d9d_6122, 0xfde5_380c,
+    0xa4be_ea44, 0x4bde_cfa9, 0xf6bb_4b60, 0xbebf_bc70,
+    0x289b_7ec6, 0xeaa1_27fa, 0xd4ef_3085, 0x0488_1d05,
+    0xd9d4_d039, 0xe6db_99e5, 0x1fa2_7cf8, 0xc4ac_5665,
+    0xf429_2244, 0x432a_ff97, 0xab94_23a7, 0xfc93_a039,
+    0x655b_59c3, 0x8f0c_cc92, 0xffef_f47d, 0x8584_5dd1,
+    0x6fa8_7e4f, 0xfe2c_e6e0, 0xa301_4314, 0x4e08_11a1,
+    0xf753_7e82, 0xbd3a_f235, 0x2ad7_d2bb, 0xeb86_d391,
+];
+
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let mut state = [0x6745_2301u32, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+    for chunk in message.chunks(64) {
+        let words: Vec<u32> = chunk
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f
+                .wrapping_add(a)
+                .wrapping_add(MD5_K[i])
+                .wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+    let mut hash = [0; 16];
+    for (out, s) in hash.chunks_mut(4).zip(&state) {
+        out.copy_from_slice(&s.to_le_bytes());
+    }
+    hash
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Some(head)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn number(n: usize) -> Vec<u8> {
+        let bytes = n.to_le_bytes();
+        let len = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        let mut out = vec![len as u8];
+        out.extend_from_slice(&bytes[..len]);
+        out
+    }
+
+    // Single-file NINJA 2.0 patch from `src` to `dst`, with one XOR record for the common bytes.
+    fn ninja(src: &[u8], dst: &[u8]) -> Vec<u8> {
+        let mut raw = MAGIC.to_vec();
+        raw.push(1);
+        for (len, value) in FIELD_LENS
+            .iter()
+            .zip(["Ann", "1.1", "Hack", "", "", "20240102"])
+        {
+            let start = raw.len();
+            raw.extend_from_slice(value.as_bytes());
+            raw.resize(start + len, 0);
+        }
+        raw.resize(HEADER_LEN, 0);
+        raw.extend_from_slice(&[COMMAND_OPEN, 0, 3]);
+        raw.extend(number(src.len()));
+        raw.extend(number(dst.len()));
+        raw.extend_from_slice(&md5(src));
+        raw.extend_from_slice(&md5(dst));
+        let common = std::cmp::min(src.len(), dst.len());
+        if src.len() != dst.len() {
+            let (kind, extra) = if dst.len() > src.len() {
+                (b'M', &dst[common..])
+            } else {
+                (b'A', &src[common..])
+            };
+            raw.push(kind);
+            raw.extend(number(extra.len()));
+            raw.extend_from_slice(extra);
+        }
+        raw.push(COMMAND_XOR);
+        raw.extend(number(0));
+        raw.extend(number(common));
+        raw.extend(src.iter().zip(dst).map(|(a, b)| a ^ b));
+        raw.push(COMMAND_END);
+        raw
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_md5() {
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        let long = b"1234567890".repeat(8);
+        assert_eq!(hex(&md5(&long)), "57edf4a22be3c955ac49da2e2107b67a");
+    }
+
+    #[test]
+    fn test_parse_apply() {
+        for (src, dst) in [
+            (&b"HELLO WORLD"[..], &b"HELLO THERE"[..]),
+            (b"HELLO", b"HELLO THERE"),
+            (b"HELLO WORLD", b"JELLO"),
+        ] {
+            let patch = NinjaPatch::parse(&ninja(src, dst)).unwrap();
+            assert_eq!(patch.metadata.title, "Hack");
+            assert_eq!(patch.metadata.author, "Ann");
+            assert_eq!(patch.metadata.date, "20240102");
+            assert_eq!(patch.metadata.genre, "");
+            assert_eq!(patch.file_type, 3);
+            assert_eq!(patch.apply(src).unwrap(), dst);
+            assert_eq!(patch.to_ups(src).unwrap().revert(dst).unwrap(), src);
+        }
+    }
+
+    #[test]
+    fn test_apply_mismatch() {
+        let patch = NinjaPatch::parse(&ninja(b"HELLO WORLD", b"HELLO THERE")).unwrap();
+        let errs = patch.apply(b"HELLO_WORLD").unwrap_err();
+        assert!(errs.iter().any(|e| matches!(
+            e,
+            UpsPatchError::SourceMetadataMismatch(MetadataMismatch::Checksum { .. })
+        )));
+        let errs = patch.apply(b"HELLO").unwrap_err();
+        assert!(errs.iter().any(|e| matches!(
+            e,
+            UpsPatchError::SourceMetadataMismatch(MetadataMismatch::Size { .. })
+        )));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let valid = ninja(b"HELLO", b"HELLO THERE");
+        let mut multi_file = valid[..valid.len() - 1].to_vec();
+        multi_file.extend_from_slice(&valid[HEADER_LEN..]);
+        assert!(matches!(
+            NinjaPatch::parse(&multi_file),
+            Err(NinjaParseError::Unsupported(_))
+        ));
+        for raw in [
+            &b"NINJA1"[..],
+            &valid[..HEADER_LEN - 1],
+            &valid[..HEADER_LEN],
+            &valid[..valid.len() - 3],
+        ] {
+            assert!(matches!(
+                NinjaPatch::parse(raw),
+                Err(NinjaParseError::FormatMismatch(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use super::{BuilderError, Patch};
+
+/// Builds a [`Patch`] from byte edits over a source file.
+///
+/// Edits are applied to a copy of the source, the resulting patch only contains the bytes which
+/// actually changed.
+///
+/// ## Example
+///
+/// ```
+/// use ups::PatchBuilder;
+///
+/// let rom = b"HELLO WORLD";
+/// let mut builder = PatchBuilder::new(rom);
+/// builder.set(6, b"THERE")?.set(11, b"!")?;
+/// let patch = builder.build();
+/// assert_eq!(patch.apply(rom).unwrap(), b"HELLO THERE!");
+/// # Ok::<_, ups::BuilderError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct PatchBuilder<'a> {
+    src: &'a [u8],
+    dst: Vec<u8>,
+}
+
+impl<'a> PatchBuilder<'a> {
+    /// Start with no edits over `src`.
+    pub fn new(src: &'a [u8]) -> Self {
+        PatchBuilder {
+            src,
+            dst: src.to_vec(),
+        }
+    }
+
+    /// Overwrite the bytes at `offset` with `bytes`, zero-extending the file if they go past its
+    /// end. Later edits win where they overlap earlier ones.
+    ///
+    /// Fails without changing the file if it can't be extended up to the end of the edit.
+    pub fn set(&mut self, offset: usize, bytes: &[u8]) -> Result<&mut Self, BuilderError> {
+        let error = BuilderError {
+            offset,
+            len: bytes.len(),
+        };
+        let end = offset
+            .checked_add(bytes.len())
+            .ok_or_else(|| error.clone())?;
+        if end > self.dst.len() {
+            self.dst
+                .try_reserve_exact(end - self.dst.len())
+                .map_err(|_| error)?;
+            self.dst.resize(end, 0);
+        }
+        self.dst[offset..end].copy_from_slice(bytes);
+        Ok(self)
+    }
+
+    /// The edited file.
+    pub fn output(&self) -> &[u8] {
+        &self.dst
+    }
+
+    /// Patch from the source to the edited file.
+    pub fn build(&self) -> Patch {
+        Patch::diff(self.src, &self.dst)
+    }
+
+    /// Returns the edited file.
+    pub fn into_output(self) -> Vec<u8> {
+        self.dst
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builder_only_captures_changes() {
+        let src = [0u8, 1, 2, 3, 4, 5, 6, 7];
+        let mut builder = PatchBuilder::new(&src);
+        // Rewriting the same bytes isn't a change
+        builder
+            .set(0, &[0, 1])
+            .unwrap()
+            .set(4, &[9, 9])
+            .unwrap()
+            .set(5, &[8])
+            .unwrap();
+        assert_eq!(builder.output(), &[0, 1, 2, 3, 9, 8, 6, 7]);
+
+        let patch = builder.build();
+        assert_eq!(
+            patch.block_offsets().changed_ranges().collect::<Vec<_>>(),
+            vec![4..6]
+        );
+        assert_eq!(patch.apply(&src).unwrap(), builder.into_output());
+    }
+
+    #[test]
+    fn test_builder_rejects_huge_offsets() {
+        let src = [0u8, 1, 2, 3];
+        let mut builder = PatchBuilder::new(&src);
+        assert_eq!(
+            builder.set(usize::MAX, &[1, 2]).unwrap_err(),
+            BuilderError {
+                offset: usize::MAX,
+                len: 2
+            }
+        );
+        assert!(builder.set(usize::MAX / 2, &[1]).is_err());
+        assert_eq!(builder.output(), &src);
+        builder.set(6, &[1]).unwrap();
+        assert_eq!(builder.output(), &[0, 1, 2, 3, 0, 0, 1]);
+    }
+}
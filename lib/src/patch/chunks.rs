@@ -0,0 +1,147 @@
+use std::borrow::Borrow;
+
+use crc32fast::Hasher;
+
+use super::{BlockOffsets, MetadataMismatch, Patch, PatchDirection, UpsPatchErrors};
+use crate::checksum::Checksum;
+
+/// Size of the zero-filled output chunks [`ChunkedPatcher::finish`] produces past the end of the
+/// input.
+const TAIL_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Push-based patcher for inputs arriving in chunks, e.g. from async streams or chunked
+/// downloads, see also [`Patch::patch_chunks`].
+///
+/// Each input chunk is patched as soon as it's [`push`](ChunkedPatcher::push)ed and handed to a
+/// callback, so neither the input nor the output is held in memory. Output chunks line up with
+/// the input chunks, except that input past the output size is dropped and
+/// [`finish`](ChunkedPatcher::finish) produces the output past the end of the input.
+///
+/// Checksums are verified by [`finish`](ChunkedPatcher::finish), after the whole output was
+/// produced.
+///
+/// ## Example
+///
+/// ```
+/// use ups::{ChunkedPatcher, Patch, PatchDirection};
+///
+/// let patch = Patch::diff(b"hello world", b"hello there");
+/// let mut patcher = ChunkedPatcher::new(&patch, PatchDirection::Apply);
+/// let mut output = Vec::new();
+/// for chunk in [&b"hello "[..], b"wor", b"ld"] {
+///     patcher.push(chunk, |out| output.extend_from_slice(out));
+/// }
+/// patcher.finish(|out| output.extend_from_slice(out))?;
+/// assert_eq!(output, b"hello there");
+///
+/// # Ok::<_, ups::UpsPatchErrors>(())
+/// ```
+#[derive(Debug)]
+pub struct ChunkedPatcher<P> {
+    patch: P,
+    direction: PatchDirection,
+    output_size: usize,
+    offsets: BlockOffsets,
+    input_len: usize,
+    input_hasher: Hasher,
+    output_hasher: Hasher,
+    buf: Vec<u8>,
+}
+
+impl<P: Borrow<Patch>> ChunkedPatcher<P> {
+    /// Patcher applying or reverting `patch`.
+    pub fn new(patch: P, direction: PatchDirection) -> Self {
+        let offsets = patch.borrow().block_offsets().clone();
+        let output_size = direction.metadata(patch.borrow()).output_size;
+        ChunkedPatcher {
+            patch,
+            direction,
+            output_size,
+            offsets,
+            input_len: 0,
+            input_hasher: Hasher::new(),
+            output_hasher: Hasher::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Patching direction for this patcher.
+    pub fn direction(&self) -> PatchDirection {
+        self.direction
+    }
+
+    /// Total size of the patched data.
+    pub fn output_size(&self) -> usize {
+        self.output_size
+    }
+
+    /// Patch the next input `chunk`, passing the output to `on_output` if there's any.
+    pub fn push<F: FnMut(&[u8])>(&mut self, chunk: &[u8], mut on_output: F) {
+        self.input_hasher.update(chunk);
+        let pos = self.input_len;
+        self.input_len += chunk.len();
+        if pos >= self.output_size {
+            return;
+        }
+        let len = std::cmp::min(chunk.len(), self.output_size - pos);
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        buf.extend_from_slice(&chunk[..len]);
+        self.emit(pos, &mut buf, &mut on_output);
+        self.buf = buf;
+    }
+
+    /// Produce the output past the end of the input and verify the checksums.
+    ///
+    /// Since the output is produced before its checksum is known, `on_output` may have received
+    /// invalid data when this returns an error. The `output` of the returned [`UpsPatchErrors`] is
+    /// always empty.
+    pub fn finish<F: FnMut(&[u8])>(mut self, mut on_output: F) -> Result<(), UpsPatchErrors> {
+        let mut pos = self.input_len;
+        let mut buf = std::mem::take(&mut self.buf);
+        while pos < self.output_size {
+            let len = std::cmp::min(TAIL_CHUNK_SIZE, self.output_size - pos);
+            buf.clear();
+            buf.resize(len, 0);
+            self.emit(pos, &mut buf, &mut on_output);
+            pos += len;
+        }
+
+        let patch = self.patch.borrow();
+        let metadata = self.direction.metadata(patch);
+        let input_checksum = Checksum(self.input_hasher.finalize());
+        let mut errors = patch.check_input_metadata(self.direction, self.input_len, input_checksum);
+        let output_checksum = Checksum(self.output_hasher.finalize());
+        if let Some(err) = MetadataMismatch::checksum(metadata.output_checksum, output_checksum) {
+            errors.push(self.direction.output_metadata_error(err));
+        }
+        UpsPatchErrors::check_errors(Vec::new(), errors)?;
+        Ok(())
+    }
+
+    // XOR `buf`, holding the input at output position `pos`, with the blocks and hand it out.
+    fn emit<F: FnMut(&[u8])>(&mut self, pos: usize, buf: &mut [u8], on_output: &mut F) {
+        let blocks = &self.patch.borrow().blocks;
+        let end = pos + buf.len();
+        // First block which may overlap the range.
+        let first = self.offsets.last_starting_at(pos).unwrap_or(0);
+        for (i, block) in blocks.iter().enumerate().skip(first) {
+            let start = match self.offsets.start(i) {
+                Some(s) if s < end => s,
+                _ => break,
+            };
+            let block_end = start.saturating_add(block.xor_data.len());
+            if block_end <= pos {
+                continue;
+            }
+            let from = std::cmp::max(start, pos);
+            let to = std::cmp::min(block_end, end);
+            let out = &mut buf[from - pos..to - pos];
+            for (out_byte, patch_byte) in out.iter_mut().zip(&block.xor_data[from - start..]) {
+                *out_byte ^= patch_byte;
+            }
+        }
+        self.output_hasher.update(buf);
+        on_output(buf);
+    }
+}
@@ -0,0 +1,153 @@
+use std::io::{Cursor, Read};
+
+use super::{ChunkedPatcher, Patch, PatchDirection, PatchedReader, UpsPatchErrors, UpsWriteError};
+
+/// Way of applying patches, see [`Patch::compare_engines`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PatchEngine {
+    /// [`Patch::patch`], the reference the other engines are compared to.
+    Memory,
+    /// [`Patch::patch_in_place`].
+    InPlace,
+    /// [`Patch::patch_to_writer`].
+    Writer,
+    /// [`ChunkedPatcher`].
+    Chunked,
+    /// [`PatchedReader`]. It doesn't verify checksums, so only its output is compared.
+    Reader,
+}
+
+/// Result of an engine differing from [`Patch::patch`], from [`Patch::compare_engines`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("{:?} engine diverged: {}", .engine, .kind)]
+pub struct EngineDivergence {
+    pub engine: PatchEngine,
+    pub kind: DivergenceKind,
+}
+
+/// How an engine differs from the reference, see [`EngineDivergence`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum DivergenceKind {
+    #[error("output is {} bytes, expected {}", .actual, .expected)]
+    OutputSize { expected: usize, actual: usize },
+    #[error("output differs at offset {}", .offset)]
+    Output { offset: usize },
+    /// Errors as displayed, in the order they're reported. Empty when patching succeeded.
+    #[error("reported {:?}, expected {:?}", .actual, .expected)]
+    Errors {
+        expected: Vec<String>,
+        actual: Vec<String>,
+    },
+}
+
+// Output and error messages of a run.
+type Run = (Vec<u8>, Vec<String>);
+
+impl Patch {
+    /// Debugging aid, run `input` through every [`PatchEngine`] and return how each one differs
+    /// from [`Patch::patch`], empty if they all agree. Outputs are compared even when patching
+    /// fails, along with the errors.
+    ///
+    /// The [`Chunked`](PatchEngine::Chunked) engine is fed `chunk_size` bytes at a time, to catch
+    /// bugs at chunk boundaries.
+    ///
+    /// ```
+    /// use ups::{Patch, PatchDirection};
+    ///
+    /// let patch = Patch::diff(b"hello world", b"hello there!");
+    /// assert!(patch
+    ///     .compare_engines(PatchDirection::Apply, b"hello world", 3)
+    ///     .is_empty());
+    /// ```
+    pub fn compare_engines(
+        &self,
+        direction: PatchDirection,
+        input: &[u8],
+        chunk_size: usize,
+    ) -> Vec<EngineDivergence> {
+        let (expected_output, expected_errors) = run_result(self.patch(direction, input));
+        let runs = [
+            (PatchEngine::InPlace, self.run_in_place(direction, input)),
+            (PatchEngine::Writer, self.run_writer(direction, input)),
+            (
+                PatchEngine::Chunked,
+                self.run_chunked(direction, input, chunk_size),
+            ),
+            (
+                PatchEngine::Reader,
+                (self.run_reader(direction, input), expected_errors.clone()),
+            ),
+        ];
+        let mut divergences = Vec::new();
+        for (engine, (output, errors)) in runs {
+            let mut diverge = |kind| divergences.push(EngineDivergence { engine, kind });
+            if output.len() != expected_output.len() {
+                diverge(DivergenceKind::OutputSize {
+                    expected: expected_output.len(),
+                    actual: output.len(),
+                });
+            } else if let Some(offset) = output
+                .iter()
+                .zip(&expected_output)
+                .position(|(a, b)| a != b)
+            {
+                diverge(DivergenceKind::Output { offset });
+            }
+            if errors != expected_errors {
+                diverge(DivergenceKind::Errors {
+                    expected: expected_errors.clone(),
+                    actual: errors,
+                });
+            }
+        }
+        divergences
+    }
+
+    fn run_in_place(&self, direction: PatchDirection, input: &[u8]) -> Run {
+        let mut buf = input.to_vec();
+        let errors = self.patch_in_place(direction, &mut buf).err();
+        (buf, error_messages(errors))
+    }
+
+    fn run_writer(&self, direction: PatchDirection, input: &[u8]) -> Run {
+        let mut output = Vec::new();
+        let errors = match self.patch_to_writer(direction, input, &mut output) {
+            Ok(()) => Vec::new(),
+            Err(UpsWriteError::Patch(errs)) => error_messages(Some(errs)),
+            Err(e) => vec![e.to_string()],
+        };
+        (output, errors)
+    }
+
+    fn run_chunked(&self, direction: PatchDirection, input: &[u8], chunk_size: usize) -> Run {
+        let mut output = Vec::new();
+        let mut patcher = ChunkedPatcher::new(self, direction);
+        for chunk in input.chunks(std::cmp::max(chunk_size, 1)) {
+            patcher.push(chunk, |out| output.extend_from_slice(out));
+        }
+        let errors = patcher.finish(|out| output.extend_from_slice(out)).err();
+        (output, error_messages(errors))
+    }
+
+    fn run_reader(&self, direction: PatchDirection, input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        PatchedReader::with_direction(self, direction, Cursor::new(input))
+            .read_to_end(&mut output)
+            .expect("reading from memory can't fail");
+        output
+    }
+}
+
+fn run_result(result: Result<Vec<u8>, UpsPatchErrors>) -> Run {
+    match result {
+        Ok(output) => (output, Vec::new()),
+        Err(mut errs) => (std::mem::take(&mut errs.output), error_messages(Some(errs))),
+    }
+}
+
+fn error_messages(errors: Option<UpsPatchErrors>) -> Vec<String> {
+    errors
+        .iter()
+        .flat_map(|errs| errs.into_iter().map(ToString::to_string))
+        .collect()
+}
@@ -0,0 +1,225 @@
+#[cfg(feature = "zstd")]
+use std::io::{self, Read, Write};
+
+use super::{Block, Patch};
+
+// Single-probe hash table of 4-byte sequences, found with greedy matching.
+const HASH_BITS: u32 = 16;
+const MIN_MATCH: usize = 4;
+const WINDOW: usize = 1 << 19;
+// Estimated cost of a match, i.e. its offset, length and literal count.
+const MATCH_COST: usize = 3;
+// Frame and block headers.
+const OVERHEAD: usize = 12;
+
+/// How well the XOR data of a patch compresses, from [`Patch::compressibility`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Compressibility {
+    /// Bytes of XOR data in the blocks, including terminators.
+    pub xor_bytes: usize,
+    /// Size of the XOR data once compressed, estimated unless [`measured`](Self::measured).
+    pub compressed_bytes: usize,
+    /// Whether `compressed_bytes` is the size of an actual zstd frame, with the `zstd` feature.
+    pub measured: bool,
+}
+
+impl Compressibility {
+    /// Compressed size over the original size, 1 for patches without blocks.
+    /// Incompressible data gives a ratio slightly over 1.
+    pub fn ratio(&self) -> f64 {
+        if self.xor_bytes == 0 {
+            1.0
+        } else {
+            self.compressed_bytes as f64 / self.xor_bytes as f64
+        }
+    }
+
+    /// Bytes saved by compressing the XOR data, 0 if it doesn't compress.
+    pub fn saved_bytes(&self) -> usize {
+        self.xor_bytes.saturating_sub(self.compressed_bytes)
+    }
+}
+
+impl Patch {
+    /// How well the XOR data of the blocks compresses, e.g. to decide whether to ship a patch as a
+    /// zstd-compressed `.upz` file rather than plain UPS.
+    ///
+    /// With the `zstd` feature, the XOR data is compressed with zstd at its fastest level, roughly
+    /// zstd level 1, and the frame size is reported. Otherwise this is a quick heuristic, not a
+    /// zstd size: greedy matches found with a hash table, and literals costed at their entropy.
+    /// It tells compressible patches, like text or graphics edits, from those which aren't, but
+    /// real compressors usually do better.
+    ///
+    /// ```
+    /// use ups::Patch;
+    ///
+    /// let patch = Patch::diff(&[0; 4096], &b"hack".repeat(1024));
+    /// assert!(patch.compressibility().ratio() < 0.1);
+    /// ```
+    pub fn compressibility(&self) -> Compressibility {
+        let data = XorData::new(&self.blocks);
+        #[cfg(feature = "zstd")]
+        let (compressed_bytes, measured) = (zstd_compressed_size(&self.blocks), true);
+        #[cfg(not(feature = "zstd"))]
+        let (compressed_bytes, measured) = (estimate_compressed_size(&data), false);
+        Compressibility {
+            xor_bytes: data.len(),
+            compressed_bytes,
+            measured,
+        }
+    }
+}
+
+// XOR data of all blocks as one sequence, without copying it.
+struct XorData<'a> {
+    blocks: &'a [Block],
+    // Position of each block in the sequence, followed by the total length.
+    starts: Vec<usize>,
+}
+
+impl<'a> XorData<'a> {
+    fn new(blocks: &'a [Block]) -> Self {
+        let mut starts = Vec::with_capacity(blocks.len() + 1);
+        starts.push(0);
+        for block in blocks {
+            starts.push(starts[starts.len() - 1] + block.xor_data.len());
+        }
+        XorData { blocks, starts }
+    }
+
+    fn len(&self) -> usize {
+        self.starts[self.starts.len() - 1]
+    }
+
+    // Bytes from `pos` to the end.
+    fn bytes_from(&self, pos: usize) -> Bytes<'a> {
+        let block = self.starts.partition_point(|start| *start <= pos) - 1;
+        Bytes {
+            blocks: self.blocks,
+            block,
+            offset: pos - self.starts[block],
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Bytes<'a> {
+    blocks: &'a [Block],
+    block: usize,
+    offset: usize,
+}
+
+impl<'a> Iterator for Bytes<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        loop {
+            let data = &self.blocks.get(self.block)?.xor_data;
+            if let Some(byte) = data.get(self.offset) {
+                self.offset += 1;
+                return Some(*byte);
+            }
+            self.block += 1;
+            self.offset = 0;
+        }
+    }
+}
+
+// Size of the XOR data compressed into a zstd frame, counted without keeping the output.
+#[cfg(feature = "zstd")]
+fn zstd_compressed_size(blocks: &[Block]) -> usize {
+    struct XorReader<'a> {
+        blocks: std::slice::Iter<'a, Block>,
+        current: &'a [u8],
+    }
+
+    impl<'a> Read for XorReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.current.is_empty() {
+                match self.blocks.next() {
+                    Some(block) => self.current = &block.xor_data,
+                    None => return Ok(0),
+                }
+            }
+            self.current.read(buf)
+        }
+    }
+
+    struct Counter(usize);
+
+    impl Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    let reader = XorReader {
+        blocks: blocks.iter(),
+        current: &[],
+    };
+    ruzstd::encoding::compress(
+        reader,
+        &mut counter,
+        ruzstd::encoding::CompressionLevel::Fastest,
+    );
+    counter.0
+}
+
+#[cfg_attr(feature = "zstd", allow(dead_code))]
+fn estimate_compressed_size(data: &XorData) -> usize {
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut histogram = [0usize; 256];
+    let mut matches = 0;
+    let mut pos = 0;
+    let mut bytes = data.bytes_from(0);
+    while pos < data.len() {
+        let mut key = [0; MIN_MATCH];
+        let key_len = key
+            .iter_mut()
+            .zip(bytes.clone())
+            .map(|(k, b)| *k = b)
+            .count();
+        if key_len < MIN_MATCH {
+            histogram[key[0] as usize] += 1;
+            bytes.next();
+            pos += 1;
+            continue;
+        }
+        let hash = u32::from_le_bytes(key).wrapping_mul(2_654_435_761) >> (32 - HASH_BITS);
+        let candidate = std::mem::replace(&mut table[hash as usize], pos);
+        if candidate != usize::MAX
+            && pos - candidate <= WINDOW
+            && data.bytes_from(candidate).take(MIN_MATCH).eq(key)
+        {
+            let len = bytes
+                .clone()
+                .zip(data.bytes_from(candidate))
+                .take_while(|(a, b)| a == b)
+                .count();
+            matches += 1;
+            pos += len;
+            bytes.nth(len - 1);
+        } else {
+            histogram[key[0] as usize] += 1;
+            bytes.next();
+            pos += 1;
+        }
+    }
+
+    let literals: usize = histogram.iter().sum();
+    let literal_bits: f64 = histogram
+        .iter()
+        .filter(|n| **n > 0)
+        .map(|n| *n as f64 * (literals as f64 / *n as f64).log2())
+        .sum();
+    let compressed = (literal_bits / 8.0).ceil() as usize + matches * MATCH_COST;
+    // Compressors store incompressible data as is.
+    compressed.min(data.len()) + OVERHEAD
+}
@@ -0,0 +1,275 @@
+use std::ops::Range;
+
+use super::{Block, BlockData, BlockEditError, Patch};
+use crate::checksum::Checksum;
+
+/// Cursor editing the blocks of a [`Patch`] in order, see [`Patch::iter_blocks_mut`].
+///
+/// Blocks are visited with [`next_block`](BlocksMut::next_block), which returns a [`BlockMut`]
+/// handle to change or delete the block, and new blocks are added with
+/// [`insert`](BlocksMut::insert). Edits keep the absolute positions of the other blocks, adjusting
+/// their relative offsets as needed.
+///
+/// ## Example
+///
+/// ```
+/// use ups::Patch;
+///
+/// let src = b"HELLO WORLD";
+/// let mut patch = Patch::diff(src, b"HELLO THERE");
+/// let mut blocks = patch.iter_blocks_mut();
+/// while let Some(block) = blocks.next_block() {
+///     block.delete();
+/// }
+/// // "!" XOR " ", the 0 terminator is added if missing.
+/// blocks.insert(5, &[b'!' ^ b' '])?;
+/// assert!(patch.is_metadata_dirty());
+///
+/// patch.recompute_metadata(Some(src), None);
+/// assert_eq!(patch.apply(src).unwrap(), b"HELLO!WORLD");
+/// # Ok::<_, ups::BlockEditError>(())
+/// ```
+#[derive(Debug)]
+pub struct BlocksMut<'a> {
+    patch: &'a mut Patch,
+    cursor: Cursor,
+}
+
+/// Block of a [`Patch`] being edited, from [`BlocksMut::next_block`].
+#[derive(Debug)]
+pub struct BlockMut<'c> {
+    patch: &'c mut Patch,
+    cursor: &'c mut Cursor,
+    start: usize,
+}
+
+#[derive(Debug)]
+struct Cursor {
+    // Index of the next block to visit.
+    index: usize,
+    // Absolute position right after the data of the last visited block.
+    end: usize,
+}
+
+impl Patch {
+    /// Edit the patch blocks in order, see [`BlocksMut`].
+    ///
+    /// Any edit marks the patch metadata as [dirty](Patch::is_metadata_dirty): the destination
+    /// checksum doesn't match the blocks anymore until
+    /// [`recompute_metadata`](Patch::recompute_metadata) is called.
+    pub fn iter_blocks_mut(&mut self) -> BlocksMut<'_> {
+        BlocksMut {
+            patch: self,
+            cursor: Cursor { index: 0, end: 0 },
+        }
+    }
+
+    /// All blocks for the patch, in order.
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// Direct access to the blocks, e.g. to drop or reorder them. Offsets are kept as they are,
+    /// relative to the end of the previous block, so prefer
+    /// [`iter_blocks_mut`](Patch::iter_blocks_mut) to keep the other blocks in place.
+    ///
+    /// Marks the metadata as [dirty](Patch::is_metadata_dirty) and drops the cached
+    /// [`block_offsets`](Patch::block_offsets).
+    pub fn blocks_mut(&mut self) -> &mut Vec<Block> {
+        self.blocks_edited();
+        &mut self.blocks
+    }
+
+    // Invalidate everything derived from the blocks.
+    fn blocks_edited(&mut self) {
+        self.metadata_dirty = true;
+        self.offsets.take();
+    }
+
+    /// Whether blocks were edited since the patch was created or its metadata was last recomputed.
+    /// Patching with dirty metadata fails with [`StaleMetadata`](crate::UpsPatchError::StaleMetadata) and serializing
+    /// panics.
+    pub fn is_metadata_dirty(&self) -> bool {
+        self.metadata_dirty
+    }
+
+    /// Refresh sizes and checksums after editing the blocks, e.g. with
+    /// [`iter_blocks_mut`](Patch::iter_blocks_mut), or to retarget the patch to other files.
+    ///
+    /// Sides given as `src` and `dst` get their size and checksum from those files. A missing
+    /// side keeps its size and gets its checksum from the other file and the patch blocks, or from
+    /// the current source checksum if both are missing. Clears the
+    /// [dirty](Patch::is_metadata_dirty) flag.
+    pub fn recompute_metadata(&mut self, src: Option<&[u8]>, dst: Option<&[u8]>) {
+        if let Some(src) = src {
+            self.src_size = src.len();
+            self.src_checksum = Checksum::from_bytes(src);
+        }
+        if let Some(dst) = dst {
+            self.dst_size = dst.len();
+            self.dst_checksum = Checksum::from_bytes(dst);
+        }
+        match (src, dst) {
+            (Some(_), Some(_)) => {}
+            (Some(src), None) => {
+                let mut output = src.to_vec();
+                output.resize(self.dst_size, 0);
+                self.dst_checksum = self.xor_blocks(&mut output);
+            }
+            (None, Some(dst)) => {
+                let mut output = dst.to_vec();
+                output.resize(self.src_size, 0);
+                self.src_checksum = self.xor_blocks(&mut output);
+            }
+            (None, None) => self.dst_checksum = self.derive_dst_checksum(),
+        }
+        self.metadata_dirty = false;
+    }
+
+    // Destination checksum from the source checksum and the blocks alone, since CRC32 is affine:
+    // crc(a ^ b) = crc(a) ^ crc(b) ^ crc(zeros) for equal lengths.
+    fn derive_dst_checksum(&self) -> Checksum {
+        let mut changes = vec![0; std::cmp::max(self.src_size, self.dst_size)];
+        self.xor_blocks(&mut changes);
+        let (changes, src_tail) = changes.split_at(self.dst_size);
+        // Past the end of the destination the blocks hold the source bytes, restored when
+        // reverting.
+        let padded_src = if self.dst_size >= self.src_size {
+            self.src_checksum
+                .extend_with_zeros(self.dst_size - self.src_size)
+        } else {
+            self.src_checksum.remove_suffix(src_tail)
+        };
+        let zeros = Checksum::from_bytes(&[]).extend_with_zeros(self.dst_size);
+        Checksum(padded_src.0 ^ Checksum::from_bytes(changes).0 ^ zeros.0)
+    }
+}
+
+impl<'a> BlocksMut<'a> {
+    /// Move to the next block, `None` after the last one.
+    pub fn next_block(&mut self) -> Option<BlockMut<'_>> {
+        let block = self.patch.blocks.get(self.cursor.index)?;
+        let start = self.cursor.end.saturating_add(block.offset);
+        self.cursor.index += 1;
+        self.cursor.end = start.saturating_add(block.xor_data.len());
+        Some(BlockMut {
+            patch: self.patch,
+            cursor: &mut self.cursor,
+            start,
+        })
+    }
+
+    /// Insert a block with `xor_data` at absolute position `start`, after the last visited block
+    /// and before the next one. The new block counts as visited.
+    ///
+    /// `xor_data` must be non-zero bytes, a 0 terminator is added if it's missing. Together with
+    /// its terminator it must fit in the unchanged bytes between both blocks.
+    pub fn insert(&mut self, start: usize, xor_data: &[u8]) -> Result<(), BlockEditError> {
+        let xor_data = terminated(xor_data).ok_or(BlockEditError::InvalidData)?;
+        let index = self.cursor.index;
+        // A previous block without terminator would swallow the new one when serialized.
+        let unterminated_prev =
+            index > 0 && self.patch.blocks[index - 1].xor_data.last() != Some(&0);
+        let free_start = self.cursor.end + usize::from(unterminated_prev);
+        let free = free_start..self.next_start();
+        let end = check_fits(start, xor_data.len(), &free)?;
+
+        if unterminated_prev {
+            self.patch.blocks[index - 1].xor_data.push(0);
+        }
+        if let Some(next) = self.patch.blocks.get_mut(index) {
+            next.offset = free.end - end;
+        }
+        self.patch.blocks.insert(
+            index,
+            Block {
+                offset: start - free_start,
+                xor_data,
+            },
+        );
+        self.cursor.index += 1;
+        self.cursor.end = end;
+        self.patch.blocks_edited();
+        Ok(())
+    }
+
+    // Absolute start of the next block to visit, `usize::MAX` if there's none.
+    fn next_start(&self) -> usize {
+        self.patch
+            .blocks
+            .get(self.cursor.index)
+            .map_or(usize::MAX, |b| self.cursor.end.saturating_add(b.offset))
+    }
+}
+
+impl<'c> BlockMut<'c> {
+    /// Absolute position of the first byte changed by the block.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// The block being edited.
+    pub fn block(&self) -> &Block {
+        &self.patch.blocks[self.cursor.index - 1]
+    }
+
+    /// Replace the block data, keeping its start. `xor_data` must be non-zero bytes, a 0
+    /// terminator is added if it's missing. Together with its terminator it must end before the
+    /// next block.
+    pub fn set_xor_data(&mut self, xor_data: &[u8]) -> Result<(), BlockEditError> {
+        let xor_data = terminated(xor_data).ok_or(BlockEditError::InvalidData)?;
+        let index = self.cursor.index - 1;
+        let next_start = self
+            .patch
+            .blocks
+            .get(index + 1)
+            .map_or(usize::MAX, |b| self.cursor.end.saturating_add(b.offset));
+        let free = self.start - self.block().offset..next_start;
+        let end = check_fits(self.start, xor_data.len(), &free)?;
+
+        if let Some(next) = self.patch.blocks.get_mut(index + 1) {
+            next.offset = next_start - end;
+        }
+        self.patch.blocks[index].xor_data = xor_data;
+        self.cursor.end = end;
+        self.patch.blocks_edited();
+        Ok(())
+    }
+
+    /// Remove the block, the bytes it changed are left unchanged.
+    pub fn delete(self) {
+        let index = self.cursor.index - 1;
+        let removed = self.patch.blocks.remove(index);
+        let removed_len = removed.offset.saturating_add(removed.xor_data.len());
+        if let Some(next) = self.patch.blocks.get_mut(index) {
+            next.offset = next.offset.saturating_add(removed_len);
+        }
+        self.cursor.index = index;
+        self.cursor.end = self.start - removed.offset;
+        self.patch.blocks_edited();
+    }
+}
+
+// `xor_data` with a 0 terminator, `None` if it's empty or has 0 bytes before the terminator.
+fn terminated(xor_data: &[u8]) -> Option<BlockData> {
+    let changes = xor_data.strip_suffix(&[0]).unwrap_or(xor_data);
+    if changes.is_empty() || changes.contains(&0) {
+        return None;
+    }
+    let mut data = BlockData::with_capacity(changes.len() + 1);
+    data.extend_from_slice(changes);
+    data.push(0);
+    Some(data)
+}
+
+// End of `len` bytes at `start`, if they're within `free`.
+fn check_fits(start: usize, len: usize, free: &Range<usize>) -> Result<usize, BlockEditError> {
+    match start.checked_add(len) {
+        Some(end) if start >= free.start && end <= free.end => Ok(end),
+        _ => Err(BlockEditError::Overlap {
+            start,
+            len,
+            free: free.clone(),
+        }),
+    }
+}
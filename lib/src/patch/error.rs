@@ -1,7 +1,10 @@
 use std::error::Error;
 use std::fmt::{self, Debug, Display, Formatter};
+use std::io;
 use std::iter::FusedIterator;
+use std::ops::Range;
 
+use super::Requirement;
 use crate::{Checksum, Patch};
 
 /// Possible errors when parsing an UPS patch file.
@@ -9,6 +12,9 @@ use crate::{Checksum, Patch};
 pub enum UpsParseError {
     #[error("this doesn't seem to be an UPS file: {}", .0)]
     FormatMismatch(String),
+    /// Text given to [`Patch::parse_hex`] or [`Patch::parse_base64`] couldn't be decoded.
+    #[error("invalid patch text: {}", .0)]
+    InvalidText(String),
     /// Calculated patch checksum doesn't match the one from the patch metadata. You can access the
     /// patch in `parsed_patch` in case you want to ignore checksum errors.
     #[error(
@@ -22,6 +28,17 @@ pub enum UpsParseError {
     },
 }
 
+impl UpsParseError {
+    /// Stable snake case name of the error variant, e.g. for machine readable output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UpsParseError::FormatMismatch(_) => "format_mismatch",
+            UpsParseError::InvalidText(_) => "invalid_text",
+            UpsParseError::PatchChecksumMismatch { .. } => "patch_checksum_mismatch",
+        }
+    }
+}
+
 pub type UpsParseResult<T> = Result<T, UpsParseError>;
 
 /// Collection of errors returned from patching. You can access the patched file in `output` in
@@ -49,6 +66,15 @@ impl UpsPatchErrors {
         }
     }
 
+    /// [`kind`](UpsPatchError::kind) of the error that best explains the failure. Source
+    /// mismatches cause the destination ones, so they're preferred.
+    pub fn kind(&self) -> &'static str {
+        self.iter()
+            .find(|e| matches!(e, UpsPatchError::SourceMetadataMismatch(_)))
+            .unwrap_or(&self.fst_error)
+            .kind()
+    }
+
     /// Iterate over all patching errors by reference.
     #[inline]
     pub fn iter(&self) -> impl Iterator<Item = &UpsPatchError> {
@@ -173,13 +199,59 @@ impl Error for UpsPatchErrors {
     }
 }
 
+/// Possible errors when streaming the output of a patch with
+/// [`Patch::patch_to_writer`](crate::Patch::patch_to_writer).
+#[derive(thiserror::Error, Debug)]
+pub enum UpsWriteError {
+    #[error("failed to write output: {}", .0)]
+    Io(#[from] io::Error),
+    /// Metadata mismatches, `output` is always empty since data was streamed to the writer.
+    #[error(transparent)]
+    Patch(#[from] UpsPatchErrors),
+}
+
 /// Possible errors when applying or reverting an UPS patch.
 #[derive(thiserror::Error, Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(tag = "kind", rename_all = "snake_case")
+)]
 pub enum UpsPatchError {
     #[error("source file {}", .0)]
     SourceMetadataMismatch(MetadataMismatch),
     #[error("destination file {}", .0)]
     DestMetadataMismatch(MetadataMismatch),
+    /// The input changed while it was being patched, see
+    /// [`Patch::patch_verified`](crate::Patch::patch_verified).
+    #[error("input changed while patching, first difference at offset {}", .offset)]
+    InputModified { offset: usize },
+    /// The patch blocks were edited without recomputing the metadata, see
+    /// [`Patch::is_metadata_dirty`](crate::Patch::is_metadata_dirty).
+    #[error("patch blocks were edited without recomputing its metadata")]
+    StaleMetadata,
+}
+
+impl UpsPatchError {
+    /// Stable snake case name of the error, e.g. `source_checksum_mismatch`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UpsPatchError::SourceMetadataMismatch(MetadataMismatch::Size { .. }) => {
+                "source_size_mismatch"
+            }
+            UpsPatchError::SourceMetadataMismatch(MetadataMismatch::Checksum { .. }) => {
+                "source_checksum_mismatch"
+            }
+            UpsPatchError::DestMetadataMismatch(MetadataMismatch::Size { .. }) => {
+                "dest_size_mismatch"
+            }
+            UpsPatchError::DestMetadataMismatch(MetadataMismatch::Checksum { .. }) => {
+                "dest_checksum_mismatch"
+            }
+            UpsPatchError::InputModified { .. } => "input_modified",
+            UpsPatchError::StaleMetadata => "stale_metadata",
+        }
+    }
 }
 
 pub type UpsPatchResult<T> = Result<T, UpsPatchErrors>;
@@ -229,3 +301,210 @@ impl Display for MetadataMismatch {
         }
     }
 }
+
+/// Error from [`Patch::split`]: `max_size` is too small for any piece, the smallest possible
+/// limit for the patch is `min_size`.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error(
+    "can't split the patch in pieces of at most {} bytes, the limit must be at least {} bytes",
+    .max_size, .min_size,
+)]
+pub struct SplitError {
+    pub max_size: usize,
+    pub min_size: usize,
+}
+
+/// Error from [`PatchBuilder::set`](crate::PatchBuilder::set): the file can't grow to hold `len`
+/// bytes at `offset`, either because the end overflows or because it can't be allocated.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("can't write {} bytes at offset {}, the file would be too large", .len, .offset)]
+pub struct BuilderError {
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// Error from [`Patch::can_compose`]: a patch outputs `dst`, but the next one applies to
+/// `next_src`.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[error("patch outputs a {}, but the next patch applies to a {}", .dst, .next_src)]
+pub struct ComposeError {
+    pub dst: Requirement,
+    pub next_src: Requirement,
+}
+
+/// Error from editing blocks with [`Patch::iter_blocks_mut`]. The patch is left unchanged.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum BlockEditError {
+    /// Block data at `start`, `len` bytes long including its terminator, doesn't fit in the
+    /// unchanged bytes between the neighbouring blocks, `free`.
+    #[error(
+        "{} bytes of block data at offset {} don't fit between blocks, free bytes are {}..{}",
+        .len, .start, .free.start, .free.end,
+    )]
+    Overlap {
+        start: usize,
+        len: usize,
+        free: Range<usize>,
+    },
+    /// Block data is empty or has 0 bytes before its terminator, which would end the block early.
+    #[error("block data must be non-zero bytes, optionally followed by a 0 terminator")]
+    InvalidData,
+}
+
+/// Suspicious contents found by [`Patch::parse_with_warnings`] in patches which are still valid,
+/// usually the output of a broken patch generator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseWarning {
+    /// Block `index` starts at `offset`, past the end of both the source and the destination, so
+    /// it changes nothing.
+    BlockPastEnd { index: usize, offset: usize },
+    /// Block `index` at `offset` is only a 0 terminator and changes nothing, e.g. from generators
+    /// writing a terminator for every unchanged byte. See [`Patch::repair_terminators`].
+    EmptyBlock { index: usize, offset: usize },
+    /// The last block, `index` at `offset`, has no 0 terminator, e.g. from generators omitting it
+    /// or a truncated patch. See [`Patch::repair_terminators`].
+    UnterminatedBlock { index: usize, offset: usize },
+    /// The last `len` bytes before the checksums don't make up a complete block offset and are
+    /// ignored.
+    TrailingBytes { len: usize },
+}
+
+impl Display for ParseWarning {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ParseWarning::BlockPastEnd { index, offset } => write!(
+                f,
+                "block {} starts at offset {}, past the end of the files",
+                index, offset,
+            ),
+            ParseWarning::EmptyBlock { index, offset } => write!(
+                f,
+                "block {} at offset {} is only a terminator, it changes nothing",
+                index, offset,
+            ),
+            ParseWarning::UnterminatedBlock { index, offset } => write!(
+                f,
+                "block {} at offset {} has no 0 terminator, the patch may be truncated",
+                index, offset,
+            ),
+            ParseWarning::TrailingBytes { len } => write!(
+                f,
+                "{} bytes after the last block aren't a complete offset and are ignored",
+                len,
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+    use super::*;
+    use crate::util::SerializeIoError;
+    use crate::SerializeChecksumHex;
+
+    // Errors are serialized as `{"kind": "<snake_case variant>", ...fields}`. Payloads which are
+    // only useful from Rust (`parsed_patch`, `output`) are skipped.
+
+    impl Serialize for UpsParseError {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                UpsParseError::FormatMismatch(reason) => {
+                    let mut s = serializer.serialize_struct("UpsParseError", 2)?;
+                    s.serialize_field("kind", "format_mismatch")?;
+                    s.serialize_field("reason", reason)?;
+                    s.end()
+                }
+                UpsParseError::InvalidText(reason) => {
+                    let mut s = serializer.serialize_struct("UpsParseError", 2)?;
+                    s.serialize_field("kind", "invalid_text")?;
+                    s.serialize_field("reason", reason)?;
+                    s.end()
+                }
+                UpsParseError::PatchChecksumMismatch {
+                    expected, actual, ..
+                } => {
+                    let mut s = serializer.serialize_struct("UpsParseError", 5)?;
+                    s.serialize_field("kind", "patch_checksum_mismatch")?;
+                    s.serialize_field("expected", expected)?;
+                    s.serialize_field("actual", actual)?;
+                    s.serialize_field("expected_hex", &SerializeChecksumHex(*expected))?;
+                    s.serialize_field("actual_hex", &SerializeChecksumHex(*actual))?;
+                    s.end()
+                }
+            }
+        }
+    }
+
+    // `{"mismatch": "size" | "checksum", "expected": ..., "actual": ...}`, checksums also in hex.
+    impl Serialize for MetadataMismatch {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                MetadataMismatch::Size { expected, actual } => {
+                    let mut s = serializer.serialize_struct("MetadataMismatch", 3)?;
+                    s.serialize_field("mismatch", "size")?;
+                    s.serialize_field("expected", expected)?;
+                    s.serialize_field("actual", actual)?;
+                    s.end()
+                }
+                MetadataMismatch::Checksum { expected, actual } => {
+                    let mut s = serializer.serialize_struct("MetadataMismatch", 5)?;
+                    s.serialize_field("mismatch", "checksum")?;
+                    s.serialize_field("expected", expected)?;
+                    s.serialize_field("actual", actual)?;
+                    s.serialize_field("expected_hex", &SerializeChecksumHex(*expected))?;
+                    s.serialize_field("actual_hex", &SerializeChecksumHex(*actual))?;
+                    s.end()
+                }
+            }
+        }
+    }
+
+    impl Serialize for ParseWarning {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                ParseWarning::BlockPastEnd { index, offset }
+                | ParseWarning::EmptyBlock { index, offset }
+                | ParseWarning::UnterminatedBlock { index, offset } => {
+                    let kind = match self {
+                        ParseWarning::BlockPastEnd { .. } => "block_past_end",
+                        ParseWarning::EmptyBlock { .. } => "empty_block",
+                        _ => "unterminated_block",
+                    };
+                    let mut s = serializer.serialize_struct("ParseWarning", 3)?;
+                    s.serialize_field("kind", kind)?;
+                    s.serialize_field("index", index)?;
+                    s.serialize_field("offset", offset)?;
+                    s.end()
+                }
+                ParseWarning::TrailingBytes { len } => {
+                    let mut s = serializer.serialize_struct("ParseWarning", 2)?;
+                    s.serialize_field("kind", "trailing_bytes")?;
+                    s.serialize_field("len", len)?;
+                    s.end()
+                }
+            }
+        }
+    }
+
+    impl Serialize for UpsPatchErrors {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let errors: Vec<_> = self.iter().collect();
+            let mut s = serializer.serialize_struct("UpsPatchErrors", 2)?;
+            s.serialize_field("kind", "patch_errors")?;
+            s.serialize_field("errors", &errors)?;
+            s.end()
+        }
+    }
+
+    impl Serialize for UpsWriteError {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                UpsWriteError::Io(e) => SerializeIoError(e).serialize(serializer),
+                UpsWriteError::Patch(e) => e.serialize(serializer),
+            }
+        }
+    }
+}
@@ -1,20 +1,52 @@
 use std::convert::TryInto;
 use std::fmt::{self, Debug, Display, Formatter};
+use std::hash::{self, Hash};
+use std::io::{self, IoSlice, Read, Seek, SeekFrom, Write};
+use std::iter::Sum;
+use std::ops::{Add, AddAssign};
+use std::sync::{Arc, OnceLock};
 
+use crc32fast::Hasher;
 use memchr::memchr;
+use smallvec::SmallVec;
 
 use crate::checksum::Checksum;
-use crate::util::SliceDiffs;
+use crate::text;
+use crate::util::{ByteSize, SliceDiffs};
 use crate::varint;
 
+mod builder;
+mod chunks;
+mod compare;
+mod compress;
+mod edit;
 mod error;
+mod offsets;
+mod reader;
+mod shared;
+mod split;
 #[cfg(test)]
 mod test;
+#[cfg(feature = "trace")]
+mod trace;
 
+pub use builder::PatchBuilder;
+pub use chunks::ChunkedPatcher;
+pub use compare::{DivergenceKind, EngineDivergence, PatchEngine};
+pub use compress::Compressibility;
+pub use edit::{BlockMut, BlocksMut};
 pub use error::*;
+pub use offsets::BlockOffsets;
+pub use reader::PatchedReader;
+pub use shared::SharedPatch;
+#[cfg(feature = "trace")]
+pub use trace::TraceEntry;
 
 const MAGIC: &[u8] = b"UPS1";
 
+/// Bytes of blocks between calls to the progress hook of [`Patch::parse_with_progress`].
+pub const PARSE_PROGRESS_INTERVAL: usize = 1 << 20;
+
 /// UPS patch. Use [`parse`](Patch::parse) to read from a file and [`diff`](Patch::diff) to compute
 /// a new patch from two files.
 ///
@@ -24,13 +56,18 @@ const MAGIC: &[u8] = b"UPS1";
 /// You can [`apply`](Patch::apply) a patch to compute `dst` from `src` and
 /// [`revert`](Patch::revert) it to compute `src` from `dst`.
 ///
+/// Sizes and offsets are `usize`: on 32-bit platforms, patches for files over 4 GiB fail to parse.
+/// Use [`diff_to_writer`](crate::diff::diff_to_writer) to generate them and
+/// [`patch_file_to_writer`](crate::diff::patch_file_to_writer) to apply or revert them.
+///
 /// # Reference
 ///
 /// http://individual.utoronto.ca/dmeunier/ups-spec.pdf
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone)]
 pub struct Patch {
-    /// All blocks for the patch, in order.
-    pub blocks: Vec<Block>,
+    // All blocks for the patch, in order. Private so edits go through `blocks_mut` or
+    // `iter_blocks_mut`, which drop the cached `offsets`.
+    pub(crate) blocks: Vec<Block>,
     /// Source file size.
     pub src_size: usize,
     /// Source file checksum.
@@ -39,19 +76,33 @@ pub struct Patch {
     pub dst_size: usize,
     /// Destination file checksum.
     pub dst_checksum: Checksum,
+    // Set by block edits through `iter_blocks_mut`, cleared by `recompute_metadata`. Not part of
+    // the patch identity.
+    pub(crate) metadata_dirty: bool,
+    // Lazily built by `block_offsets`, reset whenever the blocks change.
+    pub(crate) offsets: OnceLock<BlockOffsets>,
 }
 
 /// Diff block in a [`Patch`].
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Block {
     /// Offset from the end of the previous diff block.
-    offset: usize,
+    pub(crate) offset: usize,
     /// Diff for this block, encoded as a zero-terminated XOR of `src` and `dst`.
-    xor_data: Vec<u8>,
+    pub(crate) xor_data: BlockData,
 }
 
+/// Storage for [`Block`] data. Most blocks are only a few bytes long, e.g. text edits, so short
+/// ones are stored inline instead of in their own allocation.
+pub(crate) type BlockData = SmallVec<[u8; 16]>;
+
 /// Patching direction, either from source to patched file or back.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum PatchDirection {
     /// Apply the patch to the source file.
     Apply,
@@ -59,6 +110,114 @@ pub enum PatchDirection {
     Revert,
 }
 
+/// What patching an input file would do, see [`Patch::preflight`].
+#[derive(Debug, Clone)]
+pub struct Preflight {
+    /// Direction the input file matches, `None` if it matches neither side of the patch.
+    pub direction_hint: Option<PatchDirection>,
+    /// Output size when it differs from the input size.
+    pub will_resize_to: Option<usize>,
+    /// Input metadata mismatches for `direction_hint`, or for [`PatchDirection::Apply`] if there's
+    /// no hint. Empty if the input checksum is verified.
+    pub warnings: Vec<UpsPatchError>,
+}
+
+/// Progress of [`Patch::parse_with_progress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseProgress {
+    /// Bytes of the patch file parsed so far.
+    pub bytes_parsed: usize,
+    /// Size of the patch file.
+    pub total_bytes: usize,
+    /// Blocks parsed so far.
+    pub blocks: usize,
+}
+
+/// Size and checksum a file must have to be used with a patch, see [`Patch::requirements`].
+///
+/// Displays as e.g. `16 MiB ROM, CRC32 0xDEADBEEF`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Requirement {
+    pub size: usize,
+    pub crc32: Checksum,
+}
+
+/// Requirements for both sides of a patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Requirements {
+    /// Requirement for the file to apply the patch to.
+    pub src: Requirement,
+    /// Requirement for the file to revert the patch from.
+    pub dst: Requirement,
+}
+
+/// Rough cost of applying a patch, see [`Patch::estimated_apply_cost`].
+///
+/// Costs add up, e.g. to get the total work of a batch and report progress over all of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ApplyCost {
+    /// Bytes of output to write.
+    pub output_size: usize,
+    /// Bytes of XOR data in the blocks, the output bytes to change.
+    pub xor_bytes: usize,
+    /// Number of blocks.
+    pub blocks: usize,
+}
+
+impl ApplyCost {
+    /// Single number to order and balance costs by: the bytes written plus the bytes changed.
+    pub fn weight(&self) -> usize {
+        self.output_size.saturating_add(self.xor_bytes)
+    }
+}
+
+impl Add for ApplyCost {
+    type Output = ApplyCost;
+
+    fn add(mut self, other: ApplyCost) -> ApplyCost {
+        self += other;
+        self
+    }
+}
+
+impl AddAssign for ApplyCost {
+    fn add_assign(&mut self, other: ApplyCost) {
+        self.output_size = self.output_size.saturating_add(other.output_size);
+        self.xor_bytes = self.xor_bytes.saturating_add(other.xor_bytes);
+        self.blocks = self.blocks.saturating_add(other.blocks);
+    }
+}
+
+impl Sum for ApplyCost {
+    fn sum<I: Iterator<Item = ApplyCost>>(iter: I) -> ApplyCost {
+        iter.fold(ApplyCost::default(), Add::add)
+    }
+}
+
+impl Display for Requirement {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} ROM, CRC32 {}", ByteSize(self.size), self.crc32)
+    }
+}
+
+impl Requirements {
+    /// Check that a patch with requirements `next` applies to the output of a patch with these
+    /// requirements, see [`Patch::can_compose`].
+    pub fn can_compose(&self, next: &Requirements) -> Result<(), ComposeError> {
+        if self.dst == next.src {
+            Ok(())
+        } else {
+            Err(ComposeError {
+                dst: self.dst,
+                next_src: next.src,
+            })
+        }
+    }
+}
+
 // Struct to help implement apply/revert as a single function in Patch::patch.
 // input is the input file, src for Apply and dst for Revert. output is the other way around, dst
 // for Apply and src for Revert.
@@ -104,7 +263,27 @@ impl PatchDirection {
 
 impl Patch {
     /// Parses an UPS file.
-    pub fn parse(mut input: &[u8]) -> UpsParseResult<Self> {
+    pub fn parse(input: &[u8]) -> UpsParseResult<Self> {
+        Self::parse_with_warnings(input).map(|(patch, _)| patch)
+    }
+
+    /// Like [`parse`](Patch::parse), also returning [`ParseWarning`]s for valid but suspicious
+    /// contents, e.g. blocks starting past the end of the files.
+    ///
+    /// Blocks are only checked against the larger of the source and destination sizes: patches
+    /// shrinking a file store the source tail past the end of the destination to revert it.
+    pub fn parse_with_warnings(input: &[u8]) -> UpsParseResult<(Self, Vec<ParseWarning>)> {
+        Self::parse_with_progress(input, |_| {})
+    }
+
+    /// Like [`parse_with_warnings`](Patch::parse_with_warnings), calling `on_progress` every
+    /// [`PARSE_PROGRESS_INTERVAL`] bytes of blocks and once the blocks are parsed, so frontends
+    /// can show progress for huge patches, e.g. for disc images.
+    pub fn parse_with_progress<F: FnMut(ParseProgress)>(
+        mut input: &[u8],
+        mut on_progress: F,
+    ) -> UpsParseResult<(Self, Vec<ParseWarning>)> {
+        let total_bytes = input.len();
         if !input.starts_with(MAGIC) {
             return Err(UpsParseError::FormatMismatch(format!(
                 "invalid preamble, expected \"{}\", found \"{}\"",
@@ -117,10 +296,10 @@ impl Patch {
         let actual_patch_checksum = Checksum::from_bytes(&input[..input.len() - 4]);
         input = &input[4..];
 
-        let src_size = varint::read_bytes(&mut input).ok_or_else(|| {
+        let src_size = varint::read(&mut input).ok_or_else(|| {
             UpsParseError::FormatMismatch("error reading source file size".into())
         })?;
-        let dst_size = varint::read_bytes(&mut input)
+        let dst_size = varint::read(&mut input)
             .ok_or_else(|| UpsParseError::FormatMismatch("error reading dest file size".into()))?;
 
         if input.len() < 12 {
@@ -131,21 +310,54 @@ impl Patch {
         let (mut body, mut checksums) = input.split_at(input.len() - 12);
 
         let mut blocks = Vec::new();
+        let mut warnings = Vec::new();
+        let end = std::cmp::max(src_size, dst_size);
+        let mut pos = 0usize;
+        let mut next_progress = PARSE_PROGRESS_INTERVAL;
         while !body.is_empty() {
-            let offset = match varint::read_bytes(&mut body) {
+            let bytes_parsed = total_bytes - 12 - body.len();
+            if bytes_parsed >= next_progress {
+                on_progress(ParseProgress {
+                    bytes_parsed,
+                    total_bytes,
+                    blocks: blocks.len(),
+                });
+                next_progress = bytes_parsed + PARSE_PROGRESS_INTERVAL;
+            }
+            let remaining = body.len();
+            let offset = match varint::read(&mut body) {
                 Some(o) => o,
-                None => break,
+                None => {
+                    warnings.push(ParseWarning::TrailingBytes { len: remaining });
+                    break;
+                }
             };
-            let (xor_data, next_body) = match memchr(0, &body) {
+            let (xor_data, next_body) = match memchr(0, body) {
                 Some(i) => body.split_at(i + 1),
                 None => (body, [].as_ref()),
             };
             body = next_body;
+            pos = pos.saturating_add(offset);
+            let index = blocks.len();
+            if pos >= end {
+                warnings.push(ParseWarning::BlockPastEnd { index, offset: pos });
+            }
+            if xor_data == [0] {
+                warnings.push(ParseWarning::EmptyBlock { index, offset: pos });
+            } else if xor_data.last() != Some(&0) {
+                warnings.push(ParseWarning::UnterminatedBlock { index, offset: pos });
+            }
+            pos = pos.saturating_add(xor_data.len());
             blocks.push(Block {
                 offset,
-                xor_data: xor_data.to_vec(),
+                xor_data: xor_data.into(),
             });
         }
+        on_progress(ParseProgress {
+            bytes_parsed: total_bytes,
+            total_bytes,
+            blocks: blocks.len(),
+        });
 
         let src_checksum = read_checksum(&mut checksums)?;
         let dst_checksum = read_checksum(&mut checksums)?;
@@ -157,6 +369,8 @@ impl Patch {
             src_checksum,
             dst_size,
             dst_checksum,
+            metadata_dirty: false,
+            offsets: OnceLock::new(),
         };
 
         if actual_patch_checksum != patch_checksum {
@@ -166,18 +380,36 @@ impl Patch {
                 actual: actual_patch_checksum,
             })
         } else {
-            Ok(parsed_patch)
+            Ok((parsed_patch, warnings))
         }
     }
 
+    /// Parses an UPS file encoded as hex digits, ignoring whitespace.
+    pub fn parse_hex(text: &str) -> UpsParseResult<Self> {
+        Self::parse(&text::decode_hex(text).map_err(UpsParseError::InvalidText)?)
+    }
+
+    /// Parses an UPS file encoded as standard or URL-safe base64, ignoring whitespace.
+    pub fn parse_base64(text: &str) -> UpsParseResult<Self> {
+        Self::parse(&text::decode_base64(text).map_err(UpsParseError::InvalidText)?)
+    }
+
     /// Calculate a patch by comparing the source and destination files.
+    ///
+    /// Blocks always end at the first unchanged byte: its XOR is zero, which is the block
+    /// terminator in the UPS format. Block boundaries are therefore fully determined by the input
+    /// files and can't be tuned, e.g. to trade patch size against block count.
+    ///
+    /// Either file may be empty. Against an empty source, blocks hold the destination data itself,
+    /// split at its zero bytes. An empty destination gives a patch with the source data, which
+    /// truncates the input to zero bytes when applied and restores it when reverted.
     pub fn diff(src: &[u8], dst: &[u8]) -> Self {
         let mut blocks = Vec::new();
         // Index into the end of the previous block's data.
         let mut prev_end = 0;
         for diff_range in SliceDiffs::new(src, dst) {
             let offset = diff_range.start - prev_end;
-            let mut xor_data: Vec<_> = src[diff_range.clone()]
+            let mut xor_data: BlockData = src[diff_range.clone()]
                 .iter()
                 .zip(&dst[diff_range.clone()])
                 .map(|(a, b)| a ^ b)
@@ -200,8 +432,6 @@ impl Patch {
         let mut pending_data = &max_slice[min_len..];
         let split_pos = memchr::memchr(0, pending_data).unwrap_or(pending_data.len());
         let (last_block_data, next_pending) = pending_data.split_at(split_pos);
-        // Account for 0 byte
-        pending_data = next_pending.split_first().map_or(&[], |s| s.1);
         // The last block may have more data after the end of the source file.
         if prev_end == min_len + 1 {
             if let Some(block) = blocks.last_mut() {
@@ -211,29 +441,37 @@ impl Patch {
                 block.xor_data.push(0);
             }
         } else if !last_block_data.is_empty() {
-            let mut xor_data = last_block_data.to_vec();
+            let mut xor_data = BlockData::from_slice(last_block_data);
             xor_data.push(0);
             blocks.push(Block {
                 offset: min_len - prev_end,
                 xor_data,
             });
         }
+        if prev_end == min_len + 1 || !last_block_data.is_empty() {
+            // Account for 0 byte
+            pending_data = next_pending.split_first().map_or(&[], |s| s.1);
+            prev_end = min_len + split_pos + 1;
+        }
 
         // Emit leftover blocks if either file has pending data.
         while !pending_data.is_empty() {
-            let offset = match pending_data.iter().position(|x| *x != 0) {
+            let skip = match pending_data.iter().position(|x| *x != 0) {
                 Some(p) => p,
                 // All remaining bytes are 0.
                 None => break,
             };
-            pending_data = &pending_data[offset..];
+            // Absolute index of the block start, offsets are relative to `prev_end`.
+            let start = max_slice.len() - pending_data.len() + skip;
+            pending_data = &pending_data[skip..];
             let split_pos = memchr::memchr(0, pending_data).map_or(pending_data.len(), |x| x + 1);
             let (xor_data, next_pending) = pending_data.split_at(split_pos);
             pending_data = next_pending;
             blocks.push(Block {
-                offset,
-                xor_data: xor_data.to_vec(),
+                offset: start - prev_end,
+                xor_data: xor_data.into(),
             });
+            prev_end = start + split_pos;
         }
         // Last block may be missing a trailing 0.
         if let Some(block) = blocks.last_mut() {
@@ -248,16 +486,247 @@ impl Patch {
             src_checksum: Checksum::from_bytes(src),
             dst_size: dst.len(),
             dst_checksum: Checksum::from_bytes(dst),
+            metadata_dirty: false,
+            offsets: OnceLock::new(),
+        }
+    }
+
+    /// Returns an equivalent patch with canonical blocks: one block per run of changed bytes, with
+    /// no data past the end of both files. Two patches making the same changes have equal
+    /// normalized forms, no matter how their blocks were encoded.
+    pub fn normalize(&self) -> Patch {
+        Patch {
+            blocks: blocks_from_runs(self.xor_runs()),
+            ..self.clone()
+        }
+    }
+
+    /// Returns an equivalent patch without the terminator anomalies
+    /// [`parse_with_warnings`](Patch::parse_with_warnings) reports: empty blocks are dropped and
+    /// missing terminators added. Other blocks are kept as they are, unlike
+    /// [`normalize`](Patch::normalize).
+    pub fn repair_terminators(&self) -> Patch {
+        let mut blocks: Vec<Block> = Vec::with_capacity(self.blocks.len());
+        // Bytes covered by dropped blocks, added to the next offset.
+        let mut skipped = 0usize;
+        for block in &self.blocks {
+            let mut offset = block.offset.saturating_add(skipped);
+            skipped = 0;
+            if block.xor_data.is_empty() {
+                skipped = offset;
+                continue;
+            }
+            if let Some(prev) = blocks.last_mut().filter(|b| b.xor_data.last() != Some(&0)) {
+                if offset == 0 {
+                    // The block continues the previous one.
+                    prev.xor_data.extend_from_slice(&block.xor_data);
+                    continue;
+                }
+                prev.xor_data.push(0);
+                offset -= 1;
+            }
+            if block.xor_data[..] == [0] {
+                skipped = offset.saturating_add(1);
+                continue;
+            }
+            blocks.push(Block {
+                offset,
+                xor_data: block.xor_data.clone(),
+            });
+        }
+        if let Some(last) = blocks.last_mut().filter(|b| b.xor_data.last() != Some(&0)) {
+            last.xor_data.push(0);
+        }
+        Patch {
+            blocks,
+            ..self.clone()
         }
     }
 
+    /// Adapt the patch to a source file padded or trimmed to `new_size` with `pad_byte`, e.g. a
+    /// base with trailing `0xff` bytes added or removed. The resulting patch applies to the resized
+    /// file and produces the same destination file, reverting it gets the resized file back.
+    ///
+    /// When trimming, the removed bytes must all be `pad_byte`, otherwise the source checksum
+    /// won't match the resized file. Blocks of the resulting patch are
+    /// [normalized](Patch::normalize).
+    pub fn with_src_size(&self, new_size: usize, pad_byte: u8) -> Patch {
+        let old_size = self.src_size;
+        let (lo, hi) = if new_size > old_size {
+            (old_size, new_size)
+        } else {
+            (new_size, old_size)
+        };
+        let padding = vec![pad_byte; hi - lo];
+        let src_checksum = if new_size > old_size {
+            self.src_checksum.extend(&padding)
+        } else {
+            self.src_checksum.remove_suffix(&padding)
+        };
+
+        // Source bytes in `lo..hi` go from zero, i.e. missing, to `pad_byte` or the other way
+        // around, so their XOR data changes by `pad_byte`.
+        let mut region = padding;
+        let mut runs: Vec<(usize, BlockData)> = Vec::new();
+        for (start, data) in self.xor_runs() {
+            let end = start + data.len();
+            if start < lo {
+                let len = std::cmp::min(end, lo) - start;
+                runs.push((start, BlockData::from_slice(&data[..len])));
+            }
+            for pos in std::cmp::max(start, lo)..std::cmp::min(end, hi) {
+                region[pos - lo] ^= data[pos - start];
+            }
+            if end > hi {
+                let from = std::cmp::max(start, hi);
+                runs.push((from, BlockData::from_slice(&data[from - start..])));
+            }
+        }
+        let mut run_start = lo;
+        for run in region.split(|b| *b == 0) {
+            if !run.is_empty() {
+                runs.push((run_start, BlockData::from_slice(run)));
+            }
+            run_start += run.len() + 1;
+        }
+        runs.sort_by_key(|(start, _)| *start);
+        // Runs split at `lo` and `hi` may need to be joined back.
+        let mut merged: Vec<(usize, BlockData)> = Vec::with_capacity(runs.len());
+        for (start, data) in runs {
+            match merged.last_mut() {
+                Some((prev_start, prev)) if *prev_start + prev.len() == start => {
+                    prev.extend_from_slice(&data)
+                }
+                _ => merged.push((start, data)),
+            }
+        }
+
+        Patch {
+            blocks: blocks_from_runs(merged),
+            src_size: new_size,
+            src_checksum,
+            ..self.clone()
+        }
+    }
+
+    // Runs of non-zero XOR bytes as (absolute start, data), in order and with no data past the
+    // end of both files.
+    fn xor_runs(&self) -> Vec<(usize, BlockData)> {
+        let limit = std::cmp::max(self.src_size, self.dst_size);
+        let mut runs: Vec<(usize, BlockData)> = Vec::new();
+        let mut pos = 0usize;
+        for block in &self.blocks {
+            pos = match pos.checked_add(block.offset) {
+                Some(p) if p < limit => p,
+                _ => break,
+            };
+            let data = &block.xor_data[..std::cmp::min(block.xor_data.len(), limit - pos)];
+            let mut run_start = pos;
+            for run in data.split(|b| *b == 0) {
+                if !run.is_empty() {
+                    match runs.last_mut() {
+                        // Blocks without a terminator may be continued by the next one.
+                        Some((start, prev)) if *start + prev.len() == run_start => {
+                            prev.extend_from_slice(run)
+                        }
+                        _ => runs.push((run_start, BlockData::from_slice(run))),
+                    }
+                }
+                run_start += run.len() + 1;
+            }
+            pos += block.xor_data.len();
+        }
+        runs
+    }
+
+    /// Whether applying or reverting the patch leaves files unchanged: source and destination have
+    /// the same size and checksum and the blocks only XOR zeros. Diffs of identical files are
+    /// no-ops, they have no blocks at all.
+    pub fn is_noop(&self) -> bool {
+        self.src_size == self.dst_size
+            && self.src_checksum == self.dst_checksum
+            && self
+                .blocks
+                .iter()
+                .all(|b| b.xor_data.iter().all(|&x| x == 0))
+    }
+
+    /// Stable 64-bit hash of the [normalized](Patch::normalize) patch, equal for patches making
+    /// the same changes. Unlike [`Hash`] it doesn't depend on the platform, Rust version or
+    /// process, so it can be stored or sent to other programs.
+    ///
+    /// This is not a cryptographic hash, compare the patches themselves when collisions matter.
+    pub fn fingerprint(&self) -> u64 {
+        // 64-bit FNV-1a over every field with fixed-size integers.
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0100_0000_01b3;
+        fn feed(hash: &mut u64, bytes: &[u8]) {
+            for b in bytes {
+                *hash = (*hash ^ u64::from(*b)).wrapping_mul(PRIME);
+            }
+        }
+
+        let normalized = self.normalize();
+        let mut hash = OFFSET_BASIS;
+        feed(&mut hash, &(normalized.src_size as u64).to_le_bytes());
+        feed(&mut hash, &normalized.src_checksum.0.to_le_bytes());
+        feed(&mut hash, &(normalized.dst_size as u64).to_le_bytes());
+        feed(&mut hash, &normalized.dst_checksum.0.to_le_bytes());
+        for block in &normalized.blocks {
+            feed(&mut hash, &(block.offset as u64).to_le_bytes());
+            // Normalized blocks are zero-terminated with no zeros in between.
+            feed(&mut hash, &block.xor_data);
+        }
+        hash
+    }
+
+    /// Approximate heap memory used by the patch, in bytes. Includes allocated but unused capacity,
+    /// see [`shrink_to_fit`](Patch::shrink_to_fit), and the cached
+    /// [`block_offsets`](Patch::block_offsets) if built.
+    pub fn heap_size(&self) -> usize {
+        let blocks = self.blocks.capacity() * std::mem::size_of::<Block>();
+        let xor_data: usize = self
+            .blocks
+            .iter()
+            .filter(|b| b.xor_data.spilled())
+            .map(|b| b.xor_data.capacity())
+            .sum();
+        let offsets = self.offsets.get().map_or(0, BlockOffsets::heap_size);
+        blocks + xor_data + offsets
+    }
+
+    /// Release unused capacity from the blocks, e.g. before caching many patches for a long time.
+    /// Small blocks are moved back inline.
+    pub fn shrink_to_fit(&mut self) {
+        self.blocks.shrink_to_fit();
+        for block in &mut self.blocks {
+            block.xor_data.shrink_to_fit();
+        }
+    }
+
+    /// Consuming version of [`shrink_to_fit`](Patch::shrink_to_fit), e.g.
+    /// `Patch::parse(&raw)?.compact()`.
+    pub fn compact(mut self) -> Self {
+        self.shrink_to_fit();
+        self
+    }
+
     /// Serialize this patch as an UPS file.
+    ///
+    /// # Panics
+    ///
+    /// If the metadata is [dirty](Patch::is_metadata_dirty): the checksums wouldn't match the
+    /// blocks, call [`recompute_metadata`](Patch::recompute_metadata) first.
     pub fn serialize(&self) -> Vec<u8> {
+        assert!(
+            !self.metadata_dirty,
+            "serializing a patch with dirty metadata, call recompute_metadata first"
+        );
         let mut bytes = b"UPS1".to_vec();
-        varint::write_bytes(&mut bytes, self.src_size);
-        varint::write_bytes(&mut bytes, self.dst_size);
+        varint::write(&mut bytes, self.src_size);
+        varint::write(&mut bytes, self.dst_size);
         for block in &self.blocks {
-            varint::write_bytes(&mut bytes, block.offset);
+            varint::write(&mut bytes, block.offset);
             bytes.extend(&block.xor_data);
         }
 
@@ -268,44 +737,373 @@ impl Patch {
         bytes
     }
 
+    /// Serialize the patch to `writer`, producing the same bytes as [`serialize`](Patch::serialize)
+    /// without building them in memory.
+    ///
+    /// Block data is passed to [`Write::write_vectored`] straight from the patch, only the header
+    /// and block offsets are encoded to small stack buffers. Patches with
+    /// [dirty](Patch::is_metadata_dirty) metadata fail with an [`io::ErrorKind::InvalidInput`]
+    /// error.
+    pub fn write_vectored<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.metadata_dirty {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "patch metadata is dirty, call recompute_metadata first",
+            ));
+        }
+        let mut hasher = Hasher::new();
+        let mut header = [0; 4 + 2 * varint::MAX_LEN];
+        header[..4].copy_from_slice(MAGIC);
+        let mut len = 4;
+        len += varint::write_slice(&mut header[len..], self.src_size);
+        len += varint::write_slice(&mut header[len..], self.dst_size);
+        write_all_hashed(writer, &mut hasher, &[&header[..len]])?;
+
+        let mut offsets = [0; VECTORED_BATCH * varint::MAX_LEN];
+        let mut offset_ends = [0; VECTORED_BATCH];
+        for batch in self.blocks.chunks(VECTORED_BATCH) {
+            let mut end = 0;
+            for (block, offset_end) in batch.iter().zip(&mut offset_ends) {
+                end += varint::write_slice(&mut offsets[end..], block.offset);
+                *offset_end = end;
+            }
+            let mut parts: [&[u8]; 2 * VECTORED_BATCH] = [&[]; 2 * VECTORED_BATCH];
+            let mut start = 0;
+            for (i, block) in batch.iter().enumerate() {
+                parts[2 * i] = &offsets[start..offset_ends[i]];
+                parts[2 * i + 1] = &block.xor_data;
+                start = offset_ends[i];
+            }
+            write_all_hashed(writer, &mut hasher, &parts[..2 * batch.len()])?;
+        }
+
+        let mut checksums = [0; 8];
+        checksums[..4].copy_from_slice(&self.src_checksum.0.to_le_bytes());
+        checksums[4..].copy_from_slice(&self.dst_checksum.0.to_le_bytes());
+        write_all_hashed(writer, &mut hasher, &[&checksums])?;
+        writer.write_all(&hasher.finalize().to_le_bytes())
+    }
+
     /// Applies or reverts a patch on the given buffer and return the raw output bytes.
     pub fn patch(&self, direction: PatchDirection, input: &[u8]) -> UpsPatchResult<Vec<u8>> {
         let metadata = direction.metadata(self);
-        let mut errors = Vec::new();
+        let mut errors = self.check_input(direction, input);
 
-        if let Some(err) = MetadataMismatch::size(metadata.input_size, input.len()) {
-            errors.push(direction.input_metadata_error(err));
+        let mut output = vec![0; metadata.output_size];
+        let input_copy_len = std::cmp::min(metadata.output_size, input.len());
+        output[..input_copy_len].copy_from_slice(&input[..input_copy_len]);
+        let output_checksum = self.xor_blocks(&mut output);
+        if let Some(err) = MetadataMismatch::checksum(metadata.output_checksum, output_checksum) {
+            errors.push(direction.output_metadata_error(err));
         }
-        let input_checksum = Checksum::from_bytes(input);
-        if let Some(err) = MetadataMismatch::checksum(metadata.input_checksum, input_checksum) {
-            errors.push(direction.input_metadata_error(err));
+
+        UpsPatchErrors::check_errors(output, errors)
+    }
+
+    /// Like [`patch`](Patch::patch), reading the input from `input` and reading it again once
+    /// patched to check it didn't change meanwhile, returning [`UpsPatchError::InputModified`]
+    /// with the first changed offset otherwise.
+    ///
+    /// This is meant for inputs which can change under the patcher, e.g. ROM files shared with a
+    /// running emulator. `input` is read from its current position to its end, both times. Errors
+    /// reading it are returned in the outer result.
+    pub fn patch_verified<R: Read + Seek>(
+        &self,
+        direction: PatchDirection,
+        input: &mut R,
+    ) -> io::Result<UpsPatchResult<Vec<u8>>> {
+        let start = input.stream_position()?;
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let result = self.patch(direction, &data);
+
+        input.seek(SeekFrom::Start(start))?;
+        let offset = match first_difference(&data, input)? {
+            Some(offset) => offset,
+            None => return Ok(result),
+        };
+        let (output, mut errors) = match result {
+            Ok(output) => (output, Vec::new()),
+            Err(mut e) => (std::mem::take(&mut e.output), e.into_iter().collect()),
+        };
+        errors.push(UpsPatchError::InputModified { offset });
+        Ok(UpsPatchErrors::check_errors(output, errors))
+    }
+
+    /// Applies or reverts a patch directly over `buf`, which holds the input and is left holding
+    /// the output.
+    ///
+    /// `buf` is resized to the output size, so when both files have the same size, which is the
+    /// common case for ROM hacks, there's no allocation or copy at all. On errors `buf` holds the
+    /// possibly invalid output and the `output` of the returned [`UpsPatchErrors`] is empty.
+    pub fn patch_in_place(
+        &self,
+        direction: PatchDirection,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), UpsPatchErrors> {
+        let metadata = direction.metadata(self);
+        let mut errors = self.check_input(direction, buf);
+
+        buf.resize(metadata.output_size, 0);
+        let output_checksum = self.xor_blocks(buf);
+        if let Some(err) = MetadataMismatch::checksum(metadata.output_checksum, output_checksum) {
+            errors.push(direction.output_metadata_error(err));
         }
 
-        let mut output = vec![0; metadata.output_size];
-        let input_copy_len = std::cmp::min(metadata.output_size, metadata.input_size);
-        output[..input_copy_len].copy_from_slice(&input[..input_copy_len]);
+        UpsPatchErrors::check_errors(Vec::new(), errors).map(|_| ())
+    }
+
+    /// Apply patch over the source data in `buf`, see [`patch_in_place`](Patch::patch_in_place).
+    pub fn apply_in_place(&self, buf: &mut Vec<u8>) -> Result<(), UpsPatchErrors> {
+        self.patch_in_place(PatchDirection::Apply, buf)
+    }
+
+    /// Revert patch over the patched data in `buf`, see
+    /// [`patch_in_place`](Patch::patch_in_place).
+    pub fn revert_in_place(&self, buf: &mut Vec<u8>) -> Result<(), UpsPatchErrors> {
+        self.patch_in_place(PatchDirection::Revert, buf)
+    }
+
+    /// Applies or reverts a patch on the given buffer, returning only the changed regions as
+    /// (output offset, new bytes) pairs, in order, instead of the whole output.
+    ///
+    /// This is meant for tools writing changes directly into memory they don't own, e.g. an
+    /// emulator's loaded ROM. Checksums are not verified since the output is never built, check
+    /// the input beforehand with [`Patch::preflight`]. The output may also have a different size
+    /// than `input`, which must be resized to the output size separately.
+    pub fn patch_sparse(&self, direction: PatchDirection, input: &[u8]) -> Vec<(usize, Vec<u8>)> {
+        self.changed_spans(direction.metadata(self).output_size)
+            .into_iter()
+            .map(|(start, xor_data)| {
+                let new_bytes = (start..)
+                    .zip(xor_data)
+                    .map(|(pos, patch_byte)| input.get(pos).copied().unwrap_or(0) ^ patch_byte)
+                    .collect();
+                (start, new_bytes)
+            })
+            .collect()
+    }
+
+    // Output offset and XOR data of each block, without terminators or data past `output_size`.
+    pub(crate) fn changed_spans(&self, output_size: usize) -> Vec<(usize, &[u8])> {
+        let offsets = self.block_offsets();
+        let mut spans = Vec::with_capacity(self.blocks.len());
+        for (i, block) in self.blocks.iter().enumerate() {
+            let span = match offsets.span(i) {
+                Some(s) if s.start < output_size => s,
+                _ => break,
+            };
+            let end = std::cmp::min(span.end, output_size);
+            if span.start < end {
+                spans.push((span.start, &block.xor_data[..end - span.start]));
+            }
+        }
+        spans
+    }
+
+    /// Changed regions from applying the patch to `src`, see
+    /// [`patch_sparse`](Patch::patch_sparse).
+    pub fn apply_sparse(&self, src: &[u8]) -> Vec<(usize, Vec<u8>)> {
+        self.patch_sparse(PatchDirection::Apply, src)
+    }
+
+    /// Changed regions from reverting the patch on `dst`, see
+    /// [`patch_sparse`](Patch::patch_sparse).
+    pub fn revert_sparse(&self, dst: &[u8]) -> Vec<(usize, Vec<u8>)> {
+        self.patch_sparse(PatchDirection::Revert, dst)
+    }
 
-        let mut output_ptr: &mut [u8] = &mut output;
+    // XOR every block into `output`, ignoring data past its end, and return the checksum of the
+    // result. Each region is hashed right after it's written, while it's still in cache, instead
+    // of in a second pass over the whole output.
+    fn xor_blocks(&self, output: &mut [u8]) -> Checksum {
+        // Output regions in order with their XOR data, empty for unchanged regions. Blocks cover
+        // disjoint ranges, so they're split upfront to be processed in any order.
+        let mut regions = Vec::with_capacity(2 * self.blocks.len() + 1);
+        let mut output_ptr = output;
         for block in &self.blocks {
             if block.offset >= output_ptr.len() {
                 break;
             }
-            output_ptr = &mut output_ptr[block.offset..];
-            for (out_byte, patch_byte) in output_ptr.iter_mut().zip(&block.xor_data) {
-                *out_byte ^= patch_byte;
-            }
-            if block.xor_data.len() >= output_ptr.len() {
-                break;
-            }
-            output_ptr = &mut output_ptr[block.xor_data.len()..];
+            let (unchanged, tail) = std::mem::take(&mut output_ptr).split_at_mut(block.offset);
+            let len = std::cmp::min(block.xor_data.len(), tail.len());
+            let (chunk, tail) = tail.split_at_mut(len);
+            regions.push((unchanged, &[][..]));
+            regions.push((chunk, &block.xor_data[..len]));
+            output_ptr = tail;
+        }
+        regions.push((output_ptr, &[]));
+        xor_regions(regions)
+    }
+
+    /// Applies or reverts a patch on the given buffer, streaming the output to `writer`.
+    ///
+    /// Unlike [`patch`](Patch::patch) this never holds the whole output in memory, regions past
+    /// the end of `input` are zero-filled while writing. Since the output is written before its
+    /// checksum is known, `writer` may have received invalid data when this returns an error. The
+    /// `output` of the returned [`UpsPatchErrors`] is always empty.
+    pub fn patch_to_writer<W: Write>(
+        &self,
+        direction: PatchDirection,
+        input: &[u8],
+        mut writer: W,
+    ) -> Result<(), UpsWriteError> {
+        let metadata = direction.metadata(self);
+        let mut errors = self.check_input(direction, input);
+
+        let mut out = ChecksumWriter::new(&mut writer);
+        // Absolute output position for the start of the next block.
+        let mut block_start = 0usize;
+        for block in &self.blocks {
+            block_start = match block_start.checked_add(block.offset) {
+                Some(s) if s < metadata.output_size => s,
+                _ => break,
+            };
+            let unchanged = out.written..block_start;
+            out.write_xored(input, unchanged, &[])?;
+            let block_end = std::cmp::min(
+                block_start.saturating_add(block.xor_data.len()),
+                metadata.output_size,
+            );
+            out.write_xored(input, block_start..block_end, &block.xor_data)?;
+            block_start = block_end;
         }
+        let rest = out.written..metadata.output_size;
+        out.write_xored(input, rest, &[])?;
 
-        let output_checksum = Checksum::from_bytes(&output);
+        let output_checksum = out.checksum();
         if let Some(err) = MetadataMismatch::checksum(metadata.output_checksum, output_checksum) {
             errors.push(direction.output_metadata_error(err));
         }
 
-        UpsPatchErrors::check_errors(output, errors)
+        UpsPatchErrors::check_errors(Vec::new(), errors)?;
+        Ok(())
+    }
+
+    /// Applies or reverts a patch on an input arriving as an iterator of chunks, passing the
+    /// output chunks to `on_output`. See [`ChunkedPatcher`] to push chunks instead, e.g. from
+    /// async code.
+    ///
+    /// Like [`patch_to_writer`](Patch::patch_to_writer), `on_output` may have received invalid
+    /// data when this returns an error, and the `output` of the returned [`UpsPatchErrors`] is
+    /// always empty.
+    pub fn patch_chunks<I, F>(
+        &self,
+        direction: PatchDirection,
+        chunks: I,
+        mut on_output: F,
+    ) -> Result<(), UpsPatchErrors>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+        F: FnMut(&[u8]),
+    {
+        let mut patcher = ChunkedPatcher::new(self, direction);
+        for chunk in chunks {
+            patcher.push(chunk.as_ref(), &mut on_output);
+        }
+        patcher.finish(on_output)
+    }
+
+    /// Size and checksum required for the source and destination files.
+    pub fn requirements(&self) -> Requirements {
+        Requirements {
+            src: Requirement {
+                size: self.src_size,
+                crc32: self.src_checksum,
+            },
+            dst: Requirement {
+                size: self.dst_size,
+                crc32: self.dst_checksum,
+            },
+        }
+    }
+
+    /// Check that `next` applies to the output of this patch, from their metadata alone. This is
+    /// cheap, so patch chains can be validated before reading any ROM.
+    ///
+    /// ```
+    /// use ups::Patch;
+    ///
+    /// let v1 = Patch::diff(b"clean rom", b"hack v1");
+    /// let v2 = Patch::diff(b"hack v1", b"hack v2");
+    /// assert!(v1.can_compose(&v2).is_ok());
+    /// let err = v2.can_compose(&v1).unwrap_err();
+    /// assert_eq!(err.dst, v2.requirements().dst);
+    /// assert_eq!(err.next_src, v1.requirements().src);
+    /// ```
+    pub fn can_compose(&self, next: &Patch) -> Result<(), ComposeError> {
+        self.requirements().can_compose(&next.requirements())
+    }
+
+    /// Cheap estimate of the work applying the patch takes, to order or balance patches across
+    /// threads. Reverting costs the same, except for writing `src_size` bytes of output instead.
+    pub fn estimated_apply_cost(&self) -> ApplyCost {
+        ApplyCost {
+            output_size: self.dst_size,
+            xor_bytes: self
+                .blocks
+                .iter()
+                .fold(0usize, |n, b| n.saturating_add(b.xor_data.len())),
+            blocks: self.blocks.len(),
+        }
+    }
+
+    /// Check what patching a file with the given size and checksum would do, without needing its
+    /// contents. Useful to show users the outcome before committing to it.
+    pub fn preflight(&self, input_len: usize, input_crc: Checksum) -> Preflight {
+        let matches = |direction: PatchDirection| {
+            self.check_input_metadata(direction, input_len, input_crc)
+                .is_empty()
+        };
+        let direction_hint = if matches(PatchDirection::Apply) {
+            Some(PatchDirection::Apply)
+        } else if matches(PatchDirection::Revert) {
+            Some(PatchDirection::Revert)
+        } else {
+            None
+        };
+
+        let direction = direction_hint.unwrap_or(PatchDirection::Apply);
+        let output_size = direction.metadata(self).output_size;
+        Preflight {
+            direction_hint,
+            will_resize_to: if output_size != input_len {
+                Some(output_size)
+            } else {
+                None
+            },
+            warnings: self.check_input_metadata(direction, input_len, input_crc),
+        }
+    }
+
+    /// Verify `input` against the patch metadata for `direction`.
+    fn check_input(&self, direction: PatchDirection, input: &[u8]) -> Vec<UpsPatchError> {
+        self.check_input_metadata(direction, input.len(), Checksum::from_bytes(input))
+    }
+
+    /// Check an input of `input_len` bytes with checksum `input_checksum` against the patch
+    /// metadata for `direction`, e.g. for an input streamed from a file instead of read in memory.
+    /// Empty if it matches.
+    pub fn check_input_metadata(
+        &self,
+        direction: PatchDirection,
+        input_len: usize,
+        input_checksum: Checksum,
+    ) -> Vec<UpsPatchError> {
+        let metadata = direction.metadata(self);
+        let mut errors = Vec::new();
+
+        if self.metadata_dirty {
+            errors.push(UpsPatchError::StaleMetadata);
+        }
+        if let Some(err) = MetadataMismatch::size(metadata.input_size, input_len) {
+            errors.push(direction.input_metadata_error(err));
+        }
+        if let Some(err) = MetadataMismatch::checksum(metadata.input_checksum, input_checksum) {
+            errors.push(direction.input_metadata_error(err));
+        }
+        errors
     }
 
     /// Apply patch to source data. Returns the contents of the patched file.
@@ -313,12 +1111,226 @@ impl Patch {
         self.patch(PatchDirection::Apply, src)
     }
 
+    /// Data the patch appends past the end of the source file, i.e. the destination file from
+    /// `src_size` on. Returns `None` if the destination file isn't larger than the source.
+    ///
+    /// Source bytes past its end are zero when patching, so the appended data is in the patch
+    /// itself and doesn't need the source file.
+    pub fn appended_data(&self) -> Option<Vec<u8>> {
+        if self.dst_size <= self.src_size {
+            return None;
+        }
+        let mut appended = vec![0; self.dst_size - self.src_size];
+        let mut block_start = 0usize;
+        for block in &self.blocks {
+            block_start = match block_start.checked_add(block.offset) {
+                Some(s) if s < self.dst_size => s,
+                _ => break,
+            };
+            let block_end = std::cmp::min(
+                block_start.saturating_add(block.xor_data.len()),
+                self.dst_size,
+            );
+            if block_end > self.src_size {
+                let from = std::cmp::max(block_start, self.src_size);
+                appended[from - self.src_size..block_end - self.src_size]
+                    .copy_from_slice(&block.xor_data[from - block_start..block_end - block_start]);
+            }
+            block_start = block_end;
+        }
+        Some(appended)
+    }
+
+    /// Index of absolute block positions, for repeated lookups without rescanning the blocks.
+    ///
+    /// The index is built on the first call and cached in the patch until the blocks change
+    /// through [`blocks_mut`](Patch::blocks_mut) or [`iter_blocks_mut`](Patch::iter_blocks_mut).
+    pub fn block_offsets(&self) -> &BlockOffsets {
+        self.offsets.get_or_init(|| BlockOffsets::new(self))
+    }
+
+    /// Wrap the patch for sharing between threads, see [`SharedPatch`].
+    pub fn into_shared(self) -> Arc<SharedPatch> {
+        Arc::new(SharedPatch::new(self))
+    }
+
     /// Revert patch applied to the given buffer. Returns the contents of the reverted file.
     pub fn revert(&self, dst: &[u8]) -> UpsPatchResult<Vec<u8>> {
         self.patch(PatchDirection::Revert, dst)
     }
 }
 
+// Encode runs from `Patch::xor_runs` as zero-terminated blocks.
+fn blocks_from_runs(runs: Vec<(usize, BlockData)>) -> Vec<Block> {
+    let mut prev_end = 0;
+    runs.into_iter()
+        .map(|(start, mut xor_data)| {
+            let offset = start - prev_end;
+            prev_end = start + xor_data.len() + 1;
+            xor_data.push(0);
+            Block { offset, xor_data }
+        })
+        .collect()
+}
+
+/// Minimum amount of XOR data for [`xor_regions`] to use multiple threads.
+#[cfg(feature = "rayon")]
+const PARALLEL_MIN_BYTES: usize = 1 << 20;
+
+/// Size of the pieces [`xor_regions`] XORs and hashes at a time.
+const FUSED_CHUNK_SIZE: usize = 16 * 1024;
+
+/// XOR each output region with its block data and checksum the result, in parallel when the
+/// `rayon` feature is enabled and there's enough data and threads to be worth it.
+fn xor_regions(regions: Vec<(&mut [u8], &[u8])>) -> Checksum {
+    fn xor((region, xor_data): (&mut [u8], &[u8]), hasher: &mut Hasher) {
+        // Hash in small pieces so the XORed data is still in cache.
+        for (piece, xor_piece) in region
+            .chunks_mut(FUSED_CHUNK_SIZE)
+            .zip(xor_data.chunks(FUSED_CHUNK_SIZE))
+        {
+            for (out_byte, patch_byte) in piece.iter_mut().zip(xor_piece) {
+                *out_byte ^= patch_byte;
+            }
+            hasher.update(piece);
+        }
+        if xor_data.is_empty() {
+            hasher.update(region);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+
+        let total: usize = regions.iter().map(|(_, xor_data)| xor_data.len()).sum();
+        if total >= PARALLEL_MIN_BYTES && rayon::current_num_threads() > 1 {
+            let hashers: Vec<Hasher> = regions
+                .into_par_iter()
+                .map(|region| {
+                    let mut hasher = Hasher::new();
+                    xor(region, &mut hasher);
+                    hasher
+                })
+                .collect();
+            let mut hasher = Hasher::new();
+            for h in &hashers {
+                hasher.combine(h);
+            }
+            return Checksum(hasher.finalize());
+        }
+    }
+    let mut hasher = Hasher::new();
+    for region in regions {
+        xor(region, &mut hasher);
+    }
+    Checksum(hasher.finalize())
+}
+
+/// Number of blocks passed to each [`Write::write_vectored`] call in [`Patch::write_vectored`].
+const VECTORED_BATCH: usize = 64;
+
+// Write all of `parts` with `Write::write_vectored`, adding them to `hasher`.
+fn write_all_hashed<W: Write>(
+    writer: &mut W,
+    hasher: &mut Hasher,
+    mut parts: &[&[u8]],
+) -> io::Result<()> {
+    for part in parts {
+        hasher.update(part);
+    }
+    // Bytes of `parts[0]` written so far.
+    let mut written = 0;
+    let mut slices = [IoSlice::new(&[]); 2 * VECTORED_BATCH];
+    while !parts.is_empty() {
+        let n = std::cmp::min(parts.len(), slices.len());
+        slices[0] = IoSlice::new(&parts[0][written..]);
+        for (slice, part) in slices[1..n].iter_mut().zip(&parts[1..n]) {
+            *slice = IoSlice::new(part);
+        }
+        let mut n = match writer.write_vectored(&slices[..n]) {
+            Ok(0) if parts.iter().any(|p| !p.is_empty()) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole patch",
+                ))
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(part) = parts.first() {
+            let remaining = part.len() - written;
+            if n < remaining {
+                written += n;
+                break;
+            }
+            n -= remaining;
+            written = 0;
+            parts = &parts[1..];
+        }
+    }
+    Ok(())
+}
+
+/// Size of the buffer used by [`Patch::patch_to_writer`].
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+// Writer for `Patch::patch_to_writer` which keeps track of the output position and checksum.
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Hasher,
+    written: usize,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        ChecksumWriter {
+            inner,
+            hasher: Hasher::new(),
+            written: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    // Writes `input[range]` XORed with `xor_data`, positions past the end of either slice count as
+    // zeroes. `range.start` must be the current output position.
+    fn write_xored(
+        &mut self,
+        input: &[u8],
+        range: std::ops::Range<usize>,
+        xor_data: &[u8],
+    ) -> io::Result<()> {
+        debug_assert_eq!(range.start, self.written);
+        let mut pos = range.start;
+        while pos < range.end {
+            let end = std::cmp::min(range.end, pos + WRITE_CHUNK_SIZE);
+            self.buf.clear();
+            self.buf.resize(end - pos, 0);
+            if pos < input.len() {
+                let input_end = std::cmp::min(end, input.len());
+                self.buf[..input_end - pos].copy_from_slice(&input[pos..input_end]);
+            }
+            let xor_offset = pos - range.start;
+            if xor_offset < xor_data.len() {
+                for (out_byte, patch_byte) in self.buf.iter_mut().zip(&xor_data[xor_offset..]) {
+                    *out_byte ^= patch_byte;
+                }
+            }
+            self.hasher.update(&self.buf);
+            self.inner.write_all(&self.buf)?;
+            pos = end;
+        }
+        self.written = range.end;
+        Ok(())
+    }
+
+    fn checksum(self) -> Checksum {
+        Checksum(self.hasher.finalize())
+    }
+}
+
 /// Helper to display a byte string as ASCII, hex encoding non-ASCII chars.
 struct EscapeNonAscii<'a>(&'a [u8]);
 
@@ -348,11 +1360,35 @@ fn read_checksum(buf: &mut &[u8]) -> UpsParseResult<Checksum> {
         let (checksum_bytes, rest) = buf.split_at(4);
         *buf = rest;
         Ok(Checksum(u32::from_le_bytes(
-            (&*checksum_bytes).try_into().unwrap(),
+            checksum_bytes.try_into().unwrap(),
         )))
     }
 }
 
+// The dirty flag and the offsets cache are left out: edited patches with recomputed metadata are
+// the same patch.
+impl PartialEq for Patch {
+    fn eq(&self, other: &Self) -> bool {
+        self.blocks == other.blocks
+            && self.src_size == other.src_size
+            && self.src_checksum == other.src_checksum
+            && self.dst_size == other.dst_size
+            && self.dst_checksum == other.dst_checksum
+    }
+}
+
+impl Eq for Patch {}
+
+impl Hash for Patch {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.blocks.hash(state);
+        self.src_size.hash(state);
+        self.src_checksum.hash(state);
+        self.dst_size.hash(state);
+        self.dst_checksum.hash(state);
+    }
+}
+
 impl Debug for Patch {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("Patch")
@@ -371,6 +1407,18 @@ impl Debug for Patch {
     }
 }
 
+impl Block {
+    /// Offset from the end of the previous block, or from the start of the file for the first one.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// XOR of the source and destination bytes, usually ending with a 0 terminator.
+    pub fn xor_data(&self) -> &[u8] {
+        &self.xor_data
+    }
+}
+
 impl Debug for Block {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("Block")
@@ -401,3 +1449,31 @@ impl<'a, T: Debug> Debug for MaybeTruncate<'a, T> {
         }
     }
 }
+
+// First offset where the rest of `reader` differs from `data`, including one of them ending first.
+fn first_difference<R: Read>(data: &[u8], reader: &mut R) -> io::Result<Option<usize>> {
+    let mut buf = [0; 64 * 1024];
+    let mut offset = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let expected = &data[offset.min(data.len())..];
+        if n == 0 {
+            return Ok(if expected.is_empty() {
+                None
+            } else {
+                Some(offset)
+            });
+        }
+        if let Some(i) = buf[..n].iter().zip(expected).position(|(a, b)| a != b) {
+            return Ok(Some(offset + i));
+        }
+        if n > expected.len() {
+            return Ok(Some(offset + expected.len()));
+        }
+        offset += n;
+    }
+}
@@ -0,0 +1,121 @@
+use std::ops::Range;
+
+use super::Patch;
+
+/// Absolute output positions of the blocks in a [`Patch`], see [`Patch::block_offsets`].
+///
+/// Block offsets in a patch are relative to the end of the previous block, so finding the block
+/// at some position means scanning every block before it. Build this index once to answer these
+/// queries with a binary search instead. The patch caches its own index, an index built with
+/// [`new`](BlockOffsets::new) is a snapshot: rebuild it after modifying the patch blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockOffsets {
+    // Output bytes changed by each block, i.e. its XOR data without the terminator. Boxed to keep
+    // the cache in `Patch` small.
+    spans: Box<[Range<usize>]>,
+}
+
+impl BlockOffsets {
+    /// Build the index for `patch`.
+    pub fn new(patch: &Patch) -> Self {
+        let mut spans = Vec::with_capacity(patch.blocks.len());
+        let mut block_start = 0usize;
+        for block in &patch.blocks {
+            block_start = block_start.saturating_add(block.offset);
+            let changed = match block.xor_data.split_last() {
+                Some((0, changed)) => changed.len(),
+                _ => block.xor_data.len(),
+            };
+            spans.push(block_start..block_start.saturating_add(changed));
+            block_start = block_start.saturating_add(block.xor_data.len());
+        }
+        BlockOffsets {
+            spans: spans.into_boxed_slice(),
+        }
+    }
+
+    /// Number of blocks in the index.
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    /// Whether the patch has no blocks.
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// Absolute position of the first byte of block `index`.
+    pub fn start(&self, index: usize) -> Option<usize> {
+        self.spans.get(index).map(|s| s.start)
+    }
+
+    /// Output bytes changed by block `index`.
+    pub fn span(&self, index: usize) -> Option<Range<usize>> {
+        self.spans.get(index).cloned()
+    }
+
+    /// Index of the block changing the byte at `pos`, if any.
+    pub fn block_at(&self, pos: usize) -> Option<usize> {
+        self.last_starting_at(pos)
+            .filter(|i| self.spans[*i].contains(&pos))
+    }
+
+    // Index of the last block starting at or before `pos`.
+    // Bytes allocated for the index, see `Patch::heap_size`.
+    pub(crate) fn heap_size(&self) -> usize {
+        self.spans.len() * std::mem::size_of::<Range<usize>>()
+    }
+
+    pub(crate) fn last_starting_at(&self, pos: usize) -> Option<usize> {
+        match self.spans.binary_search_by_key(&pos, |s| s.start) {
+            Ok(i) => Some(i),
+            Err(0) => None,
+            Err(i) => Some(i - 1),
+        }
+    }
+
+    /// Ranges of output bytes changed by the patch, in order.
+    pub fn changed_ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.spans.iter().filter(|s| !s.is_empty()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_block_offsets() {
+        let patch = Patch::diff(b"abcdefgh", b"aXcdYZgh!");
+        let offsets = patch.block_offsets();
+        assert_eq!(offsets.len(), 3);
+        assert_eq!(
+            offsets.changed_ranges().collect::<Vec<_>>(),
+            vec![1..2, 4..6, 8..9],
+        );
+        assert_eq!(offsets.block_at(0), None);
+        assert_eq!(offsets.block_at(1), Some(0));
+        assert_eq!(offsets.block_at(5), Some(1));
+        assert_eq!(offsets.block_at(6), None);
+        assert_eq!(offsets.block_at(8), Some(2));
+        assert_eq!(offsets.block_at(100), None);
+        assert_eq!(offsets.start(1), Some(4));
+        assert_eq!(offsets.span(3), None);
+    }
+
+    #[test]
+    fn test_block_offsets_rebuilt_after_edit() {
+        let mut patch = Patch::diff(b"abcdefgh", b"aXcdYZgh!");
+        assert_eq!(patch.block_offsets().len(), 3);
+
+        patch.iter_blocks_mut().next_block().unwrap().delete();
+        assert_eq!(
+            patch.block_offsets().changed_ranges().collect::<Vec<_>>(),
+            vec![4..6, 8..9],
+        );
+
+        patch.blocks_mut().pop();
+        assert_eq!(patch.block_offsets(), &BlockOffsets::new(&patch));
+        assert_eq!(patch.block_offsets().len(), 1);
+    }
+}
@@ -0,0 +1,171 @@
+use std::borrow::Borrow;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::{BlockOffsets, Patch, PatchDirection};
+
+/// [`Read`] adapter producing patched data on demand, without holding the whole output in memory.
+///
+/// Bytes are read from the input only when requested and XORed with the patch blocks. Seeking
+/// works on output positions.
+///
+/// Checksums are not verified since the data is never seen as a whole. Check the input beforehand
+/// with [`Patch::preflight`] if needed.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::fs::{self, File};
+/// use std::io::Read;
+/// use ups::{Patch, PatchedReader};
+///
+/// let patch = Patch::parse(&fs::read("hack.ups")?)?;
+/// let mut reader = PatchedReader::new(&patch, File::open("game.gba")?);
+/// let mut header = [0; 192];
+/// reader.read_exact(&mut header)?;
+///
+/// # Ok::<_, Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct PatchedReader<P, R> {
+    patch: P,
+    direction: PatchDirection,
+    input: R,
+    output_size: usize,
+    offsets: BlockOffsets,
+    pos: usize,
+    // Current position of `input`, to avoid redundant seeks.
+    input_pos: Option<usize>,
+}
+
+impl<P: Borrow<Patch>, R: Read + Seek> PatchedReader<P, R> {
+    /// Read `src` with `patch` applied.
+    pub fn new(patch: P, src: R) -> Self {
+        Self::with_direction(patch, PatchDirection::Apply, src)
+    }
+
+    /// Read `input` with `patch` applied or reverted.
+    pub fn with_direction(patch: P, direction: PatchDirection, input: R) -> Self {
+        let offsets = patch.borrow().block_offsets().clone();
+        Self::with_offsets(patch, direction, input, offsets)
+    }
+
+    // Build a reader reusing an already computed index of `patch`.
+    pub(crate) fn with_offsets(
+        patch: P,
+        direction: PatchDirection,
+        input: R,
+        offsets: BlockOffsets,
+    ) -> Self {
+        let output_size = direction.metadata(patch.borrow()).output_size;
+        PatchedReader {
+            patch,
+            direction,
+            input,
+            output_size,
+            offsets,
+            pos: 0,
+            input_pos: None,
+        }
+    }
+
+    /// Patching direction for this reader.
+    pub fn direction(&self) -> PatchDirection {
+        self.direction
+    }
+
+    /// Total size of the patched data.
+    pub fn output_size(&self) -> usize {
+        self.output_size
+    }
+
+    /// Returns the underlying input.
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    // Fill `buf` from the input at the current position, zero-filling past its end.
+    fn read_input(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        if self.input_pos != Some(self.pos) {
+            self.input.seek(SeekFrom::Start(self.pos as u64))?;
+        }
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.input.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    self.input_pos = None;
+                    return Err(e);
+                }
+            }
+        }
+        self.input_pos = Some(self.pos + filled);
+        for b in &mut buf[filled..] {
+            *b = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<P: Borrow<Patch>, R: Read + Seek> Read for PatchedReader<P, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.output_size {
+            return Ok(0);
+        }
+        let len = std::cmp::min(buf.len(), self.output_size - self.pos);
+        let buf = &mut buf[..len];
+        self.read_input(buf)?;
+
+        let blocks = &self.patch.borrow().blocks;
+        let end = self.pos + len;
+        // First block which may overlap the requested range.
+        let first = self.offsets.last_starting_at(self.pos).unwrap_or(0);
+        for (i, block) in blocks.iter().enumerate().skip(first) {
+            let start = match self.offsets.start(i) {
+                Some(s) if s < end => s,
+                _ => break,
+            };
+            let block_end = start.saturating_add(block.xor_data.len());
+            if block_end <= self.pos {
+                continue;
+            }
+            let from = std::cmp::max(start, self.pos);
+            let to = std::cmp::min(block_end, end);
+            let out = &mut buf[from - self.pos..to - self.pos];
+            for (out_byte, patch_byte) in out.iter_mut().zip(&block.xor_data[from - start..]) {
+                *out_byte ^= patch_byte;
+            }
+        }
+
+        self.pos = end;
+        Ok(len)
+    }
+}
+
+impl<P: Borrow<Patch>, R: Read + Seek> Seek for PatchedReader<P, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(delta) => offset_pos(self.output_size as u64, delta),
+            SeekFrom::Current(delta) => offset_pos(self.pos as u64, delta),
+        };
+        let new_pos = new_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        // Positions past the end are allowed, reads there return 0 bytes.
+        self.pos = std::cmp::min(new_pos, usize::MAX as u64) as usize;
+        Ok(new_pos)
+    }
+}
+
+fn offset_pos(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    }
+}
@@ -0,0 +1,138 @@
+use std::io::{Read, Seek};
+use std::ops::Deref;
+
+use super::{BlockOffsets, Patch, PatchDirection, PatchedReader, Requirements};
+
+/// [`Patch`] prepared to be shared between threads, see [`Patch::into_shared`].
+///
+/// Patching only needs `&self`, so a single `Arc<SharedPatch>` can be cloned into every worker
+/// thread instead of parsing or cloning the patch for each of them. Indices needed by
+/// [`reader`](SharedPatch::reader) and the patch [`requirements`](SharedPatch::requirements) are
+/// computed once upfront. Derefs to the wrapped [`Patch`] for everything else.
+///
+/// ## Example
+///
+/// ```
+/// use std::thread;
+/// use ups::Patch;
+///
+/// let patch = Patch::diff(b"abcd", b"abXd").into_shared();
+/// let workers: Vec<_> = (0..4)
+///     .map(|_| {
+///         let patch = patch.clone();
+///         thread::spawn(move || patch.apply(b"abcd").unwrap())
+///     })
+///     .collect();
+/// for worker in workers {
+///     assert_eq!(worker.join().unwrap(), b"abXd");
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedPatch {
+    patch: Patch,
+    requirements: Requirements,
+}
+
+// `SharedPatch` is useless if it can't cross threads, fail to compile if that ever changes.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Patch>();
+    assert_send_sync::<SharedPatch>();
+};
+
+impl SharedPatch {
+    pub(super) fn new(patch: Patch) -> Self {
+        // Build the cached index now, rather than racing for it in every worker.
+        patch.block_offsets();
+        SharedPatch {
+            requirements: patch.requirements(),
+            patch,
+        }
+    }
+
+    /// The wrapped patch.
+    pub fn patch(&self) -> &Patch {
+        &self.patch
+    }
+
+    /// Precomputed [`Patch::block_offsets`].
+    pub fn offsets(&self) -> &BlockOffsets {
+        self.patch.block_offsets()
+    }
+
+    /// Precomputed [`Patch::requirements`].
+    pub fn requirements(&self) -> Requirements {
+        self.requirements
+    }
+
+    /// [`PatchedReader`] over `input`, reusing the precomputed block offsets.
+    pub fn reader<R: Read + Seek>(
+        &self,
+        direction: PatchDirection,
+        input: R,
+    ) -> PatchedReader<&Patch, R> {
+        PatchedReader::with_offsets(&self.patch, direction, input, self.offsets().clone())
+    }
+
+    /// Unwrap the patch.
+    pub fn into_inner(self) -> Patch {
+        self.patch
+    }
+}
+
+impl Deref for SharedPatch {
+    type Target = Patch;
+
+    fn deref(&self) -> &Patch {
+        &self.patch
+    }
+}
+
+impl AsRef<Patch> for SharedPatch {
+    fn as_ref(&self) -> &Patch {
+        &self.patch
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read};
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_shared_across_threads() {
+        let src: Vec<u8> = (0..10_000u32).map(|i| (i % 253) as u8).collect();
+        let mut dst = src.clone();
+        dst[5000..5010].copy_from_slice(b"0123456789");
+        dst.extend_from_slice(b"tail");
+        let patch = Patch::diff(&src, &dst);
+        let shared = patch.clone().into_shared();
+        assert_eq!(shared.offsets(), patch.block_offsets());
+        assert_eq!(shared.requirements(), patch.requirements());
+
+        let workers: Vec<_> = (0..4)
+            .map(|i| {
+                let shared = shared.clone();
+                let (src, dst) = (src.clone(), dst.clone());
+                thread::spawn(move || {
+                    if i % 2 == 0 {
+                        assert_eq!(shared.apply(&src).unwrap(), dst);
+                    } else {
+                        assert_eq!(shared.revert(&dst).unwrap(), src);
+                    }
+                    let mut read = Vec::new();
+                    shared
+                        .reader(PatchDirection::Apply, Cursor::new(&src))
+                        .read_to_end(&mut read)
+                        .unwrap();
+                    assert_eq!(read, dst);
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+}
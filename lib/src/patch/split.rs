@@ -0,0 +1,143 @@
+use super::{Block, BlockData, Patch, SplitError};
+use crate::checksum::Checksum;
+use crate::varint;
+
+// Magic and the three checksums.
+const FIXED_OVERHEAD: usize = 4 + 12;
+
+impl Patch {
+    /// Split the patch in a sequence of patches whose serialized sizes are at most `max_size`
+    /// bytes, e.g. for distribution channels with attachment size limits. Applying them in order
+    /// gives the same output as applying the patch, and reverting them in reverse order gives the
+    /// same input back.
+    ///
+    /// Intermediate files are as large as the larger of the source and destination files. Their
+    /// checksums are derived from the patch alone since CRC32 is linear, the source file isn't
+    /// needed. Blocks too large for a single piece are split across several, and blocks past the
+    /// end of both files are dropped since they change nothing.
+    ///
+    /// Returns the patch itself if it already fits.
+    pub fn split(&self, max_size: usize) -> Result<Vec<Patch>, SplitError> {
+        if self.serialized_len() <= max_size {
+            return Ok(vec![self.clone()]);
+        }
+        let size = std::cmp::max(self.src_size, self.dst_size);
+        // Sizes are at most `size`, this is an upper bound for every piece.
+        let header = FIXED_OVERHEAD + 2 * varint::encoded_len(size);
+        // A piece must fit at least one changed byte and its terminator.
+        let min_size = header + varint::encoded_len(size) + 2;
+        if max_size < min_size {
+            return Err(SplitError { max_size, min_size });
+        }
+        let budget = max_size - header;
+
+        // Pieces of absolute block positions and data.
+        let mut pieces: Vec<Vec<(usize, BlockData)>> = Vec::new();
+        let mut piece = Vec::new();
+        let mut piece_end = 0;
+        let mut piece_len = 0;
+        let mut block_start = 0usize;
+        for block in &self.blocks {
+            let mut start = block_start.saturating_add(block.offset);
+            block_start = start.saturating_add(block.xor_data.len());
+            if start >= size {
+                break;
+            }
+            let mut data = &block.xor_data[..];
+            loop {
+                let len = varint::encoded_len(start - piece_end) + data.len();
+                if piece_len + len <= budget {
+                    piece.push((start, data.into()));
+                    piece_end = start + data.len();
+                    piece_len += len;
+                    break;
+                }
+                if !piece.is_empty() {
+                    pieces.push(std::mem::take(&mut piece));
+                    piece_end = 0;
+                    piece_len = 0;
+                    continue;
+                }
+                // Too large for a piece of its own. The terminator leaves the next byte unchanged
+                // in this piece, the next one changes it.
+                let fit = budget - varint::encoded_len(start) - 1;
+                let mut head = BlockData::from(&data[..fit]);
+                head.push(0);
+                pieces.push(vec![(start, head)]);
+                start += fit;
+                data = &data[fit..];
+                // The rest of the block is past the end of both files.
+                if start >= size {
+                    break;
+                }
+            }
+        }
+        if !piece.is_empty() {
+            pieces.push(piece);
+        }
+
+        // Checksum of the changes made so far, over `size` bytes, and how far they go.
+        let mut changes = Checksum::from_bytes(&[]);
+        let mut changes_end = 0;
+        let zeros = Checksum::from_bytes(&[]).extend_with_zeros(size);
+        let padded_src = self.src_checksum.extend_with_zeros(size - self.src_size);
+
+        let mut patches = Vec::with_capacity(pieces.len());
+        let mut src_checksum = self.src_checksum;
+        let count = pieces.len();
+        for (i, piece) in pieces.into_iter().enumerate() {
+            let mut blocks = Vec::with_capacity(piece.len());
+            let mut prev_end = 0;
+            for (start, xor_data) in piece {
+                // Without the terminator, which changes nothing and may overlap the continuation
+                // of a split block.
+                let changed = match xor_data.split_last() {
+                    Some((0, changed)) => changed,
+                    _ => &xor_data[..],
+                };
+                let end = std::cmp::min(size, start + changed.len());
+                changes = changes
+                    .extend_with_zeros(start - changes_end)
+                    .extend(&changed[..end - start]);
+                changes_end = end;
+                blocks.push(Block {
+                    offset: start - prev_end,
+                    xor_data: xor_data.clone(),
+                });
+                prev_end = start + xor_data.len();
+            }
+            let last = i + 1 == count;
+            let (dst_size, dst_checksum) = if last {
+                (self.dst_size, self.dst_checksum)
+            } else {
+                // CRC32 is affine: crc(a ^ b) = crc(a) ^ crc(b) ^ crc(zeros) for equal lengths.
+                let changes = changes.extend_with_zeros(size - changes_end);
+                (size, Checksum(padded_src.0 ^ changes.0 ^ zeros.0))
+            };
+            patches.push(Patch {
+                blocks,
+                src_size: if i == 0 { self.src_size } else { size },
+                src_checksum,
+                dst_size,
+                dst_checksum,
+                metadata_dirty: false,
+                offsets: Default::default(),
+            });
+            src_checksum = dst_checksum;
+        }
+        Ok(patches)
+    }
+
+    // Length of `serialize`'s output.
+    fn serialized_len(&self) -> usize {
+        let blocks: usize = self
+            .blocks
+            .iter()
+            .map(|b| varint::encoded_len(b.offset) + b.xor_data.len())
+            .sum();
+        FIXED_OVERHEAD
+            + varint::encoded_len(self.src_size)
+            + varint::encoded_len(self.dst_size)
+            + blocks
+    }
+}
@@ -1,5 +1,6 @@
 use super::*;
 
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::matches;
 
 use proptest::array;
@@ -44,6 +45,12 @@ proptest! {
         prop_assert_eq!(patch.blocks, Vec::new());
     }
 
+    #[test]
+    fn test_is_noop(src in files(), dst in files()) {
+        prop_assert_eq!(Patch::diff(&src, &dst).is_noop(), src == dst);
+        prop_assert!(Patch::diff(&src, &src).is_noop());
+    }
+
     #[test]
     fn test_diff_apply_results_in_dst(src in files(), dst in files()) {
         let patch = Patch::diff(&src, &dst);
@@ -60,6 +67,337 @@ proptest! {
         prop_assert_eq!(applied, src);
     }
 
+    #[test]
+    fn test_patch_to_writer_matches_patch(
+        patch in patches(),
+        input in files(),
+        revert in any::<bool>(),
+    ) {
+        let direction = if revert { PatchDirection::Revert } else { PatchDirection::Apply };
+        let mut streamed = Vec::new();
+        let stream_result = patch.patch_to_writer(direction, &input, &mut streamed);
+        match patch.patch(direction, &input) {
+            Ok(output) => {
+                stream_result.prop_unwrap()?;
+                prop_assert_eq!(streamed, output);
+            }
+            Err(errs) => {
+                prop_assert_eq!(&streamed, &errs.output);
+                match stream_result.prop_unwrap_err()? {
+                    UpsWriteError::Patch(stream_errs) => {
+                        prop_assert_eq!(stream_errs.into_iter().count(), errs.into_iter().count());
+                    }
+                    e => prop_assert!(false, "Expected UpsWriteError::Patch, got {}", e),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_engines_agree(
+        patch in patches(),
+        input in files(),
+        revert in any::<bool>(),
+        chunk_size in 1..16usize,
+    ) {
+        let direction = if revert { PatchDirection::Revert } else { PatchDirection::Apply };
+        prop_assert_eq!(patch.compare_engines(direction, &input, chunk_size), Vec::new());
+    }
+
+    #[test]
+    fn test_engines_agree_on_diffs(
+        src in files(),
+        dst in files(),
+        corrupt in any::<Option<(prop::sample::Index, u8)>>(),
+        chunk_size in 1..16usize,
+    ) {
+        // Diffs apply cleanly unless the input is corrupted, which random patches rarely do.
+        let patch = Patch::diff(&src, &dst);
+        let mut input = src.clone();
+        if let Some((index, byte)) = corrupt.filter(|_| !input.is_empty()) {
+            let index = index.index(input.len());
+            input[index] ^= byte;
+        }
+        prop_assert_eq!(patch.compare_engines(PatchDirection::Apply, &input, chunk_size), Vec::new());
+        prop_assert_eq!(patch.compare_engines(PatchDirection::Revert, &dst, chunk_size), Vec::new());
+    }
+
+    #[test]
+    fn test_write_vectored_matches_serialize(patch in patches(), max_write in 1..16usize) {
+        // Writer accepting at most `max_write` bytes per call, to exercise partial writes
+        struct ShortWriter(Vec<u8>, usize);
+        impl Write for ShortWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let n = std::cmp::min(buf.len(), self.1);
+                self.0.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut vectored = Vec::new();
+        patch.write_vectored(&mut vectored).prop_unwrap()?;
+        prop_assert_eq!(&vectored, &patch.serialize());
+        let mut short = ShortWriter(Vec::new(), max_write);
+        patch.write_vectored(&mut short).prop_unwrap()?;
+        prop_assert_eq!(short.0, vectored);
+    }
+
+    #[test]
+    fn test_patch_in_place_matches_patch(
+        patch in patches(),
+        input in files(),
+        revert in any::<bool>(),
+    ) {
+        let direction = if revert { PatchDirection::Revert } else { PatchDirection::Apply };
+        let mut buf = input.clone();
+        let in_place_result = patch.patch_in_place(direction, &mut buf);
+        match patch.patch(direction, &input) {
+            Ok(output) => {
+                in_place_result.prop_unwrap()?;
+                prop_assert_eq!(buf, output);
+            }
+            Err(errs) => {
+                let in_place_errs = in_place_result.prop_unwrap_err()?;
+                prop_assert_eq!(&buf, &errs.output);
+                prop_assert_eq!(in_place_errs.into_iter().count(), errs.into_iter().count());
+            }
+        }
+    }
+
+    #[test]
+    fn test_normalize_preserves_output(
+        blocks in vec(patch_blocks(8), 0..8),
+        sizes in (file_sizes(), file_sizes()),
+        input in files(),
+        revert in any::<bool>(),
+    ) {
+        let patch = Patch {
+            blocks,
+            src_size: sizes.0,
+            src_checksum: Checksum(0),
+            dst_size: sizes.1,
+            dst_checksum: Checksum(0),
+            metadata_dirty: false,
+            offsets: Default::default(),
+        };
+        let direction = if revert { PatchDirection::Revert } else { PatchDirection::Apply };
+        let normalized = patch.normalize();
+        let output = patch.patch(direction, &input).unwrap_or_else(|e| e.output);
+        let normalized_output = normalized.patch(direction, &input).unwrap_or_else(|e| e.output);
+        prop_assert_eq!(output, normalized_output);
+        prop_assert_eq!(normalized.normalize(), normalized);
+    }
+
+    #[test]
+    fn test_with_src_size(
+        src in files(),
+        dst in files(),
+        padding in 0..64usize,
+        pad_byte in any::<u8>(),
+    ) {
+        let mut padded = src.clone();
+        padded.resize(src.len() + padding, pad_byte);
+
+        // Padding the base.
+        let patch = Patch::diff(&src, &dst).with_src_size(padded.len(), pad_byte);
+        prop_assert_eq!(patch.apply(&padded).prop_unwrap()?, dst.clone());
+        prop_assert_eq!(patch.revert(&dst).prop_unwrap()?, padded.clone());
+        prop_assert_eq!(patch.normalize(), patch.clone());
+
+        // Trimming the base.
+        let patch = Patch::diff(&padded, &dst).with_src_size(src.len(), pad_byte);
+        prop_assert_eq!(patch.apply(&src).prop_unwrap()?, dst.clone());
+        prop_assert_eq!(patch.revert(&dst).prop_unwrap()?, src.clone());
+    }
+
+    #[test]
+    fn test_patch_sparse_matches_patch(
+        src in files(),
+        dst in files(),
+        revert in any::<bool>(),
+    ) {
+        let patch = Patch::diff(&src, &dst);
+        let (direction, input, expected) = if revert {
+            (PatchDirection::Revert, &dst, &src)
+        } else {
+            (PatchDirection::Apply, &src, &dst)
+        };
+        let mut output = input.clone();
+        output.resize(expected.len(), 0);
+        for (offset, bytes) in patch.patch_sparse(direction, input) {
+            output[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+        prop_assert_eq!(&output, expected);
+    }
+
+    #[test]
+    fn test_appended_data(src in files(), dst in files()) {
+        let patch = Patch::diff(&src, &dst);
+        let expected = dst.get(src.len()..).filter(|tail| !tail.is_empty());
+        let appended = patch.appended_data();
+        prop_assert_eq!(appended.as_deref(), expected);
+    }
+
+    #[test]
+    fn test_diff_is_normalized(src in files(), dst in files()) {
+        let patch = Patch::diff(&src, &dst);
+        prop_assert_eq!(patch.normalize(), patch);
+    }
+
+    #[test]
+    fn test_patched_reader_matches_patch(
+        src in files(),
+        dst in files(),
+        chunk_size in 1..8usize,
+        seek_to in 0..40u64,
+    ) {
+        let patch = Patch::diff(&src, &dst);
+        let mut reader = PatchedReader::new(&patch, Cursor::new(&src));
+        let mut output = Vec::new();
+        let mut chunk = vec![0; chunk_size];
+        loop {
+            let n = reader.read(&mut chunk).prop_unwrap()?;
+            if n == 0 {
+                break;
+            }
+            output.extend_from_slice(&chunk[..n]);
+        }
+        prop_assert_eq!(&output, &dst);
+
+        reader.seek(SeekFrom::Start(seek_to)).prop_unwrap()?;
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).prop_unwrap()?;
+        prop_assert_eq!(&tail[..], dst.get(seek_to as usize..).unwrap_or(&[]));
+
+        let mut reverted = Vec::new();
+        PatchedReader::with_direction(&patch, PatchDirection::Revert, Cursor::new(&dst))
+            .read_to_end(&mut reverted)
+            .prop_unwrap()?;
+        prop_assert_eq!(reverted, src);
+    }
+
+    #[test]
+    fn test_patch_chunks_matches_patch(
+        patch in patches(),
+        input in files(),
+        chunk_size in 1..8usize,
+        revert in any::<bool>(),
+    ) {
+        let direction = if revert { PatchDirection::Revert } else { PatchDirection::Apply };
+        let mut output = Vec::new();
+        let result = patch.patch_chunks(direction, input.chunks(chunk_size), |chunk| {
+            output.extend_from_slice(chunk);
+        });
+        match patch.patch(direction, &input) {
+            Ok(expected) => {
+                result.prop_unwrap()?;
+                prop_assert_eq!(output, expected);
+            }
+            Err(errs) => {
+                let chunk_errs = result.prop_unwrap_err()?;
+                prop_assert_eq!(&output, &errs.output);
+                prop_assert!(chunk_errs.output.is_empty());
+                prop_assert_eq!(chunk_errs.into_iter().count(), errs.into_iter().count());
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_matches_patch(src in files(), dst in files(), max_size in 16..64usize) {
+        let patch = Patch::diff(&src, &dst);
+        let pieces = match patch.split(max_size) {
+            Ok(pieces) => pieces,
+            Err(err) => {
+                prop_assert!(max_size < err.min_size);
+                return Ok(());
+            }
+        };
+        let mut output = src.clone();
+        for piece in &pieces {
+            prop_assert!(piece.serialize().len() <= max_size || pieces.len() == 1);
+            output = piece.apply(&output).prop_unwrap()?;
+        }
+        prop_assert_eq!(&output, &dst);
+        for piece in pieces.iter().rev() {
+            output = piece.revert(&output).prop_unwrap()?;
+        }
+        prop_assert_eq!(output, src);
+    }
+
+    #[test]
+    fn test_repair_terminators_preserves_changes(
+        blocks in vec((0..4usize, vec(0..3u8, 0..6)), 0..8),
+    ) {
+        let patch = Patch {
+            blocks: blocks
+                .into_iter()
+                .map(|(offset, xor_data)| Block { offset, xor_data: xor_data.into() })
+                .collect(),
+            src_size: 32,
+            src_checksum: Checksum(0),
+            dst_size: 32,
+            dst_checksum: Checksum(0),
+            metadata_dirty: false,
+            offsets: Default::default(),
+        };
+        let repaired = patch.repair_terminators();
+        prop_assert_eq!(repaired.normalize(), patch.normalize());
+        for block in &repaired.blocks {
+            prop_assert_eq!(block.xor_data.last(), Some(&0));
+            prop_assert_ne!(&block.xor_data[..], &[0][..]);
+        }
+    }
+
+    #[test]
+    fn test_iter_blocks_mut_keeps_positions(
+        src in files(),
+        dst in files(),
+        edits in vec((0..3u8, 1..=255u8), 0..64),
+    ) {
+        let mut patch = Patch::diff(&src, &dst);
+        let mut expected = Vec::new();
+        let mut blocks = patch.iter_blocks_mut();
+        let mut edits = edits.into_iter();
+        while let Some(mut block) = blocks.next_block() {
+            let start = block.start();
+            match edits.next() {
+                Some((0, _)) => block.delete(),
+                Some((1, byte)) => {
+                    // Shorter data always fits.
+                    block.set_xor_data(&[byte]).prop_unwrap()?;
+                    expected.push((start, vec![byte, 0]));
+                }
+                _ => expected.push((start, block.block().xor_data().to_vec())),
+            }
+        }
+        let offsets = patch.block_offsets();
+        prop_assert_eq!(offsets.len(), expected.len());
+        for (i, (start, xor_data)) in expected.iter().enumerate() {
+            prop_assert_eq!(offsets.start(i), Some(*start));
+            prop_assert_eq!(patch.blocks[i].xor_data(), &xor_data[..]);
+        }
+
+        let edited = patch.clone();
+        patch.recompute_metadata(Some(&src), None);
+        let output = patch.apply(&src).prop_unwrap()?;
+        let mut from_files = edited.clone();
+        from_files.recompute_metadata(Some(&src), Some(&output));
+        prop_assert_eq!(&from_files, &patch);
+        // Blocks past the destination only matter when reverting, edits there change the source.
+        if src.len() <= dst.len() {
+            prop_assert_eq!(patch.revert(&output).prop_unwrap()?, src.clone());
+            for (src_file, dst_file) in [(None, Some(&output[..])), (None, None)] {
+                let mut recomputed = edited.clone();
+                recomputed.recompute_metadata(src_file, dst_file);
+                prop_assert_eq!(&recomputed, &patch);
+            }
+        }
+        prop_assert_eq!(Patch::parse(&patch.serialize()).prop_unwrap()?, patch);
+    }
+
     #[test]
     fn test_diff_blocks_xor_data_should_end_in_0(src in files(), dst in files()) {
         let patch = Patch::diff(&src, &dst);
@@ -76,7 +414,7 @@ proptest! {
         let dst: Vec<_> = blocks.iter().flatten().copied().collect();
         let patch = Patch::diff(&[], &dst);
         let expected_blocks: Vec<_> = blocks.into_iter().map(|xor_data| {
-            Block { offset: 0, xor_data }
+            Block { offset: 0, xor_data: xor_data.into() }
         }).collect();
         prop_assert_eq!(patch.blocks, expected_blocks);
     }
@@ -100,6 +438,678 @@ proptest! {
     }
 }
 
+#[test]
+fn test_patch_to_writer_sparse_output() {
+    // Extending a file with zeroes and a small tail shouldn't need the whole output in memory.
+    const DST_SIZE: usize = 64 * 1024 * 1024;
+    let src = b"source".to_vec();
+    let mut dst_tail = vec![0; DST_SIZE - src.len()];
+    dst_tail[DST_SIZE - src.len() - 1] = 1;
+    let dst: Vec<u8> = src.iter().copied().chain(dst_tail).collect();
+    let patch = Patch::diff(&src, &dst);
+    drop(dst);
+
+    struct CountingWriter(usize);
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut writer = CountingWriter(0);
+    patch
+        .patch_to_writer(PatchDirection::Apply, &src, &mut writer)
+        .unwrap();
+    assert_eq!(writer.0, DST_SIZE);
+}
+
+#[test]
+fn test_patch_large_blocks() {
+    // Large enough for the parallel path when the `rayon` feature is enabled
+    let src: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let dst: Vec<u8> = src
+        .iter()
+        .enumerate()
+        .map(|(i, b)| if (i / 4096) % 3 == 0 { b ^ 0x5a } else { *b })
+        .collect();
+    let patch = Patch::diff(&src, &dst);
+    assert_eq!(patch.apply(&src).unwrap(), dst);
+    assert_eq!(patch.revert(&dst).unwrap(), src);
+}
+
+#[test]
+fn test_split() {
+    let src = vec![0u8; 1000];
+    let mut dst = src.clone();
+    dst[10] = 1;
+    dst[100..900].iter_mut().for_each(|b| *b = 2);
+    dst.extend_from_slice(b"appended");
+    let patch = Patch::diff(&src, &dst);
+    assert_eq!(patch.split(usize::MAX).unwrap(), vec![patch.clone()]);
+
+    let pieces = patch.split(100).unwrap();
+    assert!(pieces.len() >= 9, "{} pieces", pieces.len());
+    assert_eq!(pieces[0].src_size, src.len());
+    assert_eq!(pieces[pieces.len() - 1].dst_size, dst.len());
+    let mut output = src.clone();
+    for piece in &pieces {
+        assert!(piece.serialize().len() <= 100);
+        output = piece.apply(&output).unwrap();
+    }
+    assert_eq!(output, dst);
+    for piece in pieces.iter().rev() {
+        output = piece.revert(&output).unwrap();
+    }
+    assert_eq!(output, src);
+
+    assert_eq!(
+        patch.split(20),
+        Err(SplitError {
+            max_size: 20,
+            min_size: 24,
+        }),
+    );
+}
+
+#[test]
+fn test_split_block_past_end() {
+    // A single block running past the end of both files, split so that a piece would start after
+    // the end.
+    let raw = [
+        0x55, 0x50, 0x53, 0x31, 0x88, 0x8d, 0x85, 0x89, 0x84, 0x87, 0x07, 0x86, 0x0c, 0x0f, 0x81,
+        0x0e, 0x0d, 0x8a, 0x8b, 0x14, 0x17, 0x15, 0x14, 0x18, 0x15, 0x08, 0x1a, 0x11, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0xce, 0xf5, 0x00, 0x4e, 0x97, 0x63, 0x1c,
+    ];
+    let patch = Patch::parse(&raw).unwrap();
+    let src = vec![0u8; patch.src_size];
+    let expected = patch
+        .patch(PatchDirection::Apply, &src)
+        .unwrap_or_else(|e| e.output);
+
+    let pieces = patch.split(40).unwrap();
+    let mut output = src;
+    for piece in &pieces {
+        assert!(piece.serialize().len() <= 40);
+        output = piece.apply(&output).unwrap_or_else(|e| e.output);
+    }
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_empty_files() {
+    let cases: &[(&[u8], &[u8])] = &[
+        (b"", b""),
+        (b"", b"abc"),
+        (b"", b"\0\0a\0"),
+        (b"abc", b""),
+        (b"\0\0a\0", b""),
+        (b"\0\0", b"\0\0"),
+    ];
+    for &(src, dst) in cases {
+        let patch = Patch::diff(src, dst);
+        assert_eq!(patch.src_size, src.len());
+        assert_eq!(patch.dst_size, dst.len());
+        assert_eq!(patch.normalize(), patch, "{:?} -> {:?}", src, dst);
+        let parsed = Patch::parse(&patch.serialize()).unwrap();
+        assert_eq!(parsed, patch);
+
+        assert_eq!(patch.apply(src).unwrap(), dst);
+        assert_eq!(patch.revert(dst).unwrap(), src);
+        let mut buf = src.to_vec();
+        patch.apply_in_place(&mut buf).unwrap();
+        assert_eq!(buf, dst);
+        let mut written = Vec::new();
+        patch
+            .patch_to_writer(PatchDirection::Apply, src, &mut written)
+            .unwrap();
+        assert_eq!(written, dst);
+        let mut read = Vec::new();
+        PatchedReader::new(&patch, Cursor::new(src))
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, dst);
+        let mut sparse = src.to_vec();
+        sparse.resize(dst.len(), 0);
+        for (offset, data) in patch.apply_sparse(src) {
+            sparse[offset..offset + data.len()].copy_from_slice(&data);
+        }
+        assert_eq!(sparse, dst);
+        let preflight = patch.preflight(src.len(), Checksum::from_bytes(src));
+        assert_eq!(preflight.direction_hint, Some(PatchDirection::Apply));
+    }
+
+    // An empty input isn't mistaken for the source of a non-empty patch.
+    let patch = Patch::diff(b"abc", b"abd");
+    assert!(patch.apply(b"").is_err());
+    assert!(Patch::diff(b"", b"abc").revert(b"").is_err());
+}
+
+#[test]
+fn test_is_noop_zero_blocks() {
+    let mut patch = Patch::diff(b"abc", b"abc");
+    patch.blocks.push(Block {
+        offset: 1,
+        xor_data: vec![0, 0].into(),
+    });
+    assert!(patch.is_noop());
+    patch.blocks[0].xor_data = vec![1, 0].into();
+    assert!(!patch.is_noop());
+
+    // Same contents up to the source size, but the destination is truncated.
+    let patch = Patch::diff(b"ab\0", b"ab");
+    assert!(patch.blocks.is_empty());
+    assert!(!patch.is_noop());
+}
+
+#[test]
+fn test_parse_with_warnings() {
+    let patch = Patch {
+        blocks: vec![
+            Block {
+                offset: 1,
+                xor_data: vec![1, 0].into(),
+            },
+            Block {
+                offset: 10,
+                xor_data: vec![1, 0].into(),
+            },
+        ],
+        src_size: 4,
+        src_checksum: Checksum(0),
+        dst_size: 4,
+        dst_checksum: Checksum(0),
+        metadata_dirty: false,
+        offsets: Default::default(),
+    };
+    let (parsed, warnings) = Patch::parse_with_warnings(&patch.serialize()).unwrap();
+    assert_eq!(parsed, patch);
+    assert_eq!(
+        warnings,
+        vec![ParseWarning::BlockPastEnd {
+            index: 1,
+            offset: 13
+        }],
+    );
+    assert_eq!(
+        warnings[0].to_string(),
+        "block 1 starts at offset 13, past the end of the files"
+    );
+
+    // Terminator anomalies from third-party generators.
+    let mut raw = b"UPS1\x84\x84".to_vec();
+    raw.extend_from_slice(&[0x81, 0]); // Empty block at 1.
+    raw.extend_from_slice(&[0x80, 5, 6]); // Unterminated block at 2.
+    raw.extend_from_slice(&[0; 8]);
+    let checksum = Checksum::from_bytes(&raw);
+    raw.extend_from_slice(&checksum.0.to_le_bytes());
+    let (parsed, warnings) = Patch::parse_with_warnings(&raw).unwrap();
+    assert_eq!(
+        warnings,
+        vec![
+            ParseWarning::EmptyBlock {
+                index: 0,
+                offset: 1
+            },
+            ParseWarning::UnterminatedBlock {
+                index: 1,
+                offset: 2
+            },
+        ],
+    );
+    let repaired = parsed.repair_terminators();
+    assert_eq!(
+        repaired.blocks,
+        vec![Block {
+            offset: 2,
+            xor_data: vec![5, 6, 0].into(),
+        }],
+    );
+    assert_eq!(
+        Patch::parse_with_warnings(&repaired.serialize()).unwrap().1,
+        vec![],
+    );
+
+    let mut raw = b"UPS1\x84\x84\x05".to_vec();
+    raw.extend_from_slice(&[0; 8]);
+    let checksum = Checksum::from_bytes(&raw);
+    raw.extend_from_slice(&checksum.0.to_le_bytes());
+    assert_eq!(
+        Patch::parse_with_warnings(&raw).unwrap().1,
+        vec![ParseWarning::TrailingBytes { len: 1 }],
+    );
+
+    // Shrinking patches keep the source tail past the end of the destination.
+    let shrink = Patch::diff(b"abcdef", b"ab").serialize();
+    assert_eq!(Patch::parse_with_warnings(&shrink).unwrap().1, vec![]);
+}
+
+#[test]
+fn test_parse_with_progress() {
+    // 3 MiB of changes split in 64 KiB blocks.
+    let src = vec![0; 3 << 20];
+    let mut dst = vec![1; 3 << 20];
+    for i in (0..dst.len()).step_by(1 << 16) {
+        dst[i] = 0;
+    }
+    let raw = Patch::diff(&src, &dst).serialize();
+    let mut progress = Vec::new();
+    let (parsed, _) = Patch::parse_with_progress(&raw, |p| progress.push(p)).unwrap();
+
+    assert_eq!(progress.len(), 3);
+    for (i, p) in progress[..2].iter().enumerate() {
+        assert!(p.bytes_parsed >= (i + 1) * PARSE_PROGRESS_INTERVAL);
+        assert!(p.bytes_parsed < (i + 1) * PARSE_PROGRESS_INTERVAL + (1 << 16) + 8);
+        assert!(p.blocks > 0 && p.blocks < parsed.blocks.len());
+    }
+    assert_eq!(
+        progress[2],
+        ParseProgress {
+            bytes_parsed: raw.len(),
+            total_bytes: raw.len(),
+            blocks: parsed.blocks.len(),
+        }
+    );
+}
+
+#[test]
+fn test_block_accessors() {
+    let patch = Patch::diff(b"abcdefgh", b"aXcdYZgh");
+    let blocks: Vec<_> = patch
+        .blocks
+        .iter()
+        .map(|b| (b.offset(), b.xor_data()))
+        .collect();
+    assert_eq!(
+        blocks,
+        vec![
+            (1, &[b'b' ^ b'X', 0][..]),
+            (1, &[b'e' ^ b'Y', b'f' ^ b'Z', 0][..])
+        ],
+    );
+}
+
+#[test]
+fn test_iter_blocks_mut() {
+    let src = b"abcdefgh";
+    let mut patch = Patch::diff(src, b"aBcdefGh");
+    let mut blocks = patch.iter_blocks_mut();
+    // The data and its terminator must end before the block at 1.
+    assert_eq!(
+        blocks.insert(0, &[1]),
+        Err(BlockEditError::Overlap {
+            start: 0,
+            len: 2,
+            free: 0..1,
+        }),
+    );
+    assert_eq!(blocks.insert(0, &[]), Err(BlockEditError::InvalidData));
+    assert_eq!(
+        blocks.insert(0, &[1, 0, 1]),
+        Err(BlockEditError::InvalidData)
+    );
+
+    assert_eq!(blocks.next_block().unwrap().start(), 1);
+    blocks.insert(3, &[1, 1, 0]).unwrap();
+    let mut block = blocks.next_block().unwrap();
+    assert_eq!(block.start(), 6);
+    // Nothing comes after the last block, data past the end of the files is allowed.
+    block.set_xor_data(&[1, 1, 1]).unwrap();
+    assert!(blocks.next_block().is_none());
+    assert_eq!(
+        patch.block_offsets().changed_ranges().collect::<Vec<_>>(),
+        vec![1..2, 3..5, 6..9],
+    );
+
+    assert!(patch.is_metadata_dirty());
+    let errs = patch.apply(src).unwrap_err();
+    assert!(errs
+        .iter()
+        .any(|e| matches!(e, UpsPatchError::StaleMetadata)));
+    patch.recompute_metadata(Some(src), None);
+    assert!(!patch.is_metadata_dirty());
+    assert_eq!(patch.apply(src).unwrap(), b"aBcedffi");
+}
+
+#[test]
+#[should_panic(expected = "dirty metadata")]
+fn test_iter_blocks_mut_dirty_serialize() {
+    let mut patch = Patch::diff(b"abcd", b"aBcd");
+    {
+        let mut blocks = patch.iter_blocks_mut();
+        blocks.next_block().unwrap().delete();
+    }
+    // The patch stays dirty after the edits are done.
+    assert!(patch.is_metadata_dirty());
+    assert!(patch.write_vectored(&mut Vec::new()).is_err());
+    patch.serialize();
+}
+
+#[test]
+fn test_iter_blocks_mut_identity() {
+    let src = b"abcdefgh";
+    let dst = b"aBcdefGh";
+    let mut patch = Patch::diff(src, b"aBcdefgh");
+    let mut blocks = patch.iter_blocks_mut();
+    blocks.next_block().unwrap();
+    blocks.insert(6, &[b'g' ^ b'G']).unwrap();
+    assert!(patch.is_metadata_dirty());
+    patch.recompute_metadata(Some(src), None);
+
+    // Edited patches compare and hash like freshly built ones.
+    let fresh = Patch::diff(src, dst);
+    assert_eq!(patch, fresh);
+    assert_eq!(patch.fingerprint(), fresh.fingerprint());
+    assert_eq!(format!("{:?}", patch), format!("{:?}", fresh));
+}
+
+#[test]
+fn test_iter_blocks_mut_delete() {
+    let src = b"abcdefgh";
+    let mut patch = Patch::diff(src, b"aXcdYZgh");
+    let mut blocks = patch.iter_blocks_mut();
+    blocks.next_block().unwrap().delete();
+    // The freed bytes can be reused before visiting the next block.
+    blocks.insert(0, &[1, 1]).unwrap();
+    let block = blocks.next_block().unwrap();
+    assert_eq!(block.start(), 4);
+    assert_eq!(block.block().offset(), 1);
+    patch.recompute_metadata(Some(src), None);
+    assert_eq!(patch.apply(src).unwrap(), b"`ccdYZgh");
+}
+
+#[test]
+fn test_recompute_metadata() {
+    let src = b"abcdefgh";
+    let dst = b"abXd";
+    let patch = Patch::diff(src, dst);
+    // The blocks hold the source bytes past the end of the destination.
+    for (src_file, dst_file) in [
+        (Some(&src[..]), None),
+        (None, Some(&dst[..])),
+        (None, None),
+        (Some(&src[..]), Some(&dst[..])),
+    ] {
+        let mut recomputed = patch.clone();
+        recomputed.src_checksum = Checksum::from_bytes(src);
+        recomputed.dst_checksum = Checksum(0);
+        if dst_file.is_some() {
+            recomputed.src_checksum = Checksum(0);
+        }
+        recomputed.recompute_metadata(src_file, dst_file);
+        assert_eq!(recomputed, patch, "{:?} {:?}", src_file, dst_file);
+    }
+
+    // Retarget to a source with a different tail, the blocks still change the same bytes.
+    let mut retargeted = patch.clone();
+    retargeted.recompute_metadata(Some(b"abcd"), None);
+    assert_eq!(retargeted.apply(b"abcd").unwrap(), dst);
+}
+
+#[test]
+fn test_estimated_apply_cost() {
+    let patch = Patch::diff(b"abcdefgh", b"aXcdYZghij");
+    let cost = patch.estimated_apply_cost();
+    let xor_bytes: usize = patch.blocks.iter().map(|b| b.xor_data.len()).sum();
+    assert_eq!(
+        cost,
+        ApplyCost {
+            output_size: 10,
+            xor_bytes,
+            blocks: patch.blocks.len(),
+        },
+    );
+    assert_eq!(cost.weight(), 10 + xor_bytes);
+
+    let empty = Patch::diff(b"abcd", b"abcd").estimated_apply_cost();
+    assert_eq!(empty.xor_bytes, 0);
+    assert_eq!(empty.blocks, 0);
+    let total: ApplyCost = vec![cost, empty].into_iter().sum();
+    assert_eq!(total.output_size, 14);
+    assert_eq!(total, cost + empty);
+}
+
+#[test]
+fn test_requirements() {
+    let patch = Patch {
+        blocks: Vec::new(),
+        src_size: 16 * 1024 * 1024,
+        src_checksum: Checksum(0xDEADBEEF),
+        dst_size: 32 * 1024 * 1024,
+        dst_checksum: Checksum(0),
+        metadata_dirty: false,
+        offsets: Default::default(),
+    };
+    let requirements = patch.requirements();
+    assert_eq!(requirements.src.size, patch.src_size);
+    assert_eq!(requirements.dst.crc32, patch.dst_checksum);
+    assert_eq!(
+        requirements.src.to_string(),
+        format!("16 MiB ROM, CRC32 {}", patch.src_checksum),
+    );
+}
+
+#[test]
+fn test_preflight() {
+    let src = b"source file";
+    let dst = b"longer destination file";
+    let patch = Patch::diff(src, dst);
+
+    let apply = patch.preflight(src.len(), Checksum::from_bytes(src));
+    assert_eq!(apply.direction_hint, Some(PatchDirection::Apply));
+    assert_eq!(apply.will_resize_to, Some(dst.len()));
+    assert!(apply.warnings.is_empty());
+
+    let revert = patch.preflight(dst.len(), Checksum::from_bytes(dst));
+    assert_eq!(revert.direction_hint, Some(PatchDirection::Revert));
+    assert_eq!(revert.will_resize_to, Some(src.len()));
+    assert!(revert.warnings.is_empty());
+
+    let other = patch.preflight(src.len(), Checksum::from_bytes(b"other file!"));
+    assert_eq!(other.direction_hint, None);
+    assert!(matches!(
+        other.warnings.as_slice(),
+        [UpsPatchError::SourceMetadataMismatch(
+            MetadataMismatch::Checksum { .. }
+        )],
+    ));
+}
+
+#[test]
+fn test_error_kinds() {
+    let src = b"source file";
+    let dst = b"longer destination file";
+    let patch = Patch::diff(src, dst);
+
+    let errs = patch.apply(b"other file!").unwrap_err();
+    assert_eq!(errs.kind(), "source_checksum_mismatch");
+    let mut kinds: Vec<_> = errs.iter().map(UpsPatchError::kind).collect();
+    kinds.sort_unstable();
+    assert_eq!(
+        kinds,
+        ["dest_checksum_mismatch", "source_checksum_mismatch"]
+    );
+
+    let errs = patch.apply(b"short").unwrap_err();
+    assert_eq!(errs.kind(), "source_size_mismatch");
+
+    let err = Patch::parse(b"not a patch").unwrap_err();
+    assert_eq!(err.kind(), "format_mismatch");
+}
+
+// Reader whose contents change to `after` once it's read to the end and rewound, like a file
+// written to by another program while being patched.
+struct ChangingReader {
+    cursor: Cursor<Vec<u8>>,
+    after: Option<Vec<u8>>,
+}
+
+impl Read for ChangingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.cursor.read(buf)
+    }
+}
+
+impl Seek for ChangingReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if self.cursor.position() > 0 {
+            if let Some(after) = self.after.take() {
+                *self.cursor.get_mut() = after;
+            }
+        }
+        self.cursor.seek(pos)
+    }
+}
+
+#[test]
+fn test_patch_verified() {
+    let src = b"abcdefgh".to_vec();
+    let patch = Patch::diff(&src, b"aXcdYZgh!");
+    let verified = |after: Option<&[u8]>| {
+        let mut input = ChangingReader {
+            cursor: Cursor::new(src.clone()),
+            after: after.map(<[u8]>::to_vec),
+        };
+        patch
+            .patch_verified(PatchDirection::Apply, &mut input)
+            .unwrap()
+    };
+    assert_eq!(verified(None).unwrap(), b"aXcdYZgh!");
+    assert_eq!(verified(Some(&src)).unwrap(), b"aXcdYZgh!");
+
+    for (after, offset) in [
+        (&b"abc?efgh"[..], 3),
+        (b"a?cdefgh", 1),
+        (b"abcdefg", 7),
+        (b"abcdefgh!", 8),
+    ] {
+        let err = verified(Some(after)).unwrap_err();
+        // The output is still from the first read.
+        assert_eq!(err.output, b"aXcdYZgh!");
+        assert!(matches!(
+            err.into_iter().collect::<Vec<_>>().as_slice(),
+            [UpsPatchError::InputModified { offset: o }] if *o == offset
+        ));
+    }
+
+    // Errors from patching are kept.
+    let mut input = ChangingReader {
+        cursor: Cursor::new(b"zzz".to_vec()),
+        after: Some(b"zzzz".to_vec()),
+    };
+    let errors: Vec<_> = patch
+        .patch_verified(PatchDirection::Apply, &mut input)
+        .unwrap()
+        .unwrap_err()
+        .into_iter()
+        .collect();
+    assert!(errors
+        .iter()
+        .any(|e| matches!(e, UpsPatchError::InputModified { offset: 3 })));
+    assert!(errors.len() > 1);
+}
+
+#[test]
+fn test_fingerprint() {
+    let patch = Patch::diff(b"abcdefgh", b"aXcdYZgh!");
+    // Same changes with the first block split in two.
+    let mut split = patch.clone();
+    split.blocks[0].xor_data.pop();
+    split.blocks.insert(
+        1,
+        Block {
+            offset: 0,
+            xor_data: vec![0].into(),
+        },
+    );
+    assert_eq!(split.normalize(), patch.normalize());
+    assert_eq!(split.fingerprint(), patch.fingerprint());
+    // Stored fingerprints must not change between versions.
+    assert_eq!(patch.fingerprint(), 0x7e6d_fea1_6f50_7c67);
+    assert_ne!(Patch::diff(b"ab", b"ac").fingerprint(), patch.fingerprint());
+}
+
+#[test]
+fn test_shrink_to_fit() {
+    let src = vec![0; 100_000];
+    let mut dst = src.clone();
+    for i in (0..dst.len()).step_by(1000) {
+        dst[i..i + 500].iter_mut().for_each(|b| *b = 1);
+    }
+    let patch = Patch::diff(&src, &dst);
+    let compact = patch.clone().compact();
+    assert_eq!(compact, patch);
+    assert_eq!(compact.blocks.capacity(), compact.blocks.len());
+    assert!(compact.heap_size() <= patch.heap_size());
+    // At least the changed bytes and their terminators.
+    assert!(compact.heap_size() >= 100 * 501);
+    assert_eq!(Patch::diff(b"abc", b"abc").heap_size(), 0);
+}
+
+#[test]
+fn test_parse_text() {
+    let patch = Patch::diff(b"abc", b"abd");
+    assert_eq!(
+        Patch::parse_hex("555053318383820700c2412435\n61d440ab0517fe46").unwrap(),
+        patch,
+    );
+    assert_eq!(
+        Patch::parse_base64("VVBTMYODggcAwkEkNWHUQKsFF/5G").unwrap(),
+        patch,
+    );
+    assert!(matches!(
+        Patch::parse_hex("not hex"),
+        Err(UpsParseError::InvalidText(_)),
+    ));
+    assert!(matches!(
+        Patch::parse_base64("VVBTMQ=="),
+        Err(UpsParseError::FormatMismatch(_)),
+    ));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serialize_errors() {
+    use crate::SerializeChecksumHex;
+
+    let err = Patch::parse(b"UPS0").unwrap_err();
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["kind"], "format_mismatch");
+
+    let patch = Patch::diff(b"abc", b"abd");
+    let errs = patch.apply(b"xyz").unwrap_err();
+    let json = serde_json::to_value(&errs).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "kind": "patch_errors",
+            "errors": [{
+                "kind": "dest_metadata_mismatch",
+                "mismatch": "checksum",
+                "expected": patch.dst_checksum.0,
+                "actual": Checksum::from_bytes(&errs.output).0,
+                "expected_hex": SerializeChecksumHex(patch.dst_checksum),
+                "actual_hex": SerializeChecksumHex(Checksum::from_bytes(&errs.output)),
+            }, {
+                "kind": "source_metadata_mismatch",
+                "mismatch": "checksum",
+                "expected": patch.src_checksum.0,
+                "actual": Checksum::from_bytes(b"xyz").0,
+                "expected_hex": SerializeChecksumHex(patch.src_checksum),
+                "actual_hex": SerializeChecksumHex(Checksum::from_bytes(b"xyz")),
+            }],
+        }),
+    );
+    let hex = serde_json::to_value(SerializeChecksumHex(Checksum(0x1716_a644))).unwrap();
+    assert_eq!(
+        hex,
+        serde_json::json!({"le": "0x44A61617", "be": "0x1716A644"})
+    );
+}
+
 fn invalid_magic() -> impl Strategy<Value = [u8; 4]> {
     array::uniform4(any::<u8>()).prop_filter("Valid magic", |v| v != b"UPS1")
 }
@@ -119,6 +1129,8 @@ prop_compose! {
             src_checksum,
             dst_size,
             dst_checksum,
+            metadata_dirty: false,
+            offsets: Default::default(),
         }
     }
 }
@@ -142,7 +1154,7 @@ prop_compose! {
             {
                 Block {
                     offset,
-                    xor_data,
+                    xor_data: xor_data.into(),
                 }
             }
 }
@@ -154,3 +1166,145 @@ fn xor_data() -> impl Strategy<Value = Vec<u8>> {
         v
     })
 }
+
+#[cfg(feature = "trace")]
+#[test]
+fn test_patch_traced() {
+    let src = b"HELLO WORLD, HELLO";
+    let dst = b"HELLO THERE, JELLO!";
+    let patch = Patch::diff(src, dst);
+    let mut entries = Vec::new();
+    let output = patch
+        .patch_traced(PatchDirection::Apply, src, |e| entries.push(e))
+        .unwrap();
+    assert_eq!(output, dst);
+    let spans: Vec<_> = entries
+        .iter()
+        .map(|e| (e.src_offset, e.dst_offset, e.len))
+        .collect();
+    assert_eq!(spans, vec![(6, 6, 5), (13, 13, 1), (18, 18, 1)]);
+    assert!(entries.iter().enumerate().all(|(i, e)| e.block == i));
+
+    // Reverting drops the byte past the end of the source.
+    let mut entries = Vec::new();
+    let output = patch
+        .patch_traced(PatchDirection::Revert, dst, |e| entries.push(e))
+        .unwrap();
+    assert_eq!(output, src);
+    assert_eq!(entries.len(), 2);
+
+    let mut line = Vec::new();
+    entries[1].write_json_line(&mut line).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&line).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({"block": 1, "src_offset": 13, "dst_offset": 13, "len": 1})
+    );
+}
+
+#[test]
+fn test_compressibility() {
+    let empty = Patch::diff(b"abcd", b"abcd").compressibility();
+    assert_eq!(empty.xor_bytes, 0);
+    assert_eq!(empty.ratio(), 1.0);
+
+    let src = vec![0; 0x10000];
+    let text = Patch::diff(&src, &b"Hello, world! ".repeat(0x1000)).compressibility();
+    assert_eq!(text.xor_bytes, 14 * 0x1000 + 1);
+    assert!(text.ratio() < 0.05, "{:?}", text);
+
+    // xorshift output, which no compressor can shrink.
+    let mut state = 1u64;
+    let noise: Vec<u8> = (0..0x10000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let noise = Patch::diff(&src, &noise).compressibility();
+    assert!(noise.ratio() > 0.95, "{:?}", noise);
+    assert!(noise.ratio() <= 1.01, "{:?}", noise);
+    assert!(noise.saved_bytes() < noise.xor_bytes / 20);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_compressibility_measured() {
+    let src = vec![0; 0x10000];
+    let dst = b"Hello, world! ".repeat(0x1000);
+    let patch = Patch::diff(&src, &dst);
+    let xor_data: Vec<u8> = patch
+        .blocks()
+        .iter()
+        .flat_map(|b| b.xor_data().to_vec())
+        .collect();
+    let frame =
+        ruzstd::encoding::compress_to_vec(&*xor_data, ruzstd::encoding::CompressionLevel::Fastest);
+
+    let compressibility = patch.compressibility();
+    assert!(compressibility.measured);
+    assert_eq!(compressibility.xor_bytes, xor_data.len());
+    assert_eq!(compressibility.compressed_bytes, frame.len());
+}
+
+#[test]
+fn test_diff_blocks_past_source_end() {
+    // Blocks after the end of the source used to be placed relative to it rather than to the end
+    // of the previous block.
+    let cases: [(&[u8], &[u8]); 4] = [
+        (b"a", b"a\0b"),
+        (b"abc", b"abc\0\0de\0f"),
+        (b"xbc", b"abc\0d"),
+        (b"abc", b"aBc\0\0d"),
+    ];
+    for (src, dst) in cases {
+        let patch = Patch::diff(src, dst);
+        assert_eq!(patch.apply(src).unwrap(), dst, "{:?} -> {:?}", src, dst);
+        assert_eq!(patch.revert(dst).unwrap(), src, "{:?} -> {:?}", src, dst);
+    }
+}
+
+// Patch for 5 GiB files with blocks past 4 GiB, written without allocating the files.
+fn raw_patch_larger_than_4gib() -> Vec<u8> {
+    const GIB: u64 = 1 << 30;
+    let mut raw = b"UPS1".to_vec();
+    varint::write_u64(&mut raw, 5 * GIB);
+    varint::write_u64(&mut raw, 5 * GIB + 2);
+    for (offset, data) in [(GIB, &[1, 0]), (3 * GIB + 5, &[2, 0]), (GIB - 8, &[3, 0])] {
+        varint::write_u64(&mut raw, offset);
+        raw.extend_from_slice(data);
+    }
+    raw.extend_from_slice(&[1, 0, 0, 0, 2, 0, 0, 0]);
+    let checksum = Checksum::from_bytes(&raw);
+    raw.extend_from_slice(&checksum.0.to_le_bytes());
+    raw
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn test_offsets_larger_than_4gib() {
+    const GIB: usize = 1 << 30;
+    let raw = raw_patch_larger_than_4gib();
+    let patch = Patch::parse(&raw).unwrap();
+    assert_eq!(patch.src_size, 5 * GIB);
+    assert_eq!(patch.dst_size, 5 * GIB + 2);
+    assert_eq!(
+        patch.block_offsets().changed_ranges().collect::<Vec<_>>(),
+        [
+            GIB..GIB + 1,
+            4 * GIB + 7..4 * GIB + 8,
+            5 * GIB + 1..5 * GIB + 2
+        ],
+    );
+    assert_eq!(patch.serialize(), raw);
+    assert_eq!(patch.normalize(), patch);
+}
+
+// Sizes and offsets of in-memory patches are `usize`, see the crate docs.
+#[test]
+#[cfg(target_pointer_width = "32")]
+fn test_offsets_larger_than_4gib() {
+    assert!(Patch::parse(&raw_patch_larger_than_4gib()).is_err());
+}
@@ -0,0 +1,73 @@
+use std::io::{self, Write};
+
+use super::{Patch, PatchDirection, UpsPatchResult};
+
+/// Block applied by [`Patch::patch_traced`].
+///
+/// Traces are meant for comparing this crate with other patchers when their outputs differ, so
+/// they describe each write in file positions rather than UPS relative offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TraceEntry {
+    /// Index of the block in the patch.
+    pub block: usize,
+    /// Position of the block in the source file.
+    pub src_offset: usize,
+    /// Position of the block in the destination file. UPS blocks XOR bytes in place, so it's
+    /// always `src_offset`.
+    pub dst_offset: usize,
+    /// Bytes changed by the block, without its 0 terminator or bytes past the end of the output.
+    pub len: usize,
+}
+
+impl TraceEntry {
+    /// Write the entry as a line of JSON, to build JSONL traces:
+    /// `{"block":0,"src_offset":16,"dst_offset":16,"len":4}`.
+    pub fn write_json_line<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(
+            writer,
+            r#"{{"block":{},"src_offset":{},"dst_offset":{},"len":{}}}"#,
+            self.block, self.src_offset, self.dst_offset, self.len
+        )
+    }
+}
+
+impl Patch {
+    /// Like [`patch`](Patch::patch), passing a [`TraceEntry`] for each block written to the
+    /// output to `on_entry`, in order. Blocks starting past the end of the output aren't traced.
+    ///
+    /// ```
+    /// use ups::{Patch, PatchDirection};
+    ///
+    /// let patch = Patch::diff(b"HELLO WORLD", b"HELLO THERE");
+    /// let mut trace = Vec::new();
+    /// let output = patch.patch_traced(PatchDirection::Apply, b"HELLO WORLD", |entry| {
+    ///     entry.write_json_line(&mut trace).unwrap();
+    /// })?;
+    /// assert_eq!(output, b"HELLO THERE");
+    /// assert_eq!(trace, b"{\"block\":0,\"src_offset\":6,\"dst_offset\":6,\"len\":5}\n");
+    /// # Ok::<_, ups::UpsPatchErrors>(())
+    /// ```
+    pub fn patch_traced<F: FnMut(TraceEntry)>(
+        &self,
+        direction: PatchDirection,
+        input: &[u8],
+        mut on_entry: F,
+    ) -> UpsPatchResult<Vec<u8>> {
+        let output_size = direction.metadata(self).output_size;
+        let offsets = self.block_offsets();
+        for block in 0..self.blocks.len() {
+            let span = match offsets.span(block) {
+                Some(s) if s.start < output_size => s,
+                _ => break,
+            };
+            on_entry(TraceEntry {
+                block,
+                src_offset: span.start,
+                dst_offset: span.start,
+                len: std::cmp::min(span.end, output_size) - span.start,
+            });
+        }
+        self.patch(direction, input)
+    }
+}
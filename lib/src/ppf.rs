@@ -0,0 +1,394 @@
+//! Parse, apply and revert PPF 3.0 patches, the common format for PlayStation disc image hacks.
+//!
+//! PPF patches are lists of records overwriting bytes at 64-bit offsets of a disc image. They have
+//! no checksums, but can include a copy of 1 KiB of the original image, the block check, which
+//! [`PpfPatch::apply`] compares with the input. Patches created with undo data also hold the
+//! original bytes of every record, so [`PpfPatch::revert`] can restore the image.
+//! [`PpfPatch::to_ups`] converts them to UPS patches for a given image.
+//!
+//! Only PPF 3.0 is supported, PPF 1.0 and 2.0 patches fail to parse. Records writing past
+//! [`MAX_IMAGE_SIZE`] are rejected too, since applying them would allocate the whole gap.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::fs;
+//! use ups::ppf::PpfPatch;
+//!
+//! let image = fs::read("samples/game.bin")?;
+//! let patch = PpfPatch::parse(&fs::read("samples/patch.ppf")?)?;
+//! fs::write("patched.bin", patch.apply(&image)?)?;
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! # Reference
+//!
+//! https://www.romhacking.net/utilities/353/
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Formatter};
+
+use crate::{Checksum, MetadataMismatch, Patch, UpsPatchError, UpsPatchErrors};
+
+const MAGIC: &[u8] = b"PPF";
+const VERSION: &[u8] = b"30";
+const ENCODING_METHOD: u8 = 2;
+const DESCRIPTION_LEN: usize = 50;
+const HEADER_LEN: usize = 60;
+const BLOCK_CHECK_LEN: usize = 1024;
+const FILE_ID_BEGIN: &[u8] = b"@BEGIN_FILE_ID.DIZ";
+const FILE_ID_END: &[u8] = b"@END_FILE_ID.DIZ";
+
+/// Largest image size records can write up to, 16 GiB, above dual-layer DVD images. Limited to
+/// `isize::MAX`, the largest `Vec`, on 32-bit platforms.
+pub const MAX_IMAGE_SIZE: u64 = 1 << 34;
+
+/// PPF patch contents, see the [module docs](self).
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PpfPatch {
+    /// Description from the header, without the padding.
+    pub description: String,
+    /// Layout of the disc image, which decides where the block check is.
+    pub image_type: PpfImageType,
+    /// Copy of the original image bytes at [`PpfImageType::block_check_offset`], if enabled.
+    pub block_check: Option<Vec<u8>>,
+    /// Records in the order they're applied.
+    pub records: Vec<PpfRecord>,
+    /// Text of the FILE_ID.DIZ attached to the patch, usually the release notes.
+    pub file_id: Option<String>,
+}
+
+/// Disc image layout of a [`PpfPatch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PpfImageType {
+    /// Raw BIN image.
+    Bin,
+    /// PrimoDVD GI image.
+    Gi,
+}
+
+/// Write to the image in a [`PpfPatch`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PpfRecord {
+    /// Offset of the first byte written.
+    pub offset: usize,
+    /// Bytes written.
+    pub data: Vec<u8>,
+    /// Original bytes, if the patch has undo data.
+    pub undo: Option<Vec<u8>>,
+}
+
+/// Error from [`PpfPatch::parse`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum PpfParseError {
+    #[error("this doesn't seem to be a PPF 3.0 file: {}", .0)]
+    FormatMismatch(String),
+}
+
+impl PpfImageType {
+    /// Offset of the block check in images of this type.
+    pub fn block_check_offset(self) -> usize {
+        match self {
+            PpfImageType::Bin => 0x9320,
+            PpfImageType::Gi => 0x80a0,
+        }
+    }
+}
+
+impl PpfPatch {
+    /// Parse a PPF 3.0 patch.
+    pub fn parse(input: &[u8]) -> Result<Self, PpfParseError> {
+        let err = |reason: &str| PpfParseError::FormatMismatch(reason.into());
+        let rest = input
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| err("invalid preamble, expected \"PPF30\""))?;
+        if !rest.starts_with(VERSION) {
+            return Err(err("only PPF 3.0 patches are supported"));
+        }
+        if input.len() < HEADER_LEN {
+            return Err(err("file too small"));
+        }
+        if input[5] != ENCODING_METHOD {
+            return Err(err("invalid encoding method"));
+        }
+        let description = String::from_utf8_lossy(&input[6..6 + DESCRIPTION_LEN])
+            .trim_end_matches(|c: char| c == '\0' || c.is_whitespace())
+            .to_string();
+        let image_type = match input[56] {
+            0 => PpfImageType::Bin,
+            1 => PpfImageType::Gi,
+            _ => return Err(err("invalid image type")),
+        };
+        let has_undo = input[58] != 0;
+
+        let (records_data, file_id) = split_file_id(&input[HEADER_LEN..]);
+        let mut buf = records_data;
+        let block_check = if input[57] != 0 {
+            Some(take(&mut buf, BLOCK_CHECK_LEN).ok_or_else(|| err("truncated block check"))?)
+        } else {
+            None
+        };
+        let mut records = Vec::new();
+        while !buf.is_empty() {
+            let record_err = |reason: &str| err(&format!("record {}: {}", records.len(), reason));
+            let header = take(&mut buf, 9).ok_or_else(|| record_err("truncated"))?;
+            let offset = u64::from_le_bytes(<[u8; 8]>::try_from(&header[..8]).unwrap());
+            let max_end = std::cmp::min(MAX_IMAGE_SIZE, isize::MAX as u64);
+            let offset = offset
+                .checked_add(u64::from(header[8]))
+                .filter(|end| *end <= max_end)
+                .and_then(|_| usize::try_from(offset).ok())
+                .ok_or_else(|| record_err("offset too large"))?;
+            let len = usize::from(header[8]);
+            let data = take(&mut buf, len).ok_or_else(|| record_err("truncated data"))?;
+            let undo = if has_undo {
+                Some(take(&mut buf, len).ok_or_else(|| record_err("truncated undo data"))?)
+            } else {
+                None
+            };
+            records.push(PpfRecord {
+                offset,
+                data: data.to_vec(),
+                undo: undo.map(<[u8]>::to_vec),
+            });
+        }
+        Ok(PpfPatch {
+            description,
+            image_type,
+            block_check: block_check.map(<[u8]>::to_vec),
+            records,
+            file_id: file_id.map(|text| String::from_utf8_lossy(text).into_owned()),
+        })
+    }
+
+    /// Whether every record has undo data, needed to [`revert`](PpfPatch::revert) the patch.
+    pub fn has_undo_data(&self) -> bool {
+        self.records.iter().all(|r| r.undo.is_some())
+    }
+
+    /// Apply the patch to `src`. Records past the end of `src` extend it, filling the gap with
+    /// zeroes.
+    ///
+    /// A block check mismatch is returned as a
+    /// [`SourceMetadataMismatch`](UpsPatchError::SourceMetadataMismatch) error with the CRC32 of
+    /// the expected and actual blocks.
+    pub fn apply(&self, src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors> {
+        let errors = self.check_block(src).into_iter().collect();
+        let mut output = src.to_vec();
+        for record in &self.records {
+            overwrite(&mut output, record.offset, &record.data);
+        }
+        UpsPatchErrors::check_errors(output, errors)
+    }
+
+    /// Revert the patch applied to `dst` with its undo data, `None` if it has none. The block
+    /// check is compared with the reverted image.
+    ///
+    /// PPF patches don't record the image size, so images extended by the patch keep their size.
+    pub fn revert(&self, dst: &[u8]) -> Option<Result<Vec<u8>, UpsPatchErrors>> {
+        if !self.has_undo_data() {
+            return None;
+        }
+        let mut output = dst.to_vec();
+        for record in self.records.iter().rev() {
+            overwrite(&mut output, record.offset, record.undo.as_deref()?);
+        }
+        let errors = self.check_block(&output).into_iter().collect();
+        Some(UpsPatchErrors::check_errors(output, errors))
+    }
+
+    /// Equivalent UPS patch for `src`, which can be checked and reverted.
+    pub fn to_ups(&self, src: &[u8]) -> Result<Patch, UpsPatchErrors> {
+        Ok(Patch::diff(src, &self.apply(src)?))
+    }
+
+    fn check_block(&self, image: &[u8]) -> Option<UpsPatchError> {
+        let expected = self.block_check.as_ref()?;
+        let start = self.image_type.block_check_offset();
+        let actual = image.get(start..).map_or(&[][..], |rest| {
+            &rest[..std::cmp::min(rest.len(), expected.len())]
+        });
+        if actual == &expected[..] {
+            return None;
+        }
+        Some(UpsPatchError::SourceMetadataMismatch(
+            MetadataMismatch::Checksum {
+                expected: Checksum::from_bytes(expected),
+                actual: Checksum::from_bytes(actual),
+            },
+        ))
+    }
+}
+
+impl Debug for PpfPatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("PpfPatch")
+            .field("description", &self.description)
+            .field("image_type", &self.image_type)
+            .field("block_check", &self.block_check.is_some())
+            .field("records", &self.records.len())
+            .field("undo", &self.has_undo_data())
+            .field("file_id", &self.file_id)
+            .finish()
+    }
+}
+
+// Split the FILE_ID.DIZ text off the end of the patch body, if there's one. It's followed by its
+// size, which generators write in 2 or 4 bytes, so only the markers are used to find it.
+fn split_file_id(body: &[u8]) -> (&[u8], Option<&[u8]>) {
+    let end = [2, 4].iter().find_map(|size_len| {
+        let end = body.len().checked_sub(size_len + FILE_ID_END.len())?;
+        body[end..].starts_with(FILE_ID_END).then_some(end)
+    });
+    let begin = end.and_then(|end| {
+        body[..end]
+            .windows(FILE_ID_BEGIN.len())
+            .rposition(|w| w == FILE_ID_BEGIN)
+            .map(|begin| (begin, end))
+    });
+    match begin {
+        Some((begin, end)) => (
+            &body[..begin],
+            Some(&body[begin + FILE_ID_BEGIN.len()..end]),
+        ),
+        None => (body, None),
+    }
+}
+
+fn overwrite(output: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    let end = offset + data.len();
+    if end > output.len() {
+        output.resize(end, 0);
+    }
+    output[offset..end].copy_from_slice(data);
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Some(head)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Offset, data and undo data.
+    type RawRecord<'a> = (u64, &'a [u8], Option<&'a [u8]>);
+
+    // PPF 3.0 patch for a BIN image.
+    fn ppf(block_check: Option<&[u8]>, records: &[RawRecord]) -> Vec<u8> {
+        let mut raw = b"PPF30\x02".to_vec();
+        let mut description = b"Test patch".to_vec();
+        description.resize(DESCRIPTION_LEN, b' ');
+        raw.extend_from_slice(&description);
+        let has_undo = records.iter().any(|(_, _, undo)| undo.is_some());
+        raw.extend_from_slice(&[0, block_check.is_some() as u8, has_undo as u8, 0]);
+        if let Some(block) = block_check {
+            raw.extend_from_slice(block);
+        }
+        for (offset, data, undo) in records {
+            raw.extend_from_slice(&offset.to_le_bytes());
+            raw.push(data.len() as u8);
+            raw.extend_from_slice(data);
+            raw.extend_from_slice(undo.unwrap_or_default());
+        }
+        raw
+    }
+
+    fn image() -> Vec<u8> {
+        (0..0xa000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_parse_apply_revert() {
+        let src = image();
+        let block = &src[0x9320..0x9320 + BLOCK_CHECK_LEN];
+        let raw = ppf(
+            Some(block),
+            &[
+                (2, b"hi", Some(&src[2..4])),
+                (0x9fff, b"!!", Some(&[src[0x9fff], 0])),
+            ],
+        );
+        let patch = PpfPatch::parse(&raw).unwrap();
+        assert_eq!(patch.description, "Test patch");
+        assert_eq!(patch.image_type, PpfImageType::Bin);
+        assert_eq!(patch.records.len(), 2);
+        assert!(patch.has_undo_data());
+
+        let dst = patch.apply(&src).unwrap();
+        assert_eq!(&dst[2..4], b"hi");
+        // Writes past the end extend the image.
+        assert_eq!(dst.len(), src.len() + 1);
+        let reverted = patch.revert(&dst).unwrap().unwrap();
+        assert_eq!(&reverted[..src.len()], &src[..]);
+
+        let ups = patch.to_ups(&src).unwrap();
+        assert_eq!(ups.revert(&dst).unwrap(), src);
+    }
+
+    #[test]
+    fn test_block_check() {
+        let src = image();
+        let raw = ppf(Some(&[0xff; BLOCK_CHECK_LEN]), &[(0, b"x", None)]);
+        let patch = PpfPatch::parse(&raw).unwrap();
+        let errs = patch.apply(&src).unwrap_err();
+        assert_eq!(&errs.output[..1], b"x");
+        assert!(errs.iter().any(|e| matches!(
+            e,
+            UpsPatchError::SourceMetadataMismatch(MetadataMismatch::Checksum { .. })
+        )));
+        // Images too short for the block fail the check too.
+        assert!(patch.apply(&src[..0x9400]).is_err());
+        // No undo data.
+        assert!(patch.revert(&src).is_none());
+
+        let patch = PpfPatch::parse(&ppf(None, &[(0, b"x", None)])).unwrap();
+        assert_eq!(&patch.apply(&src).unwrap()[..2], &[b'x', src[1]]);
+    }
+
+    #[test]
+    fn test_file_id() {
+        for size_len in [2, 4] {
+            let mut raw = ppf(None, &[(1, b"abc", None)]);
+            let text = b"Release notes";
+            raw.extend_from_slice(FILE_ID_BEGIN);
+            raw.extend_from_slice(text);
+            raw.extend_from_slice(FILE_ID_END);
+            raw.extend_from_slice(&(text.len() as u32).to_le_bytes()[..size_len]);
+            let patch = PpfPatch::parse(&raw).unwrap();
+            assert_eq!(patch.file_id.as_deref(), Some("Release notes"));
+            assert_eq!(patch.apply(b"0000").unwrap(), b"0abc");
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let valid = ppf(None, &[(1, b"abc", None)]);
+        let mut bad_image_type = valid.clone();
+        bad_image_type[56] = 7;
+        for raw in [
+            &b""[..],
+            b"UPS1",
+            b"PPF20\x01",
+            &valid[..HEADER_LEN - 1],
+            &valid[..valid.len() - 1],
+            &bad_image_type,
+            // Applying used to allocate the gap up to the offset.
+            &ppf(None, &[(1 << 62, b"abc", None)]),
+            &ppf(None, &[(MAX_IMAGE_SIZE - 2, b"abc", None)]),
+        ] {
+            assert!(
+                matches!(PpfPatch::parse(raw), Err(PpfParseError::FormatMismatch(_))),
+                "{:?}",
+                raw
+            );
+        }
+        let last = PpfPatch::parse(&ppf(None, &[(MAX_IMAGE_SIZE - 3, b"abc", None)]));
+        assert!(last.is_ok() || cfg!(target_pointer_width = "32"));
+    }
+}
@@ -0,0 +1,186 @@
+//! Patching ROM images loaded in memory, e.g. by an emulator core.
+//!
+//! [`apply_to_memory`] reads and writes only the regions changed by the patch through a
+//! [`MemWriter`], so the ROM image is never copied. Emulators can implement [`MemWriter`] over
+//! their memory bus, plain slices are supported out of the box.
+//!
+//! ## Example
+//!
+//! ```
+//! use ups::{runtime, Checksum, Patch};
+//!
+//! let mut rom = b"Hello, world!".to_vec();
+//! let patch = Patch::diff(&rom, b"Hello, there!");
+//! // Checksum computed when the ROM was loaded.
+//! let loaded_checksum = Checksum::from_bytes(&rom);
+//! runtime::apply_to_memory(&patch, Some(loaded_checksum), &mut rom[..])?;
+//! assert_eq!(rom, b"Hello, there!");
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+use crate::{Checksum, MetadataMismatch, Patch};
+
+/// Memory holding a ROM image being patched by [`apply_to_memory`].
+///
+/// Patches store the XOR of the old and new bytes, so changed regions are read before being
+/// written.
+pub trait MemWriter {
+    type Error;
+
+    /// Fill `buf` with the bytes at `offset`.
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Write `data` at `offset`.
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Access past the end of a slice used as a [`MemWriter`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("access to {} bytes at offset {} is out of bounds", .len, .offset)]
+pub struct OutOfBounds {
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl MemWriter for [u8] {
+    type Error = OutOfBounds;
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), OutOfBounds> {
+        let src = offset
+            .checked_add(buf.len())
+            .and_then(|end| self.get(offset..end))
+            .ok_or(OutOfBounds {
+                offset,
+                len: buf.len(),
+            })?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), OutOfBounds> {
+        let dst = offset
+            .checked_add(data.len())
+            .and_then(|end| self.get_mut(offset..end))
+            .ok_or(OutOfBounds {
+                offset,
+                len: data.len(),
+            })?;
+        dst.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl<M: MemWriter + ?Sized> MemWriter for &mut M {
+    type Error = M::Error;
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        (**self).read(offset, buf)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        (**self).write(offset, data)
+    }
+}
+
+/// Possible errors from [`apply_to_memory`].
+#[derive(thiserror::Error, Debug)]
+pub enum RuntimeError<E> {
+    /// The loaded ROM isn't the patch source, nothing was written.
+    #[error("loaded ROM {}", .0)]
+    BaseMismatch(MetadataMismatch),
+    /// Reading or writing memory failed, regions before `offset` were already patched.
+    #[error("memory access failed at offset {}", .offset)]
+    Memory {
+        offset: usize,
+        #[source]
+        source: E,
+    },
+}
+
+/// Apply `patch` to the ROM image in `memory`, returning the number of bytes written.
+///
+/// If `base_checksum` is given, it must be the checksum of the loaded ROM and is checked against
+/// the patch before writing anything. Pass `None` when the checksum is unknown or was already
+/// verified, e.g. with [`Patch::preflight`].
+///
+/// Patches resizing the ROM are applied as if the memory was already resized: data appended past
+/// the end of the source is written past the end of the image, data past the end of the
+/// destination is left untouched.
+pub fn apply_to_memory<M: MemWriter>(
+    patch: &Patch,
+    base_checksum: Option<Checksum>,
+    mut memory: M,
+) -> Result<usize, RuntimeError<M::Error>> {
+    if let Some(err) = base_checksum.and_then(|c| MetadataMismatch::checksum(patch.src_checksum, c))
+    {
+        return Err(RuntimeError::BaseMismatch(err));
+    }
+
+    let mut written = 0;
+    let mut buf = Vec::new();
+    for (offset, xor_data) in patch.changed_spans(patch.dst_size) {
+        let memory_err = |source| RuntimeError::Memory { offset, source };
+        buf.clear();
+        if offset < patch.src_size {
+            // Bytes past the end of the source are zero.
+            let from_src = std::cmp::min(xor_data.len(), patch.src_size - offset);
+            buf.resize(from_src, 0);
+            memory.read(offset, &mut buf).map_err(memory_err)?;
+        }
+        buf.resize(xor_data.len(), 0);
+        for (byte, patch_byte) in buf.iter_mut().zip(xor_data) {
+            *byte ^= patch_byte;
+        }
+        memory.write(offset, &buf).map_err(memory_err)?;
+        written += buf.len();
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply_to_memory() {
+        let src: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let mut dst = src.clone();
+        dst[10..20].copy_from_slice(b"0123456789");
+        dst[500] ^= 0xff;
+        let patch = Patch::diff(&src, &dst);
+
+        let mut memory = src.clone();
+        let written = apply_to_memory(&patch, Some(Checksum::from_bytes(&src)), &mut memory[..]);
+        assert_eq!(written.unwrap(), 11);
+        assert_eq!(memory, dst);
+
+        let mut memory = src.clone();
+        memory[0] ^= 1;
+        let result = apply_to_memory(&patch, Some(Checksum::from_bytes(&memory)), &mut memory[..]);
+        assert!(matches!(result, Err(RuntimeError::BaseMismatch(_))));
+        assert_eq!(memory[10..20], src[10..20]);
+    }
+
+    #[test]
+    fn test_apply_to_memory_resized() {
+        let src = b"abcdef".to_vec();
+        let dst = b"aXcdefghij";
+        let patch = Patch::diff(&src, dst);
+
+        // The image has room for the appended data.
+        let mut memory = src.clone();
+        memory.resize(dst.len(), 0xff);
+        apply_to_memory(&patch, None, &mut memory[..]).unwrap();
+        assert_eq!(memory, dst);
+
+        let mut memory = src;
+        let result = apply_to_memory(&patch, None, &mut memory[..]);
+        assert!(matches!(
+            result,
+            Err(RuntimeError::Memory {
+                offset: 6,
+                source: OutOfBounds { offset: 6, len: 4 },
+            })
+        ));
+    }
+}
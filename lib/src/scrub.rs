@@ -0,0 +1,144 @@
+//! Scrubbing UPS patches for bug reports.
+//!
+//! Patches for commercial ROMs carry copyrighted data in their blocks, so users can't always share
+//! the patch triggering a bug. [`scrub`] replaces the XOR data of every block with deterministic
+//! pseudo-random bytes, keeping everything else byte for byte: the sizes and offsets as encoded,
+//! block lengths and terminators, unparseable trailing bytes and the source and destination
+//! checksums. The patch checksum is recomputed if it was right and kept otherwise, so the scrubbed
+//! patch fails to parse the same way as the original.
+//!
+//! Scrubbing works on the raw patch rather than a parsed [`Patch`](crate::Patch), so it also
+//! handles patches which don't parse, e.g. with truncated blocks.
+//!
+//! ## Example
+//!
+//! ```
+//! use ups::Patch;
+//!
+//! let patch = Patch::diff(b"SECRET DATA", b"SECRET TEXT");
+//! let scrubbed = Patch::parse(&ups::scrub::scrub(&patch.serialize())?)?;
+//! assert_eq!(scrubbed.blocks().len(), patch.blocks().len());
+//! assert_eq!(scrubbed.dst_checksum, patch.dst_checksum);
+//! assert_ne!(scrubbed.blocks()[0].xor_data(), patch.blocks()[0].xor_data());
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+use crate::{varint, Checksum, UpsParseError};
+
+const MAGIC: &[u8] = b"UPS1";
+const FOOTER_LEN: usize = 12;
+// Fixed seed so scrubbing the same patch twice gives the same file.
+const SEED: u64 = 0x5550_5331_5343_5242;
+
+/// Replace the block data of `raw_patch` with pseudo-random bytes, see the [module docs](self).
+///
+/// Fails if `raw_patch` doesn't have a UPS header and footer, there's nothing to keep from other
+/// files.
+pub fn scrub(raw_patch: &[u8]) -> Result<Vec<u8>, UpsParseError> {
+    let err = |reason: &str| UpsParseError::FormatMismatch(reason.into());
+    let mut input = raw_patch
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| err("invalid preamble, expected \"UPS1\""))?;
+    varint::read(&mut input).ok_or_else(|| err("error reading source file size"))?;
+    varint::read(&mut input).ok_or_else(|| err("error reading dest file size"))?;
+    if input.len() < FOOTER_LEN {
+        return Err(err("failed to read checksums"));
+    }
+    let body_start = raw_patch.len() - input.len();
+    let body_end = raw_patch.len() - FOOTER_LEN;
+
+    let mut output = raw_patch.to_vec();
+    let mut rng = SEED;
+    let mut pos = body_start;
+    while pos < body_end {
+        let mut body = &raw_patch[pos..body_end];
+        if varint::read(&mut body).is_none() {
+            // Trailing bytes without a full offset, too short to hold anything worth hiding.
+            break;
+        }
+        pos = body_end - body.len();
+        while pos < body_end && raw_patch[pos] != 0 {
+            output[pos] = random_nonzero(&mut rng);
+            pos += 1;
+        }
+        // Skip the terminator.
+        pos += 1;
+    }
+
+    let patch_checksum_pos = raw_patch.len() - 4;
+    let actual = Checksum::from_bytes(&raw_patch[..patch_checksum_pos]);
+    if raw_patch[patch_checksum_pos..] == actual.0.to_le_bytes() {
+        let checksum = Checksum::from_bytes(&output[..patch_checksum_pos]);
+        output[patch_checksum_pos..].copy_from_slice(&checksum.0.to_le_bytes());
+    }
+    Ok(output)
+}
+
+// Next byte from a xorshift64 generator, skipping 0 which would end the block.
+fn random_nonzero(state: &mut u64) -> u8 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    (*state % 255) as u8 + 1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Patch;
+
+    #[test]
+    fn test_scrub() {
+        let src = b"The quick brown fox jumps over the lazy dog".to_vec();
+        let mut dst = src.clone();
+        dst[4..9].copy_from_slice(b"QUICK");
+        dst[35..].copy_from_slice(b"hazy cat");
+        dst.extend_from_slice(b" and more");
+        let patch = Patch::diff(&src, &dst);
+        let raw = patch.serialize();
+
+        let scrubbed_raw = scrub(&raw).unwrap();
+        assert_eq!(scrubbed_raw.len(), raw.len());
+        assert_eq!(scrub(&raw).unwrap(), scrubbed_raw);
+        let scrubbed = Patch::parse(&scrubbed_raw).unwrap();
+        assert_eq!(scrubbed.src_size, patch.src_size);
+        assert_eq!(scrubbed.dst_size, patch.dst_size);
+        assert_eq!(scrubbed.src_checksum, patch.src_checksum);
+        assert_eq!(scrubbed.dst_checksum, patch.dst_checksum);
+        assert_eq!(patch.block_offsets(), scrubbed.block_offsets());
+        for (block, scrubbed_block) in patch.blocks.iter().zip(&scrubbed.blocks) {
+            assert_ne!(block.xor_data(), scrubbed_block.xor_data());
+        }
+    }
+
+    #[test]
+    fn test_scrub_malformed() {
+        let raw = Patch::diff(b"abcdef", b"abCDef").serialize();
+        // Wrong patch checksum stays wrong.
+        let mut bad_checksum = raw.clone();
+        *bad_checksum.last_mut().unwrap() ^= 1;
+        let scrubbed = scrub(&bad_checksum).unwrap();
+        assert_eq!(
+            scrubbed[scrubbed.len() - 4..],
+            bad_checksum[raw.len() - 4..]
+        );
+        assert!(matches!(
+            Patch::parse(&scrubbed),
+            Err(UpsParseError::PatchChecksumMismatch { .. })
+        ));
+
+        // Unterminated block.
+        let mut unterminated = raw[..raw.len() - 13].to_vec();
+        unterminated.extend_from_slice(&raw[raw.len() - 12..]);
+        let scrubbed = scrub(&unterminated).unwrap();
+        assert_eq!(scrubbed.len(), unterminated.len());
+        assert_ne!(
+            scrubbed[..scrubbed.len() - 12],
+            unterminated[..scrubbed.len() - 12]
+        );
+
+        for raw in [&b"PATCH"[..], b"UPS1", b"UPS1\x80\x80\x00"] {
+            assert!(matches!(scrub(raw), Err(UpsParseError::FormatMismatch(_))));
+        }
+    }
+}
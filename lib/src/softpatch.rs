@@ -0,0 +1,285 @@
+//! Softpatching helpers for emulator frontends.
+//!
+//! Softpatching applies a patch to a ROM while loading it, without writing the patched ROM to disk.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::fs;
+//! use std::path::PathBuf;
+//!
+//! let rom = fs::read("game.gba")?;
+//! let candidates = [PathBuf::from("game.ups"), PathBuf::from("patches/game.ups")];
+//! let rom = ups::softpatch::load(&rom, &candidates);
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! ## Patch chains
+//!
+//! Several patches can be applied in sequence following the numbered softpatch convention:
+//! `game.ups` is applied first, then `game.ups1`, `game.ups2` and so on. Use
+//! [`numbered_patches`] to find them and [`apply_chain`] to apply them.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{Checksum, ComposeError, Patch, PatchDirection, UpsParseError, UpsPatchErrors};
+
+/// Patch `rom` with the first candidate whose source checksum matches it.
+///
+/// Candidates which can't be read or parsed, or whose source doesn't match `rom`, are skipped. If
+/// no candidate applies cleanly, returns a copy of `rom` unchanged so the frontend can still load
+/// the game.
+pub fn load<P: AsRef<Path>>(rom: &[u8], patch_candidates: &[P]) -> Vec<u8> {
+    let rom_checksum = Checksum::from_bytes(rom);
+    patch_candidates
+        .iter()
+        .filter_map(|path| fs::read(path).ok())
+        .filter_map(|raw_patch| Patch::parse(&raw_patch).ok())
+        .filter(|patch| patch.src_size == rom.len() && patch.src_checksum == rom_checksum)
+        .find_map(|patch| patch.apply(rom).ok())
+        .unwrap_or_else(|| rom.to_vec())
+}
+
+/// Error for a broken link in a patch chain. `index` is the position of the failing patch in the
+/// chain and `path` its file.
+#[derive(thiserror::Error, Debug)]
+pub enum ChainError {
+    #[error("failed to read patch #{} \"{}\": {}", .index, .path.display(), .source)]
+    Io {
+        index: usize,
+        path: PathBuf,
+        source: io::Error,
+    },
+    #[error("failed to parse patch #{} \"{}\": {}", .index, .path.display(), .source)]
+    Parse {
+        index: usize,
+        path: PathBuf,
+        source: UpsParseError,
+    },
+    /// The patch doesn't apply to the output of the previous one in the chain, found before
+    /// patching anything.
+    #[error(
+        "patch #{} \"{}\" doesn't follow the previous one: {}",
+        .index, .path.display(), .source,
+    )]
+    Compose {
+        index: usize,
+        path: PathBuf,
+        source: ComposeError,
+    },
+    #[error("patch #{} \"{}\" doesn't apply: {}", .index, .path.display(), .source)]
+    Patch {
+        index: usize,
+        path: PathBuf,
+        source: UpsPatchErrors,
+    },
+}
+
+/// Find the patch chain starting at `first`: `first` itself followed by every existing
+/// `<first>1`, `<first>2`... file, stopping at the first missing number. E.g. `game.ups`,
+/// `game.ups1`, `game.ups2`.
+pub fn numbered_patches(first: &Path) -> Vec<PathBuf> {
+    let mut chain = vec![first.to_path_buf()];
+    for n in 1.. {
+        let mut numbered = first.as_os_str().to_os_string();
+        numbered.push(n.to_string());
+        let numbered = PathBuf::from(numbered);
+        if !numbered.is_file() {
+            break;
+        }
+        chain.push(numbered);
+    }
+    chain
+}
+
+/// Apply every patch in `patches` in sequence, starting from `rom`. Each intermediate result is
+/// verified against the next patch's source metadata.
+pub fn apply_chain<P: AsRef<Path>>(rom: &[u8], patches: &[P]) -> Result<Vec<u8>, ChainError> {
+    patch_chain(PatchDirection::Apply, rom, patches)
+}
+
+/// Apply or revert a patch chain. When reverting, `input` is the output of the last patch and
+/// `patches` are reverted in reverse order, so the same `patches` list works for both directions.
+///
+/// Every patch is parsed and checked to follow the previous one with [`Patch::can_compose`]
+/// before patching, so broken chains fail early.
+pub fn patch_chain<P: AsRef<Path>>(
+    direction: PatchDirection,
+    input: &[u8],
+    patches: &[P],
+) -> Result<Vec<u8>, ChainError> {
+    let mut links = Vec::with_capacity(patches.len());
+    for (index, path) in patches.iter().map(AsRef::as_ref).enumerate() {
+        let raw_patch = fs::read(path).map_err(|source| ChainError::Io {
+            index,
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let patch = Patch::parse(&raw_patch).map_err(|source| ChainError::Parse {
+            index,
+            path: path.to_path_buf(),
+            source,
+        })?;
+        if let Some((_, _, previous)) = links.last() {
+            Patch::can_compose(previous, &patch).map_err(|source| ChainError::Compose {
+                index,
+                path: path.to_path_buf(),
+                source,
+            })?;
+        }
+        links.push((index, path, patch));
+    }
+    if direction == PatchDirection::Revert {
+        links.reverse();
+    }
+
+    let mut data = input.to_vec();
+    for (index, path, patch) in links {
+        data = patch
+            .patch(direction, &data)
+            .map_err(|source| ChainError::Patch {
+                index,
+                path: path.to_path_buf(),
+                source,
+            })?;
+    }
+    Ok(data)
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for ChainError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use crate::util::{SerializeIoError, SerializePath};
+        use serde::ser::SerializeStruct;
+
+        let (index, path) = match self {
+            ChainError::Io { index, path, .. }
+            | ChainError::Parse { index, path, .. }
+            | ChainError::Compose { index, path, .. }
+            | ChainError::Patch { index, path, .. } => (index, path),
+        };
+        let mut s = serializer.serialize_struct("ChainError", 4)?;
+        s.serialize_field("kind", "chain")?;
+        s.serialize_field("index", index)?;
+        s.serialize_field("path", &SerializePath(path))?;
+        match self {
+            ChainError::Io { source, .. } => {
+                s.serialize_field("error", &SerializeIoError(source))?
+            }
+            ChainError::Parse { source, .. } => s.serialize_field("error", source)?,
+            ChainError::Compose { source, .. } => s.serialize_field("error", source)?,
+            ChainError::Patch { source, .. } => s.serialize_field("error", source)?,
+        }
+        s.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_picks_first_matching_candidate() {
+        let dir = tempfile::tempdir().unwrap();
+        let rom = b"original rom".to_vec();
+        let other_patch = dir.path().join("other.ups");
+        fs::write(&other_patch, Patch::diff(b"other rom", b"x").serialize()).unwrap();
+        let garbage = dir.path().join("garbage.ups");
+        fs::write(&garbage, b"not a patch").unwrap();
+        let first = dir.path().join("first.ups");
+        fs::write(&first, Patch::diff(&rom, b"patched rom").serialize()).unwrap();
+        let second = dir.path().join("second.ups");
+        fs::write(&second, Patch::diff(&rom, b"second").serialize()).unwrap();
+
+        let candidates = [
+            dir.path().join("missing.ups"),
+            garbage,
+            other_patch,
+            first,
+            second,
+        ];
+        assert_eq!(load(&rom, &candidates), b"patched rom");
+    }
+
+    #[test]
+    fn test_numbered_patches_stops_at_gap() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("game.ups");
+        for name in &["game.ups", "game.ups1", "game.ups2", "game.ups4"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        assert_eq!(
+            numbered_patches(&first),
+            vec![
+                first.clone(),
+                dir.path().join("game.ups1"),
+                dir.path().join("game.ups2"),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_patch_chain_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions: [&[u8]; 3] = [b"v0 rom", b"v1 rom!", b"v2"];
+        let first = dir.path().join("game.ups");
+        fs::write(&first, Patch::diff(versions[0], versions[1]).serialize()).unwrap();
+        fs::write(
+            dir.path().join("game.ups1"),
+            Patch::diff(versions[1], versions[2]).serialize(),
+        )
+        .unwrap();
+
+        let chain = numbered_patches(&first);
+        assert_eq!(apply_chain(versions[0], &chain).unwrap(), versions[2]);
+        assert_eq!(
+            patch_chain(PatchDirection::Revert, versions[2], &chain).unwrap(),
+            versions[0],
+        );
+    }
+
+    #[test]
+    fn test_patch_chain_reports_broken_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("game.ups");
+        fs::write(&first, Patch::diff(b"v0", b"v1").serialize()).unwrap();
+        let second = dir.path().join("game.ups1");
+        fs::write(&second, Patch::diff(b"something else", b"v2").serialize()).unwrap();
+
+        let chain = [first.clone(), second.clone()];
+        match apply_chain(b"v0", &chain).unwrap_err() {
+            ChainError::Compose {
+                index,
+                path,
+                source,
+            } => {
+                assert_eq!(index, 1);
+                assert_eq!(path, second);
+                assert_eq!(
+                    source.next_src.crc32,
+                    Checksum::from_bytes(b"something else")
+                );
+            }
+            e => panic!("Expected ChainError::Compose, got {}", e),
+        }
+
+        fs::write(&second, Patch::diff(b"v1", b"v2").serialize()).unwrap();
+        match apply_chain(b"not v0", &chain).unwrap_err() {
+            ChainError::Patch { index, path, .. } => {
+                assert_eq!(index, 0);
+                assert_eq!(path, first);
+            }
+            e => panic!("Expected ChainError::Patch, got {}", e),
+        }
+    }
+
+    #[test]
+    fn test_load_without_match_returns_rom() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = dir.path().join("patch.ups");
+        fs::write(&patch, Patch::diff(b"other rom", b"x").serialize()).unwrap();
+        assert_eq!(load(b"rom", &[patch]), b"rom");
+    }
+}
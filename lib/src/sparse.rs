@@ -0,0 +1,205 @@
+use std::convert::TryFrom;
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Default size of the zero runs [`SparseWriter`] seeks over instead of writing.
+pub const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/// Writer seeking over runs of zeroes instead of writing them, so filesystems supporting sparse
+/// files don't allocate them, e.g. for patches extending a ROM with padding. Use it with
+/// [`Patch::patch_to_writer`](crate::Patch::patch_to_writer) to stream sparse output files.
+///
+/// Only zeroes from a block boundary on are skipped, shorter runs don't make holes anyway. The
+/// skipped ranges read back as zeroes only if they weren't written before, so the inner writer
+/// must start at the end of a file, e.g. a new or truncated one. Filesystems without sparse files
+/// fill them with zeroes, the contents are the same either way.
+///
+/// [`finish`](SparseWriter::finish) must be called once done, it extends the file over trailing
+/// zeroes.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::fs::File;
+/// use std::io::BufWriter;
+/// use ups::{Patch, PatchDirection, SparseWriter};
+///
+/// let patch = Patch::diff(b"rom", &[0; 1 << 20]);
+/// let mut writer = SparseWriter::new(BufWriter::new(File::create("padded.bin")?));
+/// patch.patch_to_writer(PatchDirection::Apply, b"rom", &mut writer)?;
+/// writer.finish()?;
+///
+/// # Ok::<_, Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct SparseWriter<W> {
+    inner: W,
+    block_size: usize,
+    // Absolute position, including the skipped zeroes.
+    pos: u64,
+    // Zeroes skipped since the last write.
+    skipped: u64,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    /// Wrap `inner`, skipping blocks of [`SPARSE_BLOCK_SIZE`] zeroes.
+    pub fn new(inner: W) -> Self {
+        Self::with_block_size(inner, SPARSE_BLOCK_SIZE)
+    }
+
+    /// Wrap `inner`, skipping blocks of `block_size` zeroes. `block_size` should be a multiple of
+    /// the filesystem block size for holes to be made.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is 0.
+    pub fn with_block_size(inner: W, block_size: usize) -> Self {
+        assert!(block_size > 0, "block_size must be positive");
+        SparseWriter {
+            inner,
+            block_size,
+            pos: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Bytes of zeroes skipped since the last write, not yet in the file.
+    pub fn pending_zeroes(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Extend the file over trailing skipped zeroes, flush and return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.skipped > 0 {
+            // Seeking alone doesn't change the file size, the last zero must be written.
+            self.skipped -= 1;
+            self.write_pending()?;
+            self.inner.write_all(&[0])?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        if self.skipped > 0 {
+            let skipped = i64::try_from(self.skipped)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "zero run too long"))?;
+            self.inner.seek(SeekFrom::Current(skipped))?;
+            self.skipped = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let block_size = self.block_size as u64;
+        // Split at block boundaries, writing runs of non-zero blocks at once. Zero runs may span
+        // writes, they start at a boundary.
+        let mut data_start = 0;
+        let mut start = 0;
+        while start < buf.len() {
+            let offset = (self.pos + start as u64) % block_size;
+            let end = std::cmp::min(
+                buf.len(),
+                start.saturating_add((block_size - offset) as usize),
+            );
+            let skip = offset == 0 || (start == 0 && self.skipped > 0);
+            if skip && buf[start..end].iter().all(|&b| b == 0) {
+                if data_start < start {
+                    self.write_pending()?;
+                    self.inner.write_all(&buf[data_start..start])?;
+                }
+                self.skipped += (end - start) as u64;
+                data_start = end;
+            }
+            start = end;
+        }
+        if data_start < buf.len() {
+            self.write_pending()?;
+            self.inner.write_all(&buf[data_start..])?;
+        }
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    // Skipped zeroes aren't written, see `finish`.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    // Records the ranges written, to check which were skipped.
+    #[derive(Default)]
+    struct Recorder {
+        data: Cursor<Vec<u8>>,
+        writes: Vec<(u64, usize)>,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.push((self.data.position(), buf.len()));
+            self.data.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Recorder {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.data.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_sparse_writer() {
+        let mut data = vec![0u8; 40];
+        data[0] = 1;
+        data[13] = 2;
+        data[30] = 3;
+        let mut writer = SparseWriter::with_block_size(Recorder::default(), 8);
+        // Unaligned writes, blocks span them.
+        for chunk in data.chunks(5) {
+            writer.write_all(chunk).unwrap();
+        }
+        assert_eq!(writer.pending_zeroes(), 8);
+        let recorder = writer.finish().unwrap();
+        assert_eq!(recorder.data.get_ref(), &data);
+        let written: Vec<_> = recorder
+            .writes
+            .iter()
+            .map(|&(pos, len)| pos..pos + len as u64)
+            .collect();
+        // Zeroes from 16 to 30 are skipped, the ones before aren't from a block boundary.
+        assert!(written.iter().all(|r| r.end <= 16 || r.start >= 30));
+        assert!(written.contains(&(15..16)));
+        assert_eq!(written.last(), Some(&(39..40)));
+    }
+
+    #[test]
+    fn test_sparse_writer_without_zeroes() {
+        let data: Vec<u8> = (1..=100).collect();
+        let mut writer = SparseWriter::with_block_size(Recorder::default(), 8);
+        writer.write_all(&data).unwrap();
+        let recorder = writer.finish().unwrap();
+        assert_eq!(recorder.data.get_ref(), &data);
+        assert_eq!(recorder.writes, vec![(0, 100)]);
+    }
+
+    #[test]
+    fn test_sparse_writer_all_zeroes() {
+        let mut writer = SparseWriter::with_block_size(Recorder::default(), 8);
+        writer.write_all(&[0; 20]).unwrap();
+        let recorder = writer.finish().unwrap();
+        assert_eq!(recorder.data.get_ref(), &vec![0; 20]);
+        // Only the last zero is written, to extend the file.
+        assert_eq!(recorder.writes, vec![(19, 1)]);
+    }
+}
@@ -0,0 +1,254 @@
+//! Content-addressed storage of patches on disk, e.g. for launchers downloading community patches
+//! and applying them to the ROMs they're made for.
+//!
+//! Patches are stored as `<source CRC32>/<fingerprint>.ups` under the store root, with both values
+//! in lowercase hex. The CRC32 is the usual numeric representation, e.g. `3c2b0f8a`, and the
+//! fingerprint is [`Patch::fingerprint`], so patches making the same changes are only stored once.
+//! Fingerprints aren't cryptographic hashes, so patches are compared before being deduplicated:
+//! different patches with the same fingerprint are stored as `<fingerprint>-1.ups`,
+//! `<fingerprint>-2.ups` and so on.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::fs;
+//! use ups::store::PatchStore;
+//! use ups::{Checksum, Patch};
+//!
+//! let store = PatchStore::new("patches");
+//! store.add(&Patch::parse(&fs::read("hack.ups")?)?)?;
+//!
+//! let rom = fs::read("game.gba")?;
+//! for stored in store.find_for_source(Checksum::from_bytes(&rom))? {
+//!     let patched = stored.load()?.apply(&rom)?;
+//! }
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{Checksum, Patch};
+
+/// Directory of patches stored by fingerprint, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchStore {
+    root: PathBuf,
+}
+
+/// A patch in a [`PatchStore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredPatch {
+    /// [`Patch::fingerprint`] of the patch.
+    pub fingerprint: u64,
+    /// Checksum of the patch source.
+    pub src_checksum: Checksum,
+    /// Path to the patch file.
+    pub path: PathBuf,
+}
+
+impl PatchStore {
+    /// Store rooted at `root`. Directories are created when adding the first patch.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        PatchStore { root: root.into() }
+    }
+
+    /// Root directory of the store.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Store `patch`, returning where it's stored. Patches already in the store, making the same
+    /// changes, are left as they are.
+    pub fn add(&self, patch: &Patch) -> io::Result<StoredPatch> {
+        let fingerprint = patch.fingerprint();
+        let normalized = patch.normalize();
+        let mut n = 0;
+        let stored = loop {
+            let stored = self.stored(patch.src_checksum, fingerprint, n);
+            match fs::read(&stored.path) {
+                // Unreadable patches can't be the same either, keep looking.
+                Ok(raw) => match Patch::parse(&raw) {
+                    Ok(existing) if existing.normalize() == normalized => return Ok(stored),
+                    _ => n += 1,
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break stored,
+                Err(e) => return Err(e),
+            }
+        };
+        let dir = self.source_dir(patch.src_checksum);
+        fs::create_dir_all(&dir)?;
+        // Write to a temporary file first so readers never see partial patches.
+        let tmp_path = dir.join(format!(".{:016x}-{}.tmp", fingerprint, n));
+        fs::write(&tmp_path, patch.serialize())?;
+        fs::rename(&tmp_path, &stored.path)?;
+        Ok(stored)
+    }
+
+    /// Find the patch with the given fingerprint, the first one stored if it collides with others.
+    pub fn get(&self, fingerprint: u64) -> io::Result<Option<StoredPatch>> {
+        for entry in read_dir_or_empty(&self.root)? {
+            let entry = entry?;
+            let src_checksum = match parse_checksum(&entry.file_name().to_string_lossy()) {
+                Some(c) if entry.file_type()?.is_dir() => c,
+                _ => continue,
+            };
+            let stored = self.stored(src_checksum, fingerprint, 0);
+            if stored.path.is_file() {
+                return Ok(Some(stored));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Every stored patch applying to a file with the given checksum, sorted by fingerprint, then
+    /// by the order they were stored in for colliding fingerprints.
+    pub fn find_for_source(&self, checksum: Checksum) -> io::Result<Vec<StoredPatch>> {
+        let mut found = Vec::new();
+        for entry in read_dir_or_empty(&self.source_dir(checksum))? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("ups") {
+                continue;
+            }
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            if let Some(key) = parse_stem(&stem) {
+                found.push((key, path));
+            }
+        }
+        found.sort();
+        Ok(found
+            .into_iter()
+            .map(|((fingerprint, _), path)| StoredPatch {
+                fingerprint,
+                src_checksum: checksum,
+                path,
+            })
+            .collect())
+    }
+
+    fn source_dir(&self, checksum: Checksum) -> PathBuf {
+        self.root.join(format!("{:08x}", checksum.0))
+    }
+
+    // Path of the `n`th patch stored with `fingerprint`, only colliding patches have a suffix.
+    fn stored(&self, src_checksum: Checksum, fingerprint: u64, n: usize) -> StoredPatch {
+        let name = match n {
+            0 => format!("{:016x}.ups", fingerprint),
+            n => format!("{:016x}-{}.ups", fingerprint, n),
+        };
+        StoredPatch {
+            fingerprint,
+            src_checksum,
+            path: self.source_dir(src_checksum).join(name),
+        }
+    }
+}
+
+impl StoredPatch {
+    /// Read and parse the patch. Parse errors are returned as [`io::ErrorKind::InvalidData`].
+    pub fn load(&self) -> io::Result<Patch> {
+        Patch::parse(&fs::read(&self.path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+// Entries of `dir`, none if it doesn't exist yet.
+fn read_dir_or_empty(dir: &Path) -> io::Result<Vec<io::Result<fs::DirEntry>>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => Ok(entries.collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+// Fingerprint and collision number from a stored patch file stem.
+fn parse_stem(stem: &str) -> Option<(u64, usize)> {
+    let (fingerprint, n) = match stem.split_once('-') {
+        Some((fingerprint, n)) if n.bytes().all(|b| b.is_ascii_digit()) => {
+            (fingerprint, n.parse().ok()?)
+        }
+        Some(_) => return None,
+        None => (stem, 0),
+    };
+    Some((u64::from_str_radix(fingerprint, 16).ok()?, n))
+}
+
+fn parse_checksum(name: &str) -> Option<Checksum> {
+    if name.len() != 8 {
+        return None;
+    }
+    u32::from_str_radix(name, 16).ok().map(Checksum)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_add_and_find() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PatchStore::new(dir.path().join("store"));
+        let rom = b"clean rom";
+        let hack = Patch::diff(rom, b"hacked rom");
+        let translation = Patch::diff(rom, b"translated rom");
+
+        assert_eq!(store.find_for_source(hack.src_checksum).unwrap(), vec![]);
+        let stored_hack = store.add(&hack).unwrap();
+        let stored_translation = store.add(&translation).unwrap();
+        store
+            .add(&Patch::diff(b"other rom", b"hacked rom"))
+            .unwrap();
+        // Adding again is a no-op.
+        assert_eq!(store.add(&hack).unwrap(), stored_hack);
+
+        assert_eq!(
+            stored_hack.path,
+            dir.path().join("store").join(format!(
+                "{:08x}/{:016x}.ups",
+                hack.src_checksum.0,
+                hack.fingerprint()
+            )),
+        );
+        assert_eq!(stored_hack.load().unwrap(), hack);
+
+        let mut expected = vec![stored_hack.clone(), stored_translation];
+        expected.sort_by_key(|s| s.fingerprint);
+        assert_eq!(
+            store.find_for_source(Checksum::from_bytes(rom)).unwrap(),
+            expected
+        );
+        assert_eq!(store.get(hack.fingerprint()).unwrap(), Some(stored_hack));
+        assert_eq!(store.get(0).unwrap(), None);
+    }
+
+    #[test]
+    fn test_add_fingerprint_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PatchStore::new(dir.path());
+        let hack = Patch::diff(b"clean rom", b"hacked rom");
+        let path = |name: &str| {
+            dir.path()
+                .join(format!("{:08x}", hack.src_checksum.0))
+                .join(name)
+        };
+        // Different patch under the fingerprint of `hack`, like an FNV collision.
+        fs::create_dir_all(path("")).unwrap();
+        let other = Patch::diff(b"clean rom", b"other rom");
+        let first = format!("{:016x}.ups", hack.fingerprint());
+        fs::write(path(&first), other.serialize()).unwrap();
+
+        let stored = store.add(&hack).unwrap();
+        let second = format!("{:016x}-1.ups", hack.fingerprint());
+        assert_eq!(stored.path, path(&second));
+        assert_eq!(stored.load().unwrap(), hack);
+        assert_eq!(store.add(&hack).unwrap(), stored);
+        assert_eq!(fs::read(path(&first)).unwrap(), other.serialize());
+
+        let found = store.find_for_source(hack.src_checksum).unwrap();
+        let paths: Vec<_> = found.iter().map(|s| s.path.clone()).collect();
+        assert_eq!(paths, [path(&first), path(&second)]);
+        assert!(found.iter().all(|s| s.fingerprint == hack.fingerprint()));
+        assert_eq!(parse_stem("0123-x"), None);
+    }
+}
@@ -0,0 +1,80 @@
+//! Decoding binary data from text, for patches embedded in JSON payloads or pasted around.
+//!
+//! ASCII whitespace is ignored everywhere so wrapped text decodes as is.
+
+/// Decode hex digits, in either case.
+pub fn decode_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits = text
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .map(|c| {
+            (c as char)
+                .to_digit(16)
+                .map(|d| d as u8)
+                .ok_or_else(|| format!("invalid hex digit {:?}", c as char))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if digits.len() % 2 != 0 {
+        return Err("odd number of hex digits".into());
+    }
+    Ok(digits.chunks(2).map(|d| (d[0] << 4) | d[1]).collect())
+}
+
+/// Decode standard or URL-safe base64, with optional padding.
+pub fn decode_base64(text: &str) -> Result<Vec<u8>, String> {
+    let mut sextets = text
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<Vec<_>>();
+    while sextets.last() == Some(&b'=') {
+        sextets.pop();
+    }
+    for c in &mut sextets {
+        *c = match *c {
+            b'A'..=b'Z' => *c - b'A',
+            b'a'..=b'z' => *c - b'a' + 26,
+            b'0'..=b'9' => *c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return Err(format!("invalid base64 character {:?}", *c as char)),
+        };
+    }
+    if sextets.len() % 4 == 1 {
+        return Err("truncated base64 data".into());
+    }
+
+    let mut output = Vec::with_capacity(sextets.len() * 3 / 4);
+    for chunk in sextets.chunks(4) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, s)| acc | u32::from(*s) << (18 - 6 * i));
+        let bytes = bits.to_be_bytes();
+        output.extend_from_slice(&bytes[1..chunk.len()]);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("55 50\n53 31"), Ok(b"UPS1".to_vec()));
+        assert_eq!(decode_hex("deADbeEF"), Ok(vec![0xde, 0xad, 0xbe, 0xef]));
+        assert!(decode_hex("555").is_err());
+        assert!(decode_hex("5g").is_err());
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("VVBTMQ=="), Ok(b"UPS1".to_vec()));
+        assert_eq!(decode_base64("VVBT\nMQ"), Ok(b"UPS1".to_vec()));
+        assert_eq!(decode_base64("+/8="), Ok(vec![0xfb, 0xff]));
+        assert_eq!(decode_base64("-_8"), Ok(vec![0xfb, 0xff]));
+        assert_eq!(decode_base64("aGVsbG8h"), Ok(b"hello!".to_vec()));
+        assert!(decode_base64("VVBTM").is_err());
+        assert!(decode_base64("VV*T").is_err());
+    }
+}
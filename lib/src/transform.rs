@@ -0,0 +1,198 @@
+//! Input normalization applied before checksum verification.
+//!
+//! ROM dumps of the same game often differ in ways unrelated to their contents, like SNES copier
+//! headers or N64 byte orders. An [`InputTransform`] normalizes such differences so patches made
+//! against the canonical dump still apply. Transforms can be combined with
+//! [`then`](InputTransform::then), and frontends can implement their own.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::fs;
+//! use ups::transform::{InputTransform, N64ByteOrder, SnesHeader};
+//! use ups::Patch;
+//!
+//! let mut rom = fs::read("game.bin")?;
+//! SnesHeader.then(N64ByteOrder).transform(&mut rom);
+//! let patch = Patch::parse(&fs::read("hack.ups")?)?;
+//! let output = patch.apply(&rom)?;
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+
+/// Normalization applied to input files before patching.
+pub trait InputTransform {
+    /// Short name for diagnostics.
+    fn name(&self) -> &str;
+
+    /// Transform `data` in place. Transforms should leave data they don't recognize untouched.
+    fn transform(&self, data: &mut Vec<u8>);
+
+    /// Chain another transform after this one.
+    fn then<T: InputTransform>(self, next: T) -> Chain<Self, T>
+    where
+        Self: Sized,
+    {
+        Chain {
+            first: self,
+            second: next,
+        }
+    }
+}
+
+impl<T: InputTransform + ?Sized> InputTransform for &T {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn transform(&self, data: &mut Vec<u8>) {
+        (**self).transform(data)
+    }
+}
+
+impl<T: InputTransform + ?Sized> InputTransform for Box<T> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn transform(&self, data: &mut Vec<u8>) {
+        (**self).transform(data)
+    }
+}
+
+/// Two transforms applied in sequence, see [`InputTransform::then`].
+#[derive(Debug, Clone, Copy)]
+pub struct Chain<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: InputTransform, B: InputTransform> InputTransform for Chain<A, B> {
+    fn name(&self) -> &str {
+        "chain"
+    }
+
+    fn transform(&self, data: &mut Vec<u8>) {
+        self.first.transform(data);
+        self.second.transform(data);
+    }
+}
+
+/// Size of SNES copier headers.
+const SNES_HEADER_SIZE: usize = 512;
+
+/// Strips 512-byte SNES copier headers. Headers are detected by file size: SNES ROMs are multiples
+/// of 1 KiB, so a size with 512 extra bytes has a header.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SnesHeader;
+
+impl SnesHeader {
+    /// Whether `data` seems to have a copier header.
+    pub fn detect(data: &[u8]) -> bool {
+        data.len() % 1024 == SNES_HEADER_SIZE
+    }
+}
+
+impl InputTransform for SnesHeader {
+    fn name(&self) -> &str {
+        "snes-header"
+    }
+
+    fn transform(&self, data: &mut Vec<u8>) {
+        if Self::detect(data) {
+            data.drain(..SNES_HEADER_SIZE);
+        }
+    }
+}
+
+/// Byte orders for N64 ROMs, named after their usual file extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum N64Format {
+    /// Big-endian, the native and canonical order.
+    Z64,
+    /// 16-bit byte-swapped.
+    V64,
+    /// 32-bit little-endian.
+    N64,
+}
+
+/// Converts N64 ROMs to the canonical big-endian (`.z64`) byte order. The byte order is detected
+/// from the first word of the ROM.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct N64ByteOrder;
+
+impl N64ByteOrder {
+    /// Detect the N64 byte order of `data`, `None` if it doesn't look like an N64 ROM.
+    pub fn detect(data: &[u8]) -> Option<N64Format> {
+        match data.get(..4)? {
+            [0x80, 0x37, 0x12, 0x40] => Some(N64Format::Z64),
+            [0x37, 0x80, 0x40, 0x12] => Some(N64Format::V64),
+            [0x40, 0x12, 0x37, 0x80] => Some(N64Format::N64),
+            _ => None,
+        }
+    }
+}
+
+impl InputTransform for N64ByteOrder {
+    fn name(&self) -> &str {
+        "n64-byte-order"
+    }
+
+    fn transform(&self, data: &mut Vec<u8>) {
+        match Self::detect(data) {
+            Some(N64Format::V64) => data.chunks_exact_mut(2).for_each(|c| c.swap(0, 1)),
+            Some(N64Format::N64) => data.chunks_exact_mut(4).for_each(|c| c.reverse()),
+            Some(N64Format::Z64) | None => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_snes_header() {
+        let rom: Vec<u8> = (0..2048u32).map(|i| i as u8).collect();
+        let mut headered = vec![0xff; SNES_HEADER_SIZE];
+        headered.extend_from_slice(&rom);
+
+        SnesHeader.transform(&mut headered);
+        assert_eq!(headered, rom);
+        // Already unheadered
+        SnesHeader.transform(&mut headered);
+        assert_eq!(headered, rom);
+    }
+
+    #[test]
+    fn test_n64_byte_orders() {
+        let z64 = vec![0x80, 0x37, 0x12, 0x40, 1, 2, 3, 4];
+        let v64 = vec![0x37, 0x80, 0x40, 0x12, 2, 1, 4, 3];
+        let n64 = vec![0x40, 0x12, 0x37, 0x80, 4, 3, 2, 1];
+        for mut rom in [z64.clone(), v64, n64] {
+            N64ByteOrder.transform(&mut rom);
+            assert_eq!(rom, z64);
+        }
+    }
+
+    #[test]
+    fn test_chain_and_dyn_transforms() {
+        struct Append(u8);
+        impl InputTransform for Append {
+            fn name(&self) -> &str {
+                "append"
+            }
+            fn transform(&self, data: &mut Vec<u8>) {
+                data.push(self.0);
+            }
+        }
+
+        let mut data = Vec::new();
+        Append(1).then(Append(2)).transform(&mut data);
+        let transforms: Vec<Box<dyn InputTransform>> = vec![Box::new(Append(3))];
+        for t in &transforms {
+            t.transform(&mut data);
+        }
+        assert_eq!(data, vec![1, 2, 3]);
+    }
+}
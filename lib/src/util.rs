@@ -1,3 +1,4 @@
+use std::fmt::{self, Display, Formatter};
 use std::ops::Range;
 
 #[cfg(test)]
@@ -36,8 +37,84 @@ impl<'a> Iterator for SliceDiffs<'a> {
     }
 }
 
+/// Displays a size in bytes with binary units, e.g. `512 B`, `1.5 KiB` or `16 MiB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub usize);
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut size = self.0 as f64 / 1024.0;
+        let mut unit = UNITS[0];
+        for next_unit in &UNITS[1..] {
+            if size < 1024.0 {
+                break;
+            }
+            size /= 1024.0;
+            unit = next_unit;
+        }
+        if size.fract() == 0.0 {
+            write!(f, "{} {}", size, unit)
+        } else {
+            write!(f, "{:.1} {}", size, unit)
+        }
+    }
+}
+
+/// Serializes an `io::Error` as `{"kind": "io", "reason": "<message>"}`, matching the other error
+/// types.
+#[cfg(feature = "serde")]
+pub struct SerializeIoError<'a>(pub &'a std::io::Error);
+
+#[cfg(feature = "serde")]
+impl<'a> serde::Serialize for SerializeIoError<'a> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("IoError", 2)?;
+        s.serialize_field("kind", "io")?;
+        s.serialize_field("reason", &self.0.to_string())?;
+        s.end()
+    }
+}
+
+/// Serializes a path losslessly. Valid UTF-8 paths are plain strings, others are objects with a
+/// `lossy` string for display and the raw OS string: `bytes` on Unix and UTF-16 `wide` units on
+/// Windows, e.g. `{"lossy": "\u{fffd}.gba", "bytes": [130, 46, 103, 98, 97]}`.
+#[cfg(feature = "serde")]
+pub struct SerializePath<'a>(pub &'a std::path::Path);
+
+#[cfg(feature = "serde")]
+impl<'a> serde::Serialize for SerializePath<'a> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        if let Some(path) = self.0.to_str() {
+            return serializer.serialize_str(path);
+        }
+        let mut s = serializer.serialize_struct("Path", 2)?;
+        s.serialize_field("lossy", &self.0.to_string_lossy())?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            s.serialize_field("bytes", self.0.as_os_str().as_bytes())?;
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::ffi::OsStrExt;
+            let wide: Vec<u16> = self.0.as_os_str().encode_wide().collect();
+            s.serialize_field("wide", &wide)?;
+        }
+        s.end()
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::ByteSize;
     use proptest::test_runner::{Reason, TestCaseError};
     use std::fmt::Debug;
 
@@ -51,10 +128,12 @@ mod test {
         // Since std::ops::Try is still unstable.
         fn into_result(self) -> Result<Self::Ok, Self::Error>;
 
+        #[allow(dead_code)]
         fn prop_expect(self, msg: impl Into<Reason>) -> Result<Self::Ok, TestCaseError> {
             self.into_result().map_err(|_| TestCaseError::fail(msg))
         }
 
+        #[allow(dead_code)]
         fn prop_expect_err(self, msg: impl Into<Reason>) -> Result<Self::Error, TestCaseError> {
             match self.into_result() {
                 Ok(_) => Err(TestCaseError::fail(msg)),
@@ -75,6 +154,31 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_byte_size_display() {
+        assert_eq!(ByteSize(512).to_string(), "512 B");
+        assert_eq!(ByteSize(1536).to_string(), "1.5 KiB");
+        assert_eq!(ByteSize(16 * 1024 * 1024).to_string(), "16 MiB");
+        assert_eq!(ByteSize(3 * 1024 * 1024 * 1024).to_string(), "3 GiB");
+    }
+
+    #[cfg(all(feature = "serde", unix))]
+    #[test]
+    fn test_serialize_path() {
+        use super::SerializePath;
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use std::path::Path;
+
+        let json = |path: &Path| serde_json::to_string(&SerializePath(path)).unwrap();
+        assert_eq!(json(Path::new("roms/ゲーム.gba")), r#""roms/ゲーム.gba""#);
+        let path = Path::new(OsStr::from_bytes(b"\x82.gba"));
+        assert_eq!(
+            json(path),
+            "{\"lossy\":\"\u{fffd}.gba\",\"bytes\":[130,46,103,98,97]}",
+        );
+    }
+
     impl<T: Debug> ProptestUnwrapExt for Option<T> {
         type Ok = T;
         type Error = ();
@@ -1,11 +1,41 @@
-pub fn read_bytes(buf: &mut &[u8]) -> Option<usize> {
+//! Variable-length integers as encoded in UPS and BPS files for sizes and block offsets.
+//!
+//! Values are stored in little-endian groups of 7 bits, one per byte. The high bit marks the
+//! **last** byte, which is the opposite of LEB128. After each non-final byte, the remaining value
+//! is decremented by one, so every value has exactly one encoding and there are no redundant
+//! trailing groups. For example 127 is `[0xff]` and 128 is `[0x00, 0x80]`.
+//!
+//! ## Example
+//!
+//! ```
+//! use ups::varint;
+//!
+//! let mut buf = Vec::new();
+//! varint::write(&mut buf, 300);
+//! assert_eq!(buf, [0x2c, 0x81]);
+//! assert_eq!(varint::encoded_len(300), buf.len());
+//!
+//! let mut input = &buf[..];
+//! assert_eq!(varint::read(&mut input), Some(300));
+//! assert!(input.is_empty());
+//! ```
+use std::convert::TryFrom;
+
+/// Maximum encoded length of a `u64`, and so of a `usize`, at 7 bits per byte.
+pub const MAX_LEN: usize = 10;
+
+/// Decode a varint from the start of `buf`, advancing it past the encoded bytes. Returns `None`
+/// if `buf` ends before the last byte or the value overflows a `usize`.
+pub fn read(buf: &mut &[u8]) -> Option<usize> {
+    read_u64(buf).and_then(|varint| usize::try_from(varint).ok())
+}
+
+/// Same as [`read`] for values up to `u64::MAX`, e.g. file sizes on 32-bit platforms.
+pub fn read_u64(buf: &mut &[u8]) -> Option<u64> {
     let mut varint = 0;
     let mut shift = 0;
     loop {
-        let (c, next_buf) = match buf.split_first() {
-            Some(s) => s,
-            None => return None,
-        };
+        let (c, next_buf) = buf.split_first()?;
         *buf = next_buf;
         if c & 0x80 != 0 {
             varint = varint_add_shifted(varint, c & 0x7f, shift)?;
@@ -19,38 +49,109 @@ pub fn read_bytes(buf: &mut &[u8]) -> Option<usize> {
 
 /// Returns `current + x << shift` checking for overflow.
 #[inline]
-fn varint_add_shifted(current: usize, x: u8, shift: u32) -> Option<usize> {
-    (x as usize)
+fn varint_add_shifted(current: u64, x: u8, shift: u32) -> Option<u64> {
+    // `checked_shl` only checks the shift amount, not bits shifted out.
+    u64::from(x)
         .checked_shl(shift)
+        .filter(|x2| x2 >> shift == u64::from(x))
         .and_then(|x2| current.checked_add(x2))
 }
 
-pub fn write_bytes(buf: &mut Vec<u8>, mut varint: usize) {
+/// Append the encoding of `varint` to `buf`.
+pub fn write(buf: &mut Vec<u8>, varint: usize) {
+    write_u64(buf, varint as u64);
+}
+
+/// Same as [`write`] for values up to `u64::MAX`.
+pub fn write_u64(buf: &mut Vec<u8>, varint: u64) {
+    let mut encoded = [0; MAX_LEN];
+    let len = write_slice_u64(&mut encoded, varint);
+    buf.extend_from_slice(&encoded[..len]);
+}
+
+/// Encode `varint` at the start of `buf`, returning the encoded length.
+///
+/// # Panics
+///
+/// If `buf` is shorter than the encoding, [`MAX_LEN`] bytes are always enough.
+pub fn write_slice(buf: &mut [u8], varint: usize) -> usize {
+    write_slice_u64(buf, varint as u64)
+}
+
+fn write_slice_u64(buf: &mut [u8], mut varint: u64) -> usize {
+    let mut len = 0;
     loop {
         let x = (varint & 0x7f) as u8;
         varint >>= 7;
         if varint == 0 {
-            buf.push(x | 0x80);
-            break;
+            buf[len] = x | 0x80;
+            return len + 1;
         }
-        buf.push(x);
+        buf[len] = x;
+        len += 1;
         varint -= 1;
     }
 }
 
+/// Number of bytes [`write`] takes to encode `varint`.
+pub fn encoded_len(mut varint: usize) -> usize {
+    let mut len = 1;
+    while varint >= 0x80 {
+        varint = (varint >> 7) - 1;
+        len += 1;
+    }
+    len
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    use proptest::collection::vec;
     use proptest::prelude::*;
 
     proptest! {
         #[test]
         fn test_roundtrip(x in any::<usize>()) {
             let serialized = varint_to_vec(x);
-            let deserialized = read_bytes(&mut serialized.as_ref()).unwrap();
+            prop_assert_eq!(serialized.len(), encoded_len(x));
+            let deserialized = read(&mut serialized.as_ref()).unwrap();
             prop_assert_eq!(x, deserialized);
         }
+
+        #[test]
+        fn test_read_garbage(raw in vec(any::<u8>(), 0..16)) {
+            let mut input = &raw[..];
+            if let Some(x) = read(&mut input) {
+                // Encodings are unique, so the consumed bytes are exactly the encoding of `x`.
+                let consumed = raw.len() - input.len();
+                prop_assert_eq!(varint_to_vec(x), &raw[..consumed]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_boundaries() {
+        assert_eq!(varint_to_vec(0), [0x80]);
+        assert_eq!(varint_to_vec(127), [0xff]);
+        assert_eq!(varint_to_vec(128), [0x00, 0x80]);
+        assert_eq!(varint_to_vec(16511), [0x7f, 0xff]);
+        assert_eq!(varint_to_vec(16512), [0x00, 0x00, 0x80]);
+        assert_eq!(encoded_len(usize::MAX), varint_to_vec(usize::MAX).len());
+        assert_eq!(read(&mut &[0x00, 0x00][..]), None);
+    }
+
+    #[test]
+    fn test_u64() {
+        let mut buf = Vec::new();
+        write_u64(&mut buf, u64::MAX);
+        assert_eq!(buf.len(), MAX_LEN);
+        assert_eq!(read_u64(&mut buf.as_ref()), Some(u64::MAX));
+        let mut buf = Vec::new();
+        write_u64(&mut buf, 5 << 30);
+        assert_eq!(read_u64(&mut buf.as_ref()), Some(5 << 30));
+        let expected = usize::try_from(5u64 << 30).ok();
+        assert_eq!(read(&mut buf.as_ref()), expected);
     }
 
     #[test]
@@ -60,12 +161,16 @@ mod test {
         let last = serialized.len() - 1;
         serialized[last] &= 0x7f;
         serialized.push(1);
-        assert_eq!(read_bytes(&mut serialized.as_ref()), None);
+        assert_eq!(read(&mut serialized.as_ref()), None);
+        // Last byte with bits shifted past the top of a u64.
+        let mut shifted_out = vec![0; 9];
+        shifted_out.push(0x82);
+        assert_eq!(read_u64(&mut shifted_out.as_ref()), None);
     }
 
     fn varint_to_vec(varint: usize) -> Vec<u8> {
         let mut result = Vec::new();
-        write_bytes(&mut result, varint);
+        write(&mut result, varint);
         result
     }
 }
@@ -0,0 +1,788 @@
+//! Parse and apply VCDIFF patches, the format written by xdelta3 and usually distributed as
+//! `.xdelta` files.
+//!
+//! VCDIFF patches are a sequence of windows, each one building a part of the output from `ADD`,
+//! `RUN` and `COPY` instructions reading from a segment of the source file, or of the output
+//! written so far, and from the window output itself. The format has no checksums of its own, but
+//! xdelta3 adds an Adler-32 checksum of each window output, which [`VcdiffPatch::apply`] checks.
+//! Without them a patch applied to the wrong file silently produces garbage. Like IPS and BPS
+//! patches, VCDIFF patches can't be reverted, [`VcdiffPatch::to_ups`] converts them to UPS
+//! patches for a given source file.
+//!
+//! xdelta3 secondary compression and custom code tables aren't supported, parsing fails with
+//! [`VcdiffParseError::Unsupported`] if a patch uses them. Patches created with `xdelta3 -S none`
+//! never do. Patches writing more than [`MAX_FILE_SIZE`] bytes, or reading source segments past
+//! it, fail to parse too, since applying them would allocate the declared size upfront.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::fs;
+//! use ups::vcdiff::VcdiffPatch;
+//!
+//! let rom = fs::read("samples/rom.bin")?;
+//! let patch = VcdiffPatch::parse(&fs::read("samples/patch.xdelta")?)?;
+//! fs::write("patched.bin", patch.apply(&rom)?)?;
+//!
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! # Reference
+//!
+//! https://www.rfc-editor.org/rfc/rfc3284
+use std::borrow::Cow;
+use std::fmt::{self, Debug, Formatter};
+
+use crate::{Checksum, MetadataMismatch, Patch, UpsPatchError, UpsPatchErrors};
+
+const MAGIC: &[u8] = &[0xd6, 0xc3, 0xc4, 0x00];
+
+/// Largest output size, and end of the source segments read, 16 GiB. Limited to `isize::MAX`, the
+/// largest `Vec`, on 32-bit platforms.
+pub const MAX_FILE_SIZE: u64 = 1 << 34;
+
+// Header indicator bits, `VCD_APPHEADER` is an xdelta3 extension.
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+
+// Window indicator bits, `VCD_ADLER32` is an xdelta3 extension.
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+const VCD_ADLER32: u8 = 0x04;
+
+// Instruction types in the code table.
+const NOOP: u8 = 0;
+const ADD: u8 = 1;
+const RUN: u8 = 2;
+const COPY: u8 = 3;
+
+// Address cache sizes of the default code table.
+const NEAR_SIZE: usize = 4;
+const SAME_SIZE: usize = 3;
+
+/// VCDIFF patch contents, see the [module docs](self).
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct VcdiffPatch {
+    /// Windows in output order.
+    pub windows: Vec<VcdiffWindow>,
+    /// Application header, xdelta3 stores the source and target file names in it.
+    pub app_header: Option<Vec<u8>>,
+}
+
+/// Window of a [`VcdiffPatch`], building the next `target_len` bytes of the output.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VcdiffWindow {
+    /// Segment copies read from besides the window output, if any.
+    pub source: Option<VcdiffSource>,
+    /// Size of the window output.
+    pub target_len: usize,
+    /// Instructions building the window output, in order.
+    pub instructions: Vec<VcdiffInstruction>,
+    /// Adler-32 checksum of the window output, from xdelta3.
+    pub adler32: Option<u32>,
+}
+
+/// Segment copied from by a [`VcdiffWindow`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VcdiffSource {
+    /// `len` bytes of the source file at `offset`.
+    Source { offset: usize, len: usize },
+    /// `len` bytes of the output of earlier windows at `offset`.
+    Target { offset: usize, len: usize },
+}
+
+/// Instruction in a [`VcdiffWindow`], each one appends `len` bytes to the window output.
+///
+/// Copy addresses are in the window address space: the bytes of the window source segment
+/// followed by the window output.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VcdiffInstruction {
+    /// Copy bytes from the patch.
+    Add { data: Vec<u8> },
+    /// Repeat `byte` `len` times.
+    Run { len: usize, byte: u8 },
+    /// Copy bytes at `addr`, which may overlap the bytes being written to repeat a pattern.
+    Copy { addr: usize, len: usize },
+}
+
+/// Error from [`VcdiffPatch::parse`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum VcdiffParseError {
+    #[error("this doesn't seem to be a VCDIFF file: {}", .0)]
+    FormatMismatch(String),
+    /// The patch is valid but uses a VCDIFF feature which isn't implemented, secondary
+    /// compression or a custom code table.
+    #[error("unsupported VCDIFF feature: {}", .0)]
+    Unsupported(String),
+}
+
+impl VcdiffSource {
+    /// Size of the segment.
+    pub fn len(&self) -> usize {
+        match self {
+            VcdiffSource::Source { len, .. } | VcdiffSource::Target { len, .. } => *len,
+        }
+    }
+
+    /// Whether the segment is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl VcdiffInstruction {
+    /// Number of bytes appended to the window output.
+    pub fn len(&self) -> usize {
+        match self {
+            VcdiffInstruction::Add { data } => data.len(),
+            VcdiffInstruction::Run { len, .. } | VcdiffInstruction::Copy { len, .. } => *len,
+        }
+    }
+
+    /// Whether the instruction appends nothing.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl VcdiffPatch {
+    /// Parse a VCDIFF patch, checking copies stay within their windows.
+    pub fn parse(input: &[u8]) -> Result<Self, VcdiffParseError> {
+        let err = |reason: &str| VcdiffParseError::FormatMismatch(reason.into());
+        let mut buf = input
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| err("invalid preamble, expected 0xD6 0xC3 0xC4 0x00"))?;
+        let indicator = read_byte(&mut buf).ok_or_else(|| err("missing header indicator"))?;
+        if indicator & !(VCD_DECOMPRESS | VCD_CODETABLE | VCD_APPHEADER) != 0 {
+            return Err(err("invalid header indicator"));
+        }
+        // Windows may still be uncompressed, only fail if one of them isn't.
+        let compressor = if indicator & VCD_DECOMPRESS != 0 {
+            Some(read_byte(&mut buf).ok_or_else(|| err("missing compressor id"))?)
+        } else {
+            None
+        };
+        if indicator & VCD_CODETABLE != 0 {
+            return Err(VcdiffParseError::Unsupported("custom code table".into()));
+        }
+        let app_header = if indicator & VCD_APPHEADER != 0 {
+            let len = read_int(&mut buf).ok_or_else(|| err("invalid application header size"))?;
+            let header = take(&mut buf, len)
+                .ok_or_else(|| err("application header past the end of the file"))?;
+            Some(header.to_vec())
+        } else {
+            None
+        };
+
+        let table = default_code_table();
+        let mut windows = Vec::new();
+        let mut dst_size = 0usize;
+        while !buf.is_empty() {
+            let window = parse_window(&mut buf, windows.len(), dst_size, compressor, &table)?;
+            dst_size = dst_size
+                .checked_add(window.target_len)
+                .ok_or_else(|| err("output too large"))?;
+            windows.push(window);
+        }
+        Ok(VcdiffPatch {
+            windows,
+            app_header,
+        })
+    }
+
+    /// Size of the output.
+    pub fn dst_size(&self) -> usize {
+        self.windows.iter().map(|w| w.target_len).sum()
+    }
+
+    /// Smallest source file size the patch can be applied to, the end of the last source byte
+    /// it reads.
+    pub fn min_src_size(&self) -> usize {
+        self.windows
+            .iter()
+            .filter_map(|w| match w.source {
+                Some(VcdiffSource::Source { offset, len }) => Some(offset + len),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Apply patch to source data. Returns the contents of the patched file.
+    ///
+    /// Sources shorter than [`min_src_size`](VcdiffPatch::min_src_size) are read as if padded
+    /// with zeroes and return a [`SourceMetadataMismatch`](UpsPatchError::SourceMetadataMismatch)
+    /// error. Window checksum mismatches are returned as a
+    /// [`DestMetadataMismatch`](UpsPatchError::DestMetadataMismatch) error holding the Adler-32
+    /// checksums of the first mismatching window.
+    pub fn apply(&self, src: &[u8]) -> Result<Vec<u8>, UpsPatchErrors> {
+        let mut errors = Vec::new();
+        let min_src_size = self.min_src_size();
+        if src.len() < min_src_size {
+            errors.push(UpsPatchError::SourceMetadataMismatch(
+                MetadataMismatch::Size {
+                    expected: min_src_size,
+                    actual: src.len(),
+                },
+            ));
+        }
+
+        let mut output = Vec::with_capacity(self.dst_size());
+        let mut checksum_error = None;
+        for window in &self.windows {
+            let segment = match window.source {
+                Some(VcdiffSource::Source { offset, len }) => match src.get(offset..offset + len) {
+                    Some(segment) => Cow::Borrowed(segment),
+                    None => {
+                        let mut padded = src.get(offset..).unwrap_or_default().to_vec();
+                        padded.resize(len, 0);
+                        Cow::Owned(padded)
+                    }
+                },
+                Some(VcdiffSource::Target { offset, len }) => {
+                    Cow::Owned(output[offset..offset + len].to_vec())
+                }
+                None => Cow::Borrowed(&[][..]),
+            };
+            let start = output.len();
+            for instruction in &window.instructions {
+                match instruction {
+                    VcdiffInstruction::Add { data } => output.extend_from_slice(data),
+                    VcdiffInstruction::Run { len, byte } => {
+                        output.resize(output.len() + len, *byte);
+                    }
+                    VcdiffInstruction::Copy { addr, len } => match segment.get(*addr..addr + len) {
+                        Some(bytes) => output.extend_from_slice(bytes),
+                        // Byte by byte, the copy may read what it just wrote.
+                        None => {
+                            for i in *addr..addr + len {
+                                let byte = match segment.get(i) {
+                                    Some(byte) => *byte,
+                                    None => output[start + i - segment.len()],
+                                };
+                                output.push(byte);
+                            }
+                        }
+                    },
+                }
+            }
+            if let (None, Some(expected)) = (&checksum_error, window.adler32) {
+                checksum_error = MetadataMismatch::checksum(
+                    Checksum(expected),
+                    Checksum(adler32(&output[start..])),
+                );
+            }
+        }
+
+        if let Some(err) = checksum_error {
+            errors.push(UpsPatchError::DestMetadataMismatch(err));
+        }
+        UpsPatchErrors::check_errors(output, errors)
+    }
+
+    /// Equivalent UPS patch for `src`, which can be checked and reverted.
+    pub fn to_ups(&self, src: &[u8]) -> Result<Patch, UpsPatchErrors> {
+        Ok(Patch::diff(src, &self.apply(src)?))
+    }
+}
+
+impl Debug for VcdiffPatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("VcdiffPatch")
+            .field("windows", &self.windows.len())
+            .field(
+                "app_header",
+                &self.app_header.as_deref().map(String::from_utf8_lossy),
+            )
+            .field("dst_size", &self.dst_size())
+            .finish()
+    }
+}
+
+fn parse_window(
+    buf: &mut &[u8],
+    index: usize,
+    written: usize,
+    compressor: Option<u8>,
+    table: &CodeTable,
+) -> Result<VcdiffWindow, VcdiffParseError> {
+    let err =
+        |reason: &str| VcdiffParseError::FormatMismatch(format!("window {}: {}", index, reason));
+    let truncated = || err("truncated");
+
+    let indicator = read_byte(buf).ok_or_else(truncated)?;
+    if indicator & !(VCD_SOURCE | VCD_TARGET | VCD_ADLER32) != 0
+        || indicator & (VCD_SOURCE | VCD_TARGET) == VCD_SOURCE | VCD_TARGET
+    {
+        return Err(err("invalid window indicator"));
+    }
+    let source = if indicator & (VCD_SOURCE | VCD_TARGET) != 0 {
+        let len = read_int(buf).ok_or_else(truncated)?;
+        let offset = read_int(buf).ok_or_else(truncated)?;
+        let end = offset.checked_add(len);
+        if indicator & VCD_SOURCE != 0 {
+            end.filter(|&end| end <= max_file_size())
+                .ok_or_else(|| err("source segment past the maximum supported size"))?;
+            Some(VcdiffSource::Source { offset, len })
+        } else {
+            end.filter(|&end| end <= written)
+                .ok_or_else(|| err("target segment past the output written so far"))?;
+            Some(VcdiffSource::Target { offset, len })
+        }
+    } else {
+        None
+    };
+
+    let delta_len = read_int(buf).ok_or_else(truncated)?;
+    let mut delta = take(buf, delta_len).ok_or_else(|| err("past the end of the file"))?;
+    let target_len = read_int(&mut delta)
+        .ok_or_else(truncated)?
+        .checked_add(written)
+        .filter(|&end| end <= max_file_size())
+        .ok_or_else(|| err("output larger than the maximum supported size"))?
+        - written;
+    let delta_indicator = read_byte(&mut delta).ok_or_else(truncated)?;
+    if delta_indicator != 0 {
+        let name = match compressor {
+            Some(1) => "DJW".to_string(),
+            Some(2) => "LZMA".to_string(),
+            Some(16) => "FGK".to_string(),
+            Some(id) => format!("id {}", id),
+            None => return Err(err("compressed sections without a compressor")),
+        };
+        return Err(VcdiffParseError::Unsupported(format!(
+            "window {} uses {} secondary compression",
+            index, name
+        )));
+    }
+    let data_len = read_int(&mut delta).ok_or_else(truncated)?;
+    let inst_len = read_int(&mut delta).ok_or_else(truncated)?;
+    let addr_len = read_int(&mut delta).ok_or_else(truncated)?;
+    let adler32 = if indicator & VCD_ADLER32 != 0 {
+        let bytes = take(&mut delta, 4).ok_or_else(truncated)?;
+        Some(bytes.iter().fold(0, |n, &b| (n << 8) | u32::from(b)))
+    } else {
+        None
+    };
+    let mut data = take(&mut delta, data_len).ok_or_else(truncated)?;
+    let mut inst = take(&mut delta, inst_len).ok_or_else(truncated)?;
+    let mut addrs = take(&mut delta, addr_len).ok_or_else(truncated)?;
+    if !delta.is_empty() {
+        return Err(err("unexpected data after the sections"));
+    }
+
+    let source_len = source.map_or(0, |s| s.len());
+    source_len
+        .checked_add(target_len)
+        .ok_or_else(|| err("window too large"))?;
+    let mut cache = AddressCache::default();
+    let mut instructions = Vec::new();
+    let mut out_len = 0usize;
+    while let Some(code) = read_byte(&mut inst) {
+        for &(kind, size, mode) in &table[usize::from(code)] {
+            if kind == NOOP {
+                continue;
+            }
+            let len = match size {
+                0 => read_int(&mut inst).ok_or_else(|| err("invalid instruction size"))?,
+                size => usize::from(size),
+            };
+            out_len
+                .checked_add(len)
+                .filter(|&end| end <= target_len)
+                .ok_or_else(|| err("instructions write past the window size"))?;
+            let instruction = match kind {
+                ADD => VcdiffInstruction::Add {
+                    data: take(&mut data, len)
+                        .ok_or_else(|| err("ADD data past the end of the data section"))?
+                        .to_vec(),
+                },
+                RUN => VcdiffInstruction::Run {
+                    len,
+                    byte: read_byte(&mut data)
+                        .ok_or_else(|| err("RUN byte past the end of the data section"))?,
+                },
+                _ => {
+                    let here = source_len + out_len;
+                    let addr = cache
+                        .decode(&mut addrs, here, mode)
+                        .ok_or_else(|| err("invalid COPY address"))?;
+                    if addr >= here {
+                        return Err(err("copies from out of bounds"));
+                    }
+                    VcdiffInstruction::Copy { addr, len }
+                }
+            };
+            instructions.push(instruction);
+            out_len += len;
+        }
+    }
+    if out_len != target_len {
+        return Err(err(&format!(
+            "instructions write {} bytes, expected {}",
+            out_len, target_len
+        )));
+    }
+    if !data.is_empty() || !addrs.is_empty() {
+        return Err(err("unused data or addresses"));
+    }
+    Ok(VcdiffWindow {
+        source,
+        target_len,
+        instructions,
+        adler32,
+    })
+}
+
+// `MAX_FILE_SIZE` as a `usize`.
+fn max_file_size() -> usize {
+    std::cmp::min(MAX_FILE_SIZE, isize::MAX as u64) as usize
+}
+
+// Pair of (type, size, mode) instructions for each opcode, size 0 means the size follows in the
+// instructions section.
+type CodeTable = [[(u8, u8, u8); 2]; 256];
+
+// Default code table from RFC 3284 section 5.6.
+fn default_code_table() -> CodeTable {
+    let mut table = [[(NOOP, 0, 0); 2]; 256];
+    table[0][0] = (RUN, 0, 0);
+    let mut i = 1;
+    for size in 0..=17 {
+        table[i][0] = (ADD, size, 0);
+        i += 1;
+    }
+    let modes = (2 + NEAR_SIZE + SAME_SIZE) as u8;
+    for mode in 0..modes {
+        table[i][0] = (COPY, 0, mode);
+        i += 1;
+        for size in 4..=18 {
+            table[i][0] = (COPY, size, mode);
+            i += 1;
+        }
+    }
+    for mode in 0..modes {
+        // Near modes combine with 3 copy sizes, same modes only with one.
+        let copy_sizes = if usize::from(mode) < 2 + NEAR_SIZE {
+            4..=6
+        } else {
+            4..=4
+        };
+        for add_size in 1..=4 {
+            for copy_size in copy_sizes.clone() {
+                table[i] = [(ADD, add_size, 0), (COPY, copy_size, mode)];
+                i += 1;
+            }
+        }
+    }
+    for mode in 0..modes {
+        table[i] = [(COPY, 4, mode), (ADD, 1, 0)];
+        i += 1;
+    }
+    debug_assert_eq!(i, 256);
+    table
+}
+
+// Recently used addresses, COPY instructions encode theirs relative to them.
+struct AddressCache {
+    near: [usize; NEAR_SIZE],
+    next_slot: usize,
+    same: [usize; SAME_SIZE * 256],
+}
+
+impl Default for AddressCache {
+    fn default() -> Self {
+        AddressCache {
+            near: [0; NEAR_SIZE],
+            next_slot: 0,
+            same: [0; SAME_SIZE * 256],
+        }
+    }
+}
+
+impl AddressCache {
+    fn decode(&mut self, addrs: &mut &[u8], here: usize, mode: u8) -> Option<usize> {
+        let mode = usize::from(mode);
+        let addr = match mode {
+            0 => read_int(addrs)?,
+            1 => here.checked_sub(read_int(addrs)?)?,
+            m if m < 2 + NEAR_SIZE => self.near[m - 2].checked_add(read_int(addrs)?)?,
+            m => {
+                let slot = (m - 2 - NEAR_SIZE) * 256 + usize::from(read_byte(addrs)?);
+                *self.same.get(slot)?
+            }
+        };
+        self.near[self.next_slot] = addr;
+        self.next_slot = (self.next_slot + 1) % NEAR_SIZE;
+        self.same[addr % (SAME_SIZE * 256)] = addr;
+        Some(addr)
+    }
+}
+
+// VCDIFF integers are big-endian base 128, with the high bit set on every byte but the last.
+fn read_int(buf: &mut &[u8]) -> Option<usize> {
+    let mut n = 0usize;
+    loop {
+        let byte = read_byte(buf)?;
+        n = n.checked_mul(128)? | usize::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+}
+
+fn read_byte(buf: &mut &[u8]) -> Option<u8> {
+    let (&byte, rest) = buf.split_first()?;
+    *buf = rest;
+    Some(byte)
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Some(head)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    // Most bytes summed before `b` could overflow.
+    const CHUNK: usize = 5552;
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(CHUNK) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Encoded window, with the sections given as raw bytes.
+    fn window(
+        indicator: u8,
+        segment: Option<(usize, usize)>,
+        target_len: usize,
+        adler32: Option<u32>,
+        sections: [&[u8]; 3],
+    ) -> Vec<u8> {
+        let mut delta = Vec::new();
+        write_int(&mut delta, target_len);
+        delta.push(0);
+        for section in &sections {
+            write_int(&mut delta, section.len());
+        }
+        if let Some(checksum) = adler32 {
+            delta.extend_from_slice(&checksum.to_be_bytes());
+        }
+        for section in &sections {
+            delta.extend_from_slice(section);
+        }
+        let mut raw = vec![indicator | if adler32.is_some() { VCD_ADLER32 } else { 0 }];
+        if let Some((len, offset)) = segment {
+            write_int(&mut raw, len);
+            write_int(&mut raw, offset);
+        }
+        write_int(&mut raw, delta.len());
+        raw.extend_from_slice(&delta);
+        raw
+    }
+
+    fn write_int(buf: &mut Vec<u8>, mut n: usize) {
+        let mut bytes = vec![(n & 0x7f) as u8];
+        n >>= 7;
+        while n > 0 {
+            bytes.push((n & 0x7f) as u8 | 0x80);
+            n >>= 7;
+        }
+        buf.extend(bytes.iter().rev());
+    }
+
+    // The example from RFC 3284 section 3, then a window repeating "wxyz" from the output.
+    fn sample() -> (&'static [u8], &'static [u8], Vec<u8>) {
+        let src = b"abcdefghijklmnop";
+        let dst = b"abcdwxyzefghefghefghefghzzzzwxyzwxyz";
+        let mut raw = MAGIC.to_vec();
+        raw.push(0);
+        raw.extend(window(
+            VCD_SOURCE,
+            Some((16, 0)),
+            28,
+            Some(0xa7fc0bbd),
+            [
+                b"wxyzz",
+                // COPY 4 self, ADD 4 + COPY 4 near[0], COPY 12 here, RUN with size 4.
+                &[20, 196, 44, 0, 4],
+                &[0, 4, 4],
+            ],
+        ));
+        raw.extend(window(
+            VCD_TARGET,
+            Some((8, 0)),
+            8,
+            None,
+            // COPY 4 self, COPY 4 same[0].
+            [b"", &[20, 116], &[4, 4]],
+        ));
+        (src, dst, raw)
+    }
+
+    #[test]
+    fn test_parse_and_apply() {
+        let (src, dst, raw) = sample();
+        let patch = VcdiffPatch::parse(&raw).unwrap();
+        assert_eq!(
+            patch.windows[0].instructions,
+            vec![
+                VcdiffInstruction::Copy { addr: 0, len: 4 },
+                VcdiffInstruction::Add {
+                    data: b"wxyz".to_vec()
+                },
+                VcdiffInstruction::Copy { addr: 4, len: 4 },
+                VcdiffInstruction::Copy { addr: 24, len: 12 },
+                VcdiffInstruction::Run { len: 4, byte: b'z' },
+            ],
+        );
+        assert_eq!(
+            patch.windows[1].source,
+            Some(VcdiffSource::Target { offset: 0, len: 8 }),
+        );
+        assert_eq!(patch.dst_size(), dst.len());
+        assert_eq!(patch.min_src_size(), src.len());
+        assert_eq!(patch.apply(src).unwrap(), dst);
+
+        let ups = patch.to_ups(src).unwrap();
+        assert_eq!(ups.revert(dst).unwrap(), src);
+    }
+
+    #[test]
+    fn test_apply_wrong_source() {
+        let (src, _, raw) = sample();
+        let patch = VcdiffPatch::parse(&raw).unwrap();
+        // Caught by the checksum of the first window.
+        let errs = patch.apply(b"ABCDEFGHIJKLMNOP").unwrap_err();
+        assert!(errs
+            .iter()
+            .any(|e| matches!(e, UpsPatchError::DestMetadataMismatch(_))));
+
+        // Short sources are padded instead of panicking.
+        let errs = patch.apply(&src[..3]).unwrap_err();
+        assert_eq!(errs.output.len(), patch.dst_size());
+        assert!(errs.iter().any(|e| matches!(
+            e,
+            UpsPatchError::SourceMetadataMismatch(MetadataMismatch::Size {
+                expected: 16,
+                actual: 3
+            })
+        )));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let (_, _, raw) = sample();
+        let header = |indicator: u8| {
+            let mut raw = MAGIC.to_vec();
+            raw.push(indicator);
+            raw
+        };
+        let with_window = |mut raw: Vec<u8>, window: Vec<u8>| {
+            raw.extend(window);
+            raw
+        };
+        let mut huge_run = vec![0];
+        write_int(&mut huge_run, 1 << 35);
+        for raw in [
+            b"UPS1".to_vec(),
+            MAGIC.to_vec(),
+            header(0x80),
+            raw[..raw.len() - 1].to_vec(),
+            // Copies past the window output written so far.
+            with_window(header(0), window(0, None, 4, None, [b"", &[20], &[0]])),
+            // Target segment past the output.
+            with_window(
+                header(0),
+                window(VCD_TARGET, Some((4, 0)), 4, None, [b"abcd", &[5], b""]),
+            ),
+            // Instructions write less than the window size.
+            with_window(header(0), window(0, None, 5, None, [b"abcd", &[5], b""])),
+            // Source segment past the maximum file size.
+            with_window(
+                header(0),
+                window(
+                    VCD_SOURCE,
+                    Some((4, 1 << 35)),
+                    4,
+                    None,
+                    [b"abcd", &[5], b""],
+                ),
+            ),
+            // RUN of 32 GiB, a few bytes declaring more than the maximum output.
+            with_window(
+                header(0),
+                window(0, None, 1 << 35, None, [b"z", &huge_run, b""]),
+            ),
+        ] {
+            assert!(
+                matches!(
+                    VcdiffPatch::parse(&raw),
+                    Err(VcdiffParseError::FormatMismatch(_))
+                ),
+                "{:?}",
+                raw
+            );
+        }
+
+        let mut compressed = header(VCD_DECOMPRESS);
+        compressed.push(2);
+        let mut window = window(0, None, 4, None, [b"abcd", &[5], b""]);
+        // Delta indicator, after the window indicator, delta size and target size.
+        window[3] = 1;
+        compressed.extend(window);
+        for raw in [header(VCD_CODETABLE), compressed] {
+            assert!(
+                matches!(
+                    VcdiffPatch::parse(&raw),
+                    Err(VcdiffParseError::Unsupported(_))
+                ),
+                "{:?}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn test_app_header() {
+        let (src, dst, raw) = sample();
+        let mut with_header = MAGIC.to_vec();
+        with_header.extend_from_slice(&[VCD_DECOMPRESS | VCD_APPHEADER, 2, 4]);
+        with_header.extend_from_slice(b"a//b");
+        with_header.extend_from_slice(&raw[MAGIC.len() + 1..]);
+        // Uncompressed windows apply even if the header names a compressor.
+        let patch = VcdiffPatch::parse(&with_header).unwrap();
+        assert_eq!(patch.app_header.as_deref(), Some(&b"a//b"[..]));
+        assert_eq!(patch.apply(src).unwrap(), dst);
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"abcdwxyzefghefghefghefghzzzz"), 0xa7fc0bbd);
+        // Sums past a chunk must be reduced.
+        assert_eq!(adler32(&[0xff; 100_000]), {
+            let (a, b) = (0..100_000u64).fold((1u64, 0u64), |(a, b), _| {
+                let a = (a + 0xff) % 65521;
+                (a, (b + a) % 65521)
+            });
+            ((b << 16) | a) as u32
+        });
+    }
+}
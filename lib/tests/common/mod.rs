@@ -0,0 +1,35 @@
+//! Helpers shared by the fixture based tests, the fixture formats are described in the README
+//! next to each JSON file.
+#![allow(dead_code)]
+use std::fs;
+
+use serde::de::DeserializeOwned;
+use ups::PatchDirection;
+
+/// Deserialize the JSON fixture at `path`, relative to the `lib` directory.
+pub fn read_fixture<T: DeserializeOwned>(path: &str) -> T {
+    let raw = fs::read_to_string(path).unwrap();
+    serde_json::from_str(&raw).unwrap()
+}
+
+/// Direction of fixture case `name`, either `apply` or `revert`.
+pub fn direction(name: &str, direction: &str) -> PatchDirection {
+    match direction {
+        "apply" => PatchDirection::Apply,
+        "revert" => PatchDirection::Revert,
+        d => panic!("{}: invalid direction {}", name, d),
+    }
+}
+
+/// Decode hex fixture data.
+pub fn from_hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// Encode bytes in the lowercase hex used by fixtures.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
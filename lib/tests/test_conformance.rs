@@ -0,0 +1,51 @@
+mod common;
+
+use serde::Deserialize;
+use ups::Patch;
+
+use common::{direction, from_hex, read_fixture};
+
+#[derive(Deserialize)]
+struct Suite {
+    version: u32,
+    cases: Vec<Case>,
+}
+
+#[derive(Deserialize)]
+struct Case {
+    name: String,
+    patch: String,
+    direction: String,
+    input: String,
+    expected_output: Option<String>,
+    expected_error: Option<Vec<String>>,
+}
+
+#[test]
+fn test_conformance_cases() {
+    let suite: Suite = read_fixture("tests/conformance/cases.json");
+    assert_eq!(suite.version, 1);
+
+    for case in suite.cases {
+        let direction = direction(&case.name, &case.direction);
+        let result = Patch::parse(&from_hex(&case.patch))
+            .map_err(|e| vec![e.kind().to_string()])
+            .and_then(|patch| {
+                patch
+                    .patch(direction, &from_hex(&case.input))
+                    .map_err(|errs| errs.iter().map(|e| e.kind().to_string()).collect())
+            });
+
+        match (result, case.expected_output, case.expected_error) {
+            (Ok(output), Some(expected), None) => {
+                assert_eq!(output, from_hex(&expected), "{}: wrong output", case.name)
+            }
+            (Err(mut errors), None, Some(mut expected)) => {
+                errors.sort();
+                expected.sort();
+                assert_eq!(errors, expected, "{}: wrong errors", case.name);
+            }
+            (result, _, _) => panic!("{}: unexpected result {:?}", case.name, result),
+        }
+    }
+}
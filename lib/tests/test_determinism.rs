@@ -0,0 +1,69 @@
+//! `Patch::diff` must produce byte-identical patches on every platform, so patch artifacts can be
+//! reproduced and signed. These tests pin the serialized output for fixed inputs, CI runs them on
+//! every supported OS.
+mod common;
+
+use ups::{Checksum, Patch};
+
+use common::to_hex;
+
+#[test]
+fn test_diff_small_golden() {
+    let src = b"Hello, world! This is the source file.";
+    let dst = b"Hello, World! This is the patched file, with more data\0\0at the end.";
+    let patch = Patch::diff(src, dst);
+    assert_eq!(patch.apply(src).unwrap(), dst);
+    assert_eq!(to_hex(&patch.serialize()), GOLDEN_SMALL);
+}
+
+#[test]
+fn test_diff_large_golden() {
+    let (src, dst) = large_inputs();
+    let patch = Patch::diff(&src, &dst);
+    assert_eq!(patch.apply(&src).unwrap(), dst);
+    let serialized = patch.serialize();
+    assert_eq!(serialized.len(), GOLDEN_LARGE_LEN);
+    assert_eq!(Checksum::from_bytes(&serialized), GOLDEN_LARGE_CHECKSUM);
+}
+
+#[test]
+fn test_diff_is_repeatable() {
+    let (src, dst) = large_inputs();
+    let first = Patch::diff(&src, &dst).serialize();
+    for _ in 0..4 {
+        assert_eq!(Patch::diff(&src, &dst).serialize(), first);
+    }
+}
+
+const GOLDEN_SMALL: &str = "55505331a6c387200091030e01110b008044460f05094b2c2077697468206d6f72652064617461008161742074686520656e642e004bc2ab3f3a6d115fc35207ee";
+const GOLDEN_LARGE_LEN: usize = 117_477;
+const GOLDEN_LARGE_CHECKSUM: Checksum = Checksum(0x2144_df1c);
+
+/// Deterministic pseudo-random source and destination files with scattered edits, a truncated
+/// region and appended data.
+fn large_inputs() -> (Vec<u8>, Vec<u8>) {
+    let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+    let src: Vec<u8> = (0..256 * 1024).map(|_| rng.next() as u8).collect();
+    let mut dst = src.clone();
+    for _ in 0..512 {
+        let start = (rng.next() % dst.len() as u64) as usize;
+        let len = (rng.next() % 64) as usize;
+        for b in dst.iter_mut().skip(start).take(len) {
+            *b = rng.next() as u8;
+        }
+    }
+    dst.truncate(200 * 1024);
+    dst.extend((0..96 * 1024).map(|i| if i % 7 == 0 { 0 } else { rng.next() as u8 }));
+    (src, dst)
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
@@ -0,0 +1,50 @@
+//! Byte-for-byte stability of serialized patches, see `tests/golden/README.md`.
+mod common;
+
+use serde::Deserialize;
+use ups::Patch;
+
+use common::{from_hex, read_fixture, to_hex};
+
+#[derive(Deserialize)]
+struct Golden {
+    version: u32,
+    diff: Vec<DiffCase>,
+    roundtrip: Vec<RoundtripCase>,
+}
+
+#[derive(Deserialize)]
+struct DiffCase {
+    name: String,
+    src: String,
+    dst: String,
+    expected_patch: String,
+}
+
+#[derive(Deserialize)]
+struct RoundtripCase {
+    name: String,
+    patch: String,
+}
+
+#[test]
+fn test_golden_serialize() {
+    let golden: Golden = read_fixture("tests/golden/serialize.json");
+    assert_eq!(golden.version, 1);
+
+    for case in golden.diff {
+        let patch = Patch::diff(&from_hex(&case.src), &from_hex(&case.dst));
+        assert_serializes_to(&patch, &case.expected_patch, &case.name);
+    }
+    for case in golden.roundtrip {
+        let patch = Patch::parse(&from_hex(&case.patch)).unwrap();
+        assert_serializes_to(&patch, &case.patch, &case.name);
+    }
+}
+
+fn assert_serializes_to(patch: &Patch, expected: &str, name: &str) {
+    assert_eq!(to_hex(&patch.serialize()), expected, "{}: serialize", name);
+    let mut vectored = Vec::new();
+    patch.write_vectored(&mut vectored).unwrap();
+    assert_eq!(to_hex(&vectored), expected, "{}: write_vectored", name);
+}
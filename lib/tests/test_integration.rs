@@ -28,9 +28,9 @@ fn test_samples() {
         assert_eq!(src, &reverted);
         let diff = Patch::diff(&*src, &patched);
         match patch
-            .blocks
+            .blocks()
             .iter()
-            .zip(&diff.blocks)
+            .zip(diff.blocks())
             .enumerate()
             .find(|(_, (h1, h2))| h1 != h2)
         {
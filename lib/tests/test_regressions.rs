@@ -0,0 +1,112 @@
+//! Regression corpus of edge case patches, see `tests/regressions/README.md`.
+mod common;
+
+use std::io::{Cursor, Read};
+
+use serde::Deserialize;
+use ups::{Patch, PatchDirection, PatchedReader};
+
+use common::{direction, from_hex, read_fixture};
+
+// Same limit as `ups_cli::corpus::SIZE_LIMIT`.
+const SIZE_LIMIT: usize = 16 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct Corpus {
+    version: u32,
+    cases: Vec<Case>,
+}
+
+#[derive(Deserialize)]
+struct Case {
+    name: String,
+    patch: String,
+    direction: String,
+    input: String,
+    expected_error: Vec<String>,
+}
+
+#[test]
+fn test_regression_corpus() {
+    let corpus: Corpus = read_fixture("tests/regressions/cases.json");
+    assert_eq!(corpus.version, 1);
+
+    for case in corpus.cases {
+        let direction = direction(&case.name, &case.direction);
+        let errors = run_case(
+            &case.name,
+            &from_hex(&case.patch),
+            direction,
+            &from_hex(&case.input),
+        );
+        assert_eq!(errors, case.expected_error, "{}: wrong errors", case.name);
+    }
+}
+
+// Errors produced by the case, sorted, after checking every API agrees on it.
+fn run_case(name: &str, raw: &[u8], direction: PatchDirection, input: &[u8]) -> Vec<String> {
+    let patch = match Patch::parse(raw) {
+        Ok(patch) => patch,
+        Err(e) => return vec![e.kind().to_string()],
+    };
+    // None of these look at the file sizes.
+    let _ = patch.block_offsets().changed_ranges().count();
+    let _ = patch.fingerprint();
+    let _ = patch.requirements();
+    let _ = patch.preflight(input.len(), ups::Checksum::from_bytes(input));
+    let reparsed = Patch::parse(&patch.serialize()).ok();
+    assert_eq!(
+        reparsed.as_ref(),
+        Some(&patch),
+        "{}: serialize roundtrip",
+        name
+    );
+    if patch.src_size > SIZE_LIMIT || patch.dst_size > SIZE_LIMIT {
+        return vec!["size_limit".to_string()];
+    }
+
+    let result = patch.patch(direction, input);
+    let mut in_place = input.to_vec();
+    let in_place_result = patch.patch_in_place(direction, &mut in_place);
+    let mut streamed = Vec::new();
+    let streamed_result = patch.patch_to_writer(direction, input, &mut streamed);
+    let mut read = Vec::new();
+    PatchedReader::with_direction(&patch, direction, Cursor::new(input))
+        .read_to_end(&mut read)
+        .unwrap();
+    let normalized = patch.normalize().patch(direction, input);
+    match &result {
+        Ok(output) => {
+            assert!(in_place_result.is_ok(), "{}: patch_in_place failed", name);
+            assert!(streamed_result.is_ok(), "{}: patch_to_writer failed", name);
+            assert_eq!(&in_place, output, "{}: patch_in_place output", name);
+            assert_eq!(&streamed, output, "{}: patch_to_writer output", name);
+            assert_eq!(&read, output, "{}: PatchedReader output", name);
+            assert_eq!(
+                normalized.ok().as_ref(),
+                Some(output),
+                "{}: normalized output",
+                name
+            );
+        }
+        Err(_) => {
+            assert!(
+                in_place_result.is_err(),
+                "{}: patch_in_place succeeded",
+                name
+            );
+            assert!(
+                streamed_result.is_err(),
+                "{}: patch_to_writer succeeded",
+                name
+            );
+        }
+    }
+
+    let mut errors: Vec<_> = match result {
+        Ok(_) => Vec::new(),
+        Err(errs) => errs.iter().map(|e| e.kind().to_string()).collect(),
+    };
+    errors.sort();
+    errors
+}